@@ -0,0 +1,121 @@
+//! FunctionFS descriptors and events
+//!
+//! The function consists of one vendor specific interface with a bulk IN
+//! endpoint, like the bulk log channel of `usb-log`. Interface numbers and
+//! string indices are local to the function; the kernel maps them to the ones
+//! of the composite device, also in the wIndex of requests to the interface.
+//!
+
+const DESCRIPTORS_MAGIC_V2: u32 = 3;
+const STRINGS_MAGIC: u32 = 2;
+const HAS_FS_DESC: u32 = 1;
+const HAS_HS_DESC: u32 = 2;
+
+const LANG_ID_EN_US: u16 = 0x0409;
+
+/// Length of an event read from ep0
+pub const EVENT_LEN: usize = 12;
+
+/// Request type of vendor requests to the interface, without direction
+pub const VENDOR_INTERFACE: u8 = 0x41;
+pub const DIR_IN: u8 = 0x80;
+
+/// Descriptors of the function at full and high speed
+pub fn descriptors() -> Vec<u8> {
+    let mut descs = vec![];
+    for max_packet_size in [64u16, 512] {
+        let size = max_packet_size.to_le_bytes();
+        // interface 0 with one endpoint, named by string 1
+        descs.extend_from_slice(&[9, 4, 0, 0, 1, 0xff, 0, 0, 1]);
+        descs.extend_from_slice(&[7, 5, 0x81, 0x02, size[0], size[1], 0]);
+    }
+    let mut blob = vec![];
+    blob.extend_from_slice(&DESCRIPTORS_MAGIC_V2.to_le_bytes());
+    blob.extend_from_slice(&(12 + 8 + descs.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&(HAS_FS_DESC | HAS_HS_DESC).to_le_bytes());
+    // number of descriptors per speed
+    blob.extend_from_slice(&2u32.to_le_bytes());
+    blob.extend_from_slice(&2u32.to_le_bytes());
+    blob.extend_from_slice(&descs);
+    blob
+}
+
+/// String table containing the interface name
+pub fn strings(interface_name: &str) -> Vec<u8> {
+    let mut blob = vec![];
+    blob.extend_from_slice(&STRINGS_MAGIC.to_le_bytes());
+    blob.extend_from_slice(&(16 + 2 + interface_name.len() as u32 + 1).to_le_bytes());
+    // one string in one language
+    blob.extend_from_slice(&1u32.to_le_bytes());
+    blob.extend_from_slice(&1u32.to_le_bytes());
+    blob.extend_from_slice(&LANG_ID_EN_US.to_le_bytes());
+    blob.extend_from_slice(interface_name.as_bytes());
+    blob.push(0);
+    blob
+}
+
+/// SETUP packet of a control request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+/// Event reported by FunctionFS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Bind,
+    Unbind,
+    Enable,
+    Disable,
+    Setup(Setup),
+    Suspend,
+    Resume,
+    Unknown(u8),
+}
+
+impl Event {
+    /// Parse an event read from ep0
+    pub fn parse(buf: &[u8; EVENT_LEN]) -> Event {
+        let le16 = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        match buf[8] {
+            0 => Event::Bind,
+            1 => Event::Unbind,
+            2 => Event::Enable,
+            3 => Event::Disable,
+            4 => Event::Setup(Setup {
+                request_type: buf[0],
+                request: buf[1],
+                value: le16(2),
+                index: le16(4),
+                length: le16(6),
+            }),
+            5 => Event::Suspend,
+            6 => Event::Resume,
+            other => Event::Unknown(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blobs() {
+        let descs = descriptors();
+        assert_eq!(descs.len(), 20 + 2 * (9 + 7));
+        assert_eq!(&descs[4..8], (descs.len() as u32).to_le_bytes());
+        assert_eq!(&descs[descs.len() - 7..], [7, 5, 0x81, 0x02, 0x00, 0x02, 0]);
+        let strings = strings("kiffielog");
+        assert_eq!(&strings[4..8], (strings.len() as u32).to_le_bytes());
+        assert_eq!(&strings[16..], b"\x09\x04kiffielog\0");
+
+        let setup = [0xc1, 7, 0, 0, 0, 0, 2, 0, 4, 0, 0, 0];
+        let event = Event::parse(&setup);
+        assert!(matches!(event, Event::Setup(Setup { request: 7, length: 2, .. })));
+    }
+}
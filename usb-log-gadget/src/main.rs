@@ -0,0 +1,191 @@
+//! USB Log Gadget
+//!
+//! Implements the log interface of `usb-log` as a Linux USB gadget function
+//! based on FunctionFS, so that embedded Linux boards expose their log to
+//! `usb-logread` like microcontrollers do. The log is read from stdin, or
+//! from the file given by `--input`, and sent over the bulk IN endpoint of
+//! the interface.
+//!
+//! The FunctionFS instance has to be set up by configfs and mounted before
+//! starting the gadget, and the gadget has to be bound to a UDC once the
+//! descriptors have been written:
+//!
+//! ```text
+//! mount -t functionfs kiffielog /dev/ffs-kiffielog
+//! usb-log-gadget /dev/ffs-kiffielog < /var/log/messages &
+//! echo <udc> > /sys/kernel/config/usb_gadget/<gadget>/UDC
+//! ```
+//!
+//! With the `dummy_hcd` module, whose UDC is connected to a virtual host
+//! controller of the same machine, `usb-logread` can be tested without
+//! hardware.
+//!
+//! The vendor requests for the protocol version, the time echo, pausing and
+//! the reader announcement are answered; the other ones are stalled. Reading
+//! stops while the host has paused logging, and the input waits while no
+//! host reads the endpoint.
+//!
+
+mod ffs;
+
+use clap::Parser;
+use ffs::{Event, Setup, EVENT_LEN};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INTERFACE_NAME: &str = "kiffielog";
+
+const SET_ENABLED_REQUEST: u8 = 2;
+const GET_VERSION_REQUEST: u8 = 7;
+const TIME_ECHO_REQUEST: u8 = 8;
+const SET_READER_REQUEST: u8 = 9;
+
+/// Protocol version spoken by the gadget
+const PROTOCOL_VERSION: u16 = 3;
+
+/// Logging has not been paused by the host
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[derive(Parser)]
+#[command(about = "Provides a USB log interface as a Linux USB gadget function")]
+struct Args {
+    /// Mount point of the FunctionFS instance
+    ffs_dir: PathBuf,
+
+    /// Name of the log interface
+    #[clap(short = 'i', long = "interface-name", default_value = DEFAULT_INTERFACE_NAME)]
+    interface_name: String,
+
+    /// Read the log from FILE instead of stdin
+    #[clap(long = "input", value_name = "FILE")]
+    input: Option<PathBuf>,
+}
+
+/// Answer a control request to the log interface
+///
+/// The data stage of an IN request is written to ep0. An OUT request is
+/// acknowledged by reading its data stage; an access in the opposite
+/// direction stalls the request.
+fn handle_setup(ep0: &mut File, setup: Setup, start: Instant) -> io::Result<()> {
+    let len = setup.length as usize;
+    if setup.request_type & !ffs::DIR_IN != ffs::VENDOR_INTERFACE {
+        return stall(ep0, setup);
+    }
+    if setup.request_type & ffs::DIR_IN != 0 {
+        let mut data = vec![];
+        match setup.request {
+            GET_VERSION_REQUEST => data.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes()),
+            TIME_ECHO_REQUEST => {
+                let now = start.elapsed().as_micros() as u64;
+                data.extend_from_slice(&setup.value.to_le_bytes());
+                data.extend_from_slice(&now.to_le_bytes());
+            }
+            _ => return stall(ep0, setup),
+        }
+        data.truncate(len);
+        return ep0.write_all(&data);
+    }
+    match setup.request {
+        SET_ENABLED_REQUEST => ENABLED.store(setup.value != 0, Ordering::Relaxed),
+        SET_READER_REQUEST => {
+            let state = if setup.value != 0 { "attached" } else { "detached" };
+            eprintln!("reader {state}");
+        }
+        _ => return stall(ep0, setup),
+    }
+    ep0.read_exact(&mut vec![0; len])
+}
+
+/// Stall a control request
+fn stall(ep0: &mut File, setup: Setup) -> io::Result<()> {
+    // the kernel halts ep0 and reports an error, which is expected
+    if setup.request_type & ffs::DIR_IN != 0 {
+        let _ = ep0.read(&mut []);
+    } else {
+        let _ = ep0.write(&[]);
+    }
+    Ok(())
+}
+
+/// Handle the events of ep0
+fn serve_ep0(mut ep0: File) {
+    let start = Instant::now();
+    let mut buf = [0; 4 * EVENT_LEN];
+    loop {
+        let len = match ep0.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Error: cannot read events: {e}");
+                exit(1);
+            }
+        };
+        for chunk in buf[..len].chunks_exact(EVENT_LEN) {
+            match Event::parse(chunk.try_into().unwrap()) {
+                Event::Setup(setup) => {
+                    if let Err(e) = handle_setup(&mut ep0, setup, start) {
+                        eprintln!("Warning: control request failed: {e}");
+                    }
+                }
+                Event::Enable => eprintln!("configured by the host"),
+                Event::Disable => eprintln!("deconfigured"),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let open_ep0 = || {
+        let mut ep0 = OpenOptions::new().read(true).write(true).open(args.ffs_dir.join("ep0"))?;
+        ep0.write_all(&ffs::descriptors())?;
+        ep0.write_all(&ffs::strings(&args.interface_name))?;
+        io::Result::Ok(ep0)
+    };
+    let ep0 = open_ep0().unwrap_or_else(|e| {
+        eprintln!("Error: cannot set up FunctionFS in {}: {e}", args.ffs_dir.display());
+        exit(1);
+    });
+    // the endpoint files appear once the descriptors have been written
+    let ep1 = OpenOptions::new().write(true).open(args.ffs_dir.join("ep1"));
+    let mut ep1 = ep1.unwrap_or_else(|e| {
+        eprintln!("Error: cannot open the IN endpoint: {e}");
+        exit(1);
+    });
+    thread::spawn(move || serve_ep0(ep0));
+
+    let mut input: Box<dyn Read> = match &args.input {
+        Some(path) => Box::new(File::open(path).unwrap_or_else(|e| {
+            eprintln!("Error: cannot open {}: {e}", path.display());
+            exit(1);
+        })),
+        None => Box::new(io::stdin()),
+    };
+    let mut buf = [0; 4096];
+    loop {
+        while !ENABLED.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Error: cannot read the log: {e}");
+                exit(1);
+            }
+        };
+        // writes wait for the host to configure the device and fail if it is
+        // deconfigured during the transfer
+        if let Err(e) = ep1.write_all(&buf[..len]) {
+            eprintln!("Warning: {len} bytes lost: {e}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Backtraces of panics
+//!
+//! Firmware is usually built without unwind tables, so the backtrace is found
+//! by a naive scan of the stack: each word between the stack pointer and the
+//! top of the stack that points into the code is taken as a return address.
+//! This finds the callers of the panicking function but also stale addresses
+//! left on the stack by functions that have already returned, which the
+//! reader has to keep in mind. The addresses are written to the log by
+//! [`LogBuffer::write_backtrace`] so that the host tool can symbolicate them
+//! with the ELF file of the firmware.
+//!
+//! The panic handler of the `panic-handler` feature logs a backtrace once the
+//! scan has been enabled with the bounds of the stack and the code:
+//!
+//! ```ignore
+//! extern "C" {
+//!     static _stack_start: u32;
+//!     static __stext: u32;
+//!     static __etext: u32;
+//! }
+//!
+//! unsafe {
+//!     let stack_top = core::ptr::addr_of!(_stack_start) as usize;
+//!     let code = core::ptr::addr_of!(__stext) as usize..core::ptr::addr_of!(__etext) as usize;
+//!     usb_log::backtrace::enable(log_buffer, stack_top, code);
+//! }
+//! ```
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::frame::MAX_BACKTRACE_LEN;
+use crate::log_buffer::LogBuffer;
+use crate::mutex::Lock;
+use core::mem::size_of;
+use core::ops::Range;
+
+/// Log buffer receiving backtraces, independent of its size
+trait BacktraceWriter: Sync {
+    fn write_backtrace(&self, addresses: &[usize]);
+}
+
+impl<const N: usize> BacktraceWriter for LogBuffer<N> {
+    fn write_backtrace(&self, addresses: &[usize]) {
+        LogBuffer::write_backtrace(self, addresses);
+    }
+}
+
+struct Config {
+    writer: &'static dyn BacktraceWriter,
+    stack_top: usize,
+    code: (usize, usize),
+}
+
+static CONFIG: Lock<Option<Config>> = Lock::new(None);
+
+/// Enable backtraces, which are written to `log_buffer`
+///
+/// `stack_top` is the address above the highest word of the stack and `code`
+/// the address range of the code.
+///
+/// # Safety
+///
+/// The memory from the stack pointer of any caller of [`log_backtrace`] up
+/// to `stack_top` must be readable.
+pub unsafe fn enable<const N: usize>(
+    log_buffer: &'static LogBuffer<N>,
+    stack_top: usize,
+    code: Range<usize>,
+) {
+    CONFIG.lock(|config| {
+        *config = Some(Config {
+            writer: log_buffer,
+            stack_top,
+            code: (code.start, code.end),
+        })
+    });
+}
+
+/// Write a backtrace of the caller to the log, if enabled
+///
+/// Called by the panic handler of the `panic-handler` feature; custom panic
+/// handlers can call it as well.
+#[inline(never)]
+pub fn log_backtrace() {
+    let Some((writer, stack_top, code)) =
+        CONFIG.lock(|config| config.as_ref().map(|c| (c.writer, c.stack_top, c.code)))
+    else {
+        return;
+    };
+    // the address of a local variable is close to the stack pointer
+    let marker = 0_usize;
+    let sp = core::ptr::addr_of!(marker) as usize;
+    let mut addresses = [0; MAX_BACKTRACE_LEN];
+    // SAFETY: readability of the stack is guaranteed by the caller of enable()
+    let len = unsafe { scan(sp, stack_top, code.0..code.1, &mut addresses) };
+    writer.write_backtrace(&addresses[..len]);
+}
+
+/// Collect the words in `start..end` that point into `code`
+///
+/// The lowest bit is ignored since it is set in return addresses of Thumb
+/// code. Returns the number of addresses stored in `addresses`.
+///
+/// # Safety
+///
+/// The memory in `start..end` must be readable.
+unsafe fn scan(start: usize, end: usize, code: Range<usize>, addresses: &mut [usize]) -> usize {
+    let mut addr = start.next_multiple_of(size_of::<usize>());
+    let mut len = 0;
+    while addr + size_of::<usize>() <= end && len < addresses.len() {
+        let word = (addr as *const usize).read_volatile();
+        if code.contains(&(word & !1)) {
+            addresses[len] = word;
+            len += 1;
+        }
+        addr += size_of::<usize>();
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_stack() {
+        let stack = [0x0800_0101_usize, 7, 0x2000_0000, 0x0800_1000, 0x0800_0200];
+        let start = stack.as_ptr() as usize;
+        let end = start + size_of::<usize>() * stack.len();
+        let mut addresses = [0; 2];
+        let len = unsafe { scan(start, end, 0x0800_0000..0x0800_1000, &mut addresses) };
+        assert_eq!(addresses[..len], [0x0800_0101, 0x0800_0200]);
+    }
+}
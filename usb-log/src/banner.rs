@@ -0,0 +1,57 @@
+//! Boot banner
+//!
+//! The banner identifies the firmware in the log output after a reset. It is
+//! usually emitted with the [`log_banner!`](crate::log_banner) macro right
+//! after the logger has been initialized.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::fmt;
+
+/// Firmware identification printed as a single log record
+pub struct Banner<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    /// Build date and time
+    pub built: Option<&'a str>,
+    pub git_hash: Option<&'a str>,
+}
+
+impl fmt::Display for Banner<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}", self.name, self.version)?;
+        if let Some(built) = self.built {
+            write!(f, ", built {built}")?;
+        }
+        if let Some(git_hash) = self.git_hash {
+            write!(f, ", git {git_hash}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::string::ToString;
+
+    #[test]
+    fn banner_format() {
+        let mut banner = Banner {
+            name: "app",
+            version: "1.2.3",
+            built: None,
+            git_hash: None,
+        };
+        assert_eq!(banner.to_string(), "app v1.2.3");
+        banner.built = Some("Thu, 16 Oct 2025 10:00:00 +0200");
+        banner.git_hash = Some("0123abc");
+        assert_eq!(
+            banner.to_string(),
+            "app v1.2.3, built Thu, 16 Oct 2025 10:00:00 +0200, git 0123abc"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Builder for the log channels
+//!
+//! The builder collects the configuration of a log channel and creates the
+//! channel in one go, which reads better than a series of setter calls once
+//! several options are used:
+//!
+//! ```ignore
+//! let log_channel: UsbLogChannel<_, 4096, 512> = UsbLogChannelBuilder::new(log_buffer)
+//!     .interface_name("mylog")
+//!     .fill_timeout(4)
+//!     .alt_setting_gating()
+//!     .build(&usb_bus);
+//! ```
+//!
+//! The same configuration can produce the bulk channel by `build()` or the
+//! control transfer channel by `build_control()`. Options that only apply to
+//! the bulk channel are ignored by the latter. `new()` of the channels remains
+//! the simple way to create a channel with the default configuration.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::clock::SofClock;
+use crate::control;
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat::LangID;
+use crate::usb_log_channel;
+use crate::usb_log_channel_bulk::{SuspendPolicy, UnconfiguredPolicy, UsbLogChannel};
+use usb_device::class_prelude::*;
+
+/// Configuration of a log channel
+pub struct UsbLogChannelBuilder<'a, const N: usize> {
+    log_buffer: &'a LogBuffer<N>,
+    iface_name: &'a str,
+    iface_strings: &'a [(LangID, &'a str)],
+    iface_class: (u8, u8, u8),
+    ms_os_vendor_code: Option<u8>,
+    webusb: Option<(u8, Option<&'a str>)>,
+    fill_timeout: u16,
+    suspend_policy: SuspendPolicy,
+    unconfigured_policy: UnconfiguredPolicy,
+    sof_clock: Option<&'a SofClock>,
+    out_handler: Option<fn(&[u8])>,
+    #[cfg(feature = "usb-device-03")]
+    alt_setting_gated: bool,
+    credit_flow_control: bool,
+    wakeup_hook: Option<fn()>,
+}
+
+impl<'a, const N: usize> UsbLogChannelBuilder<'a, N> {
+    /// Start with the default configuration of a channel reading `log_buffer`
+    pub fn new(log_buffer: &'a LogBuffer<N>) -> Self {
+        UsbLogChannelBuilder {
+            log_buffer,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            iface_strings: &[],
+            iface_class: (0xff, 0, 0),
+            ms_os_vendor_code: None,
+            webusb: None,
+            fill_timeout: 0,
+            suspend_policy: SuspendPolicy::Buffer,
+            unconfigured_policy: UnconfiguredPolicy::Buffer,
+            sof_clock: None,
+            out_handler: None,
+            #[cfg(feature = "usb-device-03")]
+            alt_setting_gated: false,
+            credit_flow_control: false,
+            wakeup_hook: None,
+        }
+    }
+
+    /// Set the interface name, see [`UsbLogChannel::set_interface_name`]
+    pub fn interface_name(mut self, name: &'a str) -> Self {
+        self.iface_name = name;
+        self
+    }
+
+    /// Set localized interface names, see
+    /// [`UsbLogChannel::set_interface_strings`]
+    pub fn interface_strings(mut self, strings: &'a [(LangID, &'a str)]) -> Self {
+        self.iface_strings = strings;
+        self
+    }
+
+    /// Set the class codes of the interface, see
+    /// [`UsbLogChannel::set_interface_class`]
+    pub fn interface_class(mut self, class: u8, subclass: u8, protocol: u8) -> Self {
+        self.iface_class = (class, subclass, protocol);
+        self
+    }
+
+    /// Provide Microsoft OS 2.0 descriptors, see
+    /// [`UsbLogChannel::enable_ms_os_descriptors`]
+    pub fn ms_os_descriptors(mut self, vendor_code: u8) -> Self {
+        self.ms_os_vendor_code = Some(vendor_code);
+        self
+    }
+
+    /// Announce WebUSB support, see [`UsbLogChannel::enable_webusb`]
+    pub fn webusb(mut self, vendor_code: u8, landing_page: Option<&'a str>) -> Self {
+        self.webusb = Some((vendor_code, landing_page));
+        self
+    }
+
+    /// Set the fill timeout of the bulk channel, see
+    /// [`UsbLogChannel::set_fill_timeout`]
+    pub fn fill_timeout(mut self, polls: u16) -> Self {
+        self.fill_timeout = polls;
+        self
+    }
+
+    /// Select the suspend policy of the bulk channel, see
+    /// [`UsbLogChannel::set_suspend_policy`]
+    pub fn suspend_policy(mut self, policy: SuspendPolicy) -> Self {
+        self.suspend_policy = policy;
+        self
+    }
+
+    /// Select the unconfigured policy of the bulk channel, see
+    /// [`UsbLogChannel::set_unconfigured_policy`]
+    pub fn unconfigured_policy(mut self, policy: UnconfiguredPolicy) -> Self {
+        self.unconfigured_policy = policy;
+        self
+    }
+
+    /// Read a clock counting USB frames, see [`UsbLogChannel::set_sof_clock`]
+    pub fn sof_clock(mut self, clock: &'a SofClock) -> Self {
+        self.sof_clock = Some(clock);
+        self
+    }
+
+    /// Add a bulk OUT endpoint whose packets are passed to `handler`, see
+    /// [`UsbLogChannel::with_out_endpoint`]
+    pub fn out_endpoint(mut self, handler: fn(&[u8])) -> Self {
+        self.out_handler = Some(handler);
+        self
+    }
+
+    /// Move the endpoints to alternate setting 1, see
+    /// [`UsbLogChannel::enable_alt_setting_gating`]
+    #[cfg(feature = "usb-device-03")]
+    pub fn alt_setting_gating(mut self) -> Self {
+        self.alt_setting_gated = true;
+        self
+    }
+
+    /// Send only as many bytes as the host has granted, see
+    /// [`UsbLogChannel::enable_credit_flow_control`]
+    pub fn credit_flow_control(mut self) -> Self {
+        self.credit_flow_control = true;
+        self
+    }
+
+    /// Wake up the host for urgent records, see
+    /// [`UsbLogChannel::enable_remote_wakeup`]
+    pub fn remote_wakeup(mut self, hook: fn()) -> Self {
+        self.wakeup_hook = Some(hook);
+        self
+    }
+
+    /// Create a log channel with a bulk IN endpoint of `EP_SIZE` bytes
+    pub fn build<B: UsbBus, const EP_SIZE: usize>(
+        self,
+        alloc: &'a UsbBusAllocator<B>,
+    ) -> UsbLogChannel<'a, B, N, EP_SIZE> {
+        let mut channel = match self.out_handler {
+            Some(handler) => UsbLogChannel::with_out_endpoint(alloc, self.log_buffer, handler),
+            None => UsbLogChannel::new(alloc, self.log_buffer),
+        };
+        let (class, subclass, protocol) = self.iface_class;
+        channel.set_interface_name(self.iface_name);
+        channel.set_interface_strings(self.iface_strings);
+        channel.set_interface_class(class, subclass, protocol);
+        if let Some(vendor_code) = self.ms_os_vendor_code {
+            channel.enable_ms_os_descriptors(vendor_code);
+        }
+        if let Some((vendor_code, landing_page)) = self.webusb {
+            channel.enable_webusb(vendor_code, landing_page);
+        }
+        channel.set_fill_timeout(self.fill_timeout);
+        channel.set_suspend_policy(self.suspend_policy);
+        channel.set_unconfigured_policy(self.unconfigured_policy);
+        if let Some(clock) = self.sof_clock {
+            channel.set_sof_clock(clock);
+        }
+        #[cfg(feature = "usb-device-03")]
+        if self.alt_setting_gated {
+            channel.enable_alt_setting_gating();
+        }
+        if self.credit_flow_control {
+            channel.enable_credit_flow_control();
+        }
+        if let Some(hook) = self.wakeup_hook {
+            channel.enable_remote_wakeup(hook);
+        }
+        channel
+    }
+
+    /// Create a log channel based on control transfers
+    ///
+    /// Only the interface name and strings, the class codes, the Microsoft
+    /// OS 2.0 descriptors and WebUSB apply to this channel.
+    pub fn build_control<B: UsbBus>(
+        self,
+        alloc: &'a UsbBusAllocator<B>,
+    ) -> usb_log_channel::UsbLogChannel<'a, N> {
+        let mut channel = usb_log_channel::UsbLogChannel::new(alloc, self.log_buffer);
+        let (class, subclass, protocol) = self.iface_class;
+        channel.set_interface_name(self.iface_name);
+        channel.set_interface_strings(self.iface_strings);
+        channel.set_interface_class(class, subclass, protocol);
+        if let Some(vendor_code) = self.ms_os_vendor_code {
+            channel.enable_ms_os_descriptors(vendor_code);
+        }
+        if let Some((vendor_code, landing_page)) = self.webusb {
+            channel.enable_webusb(vendor_code, landing_page);
+        }
+        channel
+    }
+}
+
+// the builder sets up alternate settings, which need usb-device 0.3
+#[cfg(all(test, feature = "usb-device-03"))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    #[test]
+    fn build_variants() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let builder = || {
+            UsbLogChannelBuilder::new(&log_buffer)
+                .interface_class(0xff, 1, 2)
+                .alt_setting_gating()
+        };
+        let mut bulk: UsbLogChannel<_, 256, 32> = builder().build(&alloc);
+        let mut ctrl = builder().build_control(&alloc);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        let get_config = setup(0x80, 6, 0x0200, 0, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut bulk, &mut ctrl], get_config).unwrap();
+        // bulk interface with two alternate settings and one endpoint
+        assert_eq!(desc.len(), 9 + 9 + 9 + 7 + 9);
+        assert_eq!(&desc[9..18], [9, 4, 0, 0, 0, 0xff, 1, 2, 4]);
+        assert_eq!(&desc[18..27], [9, 4, 0, 1, 1, 0xff, 1, 2, 4]);
+        assert_eq!(&desc[27..34], [7, 5, 0x81, 0x02, 32, 0, 0]);
+        // control interface without endpoint
+        assert_eq!(&desc[34..43], [9, 4, 1, 0, 0, 0xff, 1, 2, 5]);
+    }
+}
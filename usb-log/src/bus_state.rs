@@ -0,0 +1,195 @@
+//! State of the USB bus shared by the log channels having endpoints
+//!
+//! The channels sending the log over an IN endpoint embed a [`BusState`],
+//! which keeps track of the suspension and the configuration of the device,
+//! applies the [`SuspendPolicy`] and the [`UnconfiguredPolicy`] to the log
+//! source and notices the standard requests after which data in transit may
+//! have been lost.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::diagnostics::Diagnostics;
+use crate::log_source::LogSource;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+};
+
+/// Handling of log records while the USB is suspended
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuspendPolicy {
+    /// Keep buffering records, discarding the oldest ones on overflow
+    #[default]
+    Buffer,
+    /// Drop new records and report their number by a drop marker after the
+    /// bus has been resumed, which keeps the records preceding the suspension
+    Drop,
+}
+
+/// Handling of log records while the USB device is not configured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnconfiguredPolicy {
+    /// Keep buffering records, which are sent after the configuration
+    #[default]
+    Buffer,
+    /// Drop new records and report their number by a drop marker once the
+    /// device has been configured, which keeps the buffer free for the
+    /// records logged while a host is present
+    Drop,
+    /// Keep buffering records but discard the buffered data when the device
+    /// is configured, so that the host only gets fresh records
+    ClearOnConfigure,
+}
+
+/// Bus state of a log channel
+pub(crate) struct BusState {
+    suspend_policy: SuspendPolicy,
+    suspended: bool,
+    unconfigured_policy: UnconfiguredPolicy,
+    /// The host has selected a configuration
+    configured: bool,
+    /// Data in transit may have been lost, continue at a record boundary
+    resync: bool,
+    /// Reports problems of the transport to the log
+    pub(crate) diagnostics: Diagnostics,
+}
+
+impl BusState {
+    pub(crate) const fn new() -> Self {
+        BusState {
+            suspend_policy: SuspendPolicy::Buffer,
+            suspended: false,
+            unconfigured_policy: UnconfiguredPolicy::Buffer,
+            configured: false,
+            resync: false,
+            diagnostics: Diagnostics::new(),
+        }
+    }
+
+    /// Returns true while the USB is suspended
+    pub(crate) fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    pub(crate) fn set_suspend_policy<S: LogSource>(
+        &mut self,
+        policy: SuspendPolicy,
+        source: &mut S,
+    ) {
+        if self.suspend_policy == SuspendPolicy::Drop {
+            source.set_dropping(false);
+        }
+        self.suspend_policy = policy;
+        self.update_dropping(source);
+    }
+
+    pub(crate) fn set_unconfigured_policy<S: LogSource>(
+        &mut self,
+        policy: UnconfiguredPolicy,
+        source: &mut S,
+    ) {
+        if self.unconfigured_policy == UnconfiguredPolicy::Drop {
+            source.set_dropping(false);
+        }
+        self.unconfigured_policy = policy;
+        self.update_dropping(source);
+    }
+
+    /// Note a change of the suspend state
+    pub(crate) fn set_suspended<S: LogSource>(&mut self, suspended: bool, source: &mut S) {
+        if suspended == self.suspended {
+            return;
+        }
+        self.suspended = suspended;
+        // report while records are still written
+        if suspended {
+            self.diagnostics.suspended(true, source);
+        }
+        self.update_dropping(source);
+        if !suspended {
+            self.diagnostics.suspended(false, source);
+        }
+    }
+
+    /// Note a change of the configuration state
+    fn set_configured<S: LogSource>(&mut self, configured: bool, source: &mut S) {
+        let clear = self.unconfigured_policy == UnconfiguredPolicy::ClearOnConfigure;
+        if configured && !self.configured && clear {
+            source.clear();
+        }
+        self.configured = configured;
+        self.update_dropping(source);
+    }
+
+    /// Make the log source drop records if the suspend or the unconfigured
+    /// policy says so
+    ///
+    /// The log source is left alone if neither policy drops records.
+    fn update_dropping<S: LogSource>(&self, source: &mut S) {
+        let suspend_drop = self.suspend_policy == SuspendPolicy::Drop;
+        let unconfigured_drop = self.unconfigured_policy == UnconfiguredPolicy::Drop;
+        if suspend_drop || unconfigured_drop {
+            let dropping =
+                (suspend_drop && self.suspended) || (unconfigured_drop && !self.configured);
+            source.set_dropping(dropping);
+        }
+    }
+
+    /// Handle a bus reset, which ends the suspension and the configuration
+    pub(crate) fn reset<S: LogSource>(&mut self, source: &mut S) {
+        self.resync = true;
+        self.set_suspended(false, source);
+        self.set_configured(false, source);
+        source.set_reader_attached(false);
+    }
+
+    /// Continue at a record boundary from now on
+    #[cfg(feature = "usb-device-03")]
+    pub(crate) fn request_resync(&mut self) {
+        self.resync = true;
+    }
+
+    /// Resynchronize the source if data in transit may have been lost
+    ///
+    /// Returns true if the source has been resynchronized.
+    pub(crate) fn resync<S: LogSource>(&mut self, source: &mut S) -> bool {
+        if !self.resync {
+            return false;
+        }
+        self.resync = false;
+        source.resync();
+        true
+    }
+
+    /// Notice the host halting the IN endpoint `ep_in`, clearing its halt or
+    /// changing the configuration
+    ///
+    /// Returns true if the request has set the configuration.
+    pub(crate) fn standard_request<S: LogSource>(
+        &mut self,
+        request: &Request,
+        ep_in: Option<EndpointAddress>,
+        source: &mut S,
+    ) -> bool {
+        if request.request_type != RequestType::Standard {
+            return false;
+        }
+        let is_ep_in = request.recipient == Recipient::Endpoint
+            && request.value == Request::FEATURE_ENDPOINT_HALT
+            && Some(request.index as u8) == ep_in.map(u8::from);
+        if is_ep_in && request.request == Request::SET_FEATURE {
+            self.diagnostics.halted(request.index as u8, source);
+        }
+        let halt_cleared = is_ep_in && request.request == Request::CLEAR_FEATURE;
+        let configured = request.recipient == Recipient::Device
+            && request.request == Request::SET_CONFIGURATION;
+        if configured {
+            self.set_configured(request.value != 0, source);
+        }
+        if halt_cleared || configured {
+            self.resync = true;
+        }
+        configured
+    }
+}
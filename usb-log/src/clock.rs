@@ -0,0 +1,176 @@
+//! Clock source for log timestamps
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use log::{Level, Record};
+
+use crate::mutex::Lock;
+
+/// Source of timestamps for log records
+///
+/// A clock can be registered with a `LogBuffer` to timestamp each record.
+/// Plain functions can be used as a clock, e.g.
+/// `static CLOCK: fn() -> u64 = timer_us;`
+pub trait Clock: Sync {
+    /// Current time in microseconds
+    fn now_us(&self) -> u64;
+}
+
+impl Clock for fn() -> u64 {
+    fn now_us(&self) -> u64 {
+        self()
+    }
+}
+
+/// Clock counting USB frames
+///
+/// The USB peripheral counts the start-of-frame packets of the host, every
+/// 1 ms at full speed and every 125 µs (microframe) at high speed, so the
+/// frame number can timestamp log records without a timer of the
+/// application. The host can relate these timestamps to its own USB frame
+/// timing. The function passed to the constructors reads the frame number,
+/// typically from a register of the USB peripheral.
+///
+/// The frame number wraps around after 2048 ms. Wrap-arounds are only
+/// detected if the clock is read more often, so the clock should be
+/// registered with the USB log channel as well, which reads it when polled:
+///
+/// ```ignore
+/// static SOF_CLOCK: SofClock = SofClock::new(read_frame_number);
+/// log_buffer.set_clock(Some(&SOF_CLOCK));
+/// log_channel.set_sof_clock(&SOF_CLOCK);
+/// ```
+///
+/// The clock does not advance while the bus is suspended.
+pub struct SofClock {
+    frame_number: fn() -> u16,
+    /// Mask of the valid bits of the frame number
+    mask: u16,
+    /// Duration of a frame in microseconds
+    period_us: u64,
+    /// Last frame number read and number of frames counted so far
+    state: Lock<(u16, u64)>,
+}
+
+impl SofClock {
+    /// Create a clock from the 11 bit frame number of a full-speed device
+    pub const fn new(frame_number: fn() -> u16) -> Self {
+        SofClock {
+            frame_number,
+            mask: 0x7ff,
+            period_us: 1000,
+            state: Lock::new((0, 0)),
+        }
+    }
+
+    /// Create a clock from the 14 bit microframe number of a high-speed
+    /// device, i.e. the frame number shifted left by 3 bits combined with
+    /// the number of the microframe within the frame
+    pub const fn high_speed(microframe_number: fn() -> u16) -> Self {
+        SofClock {
+            frame_number: microframe_number,
+            mask: 0x3fff,
+            period_us: 125,
+            state: Lock::new((0, 0)),
+        }
+    }
+
+    /// Read the frame number and return the number of frames counted so far
+    pub fn update(&self) -> u64 {
+        let frame_number = (self.frame_number)() & self.mask;
+        self.state.lock(|(last, count)| {
+            *count += u64::from(frame_number.wrapping_sub(*last) & self.mask);
+            *last = frame_number;
+            *count
+        })
+    }
+}
+
+impl Clock for SofClock {
+    fn now_us(&self) -> u64 {
+        self.update() * self.period_us
+    }
+}
+
+/// Source of the clock registered with the global log buffer
+pub(crate) trait ClockSource: Sync {
+    fn now_us(&self) -> Option<u64>;
+}
+
+static GLOBAL_CLOCK: Lock<Option<&'static dyn ClockSource>> = Lock::new(None);
+
+/// Remember the log buffer registered as global logger
+pub(crate) fn set_global(source: &'static dyn ClockSource) {
+    GLOBAL_CLOCK.lock(|clock| *clock = Some(source));
+}
+
+/// Current time of the clock registered with the global log buffer
+///
+/// Returns None if no log buffer has been registered or the log buffer has no
+/// clock.
+pub fn now_us() -> Option<u64> {
+    GLOBAL_CLOCK.lock(|clock| *clock)?.now_us()
+}
+
+/// Guard logging the time elapsed since its creation when dropped
+///
+/// Created by [`log_time!`](crate::log_time).
+pub struct ScopeTimer<'a> {
+    name: &'a str,
+    start: Option<u64>,
+    module_path: &'static str,
+    file: &'static str,
+    line: u32,
+}
+
+impl<'a> ScopeTimer<'a> {
+    #[doc(hidden)]
+    pub fn new(name: &'a str, module_path: &'static str, file: &'static str, line: u32) -> Self {
+        ScopeTimer {
+            name,
+            start: now_us(),
+            module_path,
+            file,
+            line,
+        }
+    }
+}
+
+impl Drop for ScopeTimer<'_> {
+    fn drop(&mut self) {
+        let (Some(start), Some(end)) = (self.start, now_us()) else {
+            return;
+        };
+        if Level::Info > log::max_level() {
+            return;
+        }
+        log::logger().log(
+            &Record::builder()
+                .level(Level::Info)
+                .target(self.module_path)
+                .module_path_static(Some(self.module_path))
+                .file_static(Some(self.file))
+                .line(Some(self.line))
+                .args(format_args!("{}: {} µs", self.name, end.wrapping_sub(start)))
+                .build(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU16, Ordering};
+
+    #[test]
+    fn sof_clock_wraps_around() {
+        static FRAME: AtomicU16 = AtomicU16::new(0);
+        static CLOCK: SofClock = SofClock::new(|| FRAME.load(Ordering::Relaxed));
+        FRAME.store(0x7fe, Ordering::Relaxed);
+        assert_eq!(CLOCK.now_us(), 2_046_000);
+        // the upper bits are ignored
+        FRAME.store(0xf801, Ordering::Relaxed);
+        assert_eq!(CLOCK.now_us(), 2_049_000);
+    }
+}
@@ -0,0 +1,252 @@
+//! Debug console combining the log with command input
+//!
+//! The console is a bulk log channel whose interface has a bulk OUT endpoint
+//! as well, over which the host sends command lines, e.g. by `usb-logread
+//! send "led on\n"`. The firmware registers its commands by name; a received
+//! line is split into the command name and its arguments, and the handler of
+//! the command is called with the arguments:
+//!
+//! ```ignore
+//! static COMMANDS: &[Command] = &[
+//!     Command { name: "led", handler: |args| set_led(args == "on") },
+//!     Command { name: "reboot", handler: |_| reboot() },
+//! ];
+//!
+//! let console: UsbConsole<_, 4096> = UsbConsole::new(&usb_bus, log_buffer, COMMANDS);
+//! ```
+//!
+//! The handlers are called from `poll()` of the USB device. Their output, if
+//! any, is logged. Lines are terminated by `\n` or `\r`. Unknown commands and
+//! lines longer than `LINE_LEN` bytes are reported by a warning; the built-in
+//! command `help` logs the names of the registered commands.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat::LangID;
+use crate::usb_log_channel_bulk::UsbLogChannel;
+use usb_device::{class_prelude::*, Result};
+
+/// Command of the console
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// Name by which the command is invoked
+    pub name: &'static str,
+    /// Called with the arguments following the name, without leading
+    /// whitespace
+    pub handler: fn(&str),
+}
+
+/// Debug console with a bulk IN endpoint for the log and a bulk OUT endpoint
+/// for commands
+pub struct UsbConsole<
+    'a,
+    B: UsbBus,
+    const N: usize,
+    const EP_SIZE: usize = 64,
+    const LINE_LEN: usize = 80,
+> {
+    channel: UsbLogChannel<'a, B, N, EP_SIZE>,
+    ep_out: EndpointOut<'a, B>,
+    line: LineBuffer<LINE_LEN>,
+    commands: &'a [Command],
+}
+
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize, const LINE_LEN: usize>
+    UsbConsole<'a, B, N, EP_SIZE, LINE_LEN>
+{
+    /// Create a console dispatching command lines to `commands`
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+        commands: &'a [Command],
+    ) -> Self {
+        UsbConsole {
+            channel: UsbLogChannel::new(alloc, log_buffer),
+            ep_out: alloc.bulk(EP_SIZE as u16),
+            line: LineBuffer::new(),
+            commands,
+        }
+    }
+
+    /// Access the log channel, e.g. to configure it
+    pub fn channel(&mut self) -> &mut UsbLogChannel<'a, B, N, EP_SIZE> {
+        &mut self.channel
+    }
+
+    /// Periodic tasks, see [`UsbLogChannel::tasks`]
+    pub fn tasks(&mut self) {
+        self.channel.tasks();
+    }
+}
+
+impl<B: UsbBus, const N: usize, const EP_SIZE: usize, const LINE_LEN: usize> UsbClass<B>
+    for UsbConsole<'_, B, N, EP_SIZE, LINE_LEN>
+{
+    /// The OUT endpoint follows the endpoints of the log channel in the same
+    /// interface
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        self.channel.get_configuration_descriptors(writer)?;
+        writer.endpoint(&self.ep_out)
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        self.channel.get_bos_descriptors(writer)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        self.channel.get_string(index, lang_id)
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.channel.reset();
+    }
+
+    fn poll(&mut self) {
+        self.channel.poll();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.channel.control_in(xfer);
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.channel.control_out(xfer);
+    }
+
+    #[cfg(feature = "usb-device-03")]
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        self.channel.get_alt_setting(interface)
+    }
+
+    #[cfg(feature = "usb-device-03")]
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        self.channel.set_alt_setting(interface, alternative)
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.ep_out.address() {
+            return;
+        }
+        let mut packet = [0; EP_SIZE];
+        if let Ok(len) = self.ep_out.read(&mut packet) {
+            self.line.push(&packet[..len], self.commands);
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        self.channel.endpoint_in_complete(addr);
+    }
+}
+
+/// Collects the bytes of a command line
+struct LineBuffer<const LINE_LEN: usize> {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    /// The current line is too long and is skipped up to its end
+    overflow: bool,
+}
+
+impl<const LINE_LEN: usize> LineBuffer<LINE_LEN> {
+    const fn new() -> Self {
+        LineBuffer {
+            buf: [0; LINE_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// Append received bytes, dispatching each completed line
+    fn push(&mut self, data: &[u8], commands: &[Command]) {
+        for &byte in data {
+            match byte {
+                b'\n' | b'\r' => {
+                    if self.overflow {
+                        log::warn!("command line longer than {LINE_LEN} bytes");
+                    } else {
+                        dispatch(&self.buf[..self.len], commands);
+                    }
+                    self.clear();
+                }
+                _ if self.len < LINE_LEN => {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+                _ => self.overflow = true,
+            }
+        }
+    }
+}
+
+/// Call the handler of the command given by `line`
+fn dispatch(line: &[u8], commands: &[Command]) {
+    let Ok(line) = core::str::from_utf8(line) else {
+        log::warn!("command line is not valid UTF-8");
+        return;
+    };
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if let Some(command) = commands.iter().find(|command| command.name == name) {
+        (command.handler)(args.trim_start());
+    } else if name == "help" {
+        for command in commands {
+            log::info!("{}", command.name);
+        }
+    } else {
+        log::warn!("unknown command: {name}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_bus::MockBus;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static ARGS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+    static COMMANDS: &[Command] = &[Command {
+        name: "set",
+        handler: |args| {
+            assert_eq!(args, "a 1");
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            ARGS_LEN.store(args.len(), Ordering::Relaxed);
+        },
+    }];
+
+    #[test]
+    fn command_lines() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut console: UsbConsole<_, 256, 64, 8> =
+            UsbConsole::new(&alloc, &log_buffer, COMMANDS);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = console.ep_out.address();
+
+        // a line may span packets
+        usb_dev.bus().push_out(ep, b"set  a");
+        console.endpoint_out(ep);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        usb_dev.bus().push_out(ep, b" 1\r\n");
+        console.endpoint_out(ep);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(ARGS_LEN.load(Ordering::Relaxed), 3);
+
+        // too long and unknown commands are not dispatched
+        usb_dev.bus().push_out(ep, b"set a 1 and more\nget\n");
+        console.endpoint_out(ep);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,462 @@
+//! Vendor control requests
+//!
+//! Both log channel variants answer vendor requests that are addressed to the
+//! log interface. The request codes are shared by both variants and by the
+//! handlers of the `embassy` module.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_buffer::{Latency, LogBuffer, Stats};
+use crate::mutex::Lock;
+use crate::{ms_os, webusb};
+use crate::usb_device_compat::LangID;
+use log::LevelFilter;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// Default name of the log interface, by which the host tool finds it
+pub const DEFAULT_INTERFACE_NAME: &str = "kiffielog";
+
+/// Read log data (control IN)
+pub const LOG_READ_REQUEST: u8 = 0;
+
+/// Echo wValue for round-trip measurements (control IN)
+pub const ECHO_REQUEST: u8 = 1;
+
+/// Pause (wValue 0) or resume (wValue 1) logging (control OUT, no data)
+pub const SET_ENABLED_REQUEST: u8 = 2;
+
+/// Read the buffer statistics, see [`Stats::to_bytes`] (control IN)
+pub const GET_STATS_REQUEST: u8 = 3;
+
+/// Set the maximum log level to wValue, 0 (`Off`) to 5 (`Trace`) (control
+/// OUT, no data)
+pub const SET_LEVEL_REQUEST: u8 = 4;
+
+/// Discard the buffered log data (control OUT, no data)
+pub const CLEAR_REQUEST: u8 = 5;
+
+/// Allow the device to send wValue more bytes on the bulk IN endpoint if
+/// credit-based flow control is enabled (control OUT, no data)
+pub const GRANT_CREDIT_REQUEST: u8 = 6;
+
+/// Read the protocol version, see [`PROTOCOL_VERSION`] (control IN)
+pub const GET_VERSION_REQUEST: u8 = 7;
+
+/// Echo wValue followed by the current time of the log buffer clock in
+/// microseconds as little endian 64-bit integer, for round-trip and clock
+/// offset measurements (control IN)
+///
+/// The request is rejected if the log buffer has no clock.
+pub const TIME_ECHO_REQUEST: u8 = 8;
+
+/// Announce that a host reader has attached (wValue 1) or is about to
+/// detach (wValue 0), see [`LogBuffer::set_reader_hook`] (control OUT, no
+/// data)
+pub const SET_READER_REQUEST: u8 = 9;
+
+/// Read the time records spend in the log buffer, see [`Latency::to_bytes`]
+/// and [`LogBuffer::set_latency_tracking`] (control IN)
+pub const GET_LATENCY_REQUEST: u8 = 10;
+
+/// Read the number of bytes waiting in the log buffer as little endian 32-bit
+/// integer, so that the host can poll slowly while the device is idle
+/// (control IN)
+pub const GET_AVAILABLE_REQUEST: u8 = 11;
+
+/// Read information about the device, see [`info`] (control IN)
+pub const GET_INFO_REQUEST: u8 = 12;
+
+/// Read log data preceded by the number of bytes left in the log buffer, see
+/// [`read_next_into`] (control IN)
+///
+/// A host pulling a burst of data issues these requests back to back until
+/// no bytes are left instead of asking for [`GET_AVAILABLE_REQUEST`] before
+/// each read.
+pub const LOG_READ_NEXT_REQUEST: u8 = 13;
+
+/// First request code reserved for the application
+///
+/// Vendor control OUT requests with this or a higher code are passed on to
+/// the application together with their data by the log channel based on
+/// control transfers, see [`crate::usb_log_channel`].
+pub const FIRST_USER_REQUEST: u8 = 0x80;
+
+/// Version of the protocol spoken over the log interface
+///
+/// It is incremented whenever the control requests, the framing or the
+/// encodings change in a way the host has to know of. Devices not answering
+/// [`GET_VERSION_REQUEST`] speak version 0.
+///
+/// - 1: [`GET_VERSION_REQUEST`]
+/// - 2: [`TIME_ECHO_REQUEST`]
+/// - 3: [`SET_READER_REQUEST`]
+/// - 4: [`GET_LATENCY_REQUEST`]
+/// - 5: [`GET_AVAILABLE_REQUEST`]
+/// - 6: [`GET_INFO_REQUEST`]
+/// - 7: high-water mark and total bytes in the response to
+///   [`GET_STATS_REQUEST`]
+/// - 8: [`LOG_READ_NEXT_REQUEST`]
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// Maximum length of the response to [`GET_INFO_REQUEST`]
+pub const MAX_INFO_LEN: usize = 64;
+
+/// Firmware version reported to the host
+static FIRMWARE_VERSION: Lock<&'static str> = Lock::new("");
+
+/// Set the firmware version reported by [`GET_INFO_REQUEST`]
+///
+/// Versions longer than 58 bytes are truncated.
+///
+/// ```ignore
+/// usb_log::control::set_firmware_version(env!("CARGO_PKG_VERSION"));
+/// ```
+pub fn set_firmware_version(version: &'static str) {
+    FIRMWARE_VERSION.lock(|v| *v = version);
+}
+
+/// Encode the device information into `buf`
+///
+/// The information consists of the protocol version (16 bits) and the
+/// capacity of the log buffer (32 bits) as little endian integers followed by
+/// the firmware version as UTF-8 string, see [`set_firmware_version`].
+/// Returns the length of the information.
+pub fn info<const N: usize>(log_buffer: &LogBuffer<N>, buf: &mut [u8; MAX_INFO_LEN]) -> usize {
+    buf[..2].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    buf[2..6].copy_from_slice(&(log_buffer.capacity() as u32).to_le_bytes());
+    let version = FIRMWARE_VERSION.lock(|v| *v).as_bytes();
+    let len = version.len().min(MAX_INFO_LEN - 6);
+    buf[6..6 + len].copy_from_slice(&version[..len]);
+    6 + len
+}
+
+/// Returns true if `request` is a vendor request addressed to `iface`
+pub(crate) fn is_vendor_request(request: &Request, iface: InterfaceNumber) -> bool {
+    request.request_type == RequestType::Vendor
+        && request.recipient == Recipient::Interface
+        && request.index == Into::<u8>::into(iface) as u16
+}
+
+/// Returns the name of a log interface if `index` is the index of its string
+///
+/// The host tool identifies the log interface by its name, which is
+/// [`DEFAULT_INTERFACE_NAME`] unless the channel has been given another one.
+/// A product specific name has to be passed to the host tool by
+/// `usb-logread --interface-name`. Further log channels of a device are named
+/// after the main one with a suffix, e.g. `kiffielog-trace`, and are selected
+/// by `usb-logread --channel trace`.
+///
+/// The name is served for every language, as hosts may read it in any of the
+/// languages supported by the device. Each entry of `strings` gives the name
+/// for one language instead. The host tool reads the US English name, so an
+/// `EN_US` entry, if any, should keep the name the host tool looks for.
+pub fn interface_string<'a>(
+    index: StringIndex,
+    iface_string: StringIndex,
+    lang_id: LangID,
+    name: &'a str,
+    strings: &'a [(LangID, &'a str)],
+) -> Option<&'a str> {
+    if index != iface_string {
+        return None;
+    }
+    let name = strings
+        .iter()
+        .find(|(lang, _)| *lang == lang_id)
+        .map_or(name, |(_, name)| name);
+    Some(name)
+}
+
+/// Write the platform capabilities of the Microsoft OS 2.0 descriptors and
+/// of WebUSB to the BOS descriptor, if enabled
+///
+/// Windows reads the Microsoft OS 2.0 descriptors, which bind WinUSB to the
+/// log interface, by a vendor request with `ms_os_vendor_code`. The code must
+/// not be used by other vendor requests of the device, see [`ms_os`] for the
+/// requirements. Browsers read the URL of the WebUSB landing page by a vendor
+/// request with the vendor code given with it, which may be the same as the
+/// one of the Microsoft OS 2.0 descriptors, see [`webusb`].
+pub fn write_capabilities(
+    writer: &mut BosWriter,
+    ms_os_vendor_code: Option<u8>,
+    webusb: Option<(u8, Option<&str>)>,
+) -> Result<()> {
+    if let Some(vendor_code) = ms_os_vendor_code {
+        ms_os::write_capability(writer, vendor_code)?;
+    }
+    if let Some((vendor_code, landing_page)) = webusb {
+        webusb::write_capability(writer, vendor_code, landing_page)?;
+    }
+    Ok(())
+}
+
+/// Answer the requests for the Microsoft OS 2.0 descriptors and for the
+/// WebUSB landing page, if enabled
+///
+/// Returns the transfer if it is none of these requests.
+pub(crate) fn platform_request<'a, 'p, 'r, B: UsbBus>(
+    xfer: ControlIn<'a, 'p, 'r, B>,
+    iface: InterfaceNumber,
+    ms_os_vendor_code: Option<u8>,
+    webusb: Option<(u8, Option<&str>)>,
+) -> Option<ControlIn<'a, 'p, 'r, B>> {
+    let request = xfer.request();
+    if let Some(vendor_code) = ms_os_vendor_code {
+        if ms_os::is_descriptor_request(request, vendor_code) {
+            ms_os::descriptor_set(xfer, iface);
+            return None;
+        }
+    }
+    if let Some((vendor_code, landing_page)) = webusb {
+        if webusb::is_url_request(request, vendor_code) {
+            webusb::url(xfer, landing_page);
+            return None;
+        }
+    }
+    Some(xfer)
+}
+
+/// Write the response to a vendor control IN request to `buf`
+///
+/// Returns the length of the response or None if the request is to be
+/// rejected.
+pub(crate) fn response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    request: u8,
+    value: u16,
+    buf: &mut [u8],
+) -> Option<usize> {
+    match request {
+        LOG_READ_REQUEST => read_response(log_buffer, buf),
+        LOG_READ_NEXT_REQUEST => read_next_response(log_buffer, buf),
+        #[cfg(feature = "echo")]
+        ECHO_REQUEST => echo_response(value, buf),
+        GET_STATS_REQUEST => stats_response(log_buffer, buf),
+        GET_INFO_REQUEST => info_response(log_buffer, buf),
+        GET_AVAILABLE_REQUEST => available_response(log_buffer, buf),
+        GET_LATENCY_REQUEST => latency_response(log_buffer, buf),
+        TIME_ECHO_REQUEST => time_echo_response(log_buffer, value, buf),
+        GET_VERSION_REQUEST => version_response(buf),
+        _ => None,
+    }
+}
+
+/// Returns true if `request` reads the log
+pub(crate) fn is_log_read(request: u8) -> bool {
+    request == LOG_READ_REQUEST || request == LOG_READ_NEXT_REQUEST
+}
+
+/// Carry out a vendor control OUT request without data
+///
+/// Returns false if the request is to be rejected.
+pub(crate) fn perform<const N: usize>(log_buffer: &LogBuffer<N>, request: u8, value: u16) -> bool {
+    match request {
+        SET_ENABLED_REQUEST => enable_logging(log_buffer, value),
+        SET_LEVEL_REQUEST => set_max_level(value),
+        CLEAR_REQUEST => clear_log(log_buffer),
+        SET_READER_REQUEST => attach_reader(log_buffer, value),
+        _ => false,
+    }
+}
+
+/// Answer a control IN request with the response written by `respond`
+///
+/// `respond` gets a buffer of the requested length and returns the length of
+/// the response. If it returns None, the request is left unanswered, so that
+/// the USB device rejects it.
+pub(crate) fn respond<B: UsbBus>(xfer: ControlIn<B>, respond: impl FnOnce(&mut [u8]) -> Option<usize>) {
+    let request_len = xfer.request().length as usize;
+    xfer.accept(|data| {
+        let max_len = request_len.min(data.len());
+        respond(&mut data[..max_len]).ok_or(UsbError::InvalidState)
+    })
+    .ok();
+}
+
+/// Answer a control OUT request without data
+pub(crate) fn acknowledge<B: UsbBus>(xfer: ControlOut<B>, accepted: bool) {
+    if accepted {
+        xfer.accept().ok();
+    } else {
+        xfer.reject().ok();
+    }
+}
+
+/// Response to [`LOG_READ_REQUEST`]
+pub(crate) fn read_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    Some(read_into(buf, log_buffer))
+}
+
+/// Move the oldest bytes of the log buffer to `data`
+///
+/// Returns the number of bytes moved.
+pub(crate) fn read_into<const N: usize>(data: &mut [u8], log_buffer: &LogBuffer<N>) -> usize {
+    let mut len = 0;
+    // the readable bytes may wrap around the end of the log buffer
+    while len < data.len() {
+        let Some(grant) = log_buffer.read_grant() else {
+            break;
+        };
+        let n = grant.len().min(data.len() - len);
+        data[len..len + n].copy_from_slice(&grant[..n]);
+        grant.release(n);
+        len += n;
+    }
+    len
+}
+
+/// Response to [`LOG_READ_NEXT_REQUEST`], see [`read_next_into`]
+pub(crate) fn read_next_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    Some(read_next_into(buf, log_buffer))
+}
+
+/// Move the oldest bytes of the log buffer to `data` after a header telling
+/// how many bytes are left
+///
+/// The header is the number of bytes remaining in the log buffer after the
+/// move as little endian 32-bit integer. It includes the bytes written while
+/// the response was being prepared, so that data arriving in the middle of a
+/// burst extends the burst, and the bytes of a record that is still being
+/// written. The response is limited by the control buffer of `usb-device`,
+/// which can be enlarged to 256 bytes with the `control-buffer-256` feature.
+/// Returns the length of the response, which is 0 if `data` cannot hold the
+/// header.
+pub(crate) fn read_next_into<const N: usize>(data: &mut [u8], log_buffer: &LogBuffer<N>) -> usize {
+    if data.len() < 4 {
+        return 0;
+    }
+    let len = read_into(&mut data[4..], log_buffer);
+    data[..4].copy_from_slice(&(log_buffer.len() as u32).to_le_bytes());
+    4 + len
+}
+
+/// Response to [`ECHO_REQUEST`]
+///
+/// The response consists of wValue in little endian byte order repeated up
+/// to the requested length.
+#[cfg(feature = "echo")]
+pub(crate) fn echo_response(value: u16, buf: &mut [u8]) -> Option<usize> {
+    let pattern = value.to_le_bytes();
+    for (i, d) in buf.iter_mut().enumerate() {
+        *d = pattern[i % 2];
+    }
+    Some(buf.len())
+}
+
+/// Response to [`GET_VERSION_REQUEST`]
+///
+/// The version is sent as a little endian 16-bit integer.
+pub(crate) fn version_response(buf: &mut [u8]) -> Option<usize> {
+    copy_response(&PROTOCOL_VERSION.to_le_bytes(), buf)
+}
+
+/// Response to [`TIME_ECHO_REQUEST`]
+///
+/// Returns None if the log buffer has no clock.
+pub(crate) fn time_echo_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    value: u16,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let now = log_buffer.now_us()?;
+    let mut response = [0; 10];
+    response[..2].copy_from_slice(&value.to_le_bytes());
+    response[2..].copy_from_slice(&now.to_le_bytes());
+    copy_response(&response, buf)
+}
+
+/// Response to [`GET_STATS_REQUEST`]
+pub(crate) fn stats_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let stats: [u8; Stats::LEN] = log_buffer.stats().to_bytes();
+    copy_response(&stats, buf)
+}
+
+/// Response to [`GET_LATENCY_REQUEST`]
+pub(crate) fn latency_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let latency: [u8; Latency::LEN] = log_buffer.stats().latency.to_bytes();
+    copy_response(&latency, buf)
+}
+
+/// Response to [`GET_AVAILABLE_REQUEST`]
+pub(crate) fn available_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    copy_response(&(log_buffer.len() as u32).to_le_bytes(), buf)
+}
+
+/// Response to [`GET_INFO_REQUEST`], see [`info`]
+pub(crate) fn info_response<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let mut info_buf = [0; MAX_INFO_LEN];
+    let len = info(log_buffer, &mut info_buf);
+    copy_response(&info_buf[..len], buf)
+}
+
+/// Copy as much of `response` to `buf` as has been requested
+fn copy_response(response: &[u8], buf: &mut [u8]) -> Option<usize> {
+    let len = response.len().min(buf.len());
+    buf[..len].copy_from_slice(&response[..len]);
+    Some(len)
+}
+
+/// Carry out [`SET_ENABLED_REQUEST`]
+pub(crate) fn enable_logging<const N: usize>(log_buffer: &LogBuffer<N>, value: u16) -> bool {
+    log_buffer.set_enabled(value != 0);
+    true
+}
+
+/// Carry out [`SET_READER_REQUEST`]
+pub(crate) fn attach_reader<const N: usize>(log_buffer: &LogBuffer<N>, value: u16) -> bool {
+    log_buffer.set_reader_attached(value != 0);
+    true
+}
+
+/// Carry out [`SET_LEVEL_REQUEST`]
+///
+/// Returns false if `value` is not a valid level.
+pub(crate) fn set_max_level(value: u16) -> bool {
+    let Some(level) = level_filter(value) else {
+        return false;
+    };
+    log::set_max_level(level);
+    true
+}
+
+/// Returns the level filter encoded by wValue of a set level request
+pub(crate) fn level_filter(value: u16) -> Option<LevelFilter> {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    LEVELS.get(value as usize).copied()
+}
+
+/// Carry out [`CLEAR_REQUEST`]
+pub(crate) fn clear_log<const N: usize>(log_buffer: &LogBuffer<N>) -> bool {
+    log_buffer.clear();
+    true
+}
@@ -0,0 +1,78 @@
+//! Diagnostics of the USB log channels
+//!
+//! The log channels report problems of the transport, such as a stalled
+//! endpoint, data lost because the log buffer overflowed or the USB being
+//! suspended, as log records under the target [`TARGET`]. The records are
+//! added to the log source of the channel itself rather than passed to the
+//! global logger. They are written as `[usb-log] message` instead of the
+//! usual file and line and can be discarded by the device with
+//! [`LogBuffer::set_diagnostics`](crate::log_buffer::LogBuffer::set_diagnostics)
+//! or by the host with `usb-logread --no-diagnostics`.
+//!
+//! In binary mode, the records carry [`TARGET`] as file name and line 0.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_source::LogSource;
+use log::Level;
+
+/// Target of the diagnostic records
+pub const TARGET: &str = "usb-log";
+
+/// Diagnostics state of a log channel
+pub(crate) struct Diagnostics {
+    /// Number of lost bytes already counted
+    dropped_bytes: u32,
+    /// Bytes lost during the current overflow, which are reported once the
+    /// overflow has ended
+    lost: u32,
+}
+
+impl Diagnostics {
+    pub(crate) const fn new() -> Self {
+        Diagnostics {
+            dropped_bytes: 0,
+            lost: 0,
+        }
+    }
+
+    /// Report bytes lost by an overflow of the source
+    ///
+    /// A single record is written when a call finds no bytes lost since the
+    /// previous one, as each record would add to a sustained overflow. Sources
+    /// without statistics are not checked.
+    pub(crate) fn check_overflow<S: LogSource>(&mut self, source: &mut S) {
+        let Some(stats) = source.stats() else {
+            return;
+        };
+        let dropped_bytes = stats.dropped_bytes;
+        if dropped_bytes > self.dropped_bytes {
+            self.lost = self.lost.saturating_add(dropped_bytes - self.dropped_bytes);
+            self.dropped_bytes = dropped_bytes;
+            return;
+        }
+        self.dropped_bytes = dropped_bytes;
+        if self.lost == 0 {
+            return;
+        }
+        let lost = core::mem::take(&mut self.lost);
+        source.report(Level::Warn, format_args!("buffer overflowed, {lost} bytes lost"));
+        // the report itself may not have fit into the buffer
+        self.dropped_bytes = source.stats().map_or(dropped_bytes, |stats| stats.dropped_bytes);
+    }
+
+    /// Report that the host has halted an endpoint
+    pub(crate) fn halted<S: LogSource>(&self, address: u8, source: &mut S) {
+        source.report(Level::Warn, format_args!("endpoint {address:#04x} stalled"));
+    }
+
+    /// Report a change of the suspend state
+    pub(crate) fn suspended<S: LogSource>(&self, suspended: bool, source: &mut S) {
+        if suspended {
+            source.report(Level::Info, format_args!("suspended"));
+        } else {
+            source.report(Level::Info, format_args!("resumed"));
+        }
+    }
+}
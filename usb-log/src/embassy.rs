@@ -0,0 +1,120 @@
+//! Log channel for the `embassy-usb` device stack
+//!
+//! With `embassy-usb`, the application builds the log interface itself: an
+//! interface named `kiffielog` ([`crate::control::DEFAULT_INTERFACE_NAME`])
+//! with the vendor specific class and one bulk IN endpoint. The endpoint is
+//! then handed over to [`UsbLogEndpoint`], whose `run()` task sends the log
+//! in the same way as the bulk channel of the `usb-device` stack:
+//!
+//! ```ignore
+//! let mut function = builder.function(0xff, 0, 0);
+//! let mut iface = function.interface();
+//! let iface_string = iface.string();
+//! let mut alt = iface.alt_setting(0xff, 0, 0, Some(iface_string));
+//! let ep = alt.endpoint_bulk_in(None, 64);
+//! spawner.spawn(log_task(UsbLogEndpoint::new(ep, log_buffer)));
+//!
+//! #[embassy_executor::task]
+//! async fn log_task(mut log: UsbLogEndpoint<'static, Endpoint, 4096>) -> ! {
+//!     log.run().await
+//! }
+//! ```
+//!
+//! The string handler of the application returns the interface name for
+//! `iface_string`. Vendor requests to the log interface are forwarded to
+//! [`control_in`] and [`control_out`] by the request handler of the
+//! application, so that `usb-logread` can use its subcommands.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::control;
+use crate::log_buffer::LogBuffer;
+use embassy_usb_driver::EndpointIn;
+
+/// Bulk IN endpoint sending the log
+///
+/// `EP_SIZE` must not be less than the maximum packet size of the endpoint.
+pub struct UsbLogEndpoint<'a, E: EndpointIn, const N: usize, const EP_SIZE: usize = 64> {
+    ep: E,
+    log_buffer: &'a LogBuffer<N>,
+}
+
+impl<'a, E: EndpointIn, const N: usize, const EP_SIZE: usize> UsbLogEndpoint<'a, E, N, EP_SIZE> {
+    /// Create a log endpoint from a bulk IN endpoint
+    pub fn new(ep: E, log_buffer: &'a LogBuffer<N>) -> Self {
+        assert!(ep.info().max_packet_size as usize <= EP_SIZE);
+        UsbLogEndpoint { ep, log_buffer }
+    }
+
+    /// Send the log while the endpoint is enabled
+    ///
+    /// The task sleeps while the log buffer is empty. A packet is copied out
+    /// of the log buffer before it is sent, so that writers can still discard
+    /// the oldest data while the host is not reading. After the endpoint has
+    /// been disabled, e.g. by a bus reset, sending continues at the next
+    /// record boundary.
+    pub async fn run(&mut self) -> ! {
+        let max_len = self.ep.info().max_packet_size as usize;
+        let mut packet = [0; EP_SIZE];
+        loop {
+            self.ep.wait_enabled().await;
+            self.log_buffer.resync();
+            loop {
+                self.log_buffer.wait_nonempty().await;
+                let len = control::read_into(&mut packet[..max_len], self.log_buffer);
+                if self.ep.write(&packet[..len]).await.is_err() {
+                    break;
+                }
+                // a transfer ending with a full packet is terminated by a
+                // zero-length packet
+                if len == max_len && self.log_buffer.is_empty() && self.ep.write(&[]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Answer a vendor control IN request addressed to the log interface
+///
+/// `request` and `value` are the bRequest and wValue fields of the request.
+/// The response is written to `buf`, whose length is the requested one.
+/// Returns the length of the response or None if the request is to be
+/// rejected.
+pub fn control_in<const N: usize>(
+    log_buffer: &LogBuffer<N>,
+    request: u8,
+    value: u16,
+    buf: &mut [u8],
+) -> Option<usize> {
+    control::response(log_buffer, request, value, buf)
+}
+
+/// Answer a vendor control OUT request without data addressed to the log
+/// interface
+///
+/// Returns false if the request is to be rejected.
+pub fn control_out<const N: usize>(log_buffer: &LogBuffer<N>, request: u8, value: u16) -> bool {
+    control::perform(log_buffer, request, value)
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    #[test]
+    fn control_requests() {
+        let log_buffer = LogBuffer::<64>::new();
+        writeln!(log_buffer.writer(), "abc").unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(control_in(&log_buffer, control::LOG_READ_REQUEST, 0, &mut buf), Some(4));
+        assert_eq!(&buf[..4], b"abc\n");
+        assert_eq!(control_in(&log_buffer, 0xff, 0, &mut buf), None);
+
+        assert!(control_out(&log_buffer, control::SET_ENABLED_REQUEST, 0));
+        assert!(!log_buffer.is_enabled());
+        assert!(!control_out(&log_buffer, control::SET_LEVEL_REQUEST, 6));
+    }
+}
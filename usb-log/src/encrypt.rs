@@ -0,0 +1,192 @@
+//! Authenticated encryption of the log stream
+//!
+//! [`EncryptingSource`] wraps a log source and encrypts its data with
+//! ChaCha20-Poly1305 using a key provisioned to the device, so that only a
+//! host knowing the key can read the log, e.g. by `usb-logread --key`. The
+//! encrypted stream is sent by a log channel reading the source:
+//!
+//! ```ignore
+//! let source = EncryptingSource::new(log_buffer, &KEY, boot_count);
+//! let log_channel: UsbLogChannel<_, 0, 64, _> = UsbLogChannel::with_source(&usb_bus, source);
+//! ```
+//!
+//! Log read requests get the encrypted stream as well; the other vendor
+//! requests are not passed on to the wrapped source.
+//!
+//! The data is split into chunks of up to [`CHUNK_LEN`] bytes, each of which
+//! is sent as a COBS encoded frame terminated by a zero byte:
+//!
+//! - nonce: prefix (4 bytes) and counter (8 bytes), little endian
+//! - ciphertext
+//! - Poly1305 tag (16 bytes)
+//!
+//! The counter starts at 0 when the source is created. The nonce prefix must
+//! therefore differ whenever the device starts with the same key, e.g. by
+//! using a persistent boot counter or a random number; reusing a nonce
+//! reveals the log data.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::frame;
+use crate::log_source::LogSource;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+/// Maximum number of log bytes per frame
+pub const CHUNK_LEN: usize = 64;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Frame length before COBS encoding
+const RAW_LEN: usize = NONCE_LEN + CHUNK_LEN + TAG_LEN;
+
+/// Frame length after COBS encoding, including the terminating zero byte
+const ENCODED_LEN: usize = RAW_LEN + RAW_LEN / 254 + 2;
+
+/// Log source encrypting the data of another log source
+pub struct EncryptingSource<S: LogSource> {
+    source: S,
+    cipher: ChaCha20Poly1305,
+    nonce_prefix: u32,
+    counter: u64,
+    /// Encoded frame being read
+    frame: [u8; ENCODED_LEN],
+    frame_len: usize,
+    /// Read position in `frame`
+    pos: usize,
+}
+
+impl<S: LogSource> EncryptingSource<S> {
+    /// Encrypt the data of `source` with `key`
+    ///
+    /// `nonce_prefix` must not be used twice with the same key.
+    pub fn new(source: S, key: &[u8; 32], nonce_prefix: u32) -> Self {
+        EncryptingSource {
+            source,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce_prefix,
+            counter: 0,
+            frame: [0; ENCODED_LEN],
+            frame_len: 0,
+            pos: 0,
+        }
+    }
+
+    /// Encrypt the next chunk of the source
+    ///
+    /// Returns false if the source has no data.
+    fn next_frame(&mut self) -> bool {
+        let mut raw = [0; RAW_LEN];
+        let len = self.source.read_chunk(&mut raw[NONCE_LEN..NONCE_LEN + CHUNK_LEN]);
+        if len == 0 {
+            return false;
+        }
+        raw[..4].copy_from_slice(&self.nonce_prefix.to_le_bytes());
+        raw[4..NONCE_LEN].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        let nonce = *Nonce::from_slice(&raw[..NONCE_LEN]);
+        let data = &mut raw[NONCE_LEN..NONCE_LEN + len];
+        let Ok(tag) = self.cipher.encrypt_in_place_detached(&nonce, &[], data) else {
+            return false;
+        };
+        raw[NONCE_LEN + len..NONCE_LEN + len + TAG_LEN].copy_from_slice(&tag);
+        let (buf, frame_len) = (&mut self.frame, &mut self.frame_len);
+        *frame_len = 0;
+        frame::cobs_encode(&raw[..NONCE_LEN + len + TAG_LEN], |byte| {
+            buf[*frame_len] = byte;
+            *frame_len += 1;
+        });
+        self.pos = 0;
+        true
+    }
+}
+
+impl<S: LogSource> LogSource for EncryptingSource<S> {
+    fn read(&mut self) -> Option<u8> {
+        if self.pos == self.frame_len && !self.next_frame() {
+            return None;
+        }
+        self.pos += 1;
+        Some(self.frame[self.pos - 1])
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.frame_len && self.source.is_empty()
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() {
+            if self.pos == self.frame_len && !self.next_frame() {
+                break;
+            }
+            let n = (self.frame_len - self.pos).min(buf.len() - len);
+            buf[len..len + n].copy_from_slice(&self.frame[self.pos..self.pos + n]);
+            self.pos += n;
+            len += n;
+        }
+        len
+    }
+
+    /// The channel sends straight out of the encoded frame
+    fn lend(&mut self, send: &mut dyn FnMut(&[u8], bool) -> usize) -> Option<bool> {
+        if self.pos == self.frame_len && !self.next_frame() {
+            return Some(false);
+        }
+        let more = !self.source.is_empty();
+        self.pos += send(&self.frame[self.pos..self.frame_len], more);
+        Some(true)
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::log_buffer::LogBuffer;
+    use chacha20poly1305::Tag;
+    use core::fmt::Write;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    fn cobs_decode(mut data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some((&code, tail)) = data.split_first() {
+            let len = code as usize - 1;
+            out.extend_from_slice(&tail[..len]);
+            data = &tail[len..];
+            if code < 0xff && !data.is_empty() {
+                out.push(0);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn frames_decrypt() {
+        let key = [7; 32];
+        let log_buffer = LogBuffer::<256>::new();
+        let mut source = EncryptingSource::new(&log_buffer, &key, 0x1234);
+        write!(log_buffer.writer(), "{:100}", "x").unwrap();
+
+        let mut stream = [0; 256];
+        let len = source.read_chunk(&mut stream);
+        assert!(source.is_empty());
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let mut plain = Vec::new();
+        for (i, encoded) in stream[..len - 1].split(|&b| b == 0).enumerate() {
+            let mut raw = cobs_decode(encoded);
+            let (nonce, rest) = raw.split_at_mut(NONCE_LEN);
+            assert_eq!(nonce[..4], 0x1234u32.to_le_bytes());
+            assert_eq!(nonce[4..], (i as u64).to_le_bytes());
+            let (data, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+            let nonce = Nonce::from_slice(nonce);
+            let tag = Tag::from_slice(tag);
+            cipher.decrypt_in_place_detached(nonce, &[], data, tag).unwrap();
+            plain.extend_from_slice(data);
+        }
+        assert_eq!(plain.len(), 100);
+        assert!(plain.starts_with(b"x "));
+    }
+}
@@ -0,0 +1,351 @@
+//! Framed binary encoding
+//!
+//! In binary mode, each log record is encoded as a frame. Frames are COBS
+//! encoded and terminated by a zero byte so that a reader can synchronize to
+//! the stream at any frame boundary.
+//!
+//! Layout of a frame before COBS encoding:
+//!
+//! - `RECORD`: type, header, [timestamp delta], line, file length, file,
+//!   message; or with an interned file path: type, header, [timestamp
+//!   delta], line, file id, message
+//! - `TIMESTAMP`: type, absolute timestamp
+//! - `SOURCE`: type, [source tag]
+//! - `DROPPED`: type, number of records lost since the previous `DROPPED`
+//!   frame
+//! - `FILE`: type, file id, file path
+//! - `RAW`: type, tag, opaque payload
+//! - `BACKTRACE`: type, return addresses
+//!
+//! The header byte of a record contains the log level (0 for panic messages)
+//! in the lower bits and flags in the upper bits. Timestamps are given in
+//! microseconds. Records carry the delta to the timestamp of the previous
+//! record; a `TIMESTAMP` frame with the absolute value is emitted periodically
+//! so that a reader can resynchronize. A `SOURCE` frame indicates the buffer
+//! the following frames were merged from or the logical channel they belong
+//! to, or the merging buffer itself if the tag is omitted. Timestamps are
+//! tracked per source.
+//!
+//! File paths can be interned to save bandwidth: a `FILE` frame assigns an id
+//! to a path before the first record referring to it. Ids are reused for other
+//! paths later on; all ids are invalidated whenever data has been lost and
+//! periodically so that a reader attaching to the stream learns the paths.
+//! Like timestamps, ids are tracked per source.
+//!
+//! A `RAW` frame carries machine readable data of the application, e.g. sensor
+//! dumps, which the reader can separate from the log by the tag. In text mode,
+//! it is written as a line `[RAW <tag>] <payload in hex>`.
+//!
+//! A `BACKTRACE` frame lists the return addresses found on the stack when
+//! panicking, innermost first, which the reader can symbolicate. In text
+//! mode, it is written as a line `[BACKTRACE] 0x<address> ...`.
+//!
+//! All integers except the type and header bytes are unsigned LEB128 varints.
+//!
+//! Optionally, a CRC of the frame contents is appended in little endian byte
+//! order. The upper bits of the type byte indicate the kind of CRC:
+//! CRC-16/CCITT-FALSE or CRC-32 (as used by Ethernet and zlib).
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::fmt;
+
+/// Log record
+pub const FRAME_RECORD: u8 = 0x01;
+
+/// Absolute timestamp for resynchronization
+pub const FRAME_TIMESTAMP: u8 = 0x02;
+
+/// Source buffer or channel of the following frames
+pub const FRAME_SOURCE: u8 = 0x03;
+
+/// Number of records lost due to buffer overflow
+pub const FRAME_DROPPED: u8 = 0x04;
+
+/// Assignment of an id to a file path
+pub const FRAME_FILE: u8 = 0x05;
+
+/// Binary payload written by `LogBuffer::write_raw`
+pub const FRAME_RAW: u8 = 0x06;
+
+/// Return addresses written by `LogBuffer::write_backtrace`
+pub const FRAME_BACKTRACE: u8 = 0x07;
+
+/// Type byte flag indicating an appended CRC-16
+pub const FRAME_FLAG_CRC16: u8 = 0x40;
+
+/// Type byte flag indicating an appended CRC-32
+pub const FRAME_FLAG_CRC32: u8 = 0x80;
+
+/// Mask of the frame type in the type byte
+pub const FRAME_TYPE_MASK: u8 = 0x3f;
+
+/// Record header flag indicating a timestamp delta
+pub const RECORD_FLAG_TIMESTAMP: u8 = 0x80;
+
+/// Record header flag indicating an interned file path
+pub const RECORD_FLAG_FILE_ID: u8 = 0x40;
+
+/// Mask of the log level in the record header
+pub const RECORD_LEVEL_MASK: u8 = 0x07;
+
+/// Level of panic messages
+pub const LEVEL_PANIC: u8 = 0;
+
+/// Maximum length of a frame before COBS encoding
+pub const MAX_FRAME_LEN: usize = 256;
+
+/// Maximum length of a payload written by `LogBuffer::write_raw`
+pub const MAX_RAW_LEN: usize = MAX_FRAME_LEN - 2;
+
+/// Maximum number of return addresses written by
+/// `LogBuffer::write_backtrace`
+pub const MAX_BACKTRACE_LEN: usize = 16;
+
+/// Number of records after which an absolute timestamp is sent again
+pub const TIMESTAMP_SYNC_INTERVAL: u32 = 32;
+
+/// Number of records after which interned file paths are sent again
+pub const FILE_SYNC_INTERVAL: u32 = 64;
+
+/// Number of file paths interned at the same time
+const FILE_TABLE_LEN: usize = 8;
+
+/// Encoding of log records
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable text lines
+    Text,
+    /// COBS framed binary records as described in this module
+    Binary,
+}
+
+/// Integrity check appended to each frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Crc {
+    None,
+    Crc16,
+    Crc32,
+}
+
+/// Buffer for assembling a frame
+///
+/// Data exceeding the capacity is silently discarded.
+pub(crate) struct FrameBuf {
+    buf: [u8; MAX_FRAME_LEN + 4],
+    len: usize,
+}
+
+impl FrameBuf {
+    pub fn new(frame_type: u8) -> FrameBuf {
+        let mut frame = FrameBuf {
+            buf: [0; MAX_FRAME_LEN + 4],
+            len: 0,
+        };
+        frame.push(frame_type);
+        frame
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len < MAX_FRAME_LEN {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_FRAME_LEN - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    pub fn push_varint(&mut self, mut val: u64) {
+        while val >= 0x80 {
+            self.push(val as u8 | 0x80);
+            val >>= 7;
+        }
+        self.push(val as u8);
+    }
+
+    /// Number of bytes that can still be added to the frame
+    pub fn remaining(&self) -> usize {
+        MAX_FRAME_LEN - self.len
+    }
+
+    /// Append a CRC of the frame contents and flag it in the type byte
+    pub fn append_crc(&mut self, crc: Crc) {
+        match crc {
+            Crc::None => (),
+            Crc::Crc16 => {
+                self.buf[0] |= FRAME_FLAG_CRC16;
+                let crc = crc16(self.as_bytes()).to_le_bytes();
+                self.buf[self.len..self.len + 2].copy_from_slice(&crc);
+                self.len += 2;
+            }
+            Crc::Crc32 => {
+                self.buf[0] |= FRAME_FLAG_CRC32;
+                let crc = crc32(self.as_bytes()).to_le_bytes();
+                self.buf[self.len..self.len + 4].copy_from_slice(&crc);
+                self.len += 4;
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for FrameBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.extend(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// File paths for which an id has been sent
+///
+/// Paths are compared by address, which is cheap and sufficient for the
+/// `&'static str` paths provided by `file!()`.
+pub(crate) struct FileTable {
+    paths: [Option<&'static str>; FILE_TABLE_LEN],
+    /// Slot to be replaced next
+    next: usize,
+    /// Number of records since the table has been cleared
+    count: u32,
+}
+
+impl FileTable {
+    pub const fn new() -> FileTable {
+        FileTable {
+            paths: [None; FILE_TABLE_LEN],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    /// Invalidate all ids
+    pub fn clear(&mut self) {
+        *self = FileTable::new();
+    }
+
+    /// Get the id of `path`
+    ///
+    /// Returns the id and true if the id has been newly assigned and must be
+    /// sent to the reader.
+    pub fn intern(&mut self, path: &'static str) -> (u8, bool) {
+        self.count += 1;
+        if self.count > FILE_SYNC_INTERVAL {
+            self.clear();
+            self.count = 1;
+        }
+        if let Some(id) = self.paths.iter().position(|p| p.is_some_and(|p| core::ptr::eq(p, path))) {
+            return (id as u8, false);
+        }
+        let id = self.next;
+        self.paths[id] = Some(path);
+        self.next = (id + 1) % FILE_TABLE_LEN;
+        (id as u8, true)
+    }
+}
+
+/// COBS encode a frame including the terminating zero byte
+pub(crate) fn cobs_encode(data: &[u8], mut out: impl FnMut(u8)) {
+    let mut rest = data;
+    loop {
+        let max = rest.len().min(254);
+        match rest[..max].iter().position(|&b| b == 0) {
+            Some(n) => {
+                out(n as u8 + 1);
+                rest[..n].iter().for_each(|&b| out(b));
+                rest = &rest[n + 1..];
+            }
+            None if max == 254 => {
+                out(0xff);
+                rest[..max].iter().for_each(|&b| out(b));
+                rest = &rest[max..];
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            None => {
+                out(max as u8 + 1);
+                rest.iter().for_each(|&b| out(b));
+                break;
+            }
+        }
+    }
+    out(0);
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// CRC-32 (ISO HDLC)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        cobs_encode(data, |byte| out.push(byte));
+        out
+    }
+
+    #[test]
+    fn cobs() {
+        assert_eq!(encode(&[]), [0x01, 0x00]);
+        assert_eq!(encode(&[0x00]), [0x01, 0x01, 0x00]);
+        assert_eq!(encode(&[0x11, 0x22, 0x00, 0x33]), [0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+        let long = [0xaa; 300];
+        let encoded = encode(&long);
+        assert_eq!(encoded.len(), 300 + 2 + 1);
+        assert_eq!((encoded[0], encoded[255]), (0xff, 47));
+    }
+
+    #[test]
+    fn varint() {
+        let mut frame = FrameBuf::new(FRAME_TIMESTAMP);
+        frame.push_varint(0);
+        frame.push_varint(300);
+        assert_eq!(frame.as_bytes(), [FRAME_TIMESTAMP, 0x00, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,172 @@
+//! Global logger with a USB channel attached on demand
+//!
+//! The log buffer is registered as the logger at startup while the USB log
+//! channel is attached later and may be detached and attached again, e.g.
+//! when the USB peripheral is re-initialized after a clock change. Logging
+//! never touches the USB stack, so it is safe at any time; records logged
+//! while no channel is attached are kept in the buffer.
+//!
+//! At most one channel is attached at a time. The attached channel is owned
+//! by an [`Attached`] guard, which detaches it when dropped.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use log::{LevelFilter, SetLoggerError};
+use usb_device::class_prelude::*;
+
+use crate::log_buffer::LogBuffer;
+use crate::mutex::Lock;
+use crate::{usb_log_channel, usb_log_channel_bulk};
+
+/// Log buffer registered as global logger with an optional USB log channel
+///
+/// ```ignore
+/// static LOGGER: GlobalUsbLogger<4096> = GlobalUsbLogger::new();
+///
+/// LOGGER.init(LevelFilter::Info).unwrap();
+/// // ... set up the USB bus allocator
+/// let mut log_channel = LOGGER.attach_bulk(&usb_bus).unwrap();
+/// // ... poll with usb_dev.poll(&mut [&mut *log_channel])
+/// // ... tear down USB, which detaches the channel
+/// drop(log_channel);
+/// ```
+pub struct GlobalUsbLogger<const N: usize> {
+    log_buffer: LogBuffer<N>,
+    attached: Lock<bool>,
+}
+
+impl<const N: usize> GlobalUsbLogger<N> {
+    pub const fn new() -> Self {
+        GlobalUsbLogger {
+            log_buffer: LogBuffer::new(),
+            attached: Lock::new(false),
+        }
+    }
+
+    /// Register the log buffer as the global logger and set the maximum log
+    /// level
+    pub fn init(&'static self, level: LevelFilter) -> Result<(), SetLoggerError> {
+        crate::init(&self.log_buffer, level).map(|_| ())
+    }
+
+    /// Get the log buffer, e.g. to configure it
+    pub fn log_buffer(&self) -> &LogBuffer<N> {
+        &self.log_buffer
+    }
+
+    /// Returns true if a USB log channel is attached
+    pub fn is_attached(&self) -> bool {
+        self.attached.lock(|attached| *attached)
+    }
+
+    /// Attach a log channel based on control transfers
+    ///
+    /// Fails if a channel is already attached.
+    pub fn attach_control<'a, B: UsbBus>(
+        &'a self,
+        alloc: &'a UsbBusAllocator<B>,
+    ) -> Result<Attached<'a, usb_log_channel::UsbLogChannel<'a, N>, N>, AlreadyAttached> {
+        self.attach(|| usb_log_channel::UsbLogChannel::new(alloc, &self.log_buffer))
+    }
+
+    /// Attach a log channel having a bulk IN endpoint
+    ///
+    /// Fails if a channel is already attached.
+    pub fn attach_bulk<'a, B: UsbBus>(
+        &'a self,
+        alloc: &'a UsbBusAllocator<B>,
+    ) -> Result<Attached<'a, usb_log_channel_bulk::UsbLogChannel<'a, B, N>, N>, AlreadyAttached>
+    {
+        self.attach(|| usb_log_channel_bulk::UsbLogChannel::new(alloc, &self.log_buffer))
+    }
+
+    fn attach<C>(
+        &self,
+        channel: impl FnOnce() -> C,
+    ) -> Result<Attached<'_, C, N>, AlreadyAttached> {
+        if self.attached.lock(|attached| core::mem::replace(attached, true)) {
+            return Err(AlreadyAttached);
+        }
+        Ok(Attached {
+            channel: channel(),
+            logger: self,
+        })
+    }
+
+    /// Mark the log channel as detached
+    ///
+    /// A record that was partially transmitted is discarded so that the next
+    /// channel starts at a record boundary.
+    fn detach(&self) {
+        self.attached.lock(|attached| *attached = false);
+        self.log_buffer.resync();
+    }
+}
+
+impl<const N: usize> Default for GlobalUsbLogger<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Log channel `C` attached to a [`GlobalUsbLogger`]
+///
+/// Dereferences to the channel, which is passed to the USB device as
+/// `&mut *log_channel`. Dropping the guard, e.g. together with the USB stack,
+/// detaches the channel.
+pub struct Attached<'a, C, const N: usize> {
+    channel: C,
+    logger: &'a GlobalUsbLogger<N>,
+}
+
+impl<C, const N: usize> Deref for Attached<'_, C, N> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.channel
+    }
+}
+
+impl<C, const N: usize> DerefMut for Attached<'_, C, N> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.channel
+    }
+}
+
+impl<C, const N: usize> Drop for Attached<'_, C, N> {
+    fn drop(&mut self) {
+        self.logger.detach();
+    }
+}
+
+/// Error returned when attaching a log channel while another one is attached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlreadyAttached;
+
+impl fmt::Display for AlreadyAttached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a USB log channel is already attached")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_bus::MockBus;
+
+    #[test]
+    fn attach_once() {
+        let logger = GlobalUsbLogger::<64>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let channel = logger.attach_bulk(&alloc).unwrap();
+        assert!(logger.is_attached());
+        assert_eq!(logger.attach_control(&alloc).err(), Some(AlreadyAttached));
+        drop(channel);
+        assert!(!logger.is_attached());
+        let _channel = logger.attach_control(&alloc).unwrap();
+        assert!(logger.is_attached());
+    }
+}
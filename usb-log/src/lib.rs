@@ -3,6 +3,263 @@
 
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(all(feature = "usb-device-02", not(feature = "usb-device-03")))]
+extern crate usb_device_02 as usb_device;
+
+pub mod backtrace;
+pub mod banner;
+pub mod builder;
+mod bus_state;
+#[cfg_attr(feature = "null-logger", allow(dead_code))]
+pub mod clock;
+pub mod console;
+pub mod control;
+pub mod diagnostics;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+#[cfg(feature = "encryption")]
+pub mod encrypt;
+#[cfg_attr(feature = "null-logger", allow(dead_code))]
+pub mod frame;
+pub mod global_logger;
+#[cfg_attr(feature = "null-logger", path = "null_log_buffer.rs")]
 pub mod log_buffer;
+pub mod log_source;
+#[cfg(test)]
+#[cfg_attr(
+    all(feature = "null-logger", not(feature = "usb-device-03")),
+    allow(dead_code)
+)]
+mod mock_bus;
+pub mod ms_os;
+#[cfg_attr(feature = "null-logger", allow(dead_code))]
+mod mutex;
+#[cfg(feature = "panic-handler")]
+pub mod panic_handler;
+pub mod router;
+#[cfg(feature = "tracing")]
+pub mod subscriber;
 pub mod usb_log_channel;
 pub mod usb_log_channel_bulk;
+pub mod usb_log_channel_cdc;
+pub mod usb_log_channel_hid;
+pub mod usb_log_channel_interrupt;
+#[cfg(feature = "usb-device-03")]
+pub mod usb_log_channel_iso;
+pub mod usb_log_channel_notify;
+mod usb_device_compat;
+pub mod webusb;
+
+pub use log_buffer::init;
+
+#[doc(hidden)]
+pub use log as __log;
+
+/// Define a static log buffer of the given size and return a reference to it
+///
+/// Typically used together with [`init`]:
+///
+/// ```ignore
+/// let log_buffer = usb_log::init(usb_log::static_log_buffer!(4096), LevelFilter::Info).unwrap();
+/// let log_channel = UsbLogChannel::new(&usb_bus, log_buffer);
+/// ```
+#[macro_export]
+macro_rules! static_log_buffer {
+    ($size:expr) => {{
+        static LOG_BUFFER: $crate::log_buffer::LogBuffer<{ $size }> =
+            $crate::log_buffer::LogBuffer::new();
+        &LOG_BUFFER
+    }};
+}
+
+/// Log the name and version of the firmware
+///
+/// The name and version are taken from the package of the calling crate. The
+/// build date and time and the git hash are included if the environment
+/// variables `BUILD_DATETIME` and `GIT_HASH` are set at compile time, e.g. by
+/// a build script:
+///
+/// ```ignore
+/// println!("cargo:rustc-env=BUILD_DATETIME={}", Local::now().to_rfc2822());
+/// ```
+///
+/// The git hash can also be passed explicitly as `Option<&str>`:
+///
+/// ```ignore
+/// usb_log::init(usb_log::static_log_buffer!(4096), LevelFilter::Info).unwrap();
+/// usb_log::log_banner!();
+/// usb_log::log_banner!(Some(env!("MY_GIT_REV")));
+/// ```
+#[macro_export]
+macro_rules! log_banner {
+    () => {
+        $crate::log_banner!(option_env!("GIT_HASH"))
+    };
+    ($git_hash:expr) => {
+        $crate::__log::info!(
+            "{}",
+            $crate::banner::Banner {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                built: option_env!("BUILD_DATETIME"),
+                git_hash: $git_hash,
+            }
+        )
+    };
+}
+
+/// Log the execution time of a block or the rest of the enclosing scope
+///
+/// The time is measured with the clock registered with the global log buffer
+/// and logged as `name: N µs` at the `Info` level. Nothing is logged if the
+/// log buffer has no clock.
+///
+/// ```ignore
+/// // time the rest of the function
+/// usb_log::log_time!("flash_erase");
+/// // time a block and return its value
+/// let crc = usb_log::log_time!("crc", { crc32(&data) });
+/// ```
+#[macro_export]
+macro_rules! log_time {
+    ($name:expr) => {
+        let _timer = $crate::clock::ScopeTimer::new($name, module_path!(), file!(), line!());
+    };
+    ($name:expr, $block:block) => {{
+        let _timer = $crate::clock::ScopeTimer::new($name, module_path!(), file!(), line!());
+        $block
+    }};
+}
+
+/// Assert that a condition is true, logging the condition before panicking
+///
+/// The failed condition is written to the log with the location of the
+/// assertion so that it reaches the host even if the panic handler does not
+/// report the panic message. An optional message can be given as with
+/// [`assert!`].
+///
+/// ```ignore
+/// usb_log::log_assert!(len <= buf.len(), "packet too long");
+/// ```
+#[macro_export]
+macro_rules! log_assert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::__log::error!("assertion failed: {}", stringify!($cond));
+            panic!("assertion failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            // the message arguments are evaluated once for both uses
+            match format_args!($($arg)+) {
+                msg => {
+                    $crate::__log::error!("assertion failed: {}: {}", stringify!($cond), msg);
+                    panic!("{}", msg);
+                }
+            }
+        }
+    };
+}
+
+/// Assert that two values are equal, logging both values before panicking
+///
+/// Same as [`assert_eq!`] but the expressions and their values are written to
+/// the log first, see [`log_assert!`].
+#[macro_export]
+macro_rules! log_assert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::__log::error!(
+                        "assertion failed: {} == {} (left: {:?}, right: {:?})",
+                        stringify!($left),
+                        stringify!($right),
+                        left,
+                        right,
+                    );
+                    panic!(
+                        "assertion `left == right` failed\n  left: {:?}\n right: {:?}",
+                        left, right
+                    );
+                }
+            }
+        }
+    };
+    ($left:expr, $right:expr, $($arg:tt)+) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    match format_args!($($arg)+) {
+                        msg => {
+                            $crate::__log::error!(
+                                "assertion failed: {} == {} (left: {:?}, right: {:?}): {}",
+                                stringify!($left),
+                                stringify!($right),
+                                left,
+                                right,
+                                msg
+                            );
+                            panic!(
+                                "assertion `left == right` failed: {}\n  left: {:?}\n right: {:?}",
+                                msg, left, right
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn log_assert_passes() {
+        log_assert!(1 + 1 == 2);
+        log_assert!(true, "not {}", "reached");
+        log_assert_eq!(2 + 2, 4);
+        log_assert_eq!("a", "a", "strings");
+    }
+
+    #[test]
+    fn log_time_returns_value() {
+        log_time!("scope");
+        assert_eq!(log_time!("block", { 1 + 1 }), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "assertion failed: 1 + 1 == 3")]
+    fn log_assert_fails() {
+        log_assert!(1 + 1 == 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "failed: math\n  left: 4\n right: 5")]
+    fn log_assert_eq_fails() {
+        log_assert_eq!(2 + 2, 5, "math");
+    }
+
+    #[test]
+    fn log_assert_message_evaluated_once() {
+        extern crate std;
+        use core::cell::Cell;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let count = Cell::new(0);
+        let next = || {
+            count.set(count.get() + 1);
+            count.get()
+        };
+        let err = catch_unwind(AssertUnwindSafe(|| log_assert!(false, "call {}", next())));
+        assert_eq!(err.unwrap_err().downcast_ref::<std::string::String>().unwrap(), "call 1");
+        let err = catch_unwind(AssertUnwindSafe(|| log_assert_eq!(1, 2, "call {}", next())));
+        let msg = err.unwrap_err().downcast::<std::string::String>().unwrap();
+        assert!(msg.starts_with("assertion `left == right` failed: call 2\n"));
+        assert_eq!(count.get(), 2);
+    }
+}
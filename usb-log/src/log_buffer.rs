@@ -3,18 +3,150 @@
 // Copyright (C) 2022 Stephan <kiffie@mailbox.org>
 // SPDX-License-Identifier: GPL-2.0-or-later
 
-use core::cell::RefCell;
+use core::cell::UnsafeCell;
 use core::fmt::Write;
-use critical_section::Mutex;
-use log::{Metadata, Record};
+use core::future::poll_fn;
+use core::ops::{Deref, DerefMut};
+use core::task::{Poll, Waker};
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+
+use crate::clock::{self, Clock, ClockSource};
+use crate::diagnostics;
+use crate::mutex::{Lock, LockGuard};
+use crate::frame::{self, Crc, FileTable, FrameBuf};
+
+pub use crate::frame::Format;
 
 #[cfg(feature = "rtt-target")]
 use rtt_target::rprint;
 
+/// Backing storage of the ring buffer
+///
+/// The storage lives outside of the mutex protected state so that a write
+/// grant can hand out a slice of it that outlives the critical section. All
+/// accesses go through raw pointers on individual bytes or on the granted
+/// region, never through a reference to the whole array.
+struct Storage<const N: usize>(UnsafeCell<[u8; N]>);
+
+// SAFETY: the storage is only accessed while holding the critical section or
+// through a grant, whose region is excluded from conflicting accesses.
+unsafe impl<const N: usize> Sync for Storage<N> {}
+
+impl<const N: usize> Storage<N> {
+    const fn new() -> Self {
+        Storage(UnsafeCell::new([0; N]))
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        self.0.get().cast()
+    }
+
+    fn get(&self, index: usize) -> u8 {
+        assert!(index < N);
+        // SAFETY: index is in bounds and not part of an outstanding grant
+        unsafe { self.ptr().add(index).read() }
+    }
+
+    fn set(&self, index: usize, byte: u8) {
+        assert!(index < N);
+        // SAFETY: index is in bounds and not part of an outstanding grant
+        unsafe { self.ptr().add(index).write(byte) }
+    }
+
+    fn copy_from(&self, index: usize, bytes: &[u8]) {
+        assert!(index + bytes.len() <= N);
+        // SAFETY: the region is in bounds and not part of an outstanding grant
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr().add(index), bytes.len())
+        }
+    }
+
+    /// Get a region of the storage
+    ///
+    /// The caller must hold the lock of the log buffer and must not write to
+    /// the storage while the slice is in use.
+    fn slice(&self, index: usize, len: usize) -> &[u8] {
+        assert!(index + len <= N);
+        // SAFETY: the region is in bounds and not part of an outstanding grant
+        unsafe { core::slice::from_raw_parts(self.ptr().add(index), len) }
+    }
+}
+
 struct LogBufferInner<const N: usize> {
     wr: usize,
     rd: usize,
-    buf: [u8; N],
+    /// Length of an outstanding write grant
+    grant: Option<usize>,
+    /// Length of an outstanding read grant
+    read_grant: Option<usize>,
+    format: Format,
+    /// Maximum length of a log message in bytes
+    max_record_len: usize,
+    crc: Crc,
+    clock: Option<&'static dyn Clock>,
+    /// Timestamp of the previous binary record, None if a resync is needed
+    last_timestamp: Option<u64>,
+    /// Number of binary records since the last absolute timestamp
+    sync_count: u32,
+    /// Source tag of the last binary frame, None for own records
+    source: Option<u8>,
+    /// False if a `SOURCE` frame must be sent before the next frame
+    source_valid: bool,
+    /// Number of records lost since the last drop marker
+    dropped: u32,
+    /// Bytes of the current record had to be dropped
+    overrun: bool,
+    /// The oldest data is kept until the first read
+    retaining: bool,
+    /// Total number of bytes lost
+    dropped_bytes: u32,
+    /// Highest fill level in bytes
+    high_water_mark: usize,
+    /// Total number of bytes written, wrapping around
+    written_bytes: u32,
+    /// The time records spend in the buffer is measured
+    latency_tracking: bool,
+    /// Bytes to be read until the record being measured has been read
+    /// completely and the time it was written
+    latency_sample: Option<(usize, u64)>,
+    latency: Latency,
+    /// Sum of the measured latencies in microseconds
+    latency_sum_us: u64,
+    /// Task waiting for data
+    waker: Option<Waker>,
+    /// Called when data arrives after the reader has found the buffer empty
+    data_hook: Option<fn()>,
+    data_hook_armed: bool,
+    /// The last byte read was not the end of a record
+    mid_record: bool,
+    /// Records of this or higher severity wait for free space
+    blocking: LevelFilter,
+    /// Called while waiting for free space
+    wait_hook: Option<fn()>,
+    /// Records of this or higher severity are urgent
+    urgent_level: LevelFilter,
+    /// An urgent record has been written since the last `take_urgent()`
+    urgent: bool,
+    /// A host reader is attached to the log channel
+    reader_attached: bool,
+    /// Called when a reader attaches or detaches
+    reader_hook: Option<fn(bool)>,
+    /// Send file paths of binary records only once
+    file_interning: bool,
+    files: FileTable,
+    /// Records are discarded while logging is paused
+    enabled: bool,
+    /// Diagnostics of the log channels are written
+    diagnostics: bool,
+    /// Records are dropped and reported as lost
+    dropping: bool,
+    /// Fill levels in bytes at which `watermark_hook` is called
+    high_watermark: usize,
+    low_watermark: usize,
+    watermark_hook: Option<fn(bool)>,
+    /// The fill level has reached the high watermark and not yet dropped to
+    /// the low one
+    above_watermark: bool,
 }
 
 impl<const N: usize> LogBufferInner<N> {
@@ -22,7 +154,50 @@ impl<const N: usize> LogBufferInner<N> {
         LogBufferInner {
             wr: 0,
             rd: 0,
-            buf: [0; N],
+            grant: None,
+            read_grant: None,
+            format: Format::Text,
+            max_record_len: usize::MAX,
+            crc: Crc::None,
+            clock: None,
+            last_timestamp: None,
+            sync_count: 0,
+            source: None,
+            source_valid: true,
+            dropped: 0,
+            overrun: false,
+            retaining: false,
+            dropped_bytes: 0,
+            high_water_mark: 0,
+            written_bytes: 0,
+            latency_tracking: false,
+            latency_sample: None,
+            latency: Latency {
+                samples: 0,
+                min_us: 0,
+                avg_us: 0,
+                max_us: 0,
+            },
+            latency_sum_us: 0,
+            waker: None,
+            data_hook: None,
+            data_hook_armed: false,
+            mid_record: false,
+            blocking: LevelFilter::Off,
+            wait_hook: None,
+            urgent_level: LevelFilter::Off,
+            urgent: false,
+            reader_attached: false,
+            reader_hook: None,
+            file_interning: true,
+            files: FileTable::new(),
+            enabled: true,
+            diagnostics: true,
+            dropping: false,
+            high_watermark: usize::MAX,
+            low_watermark: 0,
+            watermark_hook: None,
+            above_watermark: false,
         }
     }
 
@@ -34,52 +209,483 @@ impl<const N: usize> LogBufferInner<N> {
     /// Write a byte
     ///
     /// Returns an error if the buffer is full
-    fn write(&mut self, byte: u8) -> Result<(), ()> {
+    fn write(&mut self, buf: &Storage<N>, byte: u8) -> Result<(), ()> {
         if !self.is_full() {
-            let w: usize = self.wr;
-            self.buf[w] = byte;
+            buf.set(self.wr, byte);
             self.wr = Self::inc_mod_n(self.wr);
+            self.count_written_bytes(1);
             Ok(())
         } else {
             Err(())
         }
     }
 
+    /// Write a slice of bytes with a single index update per contiguous region
+    ///
+    /// Writes as many bytes as fit and returns their number.
+    fn write_slice(&mut self, buf: &Storage<N>, bytes: &[u8]) -> usize {
+        let len = bytes.len().min(self.free());
+        let first = len.min(N - self.wr);
+        buf.copy_from(self.wr, &bytes[..first]);
+        buf.copy_from(0, &bytes[first..len]);
+        self.wr = Self::wrap(self.wr + len);
+        self.count_written_bytes(len);
+        len
+    }
+
+    /// Count bytes written for the statistics
+    fn count_written_bytes(&mut self, len: usize) {
+        self.written_bytes = self.written_bytes.wrapping_add(len as u32);
+        self.high_water_mark = self.high_water_mark.max(self.len());
+    }
+
+    /// Take the waker of a waiting task if there is data to be read
+    fn take_waker(&mut self) -> Option<Waker> {
+        if self.is_empty() {
+            None
+        } else {
+            self.waker.take()
+        }
+    }
+
+    /// Take the data hook if the reader has found the buffer empty before
+    fn take_data_hook(&mut self) -> Option<fn()> {
+        if self.is_empty() || !self.data_hook_armed {
+            return None;
+        }
+        self.data_hook_armed = false;
+        self.data_hook
+    }
+
+    /// Collect the notifications due after the buffer has changed
+    fn notify(&mut self) -> Notify {
+        Notify {
+            waker: self.take_waker(),
+            data_hook: self.take_data_hook(),
+            watermark: self.watermark_crossed(),
+        }
+    }
+
+    /// Returns the watermark hook and its argument if the fill level has
+    /// crossed a watermark
+    fn watermark_crossed(&mut self) -> Option<(fn(bool), bool)> {
+        let hook = self.watermark_hook?;
+        let len = self.len();
+        if !self.above_watermark && len >= self.high_watermark {
+            self.above_watermark = true;
+            Some((hook, true))
+        } else if self.above_watermark && len <= self.low_watermark {
+            self.above_watermark = false;
+            Some((hook, false))
+        } else {
+            None
+        }
+    }
+
+    /// Byte terminating a record in the current format
+    fn delimiter(&self) -> u8 {
+        match self.format {
+            Format::Text => b'\n',
+            Format::Binary => 0,
+        }
+    }
+
+    /// Count bytes lost for the statistics
+    fn count_dropped_bytes(&mut self, len: usize) {
+        self.dropped_bytes = self.dropped_bytes.saturating_add(len as u32);
+        // the record being measured may have been lost
+        self.latency_sample = None;
+    }
+
+    /// Start measuring the latency of the record just written unless a
+    /// measurement is in progress
+    fn sample_latency(&mut self) {
+        if !self.latency_tracking || self.latency_sample.is_some() || self.is_empty() {
+            return;
+        }
+        if let Some(clock) = self.clock {
+            self.latency_sample = Some((self.len(), clock.now_us()));
+        }
+    }
+
+    /// Note that `len` bytes have been read, finishing the latency
+    /// measurement once the record being measured has been read
+    fn track_read(&mut self, len: usize) {
+        let Some((pending, start)) = self.latency_sample else {
+            return;
+        };
+        if len < pending {
+            self.latency_sample = Some((pending - len, start));
+            return;
+        }
+        self.latency_sample = None;
+        let Some(clock) = self.clock else {
+            return;
+        };
+        let us = clock.now_us().wrapping_sub(start).min(u32::MAX.into());
+        self.latency_sum_us += us;
+        let latency = &mut self.latency;
+        let us = us as u32;
+        if latency.samples == 0 || us < latency.min_us {
+            latency.min_us = us;
+        }
+        latency.max_us = latency.max_us.max(us);
+        latency.samples = latency.samples.saturating_add(1);
+        latency.avg_us = (self.latency_sum_us / u64::from(latency.samples)) as u32;
+    }
+
+    /// Discard the oldest `len` bytes counting the records lost
+    fn discard(&mut self, buf: &Storage<N>, len: usize) {
+        let delim = self.delimiter();
+        self.count_dropped_bytes(len);
+        for _ in 0..len {
+            if self.read(buf) == Some(delim) {
+                self.dropped = self.dropped.saturating_add(1);
+            }
+        }
+        // the discarded data may contain the last absolute timestamp, source
+        // or file frame
+        self.last_timestamp = None;
+        self.source_valid = false;
+        self.files.clear();
+    }
+
+    /// Discard the rest of a record whose beginning has already been read
+    fn skip_partial_record(&mut self, buf: &Storage<N>) {
+        if !self.mid_record {
+            return;
+        }
+        while self.mid_record && self.read(buf).is_some() {
+            self.count_dropped_bytes(1);
+        }
+        self.dropped = self.dropped.saturating_add(1);
+        self.last_timestamp = None;
+        self.source_valid = false;
+        self.files.clear();
+    }
+
+    /// Length of the oldest record including the terminating `delim`
+    ///
+    /// If the buffer is full without containing `delim`, the whole content is
+    /// considered a record. Returns None if there is no complete record.
+    fn record_len(&self, buf: &Storage<N>, delim: u8) -> Option<usize> {
+        let mut index = self.rd;
+        let mut len = 0;
+        while index != self.wr {
+            len += 1;
+            if buf.get(index) == delim {
+                return Some(len);
+            }
+            index = Self::inc_mod_n(index);
+        }
+        self.is_full().then_some(len)
+    }
+
+    /// Number of bytes that can be written without discarding data
+    fn free(&self) -> usize {
+        N - 1 - self.len()
+    }
+
+    /// Number of bytes that can be read
+    fn len(&self) -> usize {
+        Self::wrap(self.wr + N - self.rd)
+    }
+
     /// Returns true if LogBuffer is empty.
     pub fn is_empty(&self) -> bool {
         self.wr == self.rd
     }
 
+    /// Returns true if the oldest bytes must not be discarded to make room,
+    /// because they are being read or retained
+    fn keeps_oldest(&self) -> bool {
+        self.read_grant.is_some() || self.retaining
+    }
+
+    /// Remove the bytes written since `wr` was at `start`
+    ///
+    /// The removed bytes may include frames the following records rely on.
+    fn truncate(&mut self, start: usize) {
+        self.wr = start;
+        self.last_timestamp = None;
+        self.source_valid = false;
+        self.files.clear();
+    }
+
     /// Read a byte.
     ///
     /// Returns None if LogBuffer is empty.
-    pub fn read(&mut self) -> Option<u8> {
+    pub fn read(&mut self, buf: &Storage<N>) -> Option<u8> {
         if !self.is_empty() {
-            let byte = self.buf[self.rd];
+            self.retaining = false;
+            let byte = buf.get(self.rd);
             self.rd = Self::inc_mod_n(self.rd);
+            self.mid_record = byte != self.delimiter();
+            self.track_read(1);
             Some(byte)
         } else {
+            self.data_hook_armed = true;
             None
         }
     }
 
+    /// Discard all readable bytes
+    ///
+    /// The bytes are not counted as lost.
+    fn clear(&mut self) {
+        self.rd = self.wr;
+        self.latency_sample = None;
+        self.mid_record = false;
+        self.retaining = false;
+        self.dropped = 0;
+        self.overrun = false;
+        self.last_timestamp = None;
+        self.source_valid = false;
+        self.files.clear();
+    }
+
+    /// Reserve a contiguous region of up to `len` bytes starting at `wr`
+    ///
+    /// The oldest bytes are discarded if the region would overlap with them.
+    /// Returns the length of the reserved region.
+    fn grant(&mut self, buf: &Storage<N>, len: usize) -> Option<usize> {
+        if self.grant.is_some() {
+            return None;
+        }
+        let dist = Self::wrap(self.rd + N - self.wr);
+        let mut len = len.min(N - self.wr).min(N - 1);
+        if self.keeps_oldest() && dist != 0 {
+            // bytes being read or retained must not be discarded
+            len = len.min(dist - 1);
+        }
+        if len == 0 {
+            return None;
+        }
+        // discard the oldest bytes if rd lies within the granted region or on
+        // the byte following it, which must stay free to tell full from empty
+        if dist != 0 && dist <= len {
+            self.discard(buf, len + 1 - dist);
+        }
+        self.grant = Some(len);
+        Some(len)
+    }
+
+    /// Finish a write grant making `used` bytes of it available to readers
+    fn commit(&mut self, used: usize) {
+        if let Some(len) = self.grant.take() {
+            self.wr = Self::wrap(self.wr + used.min(len));
+            self.count_written_bytes(used.min(len));
+        }
+    }
+
+    /// Reserve the contiguous region of readable bytes starting at `rd`
+    ///
+    /// Returns the length of the reserved region.
+    fn read_grant(&mut self) -> Option<usize> {
+        if self.is_empty() {
+            self.data_hook_armed = true;
+            return None;
+        }
+        if self.read_grant.is_some() {
+            return None;
+        }
+        let len = if self.wr > self.rd {
+            self.wr - self.rd
+        } else {
+            N - self.rd
+        };
+        self.read_grant = Some(len);
+        Some(len)
+    }
+
+    /// Finish a read grant discarding `used` bytes of it
+    fn release(&mut self, buf: &Storage<N>, used: usize) {
+        if let Some(len) = self.read_grant.take() {
+            let used = used.min(len);
+            if used > 0 {
+                self.retaining = false;
+                self.mid_record = buf.get(self.rd + used - 1) != self.delimiter();
+            }
+            self.rd = Self::wrap(self.rd + used);
+            self.track_read(used);
+        }
+    }
+
     fn inc_mod_n(val: usize) -> usize {
-        if val + 1 < N {
-            val + 1
+        Self::wrap(val + 1)
+    }
+
+    /// Reduce an index in the range `0..2 * N` modulo N
+    ///
+    /// If N is a power of two, this is a mask operation without a branch.
+    fn wrap(val: usize) -> usize {
+        if N.is_power_of_two() {
+            val & (N - 1)
+        } else if val >= N {
+            val - N
         } else {
-            0
+            val
+        }
+    }
+}
+
+/// Upper bound of the length of a record except for the message and the file
+/// name, including a drop marker and frames preceding a binary record
+const RECORD_OVERHEAD: usize = 128;
+
+/// Writer counting the bytes written to it
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Upper bound of the number of bytes a record occupies in the buffer
+fn record_len(record: &Record) -> usize {
+    let mut counter = Counter(0);
+    write!(counter, "{}", record.args()).ok();
+    let len = counter.0 + record.file().map_or(0, str::len);
+    // COBS adds one byte per 254 bytes in binary mode
+    len + len / 254 + RECORD_OVERHEAD
+}
+
+/// Marker appended to truncated messages
+const ELLIPSIS: &str = "…";
+
+/// Maximum length of a drop marker
+const DROP_MARKER_LEN: usize = 32;
+
+/// Maximum number of bytes added to a merged record for the source tag
+const MERGE_OVERHEAD: usize = 8;
+
+/// Writer limiting the length of a message
+///
+/// Text exceeding the limit is cut at a character boundary and followed by an
+/// ellipsis.
+struct Truncate<'a, W: Write> {
+    out: &'a mut W,
+    remaining: usize,
+    truncated: bool,
+}
+
+impl<'a, W: Write> Truncate<'a, W> {
+    fn new(out: &'a mut W, max_len: usize) -> Self {
+        Truncate {
+            out,
+            remaining: max_len,
+            truncated: false,
+        }
+    }
+}
+
+impl<W: Write> Write for Truncate<'_, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.truncated {
+            return Ok(());
+        }
+        if s.len() <= self.remaining {
+            self.remaining -= s.len();
+            return self.out.write_str(s);
+        }
+        let mut end = self.remaining;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.truncated = true;
+        self.out.write_str(&s[..end])?;
+        self.out.write_str(ELLIPSIS)
+    }
+}
+
+/// Formats log records into the ring buffer
+struct Writer<'a, const N: usize> {
+    inner: &'a mut LogBufferInner<N>,
+    buf: &'a Storage<N>,
+}
+
+/// Statistics of a [`LogBuffer`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Bytes of log data lost because the buffer was full
+    pub dropped_bytes: u32,
+    /// Highest number of bytes waiting to be read at any time
+    pub high_water_mark: u32,
+    /// Bytes of log data written, wrapping around at 2^32
+    pub total_bytes: u32,
+    /// Time records spend in the buffer, see
+    /// [`LogBuffer::set_latency_tracking`]
+    pub latency: Latency,
+}
+
+impl Stats {
+    /// Encoded length of the statistics
+    pub const LEN: usize = 12;
+
+    /// Encode the statistics as little endian integers in the order of the
+    /// fields
+    ///
+    /// The latency is encoded separately, see [`Latency::to_bytes`].
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        let fields = [self.dropped_bytes, self.high_water_mark, self.total_bytes];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
         }
+        bytes
     }
 }
 
+/// Time log records spend in a [`LogBuffer`] until they have been read
+///
+/// One record at a time is measured, from being written until its last byte
+/// has been read, e.g. by the log channel transmitting it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Latency {
+    /// Number of records measured
+    pub samples: u32,
+    /// Minimum latency in microseconds
+    pub min_us: u32,
+    /// Average latency in microseconds
+    pub avg_us: u32,
+    /// Maximum latency in microseconds
+    pub max_us: u32,
+}
+
+impl Latency {
+    /// Encoded length of the latency statistics
+    pub const LEN: usize = 16;
+
+    /// Encode the latency statistics as little endian integers in the order
+    /// of the fields
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        let fields = [self.samples, self.min_us, self.avg_us, self.max_us];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Ring buffer holding up to `N - 1` bytes of log data
+///
+/// Choosing a power of two for `N` replaces the wrap-around checks of the
+/// buffer indices with cheaper mask operations.
 pub struct LogBuffer<const N: usize> {
-    inner: Mutex<RefCell<LogBufferInner<N>>>,
+    inner: Lock<LogBufferInner<N>>,
+    buf: Storage<N>,
 }
 
 impl<const N: usize> LogBuffer<N> {
     pub const fn new() -> LogBuffer<N> {
         LogBuffer {
-            inner: Mutex::new(RefCell::new(LogBufferInner::new())),
+            inner: Lock::new(LogBufferInner::new()),
+            buf: Storage::new(),
         }
     }
 
@@ -87,88 +693,1863 @@ impl<const N: usize> LogBuffer<N> {
     ///
     /// Returns None if LogBuffer is empty
     pub fn read(&self) -> Option<u8> {
-        critical_section::with(|cs| {
-            let mut inner = self.inner.borrow(cs).borrow_mut();
-            inner.read()
-        })
+        let (byte, notify) = self.inner.lock(|inner| {
+            if inner.read_grant.is_some() {
+                return (None, Notify::default());
+            }
+            (inner.read(&self.buf), inner.notify())
+        });
+        notify.deliver();
+        byte
     }
 
     /// Returns true if LogBuffer is empty
     pub fn is_empty(&self) -> bool {
-        critical_section::with(|cs| {
-            self.inner.borrow(cs).borrow().is_empty()
+        self.inner.lock(|inner| inner.is_empty())
+    }
+
+    /// Number of bytes waiting to be read
+    pub fn len(&self) -> usize {
+        self.inner.lock(|inner| inner.len())
+    }
+
+    /// Number of bytes that can be written before the oldest data is
+    /// discarded
+    pub fn free_space(&self) -> usize {
+        self.inner.lock(|inner| inner.free())
+    }
+
+    /// Maximum number of bytes the buffer can hold, which is `N - 1`
+    pub const fn capacity(&self) -> usize {
+        N - 1
+    }
+
+    /// Statistics of the buffer since it was created
+    ///
+    /// The host can read them by a control request, see
+    /// [`crate::control::GET_STATS_REQUEST`].
+    pub fn stats(&self) -> Stats {
+        self.inner.lock(|inner| Stats {
+            dropped_bytes: inner.dropped_bytes,
+            high_water_mark: inner.high_water_mark as u32,
+            total_bytes: inner.written_bytes,
+            latency: inner.latency,
         })
     }
-}
 
-impl<const N: usize> Write for LogBufferInner<N> {
-    /// Write a string slice
+    /// Measure the time records spend in the buffer until they are read
     ///
-    /// If the buffer is full then the oldest bytes of the buffer are discarded
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for byte in s.bytes() {
-            // discard the oldest byte if the buffer is full
-            if self.is_full() {
-                self.read();
-            }
-            let _ = self.write(byte); // this cannot fail
+    /// This requires a clock, see [`LogBuffer::set_clock`]. The latency is
+    /// part of the [statistics](LogBuffer::stats) and can be read by the host
+    /// by a control request, see [`crate::control::GET_LATENCY_REQUEST`].
+    /// Disabled by default.
+    pub fn set_latency_tracking(&self, enable: bool) {
+        self.inner.lock(|inner| {
+            inner.latency_tracking = enable;
+            inner.latency_sample = None;
+        })
+    }
+
+    /// Discard all data waiting to be read
+    ///
+    /// Unlike data lost due to overflow, the discarded data is neither
+    /// reported by a drop marker nor counted in the statistics, so that e.g.
+    /// each test case of an automated test can start with an empty log. A
+    /// record that has been read partially is cut off. Does nothing while a
+    /// read grant is outstanding.
+    pub fn clear(&self) {
+        self.inner
+            .lock(|inner| {
+                if inner.read_grant.is_none() {
+                    inner.clear();
+                }
+                inner.notify()
+            })
+            .deliver();
+    }
+
+    /// Select the encoding of subsequent log records
+    pub fn set_format(&self, format: Format) {
+        self.inner.lock(|inner| {
+            inner.format = format;
+            inner.last_timestamp = None;
+            inner.files.clear();
+        })
+    }
+
+    /// Enable or disable interning of file paths in binary mode
+    ///
+    /// If enabled, which is the default, the path of a source file is sent
+    /// once and subsequent records refer to it by a small id. Only paths
+    /// provided as `&'static str`, as by the `log` macros, are interned.
+    pub fn set_file_interning(&self, enable: bool) {
+        self.inner.lock(|inner| {
+            inner.file_interning = enable;
+            inner.files.clear();
+        })
+    }
+
+    /// Set the maximum length of log messages in bytes
+    ///
+    /// Longer messages are truncated at a UTF-8 character boundary and an
+    /// ellipsis (`…`) is appended. In binary mode, messages are additionally
+    /// limited by the maximum frame length.
+    pub fn set_max_record_len(&self, len: usize) {
+        self.inner.lock(|inner| inner.max_record_len = len)
+    }
+
+    /// Select the CRC appended to each frame in binary mode
+    pub fn set_frame_crc(&self, crc: Crc) {
+        self.inner.lock(|inner| inner.crc = crc)
+    }
+
+    /// Register a clock to timestamp log records
+    ///
+    /// Timestamps are disabled if `clock` is None. In binary mode, timestamps
+    /// are sent as deltas to the previous record with periodic absolute
+    /// timestamps for resynchronization.
+    pub fn set_clock(&self, clock: Option<&'static dyn Clock>) {
+        self.inner.lock(|inner| {
+            inner.clock = clock;
+            inner.last_timestamp = None;
+        })
+    }
+
+    /// Current time of the registered clock in microseconds
+    ///
+    /// Returns None if no clock has been registered.
+    pub fn now_us(&self) -> Option<u64> {
+        self.inner.lock(|inner| inner.clock).map(|clock| clock.now_us())
+    }
+
+    /// Pause or resume logging
+    ///
+    /// While logging is paused, log records and text written via
+    /// [`LogBuffer::writer`] are discarded without counting them as lost, and
+    /// nothing is merged from other buffers. The data already in the buffer is
+    /// kept, e.g. to be read later on. The host can switch logging by a
+    /// control request, see [`crate::control::SET_ENABLED_REQUEST`].
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.lock(|inner| inner.enabled = enabled)
+    }
+
+    /// Returns true unless logging is paused
+    pub fn is_enabled(&self) -> bool {
+        self.inner.lock(|inner| inner.enabled)
+    }
+
+    /// Write or discard the diagnostics of the log channels
+    ///
+    /// Diagnostics are records under the target [`crate::diagnostics::TARGET`]
+    /// and written by default.
+    pub fn set_diagnostics(&self, enable: bool) {
+        self.inner.lock(|inner| inner.diagnostics = enable)
+    }
+
+    /// Drop new records, reporting them as lost
+    ///
+    /// Unlike pausing, the records dropped are counted and reported by a drop
+    /// marker once dropping has been switched off again. This is used by the
+    /// USB log channels while the bus is suspended. Blocks written via
+    /// [`LogBuffer::writer`] count as one record each.
+    pub fn set_dropping(&self, dropping: bool) {
+        self.inner.lock(|inner| inner.dropping = dropping)
+    }
+
+    /// Set a function called when the fill level crosses a watermark
+    ///
+    /// `hook(true)` is called once the buffer holds `high` or more bytes and
+    /// `hook(false)` once it has been drained to `low` or fewer bytes again,
+    /// so that verbose parts of the firmware can throttle themselves before
+    /// data is lost. The hook is called from the context writing or reading
+    /// the buffer after the buffer has been unlocked, so it may log or change
+    /// the log level.
+    pub fn set_watermarks(&self, high: usize, low: usize, hook: Option<fn(bool)>) {
+        self.inner.lock(|inner| {
+            inner.high_watermark = high;
+            inner.low_watermark = low;
+            inner.watermark_hook = hook;
+            inner.above_watermark = false;
+        })
+    }
+
+    /// Make records of `level` and higher severity wait for free space
+    ///
+    /// Instead of discarding the oldest data, logging such a record spins
+    /// until the reader has made enough room for it, calling the hook set by
+    /// [`LogBuffer::set_wait_hook`] while waiting. This is meant for debugging
+    /// sessions, e.g. single-stepping or fault analysis, where stalling is
+    /// preferred over losing messages. The buffer must be drained by another
+    /// context or by the hook; otherwise logging blocks forever. The default
+    /// `LevelFilter::Off` never blocks.
+    pub fn set_blocking(&self, level: LevelFilter) {
+        self.inner.lock(|inner| inner.blocking = level)
+    }
+
+    /// Set a function called repeatedly while waiting for free space
+    ///
+    /// The hook typically polls the USB device so that the log buffer is
+    /// drained even if logging blocks the context that normally does this.
+    pub fn set_wait_hook(&self, hook: Option<fn()>) {
+        self.inner.lock(|inner| inner.wait_hook = hook)
+    }
+
+    /// Mark records of `level` and higher severity as urgent
+    ///
+    /// Writing an urgent record sets a flag that is returned by
+    /// [`LogBuffer::take_urgent`]. The bulk log channel uses it to wake up a
+    /// suspended host, see
+    /// [`crate::usb_log_channel_bulk::UsbLogChannel::enable_remote_wakeup`].
+    /// Urgent records are written even while records are dropped during a
+    /// suspension. The default `LevelFilter::Off` marks no record as urgent.
+    pub fn set_urgent_level(&self, level: LevelFilter) {
+        self.inner.lock(|inner| inner.urgent_level = level)
+    }
+
+    /// Returns true if an urgent record has been written since the last call
+    pub fn take_urgent(&self) -> bool {
+        self.inner.lock(|inner| core::mem::take(&mut inner.urgent))
+    }
+
+    /// Set a function called when data arrives for an idle reader
+    ///
+    /// The hook is called once data has been written after the reader found
+    /// the buffer empty, i.e. after [`LogBuffer::read`] or
+    /// [`LogBuffer::read_grant`] has returned None because of an empty
+    /// buffer. This allows the USB log channel to be driven by interrupts
+    /// only, without calling `tasks()` from a busy loop or a timer: the hook
+    /// pends the USB interrupt, whose handler polls the USB device and the
+    /// log channel. With RTIC:
+    ///
+    /// ```ignore
+    /// log_buffer.set_data_hook(Some(|| rtic::pend(Interrupt::USB)));
+    ///
+    /// #[task(binds = USB, local = [usb_dev, log_channel])]
+    /// fn usb(cx: usb::Context) {
+    ///     cx.local.usb_dev.poll(&mut [cx.local.log_channel]);
+    ///     cx.local.log_channel.tasks();
+    /// }
+    /// ```
+    ///
+    /// Like the watermark hook, the hook is called after the buffer has been
+    /// unlocked. Async tasks use [`LogBuffer::wait_nonempty`] instead.
+    pub fn set_data_hook(&self, hook: Option<fn()>) {
+        self.inner.lock(|inner| {
+            inner.data_hook = hook;
+            inner.data_hook_armed = true;
+        })
+    }
+
+    /// Keep the oldest data until the first read
+    ///
+    /// Normally, the oldest data is discarded to make room for new records.
+    /// In this mode, the data in the buffer is retained instead and new
+    /// records are dropped if they do not fit, until the reader has read
+    /// something for the first time. Then the buffer continues as a ring
+    /// buffer. This is meant to be enabled before the first record is logged,
+    /// so that the log of the boot, which is typically written before the
+    /// host has attached, is not overwritten. Clearing the buffer ends the
+    /// retention as well.
+    ///
+    /// The bulk log channel reads the buffer as soon as the device has been
+    /// configured. Enabling its alternate setting gating or credit-based flow
+    /// control defers the first read until a host reader is attached.
+    pub fn retain_until_read(&self) {
+        self.inner.lock(|inner| inner.retaining = true)
+    }
+
+    /// Set a function called when a host reader attaches or detaches
+    ///
+    /// The hook is called with true once a reader has attached and with false
+    /// once it has detached, so that the firmware can select its buffering
+    /// policy, e.g. block on a full buffer only while somebody is reading:
+    ///
+    /// ```ignore
+    /// LOG_BUFFER.set_reader_hook(Some(|attached| {
+    ///     LOG_BUFFER.set_blocking(if attached { LevelFilter::Info } else { LevelFilter::Off });
+    /// }));
+    /// ```
+    ///
+    /// The hook is called after the buffer has been unlocked.
+    pub fn set_reader_hook(&self, hook: Option<fn(bool)>) {
+        self.inner.lock(|inner| inner.reader_hook = hook)
+    }
+
+    /// Tell the buffer whether a host reader is attached
+    ///
+    /// The log channels call this when the host announces its reader by
+    /// [`crate::control::SET_READER_REQUEST`], when it selects or leaves the
+    /// streaming alternate setting and on a bus reset. The reader hook is
+    /// called if the state changes.
+    pub fn set_reader_attached(&self, attached: bool) {
+        let hook = self.inner.lock(|inner| {
+            let changed = inner.reader_attached != attached;
+            inner.reader_attached = attached;
+            inner.reader_hook.filter(|_| changed)
+        });
+        if let Some(hook) = hook {
+            hook(attached);
         }
-        #[cfg(feature = "rtt-target")]
-        rprint!("{}", s);
-        Ok(())
     }
-}
 
-impl<const N: usize> log::Log for LogBuffer<N> {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    /// Returns true if a host reader is attached
+    pub fn is_reader_attached(&self) -> bool {
+        self.inner.lock(|inner| inner.reader_attached)
     }
 
-    fn log(&self, record: &Record) {
-        const MAX_FILE_LEN: usize = 32;
-        critical_section::with(|cs| {
-            let mut inner = self.inner.borrow(cs).borrow_mut();
-            if self.enabled(record.metadata()) {
-                if record.target() == "PANIC" {
-                    writeln!(inner, "[PANIC] {}", record.args()).ok();
-                } else {
-                    let (prefix, file) = if let Some(f) = record.file_static() {
-                        if f.len() <= MAX_FILE_LEN {
-                            ("", f)
-                        } else {
-                            ("...", &f[f.len()-MAX_FILE_LEN..])
-                        }
-                    } else {
-                        ("???", "")
-                    };
-                    writeln!(
-                        inner,
-                        "[{}{}:{}] {}",
-                        prefix,
-                        file,
-                        record.line().unwrap_or(0),
-                        record.args()
-                    ).ok();
+    /// Reserve a contiguous region of the buffer for direct writing
+    ///
+    /// The returned grant provides up to `len` bytes, possibly fewer if the
+    /// region would wrap around the end of the buffer. This allows DMA engines
+    /// or memcpy-based producers to fill the buffer without an intermediate
+    /// copy. The oldest bytes are discarded to make room if needed. Log
+    /// records arriving while a grant is outstanding are dropped so that they
+    /// cannot interleave with the granted data.
+    ///
+    /// Returns None if `len` is zero or if another grant is outstanding.
+    pub fn grant(&self, len: usize) -> Option<WriteGrant<'_, N>> {
+        self.inner.lock(|inner| {
+            let start = inner.wr;
+            inner.grant(&self.buf, len).map(|len| {
+                // SAFETY: the region is in bounds and excluded from all other
+                // accesses until the grant is committed
+                let buf = unsafe {
+                    core::slice::from_raw_parts_mut(self.buf.ptr().add(start), len)
+                };
+                WriteGrant {
+                    log_buffer: self,
+                    buf,
+                    used: 0,
                 }
-            }
-        });
+            })
+        })
     }
 
-    fn flush(&self) {}
-}
+    /// Get direct access to the oldest bytes of the buffer
+    ///
+    /// The returned grant provides the contiguous region of bytes up to the
+    /// write position or the end of the buffer. This allows USB peripherals
+    /// to transmit straight out of the buffer. While the grant is outstanding,
+    /// [`LogBuffer::read`] returns None and new bytes that do not fit into the
+    /// remaining space are dropped rather than overwriting the granted region.
+    ///
+    /// Returns None if the buffer is empty or if another grant is outstanding.
+    pub fn read_grant(&self) -> Option<ReadGrant<'_, N>> {
+        self.inner.lock(|inner| {
+            let start = inner.rd;
+            inner.read_grant().map(|len| {
+                // SAFETY: the region is in bounds and will not be written to
+                // until the grant is released
+                let buf = unsafe {
+                    core::slice::from_raw_parts(self.buf.ptr().add(start), len)
+                };
+                ReadGrant {
+                    log_buffer: self,
+                    buf,
+                    used: 0,
+                }
+            })
+        })
+    }
 
-#[cfg(feature = "panic-handler")]
-use core::panic::PanicInfo;
-#[cfg(feature = "panic-handler")]
-use log::error;
+    /// Move complete records from another log buffer into this one
+    ///
+    /// This allows independent buffers, e.g. one per core or per subsystem,
+    /// to be logged to without contention and to be transmitted through a
+    /// single USB log channel. Each record is tagged with `tag` so that the
+    /// host can tell the sources apart: text lines are prefixed with `#<tag> `
+    /// and binary records are preceded by a `SOURCE` frame. Records are moved
+    /// only as long as they fit into the free space of this buffer.
+    ///
+    /// Call this for each source periodically, e.g. before polling the USB
+    /// device. Buffers must not be merged into each other in both directions.
+    ///
+    /// Returns the number of bytes moved.
+    pub fn merge_from<const M: usize>(&self, source: &LogBuffer<M>, tag: u8) -> usize {
+        if core::ptr::addr_eq(self, source) {
+            return 0;
+        }
+        let (moved, notify) = self.inner.lock(|inner| {
+            if inner.grant.is_some() || !inner.enabled || inner.dropping {
+                return (0, Notify::default());
+            }
+            let moved = source.inner.lock(|src| {
+                if src.read_grant.is_some() {
+                    return 0;
+                }
+                let format = src.format;
+                let delim = match format {
+                    Format::Text => b'\n',
+                    Format::Binary => 0,
+                };
+                let mut writer = Writer {
+                    inner,
+                    buf: &self.buf,
+                };
+                let mut moved = 0;
+                while let Some(len) = src.record_len(&source.buf, delim) {
+                    if len + MERGE_OVERHEAD > writer.inner.free() && !writer.inner.is_empty() {
+                        break;
+                    }
+                    match format {
+                        Format::Text => writer.push_tag(tag),
+                        Format::Binary => writer.select_source(Some(tag)),
+                    }
+                    let first = len.min(M - src.rd);
+                    writer.push_slice(source.buf.slice(src.rd, first));
+                    writer.push_slice(source.buf.slice(0, len - first));
+                    src.rd = LogBufferInner::<M>::wrap(src.rd + len);
+                    src.track_read(len);
+                    moved += len;
+                }
+                moved
+            });
+            (moved, inner.notify())
+        });
+        notify.deliver();
+        moved
+    }
+
+    /// Write a binary payload as a separate record
+    ///
+    /// The payload is opaque to the log; the host tool can write the payloads
+    /// of each `tag` to a file of their own. Returns false if the payload is
+    /// longer than [`frame::MAX_RAW_LEN`] bytes, in which case nothing is
+    /// written. Like log records, payloads are discarded while logging is
+    /// paused.
+    pub fn write_raw(&self, tag: u8, data: &[u8]) -> bool {
+        if data.len() > frame::MAX_RAW_LEN {
+            return false;
+        }
+        self.inner
+            .lock(|inner| {
+                if !inner.enabled {
+                    return Notify::default();
+                }
+                if inner.grant.is_some() || inner.dropping {
+                    inner.dropped = inner.dropped.saturating_add(1);
+                    return Notify::default();
+                }
+                let mut writer = Writer {
+                    inner: &mut *inner,
+                    buf: &self.buf,
+                };
+                writer.write_drop_marker();
+                writer.write_raw(tag, data);
+                inner.notify()
+            })
+            .deliver();
+        true
+    }
+
+    /// Write the return addresses of a backtrace as a separate record
+    ///
+    /// At most [`frame::MAX_BACKTRACE_LEN`] addresses are written, innermost
+    /// first. Used by [`crate::backtrace`].
+    pub fn write_backtrace(&self, addresses: &[usize]) {
+        let addresses = &addresses[..addresses.len().min(frame::MAX_BACKTRACE_LEN)];
+        self.inner
+            .lock(|inner| {
+                if !inner.enabled {
+                    return Notify::default();
+                }
+                if inner.grant.is_some() {
+                    inner.dropped = inner.dropped.saturating_add(1);
+                    return Notify::default();
+                }
+                let mut writer = Writer {
+                    inner: &mut *inner,
+                    buf: &self.buf,
+                };
+                writer.write_drop_marker();
+                writer.write_backtrace(addresses);
+                inner.notify()
+            })
+            .deliver();
+    }
+
+    /// Open a block of text that is written to the buffer contiguously
+    ///
+    /// The returned writer implements [`core::fmt::Write`] so that several
+    /// `write!`/`writeln!` calls, e.g. of a multi-line dump, form one block
+    /// that cannot be interleaved with records logged by interrupt handlers
+    /// or other threads. In binary mode, each line becomes a record of level
+    /// `Info`.
+    ///
+    /// The buffer stays locked until the writer is dropped. By default, this
+    /// means that a critical section is held, so the writer should be dropped
+    /// quickly. Records must not be logged from the same context while the
+    /// writer is held. While a write grant is outstanding, the block is dropped.
+    pub fn writer(&self) -> RecordWriter<'_, N> {
+        let mut inner = self.inner.guard();
+        if inner.enabled && (inner.dropping || inner.grant.is_some()) {
+            inner.dropped = inner.dropped.saturating_add(1);
+        } else if inner.enabled {
+            Writer {
+                inner: &mut inner,
+                buf: &self.buf,
+            }
+            .write_drop_marker();
+        }
+        RecordWriter {
+            log_buffer: self,
+            inner: Some(inner),
+            frame: None,
+            channel: None,
+            line_start: true,
+        }
+    }
+
+    /// Open a block of text belonging to a logical channel
+    ///
+    /// This allows several streams, e.g. a human readable log, a trace and
+    /// telemetry data, to share one USB log channel. The text is tagged with
+    /// `channel` in the same way as records merged by
+    /// [`LogBuffer::merge_from`], so channel numbers and source tags share one
+    /// namespace: in text mode, each line is prefixed with `#<channel> `, and
+    /// in binary mode, the records are preceded by a `SOURCE` frame. The host
+    /// tool can write each channel to a file of its own.
+    ///
+    /// ```ignore
+    /// const TELEMETRY: u8 = 2;
+    /// writeln!(log_buffer.channel_writer(TELEMETRY), "vbat={vbat}").ok();
+    /// ```
+    ///
+    /// The same restrictions as for [`LogBuffer::writer`] apply.
+    pub fn channel_writer(&self, channel: u8) -> RecordWriter<'_, N> {
+        let mut writer = self.writer();
+        writer.channel = Some(channel);
+        writer
+    }
+
+    /// Resynchronize the reader to the next record boundary
+    ///
+    /// If part of the oldest record has already been read, the rest of it is
+    /// discarded. This is needed if data has been lost in transit, e.g. when
+    /// the host halted the endpoint, so that the host can decode the following
+    /// records. Does nothing while a read grant is outstanding.
+    pub fn resync(&self) {
+        self.inner.lock(|inner| {
+            if inner.read_grant.is_none() {
+                inner.skip_partial_record(&self.buf);
+            }
+        })
+    }
+
+    /// Wait until the buffer contains data
+    ///
+    /// This allows an async USB task to sleep instead of polling
+    /// [`LogBuffer::is_empty`]. The task is woken when a log record is
+    /// written, a write grant is committed or records are merged. Only one
+    /// task can wait at a time; a waker registered by another task is
+    /// replaced.
+    pub async fn wait_nonempty(&self) {
+        poll_fn(|cx| {
+            self.inner.lock(|inner| {
+                if !inner.is_empty() {
+                    return Poll::Ready(());
+                }
+                match &inner.waker {
+                    Some(waker) if waker.will_wake(cx.waker()) => (),
+                    _ => inner.waker = Some(cx.waker().clone()),
+                }
+                Poll::Pending
+            })
+        })
+        .await
+    }
+
+    /// Wait for data and read it into `buf`
+    ///
+    /// Returns the number of bytes read, which is at least one unless `buf`
+    /// is empty.
+    pub async fn read_async(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        let mut len = 0;
+        while len == 0 {
+            self.wait_nonempty().await;
+            // the readable bytes may wrap around the end of the log buffer
+            while len < buf.len() {
+                let Some(grant) = self.read_grant() else {
+                    break;
+                };
+                let n = grant.len().min(buf.len() - len);
+                buf[len..len + n].copy_from_slice(&grant[..n]);
+                grant.release(n);
+                len += n;
+            }
+        }
+        len
+    }
+}
+
+/// Register a log buffer as the global logger and set the maximum log level
+///
+/// Returns the log buffer for use with the USB log channel.
+pub fn init<const N: usize>(
+    log_buffer: &'static LogBuffer<N>,
+    level: LevelFilter,
+) -> Result<&'static LogBuffer<N>, SetLoggerError> {
+    #[cfg(target_has_atomic = "ptr")]
+    log::set_logger(log_buffer)?;
+    // SAFETY: no other thread or interrupt can call set_logger_racy() or
+    // logger() while in the critical section
+    #[cfg(not(target_has_atomic = "ptr"))]
+    critical_section::with(|_| unsafe { log::set_logger_racy(log_buffer) })?;
+    log::set_max_level(level);
+    clock::set_global(log_buffer);
+    Ok(log_buffer)
+}
+
+impl<const N: usize> ClockSource for LogBuffer<N> {
+    fn now_us(&self) -> Option<u64> {
+        LogBuffer::now_us(self)
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Contiguous region of a [`LogBuffer`] reserved for direct writing
+///
+/// Dereferences to the granted bytes. Dropping the grant without calling
+/// [`WriteGrant::commit`] discards the written data.
+pub struct WriteGrant<'a, const N: usize> {
+    log_buffer: &'a LogBuffer<N>,
+    buf: &'a mut [u8],
+    used: usize,
+}
+
+impl<const N: usize> WriteGrant<'_, N> {
+    /// Make the first `used` bytes of the grant available to readers
+    pub fn commit(mut self, used: usize) {
+        self.used = used.min(self.buf.len());
+    }
+}
+
+impl<const N: usize> Deref for WriteGrant<'_, N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf
+    }
+}
+
+impl<const N: usize> DerefMut for WriteGrant<'_, N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+}
+
+impl<const N: usize> Drop for WriteGrant<'_, N> {
+    fn drop(&mut self) {
+        self.log_buffer
+            .inner
+            .lock(|inner| {
+                inner.commit(self.used);
+                inner.notify()
+            })
+            .deliver();
+    }
+}
+
+/// Notifications collected while the buffer is locked
+///
+/// They are delivered after the lock has been released so that the woken task
+/// or the hook can access the buffer.
+#[derive(Default)]
+#[must_use]
+struct Notify {
+    waker: Option<Waker>,
+    data_hook: Option<fn()>,
+    watermark: Option<(fn(bool), bool)>,
+}
+
+impl Notify {
+    fn deliver(self) {
+        if let Some(waker) = self.waker {
+            waker.wake();
+        }
+        if let Some(hook) = self.data_hook {
+            hook();
+        }
+        if let Some((hook, above)) = self.watermark {
+            hook(above);
+        }
+    }
+}
+
+impl<const N: usize> Writer<'_, N> {
+    /// Returns true if a write grant is outstanding, counting the `len` bytes
+    /// that must not be written into the granted region as lost
+    fn blocked_by_grant(&mut self, len: usize) -> bool {
+        if self.inner.grant.is_none() {
+            return false;
+        }
+        self.inner.overrun = true;
+        self.inner.count_dropped_bytes(len);
+        true
+    }
+
+    /// Write a byte
+    ///
+    /// If the buffer is full then the oldest byte of the buffer is discarded
+    fn push(&mut self, byte: u8) {
+        if self.blocked_by_grant(1) {
+            return;
+        }
+        if self.inner.is_full() {
+            if self.inner.keeps_oldest() {
+                // the oldest bytes are being read or retained
+                self.inner.overrun = true;
+                self.inner.count_dropped_bytes(1);
+                return;
+            }
+            self.inner.discard(self.buf, 1);
+        }
+        let _ = self.inner.write(self.buf, byte); // this cannot fail
+    }
+
+    /// Write a slice of bytes
+    ///
+    /// Same as calling `push()` for each byte but copies contiguous regions at
+    /// once.
+    fn push_slice(&mut self, mut bytes: &[u8]) {
+        if self.blocked_by_grant(bytes.len()) {
+            return;
+        }
+        let free = self.inner.free();
+        if bytes.len() > free {
+            if self.inner.keeps_oldest() {
+                // the oldest bytes are being read or retained
+                self.inner.count_dropped_bytes(bytes.len() - free);
+                bytes = &bytes[..free];
+                self.inner.overrun = true;
+            } else {
+                // only the last N - 1 bytes can be kept
+                let cut = bytes.len().saturating_sub(N - 1);
+                self.inner.count_dropped_bytes(cut);
+                bytes = &bytes[cut..];
+                let discard = bytes.len().saturating_sub(free);
+                self.inner.discard(self.buf, discard);
+            }
+        }
+        self.inner.write_slice(self.buf, bytes);
+    }
+
+    /// Write a COBS encoded frame
+    fn write_frame(&mut self, frame: &mut FrameBuf) {
+        if self.blocked_by_grant(frame.as_bytes().len()) {
+            return;
+        }
+        frame.append_crc(self.inner.crc);
+        frame::cobs_encode(frame.as_bytes(), |byte| self.push(byte));
+    }
+
+    /// Write the `#<tag> ` prefix of a merged text line
+    fn push_tag(&mut self, tag: u8) {
+        let mut prefix = [0, 0, 0, 0, b' '];
+        let mut start = 4;
+        let mut val = tag;
+        loop {
+            start -= 1;
+            prefix[start] = b'0' + val % 10;
+            val /= 10;
+            if val == 0 {
+                break;
+            }
+        }
+        prefix[start - 1] = b'#';
+        self.push_slice(&prefix[start - 1..]);
+    }
+
+    /// Report records lost since the previous marker
+    ///
+    /// The marker is postponed while it would not fit into the buffer or a
+    /// write grant is outstanding.
+    fn write_drop_marker(&mut self) {
+        if self.inner.overrun {
+            self.inner.overrun = false;
+            self.inner.dropped = self.inner.dropped.saturating_add(1);
+        }
+        let dropped = self.inner.dropped;
+        let no_room = self.inner.keeps_oldest() && self.inner.free() < DROP_MARKER_LEN;
+        if dropped == 0 || self.inner.grant.is_some() || no_room {
+            return;
+        }
+        self.inner.dropped = 0;
+        match self.inner.format {
+            Format::Text => {
+                writeln!(self, "[DROPPED] {dropped} records").ok();
+            }
+            Format::Binary => {
+                self.select_source(None);
+                let mut frame = FrameBuf::new(frame::FRAME_DROPPED);
+                frame.push_varint(dropped.into());
+                self.write_frame(&mut frame);
+            }
+        }
+    }
+
+    /// Write a binary payload as a `RAW` frame or as a line of hex digits
+    fn write_raw(&mut self, tag: u8, data: &[u8]) {
+        match self.inner.format {
+            Format::Text => {
+                write!(self, "[RAW {tag}] ").ok();
+                for byte in data {
+                    write!(self, "{byte:02x}").ok();
+                }
+                self.push(b'\n');
+            }
+            Format::Binary => {
+                self.select_source(None);
+                let mut frame = FrameBuf::new(frame::FRAME_RAW);
+                frame.push(tag);
+                frame.extend(data);
+                self.write_frame(&mut frame);
+            }
+        }
+    }
+
+    /// Write return addresses as a `BACKTRACE` frame or as a text line
+    fn write_backtrace(&mut self, addresses: &[usize]) {
+        match self.inner.format {
+            Format::Text => {
+                write!(self, "[BACKTRACE]").ok();
+                for address in addresses {
+                    write!(self, " {address:#010x}").ok();
+                }
+                self.push(b'\n');
+            }
+            Format::Binary => {
+                self.select_source(None);
+                let mut frame = FrameBuf::new(frame::FRAME_BACKTRACE);
+                for &address in addresses {
+                    frame.push_varint(address as u64);
+                }
+                self.write_frame(&mut frame);
+            }
+        }
+    }
+
+    /// Write a `SOURCE` frame if the following frames come from another source
+    fn select_source(&mut self, source: Option<u8>) {
+        if self.inner.source_valid && self.inner.source == source {
+            return;
+        }
+        let mut frame = FrameBuf::new(frame::FRAME_SOURCE);
+        if let Some(tag) = source {
+            frame.push(tag);
+        }
+        self.write_frame(&mut frame);
+        self.inner.source = source;
+        self.inner.source_valid = true;
+    }
+
+    /// Write a binary record preceded by an absolute timestamp if needed
+    fn write_binary(&mut self, record: &Record, timestamp: Option<u64>) {
+        self.select_source(None);
+        let mut header = match record.target() {
+            "PANIC" => frame::LEVEL_PANIC,
+            _ => record.level() as u8,
+        };
+        let mut delta = None;
+        if let Some(ts) = timestamp {
+            match self.inner.last_timestamp {
+                Some(last) if self.inner.sync_count < frame::TIMESTAMP_SYNC_INTERVAL => {
+                    self.inner.sync_count += 1;
+                    delta = Some(ts.wrapping_sub(last));
+                }
+                _ => {
+                    let mut sync = FrameBuf::new(frame::FRAME_TIMESTAMP);
+                    sync.push_varint(ts);
+                    self.write_frame(&mut sync);
+                    self.inner.sync_count = 0;
+                    delta = Some(0);
+                }
+            }
+            self.inner.last_timestamp = Some(ts);
+            header |= frame::RECORD_FLAG_TIMESTAMP;
+        }
+        // diagnostics are told apart by their file name
+        let diagnostic = record.target() == diagnostics::TARGET;
+        let (file, line) = if diagnostic {
+            (Some(diagnostics::TARGET), 0)
+        } else {
+            (record.file_static(), record.line().unwrap_or(0))
+        };
+        let file_id = match file.filter(|_| self.inner.file_interning) {
+            Some(file) => {
+                let (id, new) = self.inner.files.intern(file);
+                if new {
+                    let mut def = FrameBuf::new(frame::FRAME_FILE);
+                    def.push_varint(id.into());
+                    def.extend(file.as_bytes());
+                    self.write_frame(&mut def);
+                }
+                header |= frame::RECORD_FLAG_FILE_ID;
+                Some(id)
+            }
+            None => None,
+        };
+        let mut rec = FrameBuf::new(frame::FRAME_RECORD);
+        rec.push(header);
+        if let Some(delta) = delta {
+            rec.push_varint(delta);
+        }
+        rec.push_varint(line.into());
+        match file_id {
+            Some(id) => rec.push_varint(id.into()),
+            None => {
+                let file = match file {
+                    Some(file) => file,
+                    None => record.file().unwrap_or(""),
+                };
+                rec.push_varint(file.len() as u64);
+                rec.extend(file.as_bytes());
+            }
+        }
+        let max_len = self
+            .inner
+            .max_record_len
+            .min(rec.remaining().saturating_sub(ELLIPSIS.len()));
+        write!(Truncate::new(&mut rec, max_len), "{}", record.args()).ok();
+        self.write_frame(&mut rec);
+    }
+}
+
+/// Contiguous region of readable bytes of a [`LogBuffer`]
+///
+/// Dereferences to the granted bytes. Dropping the grant without calling
+/// [`ReadGrant::release`] leaves the bytes in the buffer.
+pub struct ReadGrant<'a, const N: usize> {
+    log_buffer: &'a LogBuffer<N>,
+    buf: &'a [u8],
+    used: usize,
+}
+
+impl<const N: usize> ReadGrant<'_, N> {
+    /// Remove the first `used` bytes of the grant from the buffer
+    pub fn release(mut self, used: usize) {
+        self.used = used.min(self.buf.len());
+    }
+}
+
+impl<const N: usize> Deref for ReadGrant<'_, N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf
+    }
+}
+
+impl<const N: usize> Drop for ReadGrant<'_, N> {
+    fn drop(&mut self) {
+        self.log_buffer
+            .inner
+            .lock(|inner| {
+                inner.release(&self.log_buffer.buf, self.used);
+                inner.notify()
+            })
+            .deliver();
+    }
+}
+
+impl<const N: usize> Write for Writer<'_, N> {
+    /// Write a string slice
+    ///
+    /// If the buffer is full then the oldest bytes of the buffer are discarded
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.inner.grant.is_some() {
+            return Err(core::fmt::Error);
+        }
+        self.push_slice(s.as_bytes());
+        #[cfg(feature = "rtt-target")]
+        rprint!("{}", s);
+        Ok(())
+    }
+}
+
+/// Block of text written to a [`LogBuffer`] contiguously
+///
+/// Obtained by [`LogBuffer::writer`]. The block is completed when the writer
+/// is dropped.
+pub struct RecordWriter<'a, const N: usize> {
+    log_buffer: &'a LogBuffer<N>,
+    /// Locked state, None after the writer has been finished
+    inner: Option<LockGuard<'a, LogBufferInner<N>>>,
+    /// Binary record of the current line
+    frame: Option<FrameBuf>,
+    /// Logical channel of the text, None for the buffer's own records
+    channel: Option<u8>,
+    /// The next text written starts a new line
+    line_start: bool,
+}
+
+impl<const N: usize> RecordWriter<'_, N> {
+    fn writer(&mut self) -> Writer<'_, N> {
+        Writer {
+            inner: self.inner.as_mut().unwrap(),
+            buf: &self.log_buffer.buf,
+        }
+    }
+
+    /// Write the record of the current line in binary mode
+    fn finish_line(&mut self) {
+        if let Some(mut frame) = self.frame.take() {
+            let channel = self.channel;
+            let mut writer = self.writer();
+            writer.select_source(channel);
+            writer.write_frame(&mut frame);
+        }
+    }
+
+    /// Write text in text mode prefixing each line with the channel tag
+    fn write_text(&mut self, s: &str) -> core::fmt::Result {
+        if self.writer().inner.grant.is_some() {
+            return Err(core::fmt::Error);
+        }
+        let Some(channel) = self.channel else {
+            return self.writer().write_str(s);
+        };
+        for line in s.split_inclusive('\n') {
+            let line_start = core::mem::replace(&mut self.line_start, line.ends_with('\n'));
+            let mut writer = self.writer();
+            if line_start {
+                writer.push_tag(channel);
+            }
+            writer.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Write for RecordWriter<'_, N> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let inner = self.writer().inner;
+        if !inner.enabled || inner.dropping || inner.grant.is_some() {
+            // the block has been counted as dropped
+            return Ok(());
+        }
+        if inner.format == Format::Text {
+            return self.write_text(s);
+        }
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.frame.get_or_insert_with(line_record).extend(first.as_bytes());
+        }
+        for line in lines {
+            self.finish_line();
+            if !line.is_empty() {
+                self.frame.get_or_insert_with(line_record).extend(line.as_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Drop for RecordWriter<'_, N> {
+    fn drop(&mut self) {
+        self.finish_line();
+        let notify = self.inner.take().map(|mut inner| inner.notify());
+        // deliver after the lock has been released
+        if let Some(notify) = notify {
+            notify.deliver();
+        }
+    }
+}
+
+/// Start a binary record of level `Info` without location
+fn line_record() -> FrameBuf {
+    let mut frame = FrameBuf::new(frame::FRAME_RECORD);
+    frame.push(log::Level::Info as u8);
+    frame.push_varint(0);
+    frame.push_varint(0);
+    frame
+}
+
+impl<const N: usize> log::Log for LogBuffer<N> {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // space required by a record that must not be dropped, None if the
+        // record can be written right away
+        let mut needed = None;
+        let notify = loop {
+            let res = self.inner.lock(|inner| {
+                let diagnostic = record.target() == diagnostics::TARGET;
+                if !inner.enabled || (diagnostic && !inner.diagnostics) {
+                    return Ok(Notify::default());
+                }
+                if record.level() <= inner.blocking && !inner.dropping {
+                    let needed = *needed.get_or_insert_with(|| record_len(record).min(N - 1));
+                    if inner.grant.is_some() || inner.free() < needed {
+                        return Err(inner.wait_hook);
+                    }
+                }
+                Ok(self.log_locked(inner, record))
+            });
+            match res {
+                Ok(notify) => break notify,
+                Err(Some(hook)) => hook(),
+                Err(None) => core::hint::spin_loop(),
+            }
+        };
+        notify.deliver();
+    }
+
+    fn flush(&self) {}
+}
+
+impl<const N: usize> LogBuffer<N> {
+    /// Write a record of a log channel under [`diagnostics::TARGET`]
+    ///
+    /// Unlike logging the record, this never waits for free space, as it is
+    /// called by the channel that frees the space.
+    pub(crate) fn write_diagnostic(&self, level: log::Level, args: core::fmt::Arguments) {
+        let record = Record::builder().level(level).target(diagnostics::TARGET).args(args).build();
+        let notify = self.inner.lock(|inner| {
+            if !inner.enabled || !inner.diagnostics {
+                return Notify::default();
+            }
+            self.log_locked(inner, &record)
+        });
+        notify.deliver();
+    }
+
+    /// Write an enabled record to the buffer
+    fn log_locked(&self, inner: &mut LogBufferInner<N>, record: &Record) -> Notify {
+        let (start, dropped, overrun) = (inner.wr, inner.dropped, inner.overrun);
+        self.write_record(inner, record);
+        if inner.retaining && inner.overrun {
+            // drop the whole record rather than keeping a part of it
+            inner.truncate(start);
+            inner.overrun = false;
+            inner.dropped = dropped.saturating_add(overrun as u32 + 1);
+        }
+        if record.level() <= inner.urgent_level {
+            inner.urgent = true;
+        }
+        inner.sample_latency();
+        inner.notify()
+    }
+
+    /// Format a log record into the buffer
+    fn write_record(&self, inner: &mut LogBufferInner<N>, record: &Record) {
+        const MAX_FILE_LEN: usize = 32;
+        let urgent = record.level() <= inner.urgent_level;
+        if inner.grant.is_some() || (inner.dropping && !urgent) {
+            inner.dropped = inner.dropped.saturating_add(1);
+            return;
+        }
+        let timestamp = inner.clock.map(|clock| clock.now_us());
+        let format = inner.format;
+        let mut inner = Writer {
+            inner,
+            buf: &self.buf,
+        };
+        inner.write_drop_marker();
+        if format == Format::Binary {
+            inner.write_binary(record, timestamp);
+            return;
+        }
+        if let Some(ts) = timestamp {
+            write!(inner, "[{}.{:06}]", ts / 1_000_000, ts % 1_000_000).ok();
+        }
+        if record.target() == "PANIC" {
+            write!(inner, "[PANIC] ").ok();
+        } else if record.target() == diagnostics::TARGET {
+            write!(inner, "[{}] ", diagnostics::TARGET).ok();
+        } else {
+            let (prefix, file) = if let Some(f) = record.file_static() {
+                if f.len() <= MAX_FILE_LEN {
+                    ("", f)
+                } else {
+                    ("...", &f[f.len()-MAX_FILE_LEN..])
+                }
+            } else {
+                ("???", "")
+            };
+            write!(
+                inner,
+                "[{}{}:{}] ",
+                prefix,
+                file,
+                record.line().unwrap_or(0),
+            ).ok();
+        }
+        let max_len = inner.inner.max_record_len;
+        write!(Truncate::new(&mut inner, max_len), "{}", record.args()).ok();
+        writeln!(inner).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Log};
+
+    extern crate std;
+    use std::vec::Vec;
+
+    fn log_info<const N: usize>(log_buffer: &LogBuffer<N>, args: core::fmt::Arguments) {
+        let record = Record::builder()
+            .level(Level::Info)
+            .file_static(Some("src/main.rs"))
+            .line(Some(10))
+            .args(args)
+            .build();
+        log_buffer.log(&record);
+    }
+
+    fn read_all<const N: usize>(log_buffer: &LogBuffer<N>) -> Vec<u8> {
+        core::iter::from_fn(|| log_buffer.read()).collect()
+    }
+
+    #[test]
+    fn text_record() {
+        let log_buffer = LogBuffer::<64>::new();
+        assert!(log_buffer.is_empty());
+        log_info(&log_buffer, format_args!("hello {}", 42));
+        assert_eq!(read_all(&log_buffer), b"[src/main.rs:10] hello 42\n");
+        assert!(log_buffer.is_empty());
+    }
+
+    #[test]
+    fn text_record_with_timestamp() {
+        static CLOCK: fn() -> u64 = || 12_000_345;
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.set_clock(Some(&CLOCK));
+        log_info(&log_buffer, format_args!("hello"));
+        assert_eq!(read_all(&log_buffer), b"[12.000345][src/main.rs:10] hello\n");
+    }
+
+    #[test]
+    fn overflow_discards_oldest_bytes() {
+        let log_buffer = LogBuffer::<8>::new();
+        log_info(&log_buffer, format_args!("0123456789"));
+        assert_eq!(read_all(&log_buffer), b"456789\n");
+    }
+
+    #[test]
+    fn overflow_without_power_of_two_capacity() {
+        let log_buffer = LogBuffer::<10>::new();
+        log_info(&log_buffer, format_args!("0123456789"));
+        assert_eq!(read_all(&log_buffer), b"23456789\n");
+        log_info(&log_buffer, format_args!("ab"));
+        assert_eq!(read_all(&log_buffer), b"s:10] ab\n");
+    }
+
+    #[test]
+    fn drop_marker_after_overflow() {
+        let log_buffer = LogBuffer::<64>::new();
+        // the first record and part of the second one are overwritten
+        for i in 0..5 {
+            log_info(&log_buffer, format_args!("{i}"));
+        }
+        assert!(read_all(&log_buffer).starts_with(b"10] 1\n"));
+        log_info(&log_buffer, format_args!("5"));
+        assert_eq!(read_all(&log_buffer), b"[DROPPED] 1 records\n[src/main.rs:10] 5\n");
+    }
+
+    #[test]
+    fn drop_marker_for_records_dropped_during_grant() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.set_format(Format::Binary);
+        let grant = log_buffer.grant(4).unwrap();
+        log_info(&log_buffer, format_args!("dropped"));
+        log_info(&log_buffer, format_args!("dropped"));
+        grant.commit(0);
+        log_info(&log_buffer, format_args!("a"));
+        let data = read_all(&log_buffer);
+        assert!(data.starts_with(&[3, frame::FRAME_DROPPED, 2, 0]));
+    }
+
+    #[test]
+    fn truncation_at_char_boundary() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.set_max_record_len(5);
+        log_info(&log_buffer, format_args!("äöü"));
+        log_info(&log_buffer, format_args!("abcde"));
+        assert_eq!(
+            read_all(&log_buffer),
+            "[src/main.rs:10] äö…\n[src/main.rs:10] abcde\n".as_bytes()
+        );
+    }
+
+    #[test]
+    fn binary_record_is_cobs_framed() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.set_format(Format::Binary);
+        log_buffer.set_file_interning(false);
+        log_info(&log_buffer, format_args!("hi"));
+        let data = read_all(&log_buffer);
+        assert_eq!(data.last(), Some(&0));
+        assert!(!data[..data.len() - 1].contains(&0));
+        assert!(data.ends_with(b"hi\0"));
+    }
+
+    #[test]
+    fn file_paths_are_interned() {
+        let log_buffer = LogBuffer::<128>::new();
+        log_buffer.set_format(Format::Binary);
+        log_info(&log_buffer, format_args!("a"));
+        log_info(&log_buffer, format_args!("b"));
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        // file, a, b
+        assert_eq!(frames.len(), 4);
+        assert!(frames[0].ends_with(b"src/main.rs"));
+        assert_eq!(frames[1][2] & frame::RECORD_FLAG_FILE_ID, frame::RECORD_FLAG_FILE_ID);
+        assert!(!frames[2].windows(7).any(|w| w == b"main.rs"));
+
+        // the path is sent again after data has been lost
+        for _ in 0..10 {
+            log_info(&log_buffer, format_args!("0123456789"));
+        }
+        assert!(read_all(&log_buffer).windows(11).any(|w| w == b"src/main.rs"));
+    }
+
+    #[test]
+    fn merge_text_records() {
+        let log_buffer = LogBuffer::<128>::new();
+        let core1 = LogBuffer::<64>::new();
+        log_info(&core1, format_args!("a"));
+        log_info(&log_buffer, format_args!("b"));
+        log_info(&core1, format_args!("c"));
+        assert_eq!(log_buffer.merge_from(&core1, 1), 38);
+        assert!(core1.is_empty());
+        assert_eq!(
+            read_all(&log_buffer),
+            b"[src/main.rs:10] b\n#1 [src/main.rs:10] a\n#1 [src/main.rs:10] c\n"
+        );
+    }
+
+    #[test]
+    fn merge_stops_when_full() {
+        let log_buffer = LogBuffer::<40>::new();
+        let core1 = LogBuffer::<64>::new();
+        log_info(&core1, format_args!("a"));
+        log_info(&core1, format_args!("b"));
+        assert_eq!(log_buffer.merge_from(&core1, 1), 19);
+        assert_eq!(log_buffer.merge_from(&core1, 1), 0);
+        assert_eq!(read_all(&log_buffer), b"#1 [src/main.rs:10] a\n");
+        assert_eq!(log_buffer.merge_from(&core1, 1), 19);
+    }
+
+    #[test]
+    fn merge_binary_records() {
+        let log_buffer = LogBuffer::<128>::new();
+        let core1 = LogBuffer::<64>::new();
+        log_buffer.set_format(Format::Binary);
+        core1.set_format(Format::Binary);
+        log_buffer.set_file_interning(false);
+        core1.set_file_interning(false);
+        log_info(&core1, format_args!("a"));
+        log_info(&core1, format_args!("b"));
+        log_buffer.merge_from(&core1, 7);
+        log_info(&log_buffer, format_args!("c"));
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        // source 7, a, b, own source, c
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[0], [3, frame::FRAME_SOURCE, 7]);
+        assert!(frames[2].ends_with(b"b"));
+        assert_eq!(frames[3], [2, frame::FRAME_SOURCE]);
+        assert!(frames[4].ends_with(b"c"));
+    }
+
+    #[test]
+    fn wait_nonempty_is_woken_by_log() {
+        use core::future::Future;
+        use core::pin::pin;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use core::task::Context;
+        use std::sync::Arc;
+        use std::task::Wake;
+
+        struct CountingWaker(AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let log_buffer = LogBuffer::<64>::new();
+        let mut buf = [0; 64];
+        {
+            let mut read = pin!(log_buffer.read_async(&mut buf));
+            assert!(read.as_mut().poll(&mut cx).is_pending());
+            assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+            log_info(&log_buffer, format_args!("a"));
+            assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+            assert_eq!(read.as_mut().poll(&mut cx), Poll::Ready(19));
+        }
+        assert_eq!(&buf[..19], b"[src/main.rs:10] a\n");
+    }
+
+    #[test]
+    fn resync_skips_partial_record() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_info(&log_buffer, format_args!("a"));
+        log_info(&log_buffer, format_args!("b"));
+        log_buffer.resync();
+        for _ in 0..4 {
+            log_buffer.read();
+        }
+        log_buffer.resync();
+        assert_eq!(read_all(&log_buffer), b"[src/main.rs:10] b\n");
+        log_buffer.resync();
+        assert!(log_buffer.is_empty());
+    }
+
+    #[test]
+    fn blocking_record_waits_for_reader() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static LOG_BUFFER: LogBuffer<256> = LogBuffer::new();
+        static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        // reads one record per call like a slow USB host
+        fn drain_record() {
+            HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+            while LOG_BUFFER.read().is_some_and(|b| b != b'\n') {}
+        }
+
+        LOG_BUFFER.set_blocking(LevelFilter::Warn);
+        LOG_BUFFER.set_wait_hook(Some(drain_record));
+        for _ in 0..12 {
+            log_info(&LOG_BUFFER, format_args!("x"));
+        }
+        assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 0);
+        let record = Record::builder()
+            .level(Level::Warn)
+            .file_static(Some("src/main.rs"))
+            .line(Some(10))
+            .args(format_args!("must log"))
+            .build();
+        LOG_BUFFER.log(&record);
+        assert!(HOOK_CALLS.load(Ordering::Relaxed) > 0);
+        let data = read_all(&LOG_BUFFER);
+        assert!(data.ends_with(b"x\n[src/main.rs:10] must log\n"));
+        assert!(!data.windows(9).any(|w| w == b"[DROPPED]"));
+    }
+
+    #[test]
+    fn urgent_record() {
+        let log_buffer = LogBuffer::<256>::new();
+        log_buffer.set_urgent_level(LevelFilter::Error);
+        log_buffer.set_dropping(true);
+        log_info(&log_buffer, format_args!("x"));
+        assert!(!log_buffer.take_urgent());
+        let record = Record::builder()
+            .level(Level::Error)
+            .file_static(Some("src/main.rs"))
+            .line(Some(10))
+            .args(format_args!("fault"))
+            .build();
+        log_buffer.log(&record);
+        assert!(log_buffer.take_urgent());
+        assert!(!log_buffer.take_urgent());
+        assert_eq!(read_all(&log_buffer), b"[DROPPED] 1 records\n[src/main.rs:10] fault\n");
+    }
+
+    #[test]
+    fn writer_block() {
+        let log_buffer = LogBuffer::<128>::new();
+        {
+            let mut writer = log_buffer.writer();
+            writeln!(writer, "registers:").unwrap();
+            for i in 0..2 {
+                writeln!(writer, "r{i} = {:#x}", i * 16).unwrap();
+            }
+        }
+        assert_eq!(read_all(&log_buffer), b"registers:\nr0 = 0x0\nr1 = 0x10\n");
+
+        log_buffer.set_format(Format::Binary);
+        write!(log_buffer.writer(), "a\nb").unwrap();
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames[0].ends_with(b"a"));
+        assert!(frames[1].ends_with(b"b"));
+    }
+
+    #[test]
+    fn writer_during_grant() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.set_format(Format::Binary);
+        let mut grant = log_buffer.grant(4).unwrap();
+        grant.copy_from_slice(b"abcd");
+        writeln!(log_buffer.writer(), "dropped").unwrap();
+        grant.commit(4);
+        log_info(&log_buffer, format_args!("a"));
+        let data = read_all(&log_buffer);
+        assert_eq!(data[..4], *b"abcd");
+        assert_eq!(data[4..8], [3, frame::FRAME_DROPPED, 1, 0]);
+
+        log_buffer.set_format(Format::Text);
+        let mut grant = log_buffer.grant(2).unwrap();
+        grant.copy_from_slice(b"ab");
+        writeln!(log_buffer.writer(), "dropped").unwrap();
+        grant.commit(2);
+        log_info(&log_buffer, format_args!("c"));
+        assert_eq!(
+            read_all(&log_buffer),
+            b"ab[DROPPED] 1 records\n[src/main.rs:10] c\n"
+        );
+    }
+
+    #[test]
+    fn dropping_records_are_reported() {
+        let log_buffer = LogBuffer::<128>::new();
+        log_buffer.set_dropping(true);
+        log_info(&log_buffer, format_args!("a"));
+        writeln!(log_buffer.writer(), "b").unwrap();
+        assert!(!log_buffer.write_raw(1, &[0; frame::MAX_RAW_LEN + 1]));
+        log_buffer.write_raw(1, &[0]);
+        log_buffer.set_dropping(false);
+        log_info(&log_buffer, format_args!("c"));
+        assert_eq!(
+            read_all(&log_buffer),
+            b"[DROPPED] 3 records\n[src/main.rs:10] c\n"
+        );
+    }
+
+    #[test]
+    fn channel_writer() {
+        let log_buffer = LogBuffer::<128>::new();
+        {
+            let mut writer = log_buffer.channel_writer(2);
+            write!(writer, "x=").unwrap();
+            writeln!(writer, "1").unwrap();
+            write!(writer, "y=2\nz=3\n").unwrap();
+        }
+        log_info(&log_buffer, format_args!("a"));
+        assert_eq!(
+            read_all(&log_buffer),
+            b"#2 x=1\n#2 y=2\n#2 z=3\n[src/main.rs:10] a\n"
+        );
+
+        log_buffer.set_format(Format::Binary);
+        writeln!(log_buffer.channel_writer(2), "t").unwrap();
+        log_info(&log_buffer, format_args!("a"));
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        // the record of the buffer itself is preceded by a FILE frame
+        assert_eq!(frames.len(), 6);
+        assert_eq!(frames[0], [3, frame::FRAME_SOURCE, 2]);
+        assert!(frames[1].ends_with(b"t"));
+        assert_eq!(frames[2], [2, frame::FRAME_SOURCE]);
+        assert!(frames[4].ends_with(b"a"));
+    }
+
+    #[test]
+    fn channel_writer_during_grant() {
+        let log_buffer = LogBuffer::<64>::new();
+        let mut grant = log_buffer.grant(2).unwrap();
+        grant.copy_from_slice(b"ab");
+        writeln!(log_buffer.channel_writer(2), "dropped").unwrap();
+        grant.commit(2);
+        writeln!(log_buffer.channel_writer(2), "x").unwrap();
+        assert_eq!(read_all(&log_buffer), b"ab[DROPPED] 1 records\n#2 x\n");
+    }
+
+    #[test]
+    fn paused_logging_keeps_buffer() {
+        let log_buffer = LogBuffer::<128>::new();
+        log_info(&log_buffer, format_args!("a"));
+        log_buffer.set_enabled(false);
+        assert!(!log_buffer.is_enabled());
+        log_info(&log_buffer, format_args!("b"));
+        writeln!(log_buffer.writer(), "c").unwrap();
+        log_buffer.set_enabled(true);
+        log_info(&log_buffer, format_args!("d"));
+        assert_eq!(
+            read_all(&log_buffer),
+            b"[src/main.rs:10] a\n[src/main.rs:10] d\n"
+        );
+    }
+
+    #[test]
+    fn watermark_hook() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static LOG_BUFFER: LogBuffer<128> = LogBuffer::new();
+        // number of calls with true and with false
+        static ABOVE: AtomicU32 = AtomicU32::new(0);
+        static BELOW: AtomicU32 = AtomicU32::new(0);
+        LOG_BUFFER.set_watermarks(40, 10, Some(|above| {
+            let count = if above { &ABOVE } else { &BELOW };
+            count.fetch_add(1, Ordering::Relaxed);
+            // the buffer is unlocked
+            assert!(!LOG_BUFFER.is_empty());
+        }));
+        log_info(&LOG_BUFFER, format_args!("a"));
+        assert_eq!(ABOVE.load(Ordering::Relaxed), 0);
+        log_info(&LOG_BUFFER, format_args!("b"));
+        log_info(&LOG_BUFFER, format_args!("c"));
+        assert_eq!(ABOVE.load(Ordering::Relaxed), 1);
+        for _ in 0..40 {
+            LOG_BUFFER.read();
+        }
+        assert_eq!(BELOW.load(Ordering::Relaxed), 0);
+        let grant = LOG_BUFFER.read_grant().unwrap();
+        grant.release(10);
+        assert_eq!(BELOW.load(Ordering::Relaxed), 1);
+        assert_eq!(ABOVE.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn data_hook() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static LOG_BUFFER: LogBuffer<128> = LogBuffer::new();
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        LOG_BUFFER.set_data_hook(Some(|| {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            // the buffer is unlocked
+            assert!(!LOG_BUFFER.is_empty());
+        }));
+        log_info(&LOG_BUFFER, format_args!("a"));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        // the reader has not been idle in between
+        log_info(&LOG_BUFFER, format_args!("b"));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        read_all(&LOG_BUFFER);
+        assert!(LOG_BUFFER.read_grant().is_none());
+        log_info(&LOG_BUFFER, format_args!("c"));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn retain_until_read() {
+        let log_buffer = LogBuffer::<64>::new();
+        log_buffer.retain_until_read();
+        for i in 0..5 {
+            log_info(&log_buffer, format_args!("{i}"));
+        }
+        assert_eq!(
+            read_all(&log_buffer),
+            b"[src/main.rs:10] 0\n[src/main.rs:10] 1\n[src/main.rs:10] 2\n"
+        );
+        log_info(&log_buffer, format_args!("5"));
+        assert_eq!(read_all(&log_buffer), b"[DROPPED] 2 records\n[src/main.rs:10] 5\n");
+
+        // the buffer is a ring buffer again
+        for i in 6..10 {
+            log_info(&log_buffer, format_args!("{i}"));
+        }
+        assert!(read_all(&log_buffer).ends_with(b"[src/main.rs:10] 9\n"));
+    }
+
+    #[test]
+    fn reader_hook() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static LOG_BUFFER: LogBuffer<128> = LogBuffer::new();
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        LOG_BUFFER.set_reader_hook(Some(|attached| {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            let level = if attached { LevelFilter::Info } else { LevelFilter::Off };
+            LOG_BUFFER.set_blocking(level);
+        }));
+        assert!(!LOG_BUFFER.is_reader_attached());
+        LOG_BUFFER.set_reader_attached(true);
+        LOG_BUFFER.set_reader_attached(true);
+        assert!(LOG_BUFFER.is_reader_attached());
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert_eq!(LOG_BUFFER.inner.lock(|inner| inner.blocking), LevelFilter::Info);
+        LOG_BUFFER.set_reader_attached(false);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+        assert_eq!(LOG_BUFFER.inner.lock(|inner| inner.blocking), LevelFilter::Off);
+    }
+
+    #[test]
+    fn fill_level() {
+        let log_buffer = LogBuffer::<100>::new();
+        assert_eq!(log_buffer.capacity(), 99);
+        assert_eq!((log_buffer.len(), log_buffer.free_space()), (0, 99));
+        log_info(&log_buffer, format_args!("a"));
+        assert_eq!((log_buffer.len(), log_buffer.free_space()), (19, 80));
+        for _ in 0..5 {
+            log_info(&log_buffer, format_args!("a"));
+        }
+        assert_eq!((log_buffer.len(), log_buffer.free_space()), (99, 0));
+        log_buffer.read();
+        assert_eq!((log_buffer.len(), log_buffer.free_space()), (98, 1));
+    }
+
+    #[test]
+    fn stats_and_clear() {
+        let log_buffer = LogBuffer::<100>::new();
+        for _ in 0..6 {
+            log_info(&log_buffer, format_args!("a"));
+        }
+        // six records of 19 bytes do not fit into 99 bytes
+        assert_eq!(log_buffer.stats().dropped_bytes, 15);
+        log_buffer.clear();
+        assert!(log_buffer.is_empty());
+        log_info(&log_buffer, format_args!("a"));
+        assert_eq!(read_all(&log_buffer), b"[src/main.rs:10] a\n");
+        let stats = log_buffer.stats();
+        assert_eq!(stats.dropped_bytes, 15);
+        assert_eq!(stats.high_water_mark, 99);
+        assert_eq!(stats.total_bytes, 7 * 19);
+    }
+
+    #[test]
+    fn raw_payload() {
+        let log_buffer = LogBuffer::<600>::new();
+        assert!(log_buffer.write_raw(3, &[0x00, 0xab, 0x10]));
+        assert_eq!(read_all(&log_buffer), b"[RAW 3] 00ab10\n");
+
+        log_buffer.set_format(Format::Binary);
+        assert!(log_buffer.write_raw(3, &[0x00, 0xab]));
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], [3, frame::FRAME_RAW, 3, 2, 0xab]);
+
+        assert!(log_buffer.write_raw(1, &[0x55; frame::MAX_RAW_LEN]));
+        assert!(!log_buffer.write_raw(1, &[0x55; frame::MAX_RAW_LEN + 1]));
+        let data = read_all(&log_buffer);
+        assert_eq!(data.iter().filter(|&&b| b == 0x55).count(), frame::MAX_RAW_LEN);
+    }
+
+    #[test]
+    fn backtrace() {
+        let log_buffer = LogBuffer::<256>::new();
+        log_buffer.write_backtrace(&[0x0800_1235, 0x0800_0101]);
+        assert_eq!(read_all(&log_buffer), b"[BACKTRACE] 0x08001235 0x08000101\n");
+
+        log_buffer.set_format(Format::Binary);
+        log_buffer.write_backtrace(&[0x81]);
+        let data = read_all(&log_buffer);
+        let frames: Vec<&[u8]> = data.split(|&b| b == 0).collect();
+        assert_eq!(frames[0], [4, frame::FRAME_BACKTRACE, 0x81, 0x01]);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let log_buffer = LogBuffer::<128>::new();
+        let record = Record::builder()
+            .level(Level::Warn)
+            .target(diagnostics::TARGET)
+            .file_static(Some("src/usb_log_channel_bulk.rs"))
+            .line(Some(500))
+            .args(format_args!("suspended"))
+            .build();
+        log_buffer.log(&record);
+        assert_eq!(read_all(&log_buffer), b"[usb-log] suspended\n");
+
+        log_buffer.set_diagnostics(false);
+        log_buffer.log(&record);
+        log_info(&log_buffer, format_args!("a"));
+        assert_eq!(read_all(&log_buffer), b"[src/main.rs:10] a\n");
+    }
+
+    #[test]
+    fn latency() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        static CLOCK: fn() -> u64 = || NOW.load(Ordering::Relaxed);
+        let log_buffer = LogBuffer::<256>::new();
+        log_buffer.set_clock(Some(&CLOCK));
+        log_buffer.set_latency_tracking(true);
+        NOW.store(100, Ordering::Relaxed);
+        log_info(&log_buffer, format_args!("a"));
+        // not measured while the first record is
+        NOW.store(150, Ordering::Relaxed);
+        log_info(&log_buffer, format_args!("b"));
+        NOW.store(400, Ordering::Relaxed);
+        read_all(&log_buffer);
+        NOW.store(500, Ordering::Relaxed);
+        log_info(&log_buffer, format_args!("c"));
+        NOW.store(600, Ordering::Relaxed);
+        read_all(&log_buffer);
+
+        let latency = log_buffer.stats().latency;
+        assert_eq!(latency.samples, 2);
+        assert_eq!((latency.min_us, latency.avg_us, latency.max_us), (100, 200, 300));
+        assert_eq!(latency.to_bytes()[..8], [2, 0, 0, 0, 100, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_grant() {
+        let log_buffer = LogBuffer::<16>::new();
+        let mut grant = log_buffer.grant(4).unwrap();
+        assert_eq!(grant.len(), 4);
+        assert!(log_buffer.grant(1).is_none());
+        // records are dropped while the grant is outstanding
+        log_info(&log_buffer, format_args!("dropped"));
+        grant[..2].copy_from_slice(b"ab");
+        grant.commit(2);
+        assert_eq!(read_all(&log_buffer), b"ab");
+
+        // dropping a grant discards its contents
+        drop(log_buffer.grant(4).unwrap());
+        assert!(log_buffer.is_empty());
+    }
+
+    #[test]
+    fn write_grant_stops_at_end_of_buffer() {
+        let log_buffer = LogBuffer::<16>::new();
+        log_buffer.grant(12).unwrap().commit(12);
+        assert_eq!(read_all(&log_buffer).len(), 12);
+        let grant = log_buffer.grant(8).unwrap();
+        assert_eq!(grant.len(), 4);
+    }
+
+    #[test]
+    fn write_grant_discards_oldest_bytes() {
+        let log_buffer = LogBuffer::<8>::new();
+        log_buffer.grant(4).unwrap().copy_from_slice(b"0123");
+        log_buffer.grant(4).unwrap().commit(0);
+        let mut grant = log_buffer.grant(4).unwrap();
+        grant.copy_from_slice(b"abcd");
+        grant.commit(4);
+        let mut grant = log_buffer.grant(7).unwrap();
+        assert_eq!(grant.len(), 4);
+        grant.copy_from_slice(b"efgh");
+        grant.commit(4);
+        assert_eq!(read_all(&log_buffer), b"bcdefgh");
+    }
+
+    #[test]
+    fn read_grant() {
+        let log_buffer = LogBuffer::<16>::new();
+        let mut grant = log_buffer.grant(6).unwrap();
+        grant.copy_from_slice(b"abcdef");
+        grant.commit(6);
+        let grant = log_buffer.read_grant().unwrap();
+        assert_eq!(&*grant, b"abcdef");
+        assert!(log_buffer.read_grant().is_none());
+        assert_eq!(log_buffer.read(), None);
+        grant.release(4);
+        assert_eq!(read_all(&log_buffer), b"ef");
+    }
 
-#[cfg(feature = "panic-handler")]
-#[panic_handler]
-fn panic(panic_info: &PanicInfo<'_>) -> ! {
-    if let Some(l) = panic_info.location() {
-        error!(target: "PANIC", "at {}:{}", l.file(), l.line());
+    #[test]
+    fn read_grant_is_not_overwritten() {
+        let log_buffer = LogBuffer::<8>::new();
+        log_info(&log_buffer, format_args!(""));
+        let grant = log_buffer.read_grant().unwrap();
+        let granted: Vec<u8> = grant.to_vec();
+        log_info(&log_buffer, format_args!("overflow"));
+        assert_eq!(&*grant, &granted[..]);
+        grant.release(granted.len());
     }
-    error!(target: "PANIC", "{}", panic_info.message());
-    error!(target: "PANIC", "entering endless loop.");
-    loop {}
 }
@@ -0,0 +1,222 @@
+//! Source of the log data sent by a log channel
+//!
+//! The log channels read their data from a [`LogSource`], which is a
+//! [`LogBuffer`] by default. Other sources let the log be fed from queues of
+//! the application, e.g. a heapless SPSC queue or a defmt buffer:
+//!
+//! ```ignore
+//! struct Trace(heapless::spsc::Consumer<'static, u8, 1024>);
+//!
+//! impl LogSource for Trace {
+//!     fn read(&mut self) -> Option<u8> {
+//!         self.0.dequeue()
+//!     }
+//!
+//!     fn is_empty(&self) -> bool {
+//!         !self.0.ready()
+//!     }
+//! }
+//! ```
+//!
+//! ```ignore
+//! let trace_channel: UsbLogChannel<_, 0, 64, Trace> = UsbLogChannel::with_source(&usb_bus, trace);
+//! ```
+//!
+//! The features depending on the log buffer, such as the statistics, the
+//! drop markers or resynchronization at record boundaries, are only
+//! available for sources implementing the respective methods. Of the vendor
+//! requests, other sources answer the log read and the protocol version
+//! requests by default.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::fmt;
+
+use crate::control;
+use crate::log_buffer::{LogBuffer, Stats};
+
+/// Source of log bytes
+pub trait LogSource {
+    /// Read a byte
+    ///
+    /// Returns None if no data is available.
+    fn read(&mut self) -> Option<u8>;
+
+    /// Returns true if no data is available
+    fn is_empty(&self) -> bool;
+
+    /// Move the available bytes to `buf`, as many as fit
+    ///
+    /// Returns the number of bytes moved. Sources holding their data in
+    /// contiguous memory should override this to copy it in one go.
+    fn read_chunk(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for byte in buf {
+            let Some(b) = self.read() else {
+                break;
+            };
+            *byte = b;
+            len += 1;
+        }
+        len
+    }
+
+    /// Pass the oldest bytes to `send` without reading them out first
+    ///
+    /// `send` gets the bytes and whether more bytes follow them and returns
+    /// the number of bytes it has taken, which are removed from the source.
+    /// Returns None if the source cannot lend its data, which is then read by
+    /// `read_chunk()`, or Some(false) if no data is available.
+    fn lend(&mut self, _send: &mut dyn FnMut(&[u8], bool) -> usize) -> Option<bool> {
+        None
+    }
+
+    /// Returns the buffer statistics, if the source keeps them
+    fn stats(&self) -> Option<Stats> {
+        None
+    }
+
+    /// Continue at a record boundary, as data in transit may have been lost
+    fn resync(&mut self) {}
+
+    /// Drop new data while `dropping` is set, see [`LogBuffer::set_dropping`]
+    fn set_dropping(&mut self, _dropping: bool) {}
+
+    /// Discard the available data
+    fn clear(&mut self) {
+        while self.read().is_some() {}
+    }
+
+    /// Returns true if an urgent record has been written since the last call,
+    /// see [`LogBuffer::take_urgent`]
+    fn take_urgent(&mut self) -> bool {
+        false
+    }
+
+    /// Note the attachment or detachment of a host reader
+    fn set_reader_attached(&mut self, _attached: bool) {}
+
+    /// Add a diagnostic record of the log channel reading the source, see
+    /// [`diagnostics`](crate::diagnostics)
+    ///
+    /// By default, the record is discarded.
+    fn report(&mut self, _level: log::Level, _args: fmt::Arguments) {}
+
+    /// Answer a vendor control IN request to the log interface
+    ///
+    /// Returns the length of the response written to `buf` or None if the
+    /// request is to be rejected. By default, the log read and the protocol
+    /// version requests are answered.
+    fn control_in(&mut self, request: u8, _value: u16, buf: &mut [u8]) -> Option<usize> {
+        match request {
+            control::LOG_READ_REQUEST => Some(self.read_chunk(buf)),
+            control::GET_VERSION_REQUEST => control::version_response(buf),
+            _ => None,
+        }
+    }
+
+    /// Carry out a vendor control OUT request without data to the log
+    /// interface
+    ///
+    /// Returns false if the request is to be rejected. By default, only the
+    /// maximum log level can be set.
+    fn control_out(&mut self, request: u8, value: u16) -> bool {
+        request == control::SET_LEVEL_REQUEST && control::set_max_level(value)
+    }
+}
+
+impl<const N: usize> LogSource for &LogBuffer<N> {
+    fn read(&mut self) -> Option<u8> {
+        LogBuffer::read(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        LogBuffer::is_empty(self)
+    }
+
+    fn read_chunk(&mut self, buf: &mut [u8]) -> usize {
+        control::read_into(buf, self)
+    }
+
+    /// The data is lent straight out of the ring buffer and stays there
+    /// until taken
+    fn lend(&mut self, send: &mut dyn FnMut(&[u8], bool) -> usize) -> Option<bool> {
+        let Some(grant) = self.read_grant() else {
+            return Some(false);
+        };
+        // the bytes up to the end of the ring buffer may be followed by more
+        // at its start
+        let more = self.len() > grant.len();
+        let used = send(&grant, more);
+        grant.release(used);
+        Some(true)
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        Some(LogBuffer::stats(self))
+    }
+
+    fn resync(&mut self) {
+        LogBuffer::resync(self);
+    }
+
+    fn set_dropping(&mut self, dropping: bool) {
+        LogBuffer::set_dropping(self, dropping);
+    }
+
+    fn clear(&mut self) {
+        LogBuffer::clear(self);
+    }
+
+    fn take_urgent(&mut self) -> bool {
+        LogBuffer::take_urgent(self)
+    }
+
+    fn set_reader_attached(&mut self, attached: bool) {
+        LogBuffer::set_reader_attached(self, attached);
+    }
+
+    fn report(&mut self, level: log::Level, args: fmt::Arguments) {
+        self.write_diagnostic(level, args);
+    }
+
+    fn control_in(&mut self, request: u8, value: u16, buf: &mut [u8]) -> Option<usize> {
+        control::response(self, request, value, buf)
+    }
+
+    fn control_out(&mut self, request: u8, value: u16) -> bool {
+        control::perform(self, request, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source providing the bytes of a slice
+    struct Bytes<'a>(&'a [u8]);
+
+    impl LogSource for Bytes<'_> {
+        fn read(&mut self) -> Option<u8> {
+            let (&first, rest) = self.0.split_first()?;
+            self.0 = rest;
+            Some(first)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    #[test]
+    fn read_chunk() {
+        let mut source = Bytes(b"abcde");
+        let mut buf = [0; 3];
+        assert_eq!(source.read_chunk(&mut buf), 3);
+        assert_eq!(&buf, b"abc");
+        assert_eq!(source.read_chunk(&mut buf), 2);
+        assert_eq!(&buf[..2], b"de");
+        assert!(source.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! USB bus for unit tests of the USB classes
+//!
+//! The bus accepts every packet written to an IN endpoint and records it so
+//! that tests can check the data a class has sent. Optionally, an endpoint
+//! holds a limited number of packets not yet taken by the test, like the
+//! packet buffers of a USB peripheral not yet read by the host. Packets from
+//! the host are queued by [`MockBus::push_out`].
+//!
+//! Control transfers are run by [`control_transfer`], which passes a SETUP
+//! packet to the USB device and collects the response, or by
+//! [`control_transfer_out`] for requests with an OUT data stage.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+extern crate std;
+
+use std::sync::Mutex;
+use std::vec::Vec;
+use usb_device::bus::PollResult;
+use usb_device::class_prelude::*;
+use usb_device::device::UsbDevice;
+use usb_device::endpoint::EndpointType;
+use usb_device::{Result, UsbDirection};
+
+/// Address of the control IN endpoint
+const EP0_IN: u8 = 0x80;
+/// Address of the control OUT endpoint
+const EP0_OUT: u8 = 0x00;
+/// Maximum packet size of the control endpoints of the test devices
+const EP0_SIZE: usize = 8;
+
+pub(crate) struct MockBus {
+    /// Number of endpoints allocated so far per direction
+    allocated: [u8; 2],
+    /// Packets written to IN endpoints in the order of writing
+    written: Mutex<Vec<(EndpointAddress, Vec<u8>)>>,
+    /// Number of packets an IN endpoint holds
+    in_capacity: usize,
+    /// SETUP packet not yet read by the USB device
+    setup: Mutex<Option<[u8; 8]>>,
+    /// A packet written to the control IN endpoint is waiting for its
+    /// completion to be reported
+    ep0_in_pending: Mutex<bool>,
+    stalled: Mutex<Vec<EndpointAddress>>,
+    /// Packets sent by the host to OUT endpoints, not yet read
+    out_packets: Mutex<Vec<(EndpointAddress, Vec<u8>)>>,
+}
+
+impl MockBus {
+    pub(crate) fn new() -> Self {
+        Self::with_in_capacity(usize::MAX)
+    }
+
+    /// Create a bus whose IN endpoints hold `capacity` packets each
+    pub(crate) fn with_in_capacity(capacity: usize) -> Self {
+        MockBus {
+            allocated: [0; 2],
+            written: Mutex::new(Vec::new()),
+            in_capacity: capacity,
+            setup: Mutex::new(None),
+            ep0_in_pending: Mutex::new(false),
+            stalled: Mutex::new(Vec::new()),
+            out_packets: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take the packets written to `ep` so far
+    pub(crate) fn take_packets(&self, ep: EndpointAddress) -> Vec<Vec<u8>> {
+        let mut written = self.written.lock().unwrap();
+        let (packets, rest) = written.drain(..).partition(|(addr, _)| *addr == ep);
+        *written = rest;
+        packets.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    /// Queue a packet sent by the host to the OUT endpoint `ep`
+    pub(crate) fn push_out(&self, ep: EndpointAddress, packet: &[u8]) {
+        self.out_packets.lock().unwrap().push((ep, packet.to_vec()));
+    }
+
+    fn has_ep0_out_packet(&self) -> bool {
+        let out_packets = self.out_packets.lock().unwrap();
+        out_packets.iter().any(|(addr, _)| *addr == EP0_OUT.into())
+    }
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        if let Some(addr) = ep_addr {
+            return Ok(addr);
+        }
+        // endpoint 0 is reserved for the control pipe
+        let count = &mut self.allocated[(ep_dir == UsbDirection::In) as usize];
+        *count += 1;
+        Ok(EndpointAddress::from_parts(*count as usize, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut written = self.written.lock().unwrap();
+        if ep_addr == EP0_IN.into() {
+            *self.ep0_in_pending.lock().unwrap() = true;
+        } else if written.iter().filter(|(addr, _)| *addr == ep_addr).count() >= self.in_capacity {
+            return Err(UsbError::WouldBlock);
+        }
+        written.push((ep_addr, buf.to_vec()));
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        if ep_addr.index() != 0 {
+            let mut out_packets = self.out_packets.lock().unwrap();
+            let pos = out_packets.iter().position(|(addr, _)| *addr == ep_addr);
+            let (_, packet) = out_packets.remove(pos.ok_or(UsbError::WouldBlock)?);
+            buf[..packet.len()].copy_from_slice(&packet);
+            return Ok(packet.len());
+        }
+        if let Some(setup) = self.setup.lock().unwrap().take() {
+            buf[..setup.len()].copy_from_slice(&setup);
+            return Ok(setup.len());
+        }
+        // data stage of a control OUT transfer
+        let mut out_packets = self.out_packets.lock().unwrap();
+        let pos = out_packets.iter().position(|(addr, _)| *addr == EP0_OUT.into());
+        let (_, packet) = out_packets.remove(pos.ok_or(UsbError::WouldBlock)?);
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut list = self.stalled.lock().unwrap();
+        list.retain(|addr| *addr != ep_addr);
+        if stalled {
+            list.push(ep_addr);
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.stalled.lock().unwrap().contains(&ep_addr)
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    /// Report a pending SETUP packet, else a packet of a control OUT data
+    /// stage or else the completion of a control IN packet
+    fn poll(&self) -> PollResult {
+        if self.setup.lock().unwrap().is_some() {
+            return PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 1,
+            };
+        }
+        if self.has_ep0_out_packet() {
+            return PollResult::Data {
+                ep_out: 1,
+                ep_in_complete: 0,
+                ep_setup: 0,
+            };
+        }
+        if core::mem::take(&mut *self.ep0_in_pending.lock().unwrap()) {
+            return PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 1,
+                ep_setup: 0,
+            };
+        }
+        PollResult::None
+    }
+}
+
+/// Build a SETUP packet
+pub(crate) fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let mut packet = [request_type, request, 0, 0, 0, 0, 0, 0];
+    packet[2..4].copy_from_slice(&value.to_le_bytes());
+    packet[4..6].copy_from_slice(&index.to_le_bytes());
+    packet[6..8].copy_from_slice(&length.to_le_bytes());
+    packet
+}
+
+/// Run a control transfer without OUT data stage
+///
+/// Returns the data sent by the device, which is empty for OUT requests, or
+/// None if the device has rejected the request.
+pub(crate) fn control_transfer(
+    usb_dev: &mut UsbDevice<'_, MockBus>,
+    classes: &mut [&mut dyn UsbClass<MockBus>],
+    setup: [u8; 8],
+) -> Option<Vec<u8>> {
+    control_transfer_out(usb_dev, classes, setup, &[])
+}
+
+/// Run a control transfer sending `data` in the OUT data stage
+///
+/// The length in `setup` must match the length of `data`.
+pub(crate) fn control_transfer_out(
+    usb_dev: &mut UsbDevice<'_, MockBus>,
+    classes: &mut [&mut dyn UsbClass<MockBus>],
+    setup: [u8; 8],
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    // a SETUP packet clears a stall of the control endpoints
+    usb_dev.bus().set_stalled(EP0_IN.into(), false);
+    *usb_dev.bus().setup.lock().unwrap() = Some(setup);
+    for packet in data.chunks(EP0_SIZE) {
+        usb_dev.bus().push_out(EP0_OUT.into(), packet);
+    }
+    // each poll handles the SETUP packet, an OUT packet or the completion of
+    // an IN packet
+    let pending = |bus: &MockBus| {
+        bus.setup.lock().unwrap().is_some()
+            || bus.has_ep0_out_packet()
+            || *bus.ep0_in_pending.lock().unwrap()
+    };
+    while pending(usb_dev.bus()) {
+        usb_dev.poll(classes);
+        if usb_dev.bus().is_stalled(EP0_IN.into()) {
+            let bus = usb_dev.bus();
+            bus.take_packets(EP0_IN.into());
+            bus.out_packets.lock().unwrap().retain(|(addr, _)| *addr != EP0_OUT.into());
+            return None;
+        }
+    }
+    Some(usb_dev.bus().take_packets(EP0_IN.into()).concat())
+}
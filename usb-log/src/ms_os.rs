@@ -0,0 +1,176 @@
+//! Microsoft OS 2.0 descriptors
+//!
+//! These descriptors make Windows 8.1 and later bind the WinUSB driver to the
+//! log interface so that the host tool can access it without installing a
+//! driver, e.g. by Zadig. The device announces the descriptor set by a
+//! platform capability in its BOS descriptor; Windows then reads the set by a
+//! vendor request with the vendor code given in the capability.
+//!
+//! The descriptor set is 178 bytes long, which exceeds the control buffer of
+//! `usb-device` unless its `control-buffer-256` feature is enabled. The BOS
+//! descriptor is only served if the device is built with `UsbRev::Usb210`,
+//! which is the default.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// Interface GUID under which Windows registers the log interface
+pub const DEVICE_INTERFACE_GUID: &str = "{8B5E2D4A-6C3F-4E1B-9A7D-2F0C5B8E4A61}";
+
+/// Length of the descriptor set
+pub const DESCRIPTOR_SET_LEN: usize = 178;
+
+/// wIndex of the request reading the descriptor set
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+
+/// Device capability type of a platform capability
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// Platform capability UUID {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}
+const MS_OS_20_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+/// Windows 8.1
+const WINDOWS_VERSION: u32 = 0x0603_0000;
+
+const SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const FEATURE_REG_PROPERTY: u16 = 0x04;
+const REG_MULTI_SZ: u16 = 0x07;
+
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+
+/// Write the platform capability announcing the descriptor set
+pub(crate) fn write_capability(writer: &mut BosWriter, vendor_code: u8) -> Result<()> {
+    let mut data = [0; 25];
+    data[1..17].copy_from_slice(&MS_OS_20_UUID);
+    data[17..21].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+    data[21..23].copy_from_slice(&(DESCRIPTOR_SET_LEN as u16).to_le_bytes());
+    data[23] = vendor_code;
+    writer.capability(CAPABILITY_PLATFORM, &data)
+}
+
+/// Returns true if `request` reads the descriptor set
+pub(crate) fn is_descriptor_request(request: &Request, vendor_code: u8) -> bool {
+    request.request_type == RequestType::Vendor
+        && request.recipient == Recipient::Device
+        && request.request == vendor_code
+        && request.index == MS_OS_20_DESCRIPTOR_INDEX
+}
+
+/// Answer a request for the descriptor set
+pub(crate) fn descriptor_set<B: UsbBus>(xfer: ControlIn<B>, iface: InterfaceNumber) {
+    let request_len = xfer.request().length as usize;
+    xfer.accept(|buf| {
+        let len = write_descriptor_set(buf, iface.into())?;
+        Ok(len.min(request_len))
+    })
+    .ok();
+}
+
+/// Write the descriptor set binding WinUSB to interface `iface`
+///
+/// The descriptor set has a function subset so that it also applies to
+/// composite devices.
+fn write_descriptor_set(buf: &mut [u8], iface: u8) -> Result<usize> {
+    if buf.len() < DESCRIPTOR_SET_LEN {
+        return Err(UsbError::BufferOverflow);
+    }
+    let mut w = Writer { buf, pos: 0 };
+    w.u16(10);
+    w.u16(SET_HEADER_DESCRIPTOR);
+    w.u32(WINDOWS_VERSION);
+    w.u16(DESCRIPTOR_SET_LEN as u16);
+
+    w.u16(8);
+    w.u16(SUBSET_HEADER_CONFIGURATION);
+    w.bytes(&[0, 0]); // configuration index, reserved
+    w.u16(DESCRIPTOR_SET_LEN as u16 - 10);
+
+    w.u16(8);
+    w.u16(SUBSET_HEADER_FUNCTION);
+    w.bytes(&[iface, 0]);
+    w.u16(DESCRIPTOR_SET_LEN as u16 - 18);
+
+    w.u16(20);
+    w.u16(FEATURE_COMPATIBLE_ID);
+    w.bytes(b"WINUSB\0\0");
+    w.bytes(&[0; 8]);
+
+    // the name and the data are null terminated UTF-16 strings, the data is
+    // a list terminated by another null character
+    let name_len = 2 * (PROPERTY_NAME.len() + 1);
+    let data_len = 2 * (DEVICE_INTERFACE_GUID.len() + 2);
+    w.u16((10 + name_len + data_len) as u16);
+    w.u16(FEATURE_REG_PROPERTY);
+    w.u16(REG_MULTI_SZ);
+    w.u16(name_len as u16);
+    w.utf16(PROPERTY_NAME);
+    w.u16(0);
+    w.u16(data_len as u16);
+    w.utf16(DEVICE_INTERFACE_GUID);
+    w.u16(0);
+    w.u16(0);
+    Ok(w.pos)
+}
+
+/// Writer of little endian fields
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_le_bytes());
+    }
+
+    /// Write an ASCII string as UTF-16 without terminating null character
+    fn utf16(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.u16(byte.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_set_lengths() {
+        let mut buf = [0; 256];
+        assert!(matches!(write_descriptor_set(&mut buf, 2), Ok(DESCRIPTOR_SET_LEN)));
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+        assert_eq!(u16_at(8), DESCRIPTOR_SET_LEN as u16);
+        // function subset
+        assert_eq!(buf[22], 2);
+        assert_eq!(u16_at(24), 160);
+        // registry property
+        assert_eq!(u16_at(46), 132);
+        assert_eq!(&buf[30..36], b"WINUSB");
+        assert_eq!(buf[DESCRIPTOR_SET_LEN - 4..DESCRIPTOR_SET_LEN], [0; 4]);
+        assert!(matches!(
+            write_descriptor_set(&mut [0; 128], 2),
+            Err(UsbError::BufferOverflow)
+        ));
+    }
+}
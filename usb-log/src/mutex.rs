@@ -0,0 +1,111 @@
+//! Mutex protecting the state of a log buffer
+//!
+//! By default, the state is protected by a critical section. With the `std`
+//! feature, a std mutex is used instead so that the log buffer can be tested
+//! on the host.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+#[cfg(not(feature = "std"))]
+use core::cell::{RefCell, RefMut};
+use core::ops::{Deref, DerefMut};
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct Lock<T>(critical_section::Mutex<RefCell<T>>);
+
+#[cfg(not(feature = "std"))]
+impl<T> Lock<T> {
+    pub const fn new(val: T) -> Self {
+        Lock(critical_section::Mutex::new(RefCell::new(val)))
+    }
+
+    /// Call `f` with exclusive access to the protected value
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.0.borrow(cs).borrow_mut()))
+    }
+
+    /// Acquire exclusive access until the returned guard is dropped
+    ///
+    /// The critical section is held for the lifetime of the guard.
+    pub fn guard(&self) -> LockGuard<'_, T> {
+        // SAFETY: the critical section is released in the same order when
+        // the guard is dropped, after the borrow has ended
+        let state = unsafe { critical_section::acquire() };
+        let cs = unsafe { critical_section::CriticalSection::new() };
+        LockGuard {
+            inner: Some(self.0.borrow(cs).borrow_mut()),
+            state,
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) struct LockGuard<'a, T> {
+    inner: Option<RefMut<'a, T>>,
+    state: critical_section::RestoreState,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Drop for LockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.inner = None;
+        // SAFETY: acquired in Lock::guard()
+        unsafe { critical_section::release(self.state) }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.as_mut().unwrap()
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) struct Lock<T>(std::sync::Mutex<T>);
+
+#[cfg(feature = "std")]
+impl<T> Lock<T> {
+    pub const fn new(val: T) -> Self {
+        Lock(std::sync::Mutex::new(val))
+    }
+
+    /// Call `f` with exclusive access to the protected value
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.guard())
+    }
+
+    /// Acquire exclusive access until the returned guard is dropped
+    pub fn guard(&self) -> LockGuard<'_, T> {
+        LockGuard(self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner))
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) struct LockGuard<'a, T>(std::sync::MutexGuard<'a, T>);
+
+#[cfg(feature = "std")]
+impl<T> Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
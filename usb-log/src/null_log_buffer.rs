@@ -0,0 +1,358 @@
+//! Log buffer that discards all log records
+//!
+//! This replaces the `log_buffer` module if the `null-logger` feature is
+//! enabled. It has the same API but neither stores nor formats anything so that
+//! release builds can drop the buffer RAM and the formatting code without
+//! changes to the application code. The feature also sets the static maximum
+//! level of the `log` crate to `Off`, which removes the log statements of the
+//! whole firmware at compile time.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::convert::Infallible;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+
+use crate::clock::{Clock, ClockSource};
+use crate::frame::Crc;
+
+pub use crate::frame::Format;
+
+/// Statistics of a [`LogBuffer`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Bytes of log data lost because the buffer was full
+    pub dropped_bytes: u32,
+    /// Highest number of bytes waiting to be read at any time
+    pub high_water_mark: u32,
+    /// Bytes of log data written, wrapping around at 2^32
+    pub total_bytes: u32,
+    /// Time records spend in the buffer
+    pub latency: Latency,
+}
+
+impl Stats {
+    /// Encoded length of the statistics
+    pub const LEN: usize = 12;
+
+    /// Encode the statistics as little endian integers, which are all zero
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [0; Self::LEN]
+    }
+}
+
+/// Time log records spend in a [`LogBuffer`] until they have been read
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Latency {
+    /// Number of records measured
+    pub samples: u32,
+    /// Minimum latency in microseconds
+    pub min_us: u32,
+    /// Average latency in microseconds
+    pub avg_us: u32,
+    /// Maximum latency in microseconds
+    pub max_us: u32,
+}
+
+impl Latency {
+    /// Encoded length of the latency statistics
+    pub const LEN: usize = 16;
+
+    /// Encode the latency statistics as little endian integers, which are
+    /// all zero
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [0; Self::LEN]
+    }
+}
+
+pub struct LogBuffer<const N: usize>;
+
+impl<const N: usize> LogBuffer<N> {
+    pub const fn new() -> LogBuffer<N> {
+        LogBuffer
+    }
+
+    /// Read a byte
+    ///
+    /// Always returns None
+    pub fn read(&self) -> Option<u8> {
+        None
+    }
+
+    /// Returns true if LogBuffer is empty, which it always is
+    pub fn is_empty(&self) -> bool {
+        true
+    }
+
+    /// Number of bytes waiting to be read, which is always 0
+    pub fn len(&self) -> usize {
+        0
+    }
+
+    /// Number of bytes that can be written before the oldest data is
+    /// discarded, which is always 0
+    pub fn free_space(&self) -> usize {
+        0
+    }
+
+    /// Maximum number of bytes the buffer can hold, which is 0
+    pub const fn capacity(&self) -> usize {
+        0
+    }
+
+    /// Statistics of the buffer, which are always zero
+    pub fn stats(&self) -> Stats {
+        Stats::default()
+    }
+
+    /// Measure the time records spend in the buffer until they are read
+    pub fn set_latency_tracking(&self, _enable: bool) {}
+
+    /// Discard all data waiting to be read
+    pub fn clear(&self) {}
+
+    /// Select the encoding of subsequent log records
+    pub fn set_format(&self, _format: Format) {}
+
+    /// Enable or disable interning of file paths in binary mode
+    pub fn set_file_interning(&self, _enable: bool) {}
+
+    /// Set the maximum length of log messages in bytes
+    pub fn set_max_record_len(&self, _len: usize) {}
+
+    /// Select the CRC appended to each frame in binary mode
+    pub fn set_frame_crc(&self, _crc: Crc) {}
+
+    /// Register a clock to timestamp log records
+    pub fn set_clock(&self, _clock: Option<&'static dyn Clock>) {}
+
+    /// Current time of the registered clock in microseconds
+    ///
+    /// Always returns None
+    pub fn now_us(&self) -> Option<u64> {
+        None
+    }
+
+    /// Pause or resume logging
+    pub fn set_enabled(&self, _enabled: bool) {}
+
+    /// Returns true unless logging is paused
+    ///
+    /// Always returns false
+    pub fn is_enabled(&self) -> bool {
+        false
+    }
+
+    /// Write or discard the diagnostics of the log channels
+    pub fn set_diagnostics(&self, _enable: bool) {}
+
+    /// Drop new records, reporting them as lost
+    pub fn set_dropping(&self, _dropping: bool) {}
+
+    /// Write a record of a log channel, which is discarded
+    pub(crate) fn write_diagnostic(&self, _level: log::Level, _args: core::fmt::Arguments) {}
+
+    /// Set a function called when the fill level crosses a watermark
+    ///
+    /// The hook is never called
+    pub fn set_watermarks(&self, _high: usize, _low: usize, _hook: Option<fn(bool)>) {}
+
+    /// Make records of `level` and higher severity wait for free space
+    pub fn set_blocking(&self, _level: LevelFilter) {}
+
+    /// Set a function called repeatedly while waiting for free space
+    pub fn set_wait_hook(&self, _hook: Option<fn()>) {}
+
+    /// Mark records of `level` and higher severity as urgent
+    pub fn set_urgent_level(&self, _level: LevelFilter) {}
+
+    /// Returns true if an urgent record has been written since the last call
+    ///
+    /// Always returns false
+    pub fn take_urgent(&self) -> bool {
+        false
+    }
+
+    /// Set a function called when data arrives for an idle reader
+    ///
+    /// The hook is never called
+    pub fn set_data_hook(&self, _hook: Option<fn()>) {}
+
+    /// Keep the oldest data until the first read
+    pub fn retain_until_read(&self) {}
+
+    /// Set a function called when a host reader attaches or detaches
+    ///
+    /// The hook is never called
+    pub fn set_reader_hook(&self, _hook: Option<fn(bool)>) {}
+
+    /// Tell the buffer whether a host reader is attached
+    pub fn set_reader_attached(&self, _attached: bool) {}
+
+    /// Returns true if a host reader is attached
+    ///
+    /// Always returns false
+    pub fn is_reader_attached(&self) -> bool {
+        false
+    }
+
+    /// Reserve a contiguous region of the buffer for direct writing
+    ///
+    /// Always returns None
+    pub fn grant(&self, _len: usize) -> Option<WriteGrant<'_, N>> {
+        None
+    }
+
+    /// Get direct access to the oldest bytes of the buffer
+    ///
+    /// Always returns None
+    pub fn read_grant(&self) -> Option<ReadGrant<'_, N>> {
+        None
+    }
+
+    /// Move complete records from another log buffer into this one
+    ///
+    /// Always returns 0
+    pub fn merge_from<const M: usize>(&self, _source: &LogBuffer<M>, _tag: u8) -> usize {
+        0
+    }
+
+    /// Write a binary payload as a separate record
+    ///
+    /// The payload is discarded. Returns false if it is longer than
+    /// [`crate::frame::MAX_RAW_LEN`] bytes.
+    pub fn write_raw(&self, _tag: u8, data: &[u8]) -> bool {
+        data.len() <= crate::frame::MAX_RAW_LEN
+    }
+
+    /// Write the return addresses of a backtrace as a separate record
+    ///
+    /// The addresses are discarded.
+    pub fn write_backtrace(&self, _addresses: &[usize]) {}
+
+    /// Open a block of text that is written to the buffer contiguously
+    pub fn writer(&self) -> RecordWriter<'_, N> {
+        RecordWriter(PhantomData)
+    }
+
+    /// Open a block of text belonging to a logical channel
+    pub fn channel_writer(&self, _channel: u8) -> RecordWriter<'_, N> {
+        RecordWriter(PhantomData)
+    }
+
+    /// Resynchronize the reader to the next record boundary
+    pub fn resync(&self) {}
+
+    /// Wait until the buffer contains data
+    ///
+    /// Never completes
+    pub async fn wait_nonempty(&self) {
+        core::future::pending().await
+    }
+
+    /// Wait for data and read it into `buf`
+    ///
+    /// Never completes unless `buf` is empty
+    pub async fn read_async(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+        core::future::pending().await
+    }
+}
+
+/// Register a log buffer as the global logger
+///
+/// The maximum log level is set to `Off` regardless of `level`. Log statements
+/// are already removed at compile time, this only covers code that bypasses
+/// the static maximum level of the `log` crate.
+pub fn init<const N: usize>(
+    log_buffer: &'static LogBuffer<N>,
+    _level: LevelFilter,
+) -> Result<&'static LogBuffer<N>, SetLoggerError> {
+    #[cfg(target_has_atomic = "ptr")]
+    log::set_logger(log_buffer)?;
+    // SAFETY: no other thread or interrupt can call set_logger_racy() or
+    // logger() while in the critical section
+    #[cfg(not(target_has_atomic = "ptr"))]
+    critical_section::with(|_| unsafe { log::set_logger_racy(log_buffer) })?;
+    log::set_max_level(LevelFilter::Off);
+    Ok(log_buffer)
+}
+
+impl<const N: usize> ClockSource for LogBuffer<N> {
+    fn now_us(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<const N: usize> Default for LogBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write grant, which cannot be obtained from a null log buffer
+pub struct WriteGrant<'a, const N: usize>(Infallible, PhantomData<&'a mut [u8]>);
+
+impl<const N: usize> WriteGrant<'_, N> {
+    /// Make the first `used` bytes of the grant available to readers
+    pub fn commit(self, _used: usize) {
+        match self.0 {}
+    }
+}
+
+impl<const N: usize> Deref for WriteGrant<'_, N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {}
+    }
+}
+
+impl<const N: usize> DerefMut for WriteGrant<'_, N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.0 {}
+    }
+}
+
+/// Read grant, which cannot be obtained from a null log buffer
+pub struct ReadGrant<'a, const N: usize>(Infallible, PhantomData<&'a [u8]>);
+
+impl<const N: usize> ReadGrant<'_, N> {
+    /// Remove the first `used` bytes of the grant from the buffer
+    pub fn release(self, _used: usize) {
+        match self.0 {}
+    }
+}
+
+impl<const N: usize> Deref for ReadGrant<'_, N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.0 {}
+    }
+}
+
+/// Block of text, which is discarded
+pub struct RecordWriter<'a, const N: usize>(PhantomData<&'a LogBuffer<N>>);
+
+impl<const N: usize> core::fmt::Write for RecordWriter<'_, N> {
+    fn write_str(&mut self, _s: &str) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
+impl<const N: usize> log::Log for LogBuffer<N> {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        false
+    }
+
+    fn log(&self, _record: &Record) {}
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,57 @@
+//! Panic handler writing the panic message to the log
+//!
+//! The message is followed by a backtrace if enabled, see
+//! [`crate::backtrace`].
+//!
+//! After logging, the panic handler spins while the USB interrupt handler
+//! sends the log. A watchdog would reset the device before the log has been
+//! sent, so the firmware can register a hook petting the watchdog for a
+//! bounded time:
+//!
+//! ```ignore
+//! usb_log::panic_handler::set_watchdog_hook(|| watchdog.feed(), 500_000);
+//! ```
+//!
+// Copyright (C) 2022 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::clock;
+use crate::mutex::Lock;
+use core::panic::PanicInfo;
+use log::error;
+
+/// Hook petting the watchdog and maximum flush time in microseconds
+#[derive(Clone, Copy)]
+struct Watchdog {
+    hook: fn(),
+    max_flush_us: u64,
+}
+
+static WATCHDOG: Lock<Option<Watchdog>> = Lock::new(None);
+
+/// Pet the watchdog while the panic handler waits for the log to be sent
+///
+/// `hook` is called repeatedly until `max_flush_us` microseconds have passed
+/// since the panic, after which the watchdog is left to reset the device.
+/// The time is measured with the clock of the global log buffer; without a
+/// clock, the hook is not called since the flush time could not be bounded.
+pub fn set_watchdog_hook(hook: fn(), max_flush_us: u64) {
+    WATCHDOG.lock(|watchdog| *watchdog = Some(Watchdog { hook, max_flush_us }));
+}
+
+#[panic_handler]
+fn panic(panic_info: &PanicInfo<'_>) -> ! {
+    if let Some(l) = panic_info.location() {
+        error!(target: "PANIC", "at {}:{}", l.file(), l.line());
+    }
+    error!(target: "PANIC", "{}", panic_info.message());
+    crate::backtrace::log_backtrace();
+    error!(target: "PANIC", "entering endless loop.");
+    let watchdog = WATCHDOG.lock(|watchdog| *watchdog);
+    if let (Some(watchdog), Some(start)) = (watchdog, clock::now_us()) {
+        while clock::now_us().is_some_and(|now| now.wrapping_sub(start) < watchdog.max_flush_us) {
+            (watchdog.hook)();
+        }
+    }
+    loop {}
+}
@@ -0,0 +1,138 @@
+//! Routing of log records to several log buffers by level
+//!
+//! A [`Router`] is registered as the global logger instead of a single log
+//! buffer and passes each record on to the log buffers whose level filter
+//! accepts it. Each log buffer is read by a log channel of its own, so that
+//! e.g. errors and warnings are mirrored to the log channel based on control
+//! transfers, which can be read even while no reader has claimed the bulk
+//! interface, while all records go to the bulk channel:
+//!
+//! ```ignore
+//! static BULK_BUFFER: LogBuffer<4096> = LogBuffer::new();
+//! static CONTROL_BUFFER: LogBuffer<512> = LogBuffer::new();
+//! static ROUTER: Router<2> = Router::new([
+//!     Route::new(&BULK_BUFFER, LevelFilter::Trace),
+//!     Route::new(&CONTROL_BUFFER, LevelFilter::Warn),
+//! ]);
+//!
+//! ROUTER.init(LevelFilter::Info).unwrap();
+//! let mut bulk_channel = UsbLogChannel::new(&usb_bus, &BULK_BUFFER);
+//! let mut control_channel = usb_log_channel::UsbLogChannel::new(&usb_bus, &CONTROL_BUFFER);
+//! control_channel.set_interface_name("kiffielog-errors");
+//! ```
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::clock::{self, ClockSource};
+use crate::log_buffer::LogBuffer;
+
+/// Log buffer receiving the records up to a level
+pub struct Route {
+    logger: &'static dyn Log,
+    clock: &'static dyn ClockSource,
+    level: LevelFilter,
+}
+
+impl Route {
+    /// Route the records of `level` and higher severity to `log_buffer`
+    pub const fn new<const N: usize>(
+        log_buffer: &'static LogBuffer<N>,
+        level: LevelFilter,
+    ) -> Self {
+        Route {
+            logger: log_buffer,
+            clock: log_buffer,
+            level,
+        }
+    }
+}
+
+/// Logger passing records on to `R` log buffers
+pub struct Router<const R: usize> {
+    routes: [Route; R],
+}
+
+impl<const R: usize> Router<R> {
+    pub const fn new(routes: [Route; R]) -> Self {
+        Router { routes }
+    }
+
+    /// Register the router as the global logger and set the maximum log level
+    ///
+    /// The clock of the first log buffer serves as the global clock, e.g. for
+    /// the panic handler. With the `null-logger` feature, the maximum log
+    /// level is set to `Off` like by [`crate::init`].
+    pub fn init(&'static self, level: LevelFilter) -> Result<(), SetLoggerError> {
+        #[cfg(target_has_atomic = "ptr")]
+        log::set_logger(self)?;
+        // SAFETY: no other thread or interrupt can call set_logger_racy() or
+        // logger() while in the critical section
+        #[cfg(not(target_has_atomic = "ptr"))]
+        critical_section::with(|_| unsafe { log::set_logger_racy(self) })?;
+        log::set_max_level(if cfg!(feature = "null-logger") { LevelFilter::Off } else { level });
+        if let Some(route) = self.routes.first() {
+            clock::set_global(route.clock);
+        }
+        Ok(())
+    }
+}
+
+impl<const R: usize> Log for Router<R> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.routes
+            .iter()
+            .any(|route| metadata.level() <= route.level && route.logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for route in &self.routes {
+            if record.level() <= route.level {
+                route.logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for route in &self.routes {
+            route.logger.flush();
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    fn read_all<const N: usize>(log_buffer: &LogBuffer<N>) -> Vec<u8> {
+        core::iter::from_fn(|| log_buffer.read()).collect()
+    }
+
+    #[test]
+    fn route_by_level() {
+        static ALL: LogBuffer<128> = LogBuffer::new();
+        static ERRORS: LogBuffer<64> = LogBuffer::new();
+        static ROUTER: Router<2> = Router::new([
+            Route::new(&ALL, LevelFilter::Trace),
+            Route::new(&ERRORS, LevelFilter::Warn),
+        ]);
+        for (level, msg) in [(Level::Info, "a"), (Level::Error, "b")] {
+            ROUTER.log(
+                &Record::builder()
+                    .level(level)
+                    .file_static(Some("main.rs"))
+                    .line(Some(1))
+                    .args(format_args!("{msg}"))
+                    .build(),
+            );
+        }
+        assert_eq!(read_all(&ALL), b"[main.rs:1] a\n[main.rs:1] b\n");
+        assert_eq!(read_all(&ERRORS), b"[main.rs:1] b\n");
+    }
+}
@@ -0,0 +1,308 @@
+//! Subscriber writing `tracing` events to a log buffer
+//!
+//! Events are converted into log records so that they are encoded like records
+//! of the `log` facade and shown by the host tool without changes. The message
+//! of an event is followed by its other fields as `name=value` pairs. Entering
+//! and leaving a span is logged as `-> name` and `<- name`, respectively.
+//!
+//! Spans are kept in a small table of fixed size. Spans created while the
+//! table is full are not logged.
+//!
+//! Note that `tracing-core` requires a global allocator for the dispatcher.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use core::fmt::{self, Debug, Write};
+use tracing_core::dispatcher::{self, Dispatch, SetGlobalDefaultError};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level, LevelFilter, Metadata, Subscriber};
+
+use crate::log_buffer::LogBuffer;
+use crate::mutex::Lock;
+
+/// Maximum number of spans that exist at the same time
+const MAX_SPANS: usize = 16;
+
+/// Id of spans that did not fit into the span table
+const UNKNOWN_SPAN: u64 = u64::MAX;
+
+#[derive(Clone, Copy)]
+struct Span {
+    metadata: &'static Metadata<'static>,
+    refs: usize,
+}
+
+/// Subscriber writing events and span transitions to a log buffer
+///
+/// ```ignore
+/// let log_buffer = usb_log::static_log_buffer!(4096);
+/// UsbSubscriber::new(log_buffer, LevelFilter::INFO).init().unwrap();
+/// tracing::info!(voltage = 3.3, "power good");
+/// ```
+pub struct UsbSubscriber<const N: usize> {
+    log_buffer: &'static LogBuffer<N>,
+    max_level: LevelFilter,
+    spans: Lock<[Option<Span>; MAX_SPANS]>,
+}
+
+impl<const N: usize> UsbSubscriber<N> {
+    /// Create a subscriber for events of `max_level` and higher severity
+    pub const fn new(log_buffer: &'static LogBuffer<N>, max_level: LevelFilter) -> Self {
+        UsbSubscriber {
+            log_buffer,
+            max_level,
+            spans: Lock::new([None; MAX_SPANS]),
+        }
+    }
+
+    /// Register the subscriber as the global default
+    pub fn init(self) -> Result<(), SetGlobalDefaultError> {
+        dispatcher::set_global_default(Dispatch::new(self))
+    }
+
+    fn span(&self, id: &Id) -> Option<&'static Metadata<'static>> {
+        let index = (id.into_u64() as usize).checked_sub(1)?;
+        self.spans
+            .lock(|spans| spans.get(index).copied().flatten())
+            .map(|span| span.metadata)
+    }
+
+    fn write(&self, metadata: &'static Metadata<'static>, args: fmt::Arguments) {
+        let record = log::Record::builder()
+            .level(log_level(metadata.level()))
+            .target(metadata.target())
+            .module_path_static(metadata.module_path())
+            .file_static(metadata.file())
+            .line(metadata.line())
+            .args(args)
+            .build();
+        log::Log::log(self.log_buffer, &record);
+    }
+}
+
+impl<const N: usize> Subscriber for UsbSubscriber<N> {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= &self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let metadata = span.metadata();
+        let index = self.spans.lock(|spans| {
+            let index = spans.iter().position(Option::is_none)?;
+            spans[index] = Some(Span { metadata, refs: 1 });
+            Some(index)
+        });
+        match index {
+            Some(index) => Id::from_u64(index as u64 + 1),
+            None => Id::from_u64(UNKNOWN_SPAN),
+        }
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        self.write(event.metadata(), format_args!("{}", EventFields(event)));
+    }
+
+    fn enter(&self, span: &Id) {
+        if let Some(metadata) = self.span(span) {
+            self.write(metadata, format_args!("-> {}", metadata.name()));
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        if let Some(metadata) = self.span(span) {
+            self.write(metadata, format_args!("<- {}", metadata.name()));
+        }
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(index) = (id.into_u64() as usize).checked_sub(1) {
+            self.spans.lock(|spans| {
+                if let Some(Some(span)) = spans.get_mut(index) {
+                    span.refs += 1;
+                }
+            });
+        }
+        id.clone()
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        let Some(index) = (id.into_u64() as usize).checked_sub(1) else {
+            return false;
+        };
+        self.spans.lock(|spans| {
+            let Some(slot) = spans.get_mut(index) else {
+                return false;
+            };
+            let Some(span) = slot else {
+                return false;
+            };
+            span.refs -= 1;
+            if span.refs > 0 {
+                return false;
+            }
+            *slot = None;
+            true
+        })
+    }
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+/// Message and fields of an event
+struct EventFields<'a>(&'a Event<'a>);
+
+impl fmt::Display for EventFields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the message comes first regardless of the order of the fields
+        let mut visitor = FieldWriter {
+            f,
+            message: true,
+            first: true,
+            result: Ok(()),
+        };
+        self.0.record(&mut visitor);
+        visitor.message = false;
+        self.0.record(&mut visitor);
+        visitor.result
+    }
+}
+
+/// Visitor writing either the message or the other fields of an event
+struct FieldWriter<'a, 'b> {
+    f: &'a mut fmt::Formatter<'b>,
+    message: bool,
+    first: bool,
+    result: fmt::Result,
+}
+
+impl FieldWriter<'_, '_> {
+    fn write_field(&mut self, field: &Field, value: fmt::Arguments) {
+        let is_message = field.name() == "message";
+        if self.result.is_err() || is_message != self.message {
+            return;
+        }
+        if !self.first {
+            self.result = self.f.write_char(' ');
+        }
+        self.first = false;
+        if !is_message {
+            self.result = self.result.and_then(|_| write!(self.f, "{}=", field.name()));
+        }
+        self.result = self.result.and_then(|_| self.f.write_fmt(value));
+    }
+}
+
+impl Visit for FieldWriter<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.write_field(field, format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.write_field(field, format_args!("{value:?}"));
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::frame::Format;
+    use std::vec::Vec;
+    use tracing_core::{callsite, field::FieldSet, metadata, Kind};
+
+    struct TestCallsite;
+
+    impl callsite::Callsite for TestCallsite {
+        fn set_interest(&self, _interest: tracing_core::Interest) {}
+
+        fn metadata(&self) -> &Metadata<'_> {
+            &EVENT
+        }
+    }
+
+    static CALLSITE: TestCallsite = TestCallsite;
+
+    static EVENT: Metadata<'static> = metadata! {
+        name: "event",
+        target: "app",
+        level: Level::WARN,
+        fields: &["count", "message"],
+        callsite: &CALLSITE,
+        kind: Kind::EVENT,
+    };
+
+    static SPAN: Metadata<'static> = metadata! {
+        name: "transfer",
+        target: "app",
+        level: Level::INFO,
+        fields: &[],
+        callsite: &CALLSITE,
+        kind: Kind::SPAN,
+    };
+
+    fn read_all<const N: usize>(log_buffer: &LogBuffer<N>) -> Vec<u8> {
+        core::iter::from_fn(|| log_buffer.read()).collect()
+    }
+
+    #[test]
+    fn event_and_span() {
+        static LOG_BUFFER: LogBuffer<256> = LogBuffer::new();
+        LOG_BUFFER.set_format(Format::Text);
+        let subscriber = UsbSubscriber::new(&LOG_BUFFER, LevelFilter::INFO);
+
+        let fields: &FieldSet = EVENT.fields();
+        let count = fields.field("count").unwrap();
+        let message = fields.field("message").unwrap();
+        let values = [
+            (&count, Some(&3 as &dyn tracing_core::field::Value)),
+            (&message, Some(&format_args!("retry") as &dyn tracing_core::field::Value)),
+        ];
+        let values = fields.value_set(&values);
+        subscriber.event(&Event::new(&EVENT, &values));
+
+        let attrs_values = SPAN.fields().value_set(&[]);
+        let attrs = Attributes::new(&SPAN, &attrs_values);
+        let id = subscriber.new_span(&attrs);
+        subscriber.enter(&id);
+        subscriber.exit(&id);
+        assert!(subscriber.try_close(id));
+        assert!(subscriber.spans.lock(|spans| spans.iter().all(Option::is_none)));
+
+        let text = std::string::String::from_utf8(read_all(&LOG_BUFFER)).unwrap();
+        let messages: Vec<&str> = text
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect();
+        assert_eq!(messages, ["retry count=3", "-> transfer", "<- transfer"]);
+        assert!(text.starts_with("[src/subscriber.rs:"));
+        assert!(!subscriber.enabled(&Metadata::new(
+            "debug",
+            "app",
+            Level::DEBUG,
+            None,
+            None,
+            None,
+            FieldSet::new(&[], callsite::Identifier(&CALLSITE)),
+            Kind::EVENT,
+        )));
+    }
+}
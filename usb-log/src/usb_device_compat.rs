@@ -0,0 +1,69 @@
+//! Differences between the supported versions of usb-device
+//!
+//! The USB classes are built against usb-device 0.3 by default or against
+//! usb-device 0.2 with the `usb-device-02` feature, for HALs that still
+//! depend on the older version. Only one of the two versions can be
+//! selected. usb-device 0.2 passes the language of string requests as a
+//! plain `u16` and has no function string in the interface association
+//! descriptor. Isochronous endpoints cannot be allocated with usb-device 0.2
+//! and its classes cannot have alternate settings, so the isochronous log
+//! channel and the alternate setting gating of the bulk channel are only
+//! available with usb-device 0.3.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+#[cfg(all(feature = "usb-device-02", feature = "usb-device-03"))]
+compile_error!("the features `usb-device-02` and `usb-device-03` are mutually exclusive");
+
+#[cfg(not(any(feature = "usb-device-02", feature = "usb-device-03")))]
+compile_error!("either the feature `usb-device-02` or `usb-device-03` must be enabled");
+
+use usb_device::{class_prelude::*, Result};
+
+/// Language of a string descriptor
+#[cfg(feature = "usb-device-03")]
+pub use usb_device::LangID;
+
+/// Language of a string descriptor
+#[cfg(not(feature = "usb-device-03"))]
+pub type LangID = u16;
+
+/// Write an interface association descriptor without function string
+#[cfg(feature = "usb-device-03")]
+pub(crate) fn iad(
+    writer: &mut DescriptorWriter,
+    first_interface: InterfaceNumber,
+    interface_count: u8,
+    function_class: u8,
+    function_sub_class: u8,
+    function_protocol: u8,
+) -> Result<()> {
+    writer.iad(
+        first_interface,
+        interface_count,
+        function_class,
+        function_sub_class,
+        function_protocol,
+        None,
+    )
+}
+
+/// Write an interface association descriptor
+#[cfg(not(feature = "usb-device-03"))]
+pub(crate) fn iad(
+    writer: &mut DescriptorWriter,
+    first_interface: InterfaceNumber,
+    interface_count: u8,
+    function_class: u8,
+    function_sub_class: u8,
+    function_protocol: u8,
+) -> Result<()> {
+    writer.iad(
+        first_interface,
+        interface_count,
+        function_class,
+        function_sub_class,
+        function_protocol,
+    )
+}
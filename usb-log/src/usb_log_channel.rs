@@ -3,24 +3,45 @@
 //! This log channel provides an USB interface without an endpoint. All data
 //! transfer is done via control (SETUP) transfers.
 //!
+//! Besides reading the log, the host can pause logging, change the maximum
+//! log level at runtime, e.g. by `usb-logread level debug`, see
+//! [`control::SET_LEVEL_REQUEST`], and discard the buffered log, e.g. at the
+//! start of each test case, see [`control::CLEAR_REQUEST`]. Commands for the
+//! application are sent as vendor requests with codes reserved for it and
+//! passed to the handler set by [`UsbLogChannel::set_command_handler`].
+//!
+//! Instead of a log buffer, the channel can read another [`LogSource`], see
+//! [`UsbLogChannel::with_source`].
+//!
 // Copyright (C) 2025 Stephan <kiffie@mailbox.org>
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::control;
 use crate::log_buffer::LogBuffer;
-use usb_device::{
-    class_prelude::*,
-    control::{Recipient, RequestType},
-    Result,
-};
+use crate::log_source::LogSource;
+use crate::usb_device_compat::LangID;
+use usb_device::{class_prelude::*, Result};
 
-const INTERFACE_NAME: &str = "kiffielog";
 // const XFER_MAX_LEN: usize = 128;
-const LOG_READ_REQUEST: u8 = 0;
 
-pub struct UsbLogChannel<'a, const N: usize> {
+/// Log channel based on control transfers
+///
+/// The data is read from a log buffer of `N` bytes unless another source `S`
+/// is given.
+pub struct UsbLogChannel<'a, const N: usize, S: LogSource = &'a LogBuffer<N>> {
     iface: InterfaceNumber,
     iface_string: StringIndex,
-    log_buffer: &'a LogBuffer<N>,
+    iface_name: &'a str,
+    /// Interface class, subclass and protocol codes
+    iface_class: (u8, u8, u8),
+    /// Vendor code of the Microsoft OS 2.0 descriptor request, if enabled
+    ms_os_vendor_code: Option<u8>,
+    /// Vendor code of WebUSB requests and landing page, if enabled
+    webusb: Option<(u8, Option<&'a str>)>,
+    iface_strings: &'a [(LangID, &'a str)],
+    source: S,
+    /// Receives vendor requests reserved for the application
+    command_handler: Option<fn(u8, &[u8])>,
 }
 
 impl<'a, const N: usize> UsbLogChannel<'a, N> {
@@ -29,51 +50,215 @@ impl<'a, const N: usize> UsbLogChannel<'a, N> {
         alloc: &'a UsbBusAllocator<B>,
         log_buffer: &'a LogBuffer<N>,
     ) -> UsbLogChannel<'a, N> {
+        Self::with_source(alloc, log_buffer)
+    }
+}
+
+impl<'a, const N: usize, S: LogSource> UsbLogChannel<'a, N, S> {
+    /// Create a new USB log channel reading `source`
+    ///
+    /// `N` is not used by other sources than a log buffer, see
+    /// [`crate::log_source`].
+    pub fn with_source<B: UsbBus>(
+        alloc: &'a UsbBusAllocator<B>,
+        source: S,
+    ) -> UsbLogChannel<'a, N, S> {
         let iface = alloc.interface();
         let iface_string = alloc.string();
         UsbLogChannel {
             iface,
             iface_string,
-            log_buffer,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            iface_class: (0xff, 0, 0),
+            ms_os_vendor_code: None,
+            webusb: None,
+            iface_strings: &[],
+            source,
+            command_handler: None,
         }
     }
+
+    /// Access the log source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Set a function receiving commands from the host
+    ///
+    /// Vendor control OUT requests to the log interface with request codes
+    /// from [`control::FIRST_USER_REQUEST`] up are passed to `handler` with
+    /// the request code and the data of the request, like data received by
+    /// the OUT endpoint of the bulk log channel. `usb-logread send` uses
+    /// [`control::FIRST_USER_REQUEST`] for interfaces without an OUT
+    /// endpoint. The data is limited by the control buffer of `usb-device`.
+    /// Without a handler, these requests are rejected.
+    pub fn set_command_handler(&mut self, handler: fn(u8, &[u8])) {
+        self.command_handler = Some(handler);
+    }
+
+    /// Set the interface name, see [`control::interface_string`]
+    pub fn set_interface_name(&mut self, name: &'a str) {
+        self.iface_name = name;
+    }
+
+    /// Set the class, subclass and protocol codes of the interface, see
+    /// [`crate::usb_log_channel_bulk::UsbLogChannel::set_interface_class`]
+    pub fn set_interface_class(&mut self, class: u8, subclass: u8, protocol: u8) {
+        self.iface_class = (class, subclass, protocol);
+    }
+
+    /// Provide Microsoft OS 2.0 descriptors binding WinUSB to the interface, see
+    /// [`control::write_capabilities`]
+    pub fn enable_ms_os_descriptors(&mut self, vendor_code: u8) {
+        self.ms_os_vendor_code = Some(vendor_code);
+    }
+
+    /// Announce WebUSB support with an optional landing page, see
+    /// [`control::write_capabilities`]
+    pub fn enable_webusb(&mut self, vendor_code: u8, landing_page: Option<&'a str>) {
+        self.webusb = Some((vendor_code, landing_page));
+    }
+
+    /// Set localized interface names, see [`control::interface_string`]
+    pub fn set_interface_strings(&mut self, strings: &'a [(LangID, &'a str)]) {
+        self.iface_strings = strings;
+    }
 }
 
-impl<B: UsbBus, const N: usize> UsbClass<B> for UsbLogChannel<'_, N> {
+impl<B: UsbBus, const N: usize, S: LogSource> UsbClass<B> for UsbLogChannel<'_, N, S> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface_alt(self.iface, 0, 0xff, 0, 0, Some(self.iface_string))
+        let (class, subclass, protocol) = self.iface_class;
+        writer.interface_alt(self.iface, 0, class, subclass, protocol, Some(self.iface_string))
     }
 
-    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
-        if index == self.iface_string {
-            Some(INTERFACE_NAME)
-        } else {
-            None
-        }
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        let (name, strings) = (self.iface_name, self.iface_strings);
+        control::interface_string(index, self.iface_string, lang_id, name, strings)
+    }
+
+    /// A log read transfer may have been aborted, so continue at a record
+    /// boundary
+    fn reset(&mut self) {
+        self.source.resync();
+        self.source.set_reader_attached(false);
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        control::write_capabilities(writer, self.ms_os_vendor_code, self.webusb)
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
+        let (ms_os_vendor_code, webusb) = (self.ms_os_vendor_code, self.webusb);
+        let Some(xfer) = control::platform_request(xfer, self.iface, ms_os_vendor_code, webusb)
+        else {
+            return;
+        };
         let request = xfer.request();
-        if request.request_type != RequestType::Vendor
-            || request.recipient != Recipient::Interface
-            || request.index != Into::<u8>::into(self.iface) as u16
-            || request.request != LOG_READ_REQUEST
-        {
+        if !control::is_vendor_request(request, self.iface) {
             return;
         }
-        let request_len = request.length as usize;
-        xfer.accept(|data| {
-            let max_len =  request_len.min(data.len());
-            let mut len = 0;
-            for d in &mut data[..max_len] {
-                if let Some(byte) = self.log_buffer.read() {
-                    *d = byte;
-                    len += 1;
-                } else {
-                    break;
+        let (request, value) = (request.request, request.value);
+        control::respond(xfer, |buf| self.source.control_in(request, value, buf));
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if !control::is_vendor_request(request, self.iface) {
+            return;
+        }
+        match request.request {
+            code if code >= control::FIRST_USER_REQUEST => {
+                if let Some(handler) = self.command_handler {
+                    handler(code, xfer.data());
+                    xfer.accept().ok();
                 }
             }
-            Ok(len)
-        }).unwrap();
+            code => {
+                let accepted = self.source.control_out(code, request.value);
+                control::acknowledge(xfer, accepted);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, control_transfer_out, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    extern crate std;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    #[test]
+    fn control_requests() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel = UsbLogChannel::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let iface = u8::from(channel.iface) as u16;
+        let mut transfer = |channel: &mut UsbLogChannel<256>, setup| {
+            control_transfer(&mut usb_dev, &mut [channel], setup)
+        };
+
+        // the interface has no endpoint
+        let desc = transfer(&mut channel, setup(0x80, 6, 0x0200, 0, 255)).unwrap();
+        assert_eq!(&desc[9..], [9, 4, iface as u8, 0, 0, 0xff, 0, 0, 4]);
+
+        // the log is read in several packets of the control endpoint
+        write!(log_buffer.writer(), "{:20}", "x").unwrap();
+        let request = setup(0xc1, control::GET_AVAILABLE_REQUEST, 0, iface, 4);
+        assert_eq!(transfer(&mut channel, request).unwrap(), 20u32.to_le_bytes());
+        let stats = transfer(&mut channel, setup(0xc1, control::GET_STATS_REQUEST, 0, iface, 12));
+        assert_eq!(stats.unwrap()[4..], [20, 0, 0, 0, 20, 0, 0, 0]);
+        let data = transfer(&mut channel, setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 16));
+        assert_eq!(data.unwrap().len(), 16);
+        let data = transfer(&mut channel, setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 16));
+        assert_eq!(data.unwrap().len(), 4);
+        let data = transfer(&mut channel, setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 16));
+        assert_eq!(data.unwrap(), []);
+        // a burst is read without asking for the available bytes
+        write!(log_buffer.writer(), "{:20}", "y").unwrap();
+        let request = setup(0xc1, control::LOG_READ_NEXT_REQUEST, 0, iface, 16);
+        assert_eq!(transfer(&mut channel, request).unwrap()[..4], 8u32.to_le_bytes());
+        write!(log_buffer.writer(), "z").unwrap();
+        let data = transfer(&mut channel, request).unwrap();
+        assert_eq!(data[..4], 0u32.to_le_bytes());
+        assert_eq!(data[4..], *b"        z");
+        assert_eq!(transfer(&mut channel, request).unwrap(), [0, 0, 0, 0]);
+
+        control::set_firmware_version("1.0");
+        let info = transfer(&mut channel, setup(0xc1, control::GET_INFO_REQUEST, 0, iface, 64));
+        let version = control::PROTOCOL_VERSION.to_le_bytes();
+        assert_eq!(info.unwrap(), [version[0], version[1], 255, 0, 0, 0, b'1', b'.', b'0']);
+
+        write!(log_buffer.writer(), "old").unwrap();
+        let res = transfer(&mut channel, setup(0x41, control::CLEAR_REQUEST, 0, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        assert!(log_buffer.is_empty());
+
+        let res = transfer(&mut channel, setup(0x41, control::SET_ENABLED_REQUEST, 0, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        assert!(!log_buffer.is_enabled());
+        let res = transfer(&mut channel, setup(0x41, control::SET_LEVEL_REQUEST, 3, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        assert_eq!(log::max_level(), log::LevelFilter::Info);
+        // requests to another interface
+        assert!(transfer(&mut channel, setup(0xc1, control::LOG_READ_REQUEST, 0, iface + 1, 16)).is_none());
+
+        // commands for the application
+        static COMMANDS: Mutex<Vec<(u8, Vec<u8>)>> = Mutex::new(Vec::new());
+        let command = setup(0x41, control::FIRST_USER_REQUEST, 0, iface, 10);
+        let mut send = |channel: &mut UsbLogChannel<256>| {
+            control_transfer_out(&mut usb_dev, &mut [channel], command, b"reboot now")
+        };
+        assert!(send(&mut channel).is_none());
+        channel.set_command_handler(|code, data| {
+            COMMANDS.lock().unwrap().push((code, data.to_vec()));
+        });
+        assert_eq!(send(&mut channel).unwrap(), []);
+        assert_eq!(*COMMANDS.lock().unwrap(), [(0x80, b"reboot now".to_vec())]);
     }
 }
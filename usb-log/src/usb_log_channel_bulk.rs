@@ -4,87 +4,977 @@
 //! interface can be labelled with a specific string so that a libusb based log
 //! client can identify the interface and the respective USB endpoint
 //!
+//! Optionally, the interface has a bulk OUT endpoint as well, over which the
+//! host can send commands to the application.
+//!
+//! Optionally, the endpoints are only present in alternate setting 1 of the
+//! interface, so that the device sends data only after a host reader has
+//! selected it.
+//!
+//! Optionally, the device sends only as many bytes as the host has granted,
+//! see [`UsbLogChannel::enable_credit_flow_control`].
+//!
+//! A device can have several log channels, each reading a log buffer of its
+//! own, e.g. one for the human readable log and one for binary traces. The
+//! channels are told apart by their interface names, see
+//! [`UsbLogChannel::set_interface_name`].
+//!
+//! The log can also be read by control transfers like from the control
+//! transfer variant, so that hosts or ports that cannot do bulk transfers are
+//! served by the same firmware. If no bulk endpoint is left, the interface has
+//! no endpoint and control transfers are the only way of reading the log.
+//!
+//! Instead of a log buffer, the channel can send the data of another
+//! [`LogSource`], see [`UsbLogChannel::with_source`].
+//!
 // Copyright (C) 2022 Stephan <kiffie@mailbox.org>
 // SPDX-License-Identifier: GPL-2.0-or-later
 
+use crate::bus_state::BusState;
+use crate::clock::SofClock;
+use crate::control;
 use crate::log_buffer::LogBuffer;
-use usb_device::{class_prelude::*, Result};
+use crate::log_source::LogSource;
+use crate::usb_device_compat::LangID;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
 
-const EP_SIZE: usize = 64;
+pub use crate::bus_state::{SuspendPolicy, UnconfiguredPolicy};
 
-const INTERFACE_NAME: &str = "kiffielog";
-
-pub struct UsbLogChannel<'a, B: UsbBus, const N: usize> {
+/// Log channel with a bulk IN endpoint of `EP_SIZE` bytes
+///
+/// The endpoint size defaults to 64 bytes, the maximum for full-speed bulk
+/// endpoints. High-speed devices should use 512 bytes, which moves more data
+/// per microframe:
+///
+/// ```ignore
+/// let log_channel: UsbLogChannel<_, 4096, 512> = UsbLogChannel::new(&usb_bus, log_buffer);
+/// ```
+///
+/// The data is read from a log buffer of `N` bytes unless another source `S`
+/// is given.
+pub struct UsbLogChannel<
+    'a,
+    B: UsbBus,
+    const N: usize,
+    const EP_SIZE: usize = 64,
+    S: LogSource = &'a LogBuffer<N>,
+> {
     iface: InterfaceNumber,
     iface_string: StringIndex,
-    ep_in: EndpointIn<'a, B>,
-    log_buffer: &'a LogBuffer<N>,
-    packet_buffer: [u8; EP_SIZE],
-    packet_buffer_len: usize,
+    /// None if no endpoint could be allocated
+    ep_in: Option<EndpointIn<'a, B>>,
+    ep_out: Option<EndpointOut<'a, B>>,
+    /// Called with each packet received on the OUT endpoint
+    out_handler: fn(&[u8]),
+    iface_name: &'a str,
+    /// Interface class, subclass and protocol codes
+    iface_class: (u8, u8, u8),
+    /// Vendor code of the Microsoft OS 2.0 descriptor request, if enabled
+    ms_os_vendor_code: Option<u8>,
+    /// Vendor code of WebUSB requests and landing page, if enabled
+    webusb: Option<(u8, Option<&'a str>)>,
+    iface_strings: &'a [(LangID, &'a str)],
+    source: S,
+    /// Packet read from a source that cannot lend its data but not yet
+    /// accepted by the endpoint
+    packet: [u8; EP_SIZE],
+    packet_len: usize,
+    fill_timeout: u16,
+    fill_polls: u16,
+    /// Suspension, configuration and resynchronization
+    bus: BusState,
+    /// Send partial packets without waiting for the fill timeout
+    flush: bool,
+    /// The last packet was a full one, so the host waits for more data
+    zlp_pending: bool,
+    /// Clock counting USB frames, which is read when polled
+    sof_clock: Option<&'a SofClock>,
+    /// The endpoints are only present in alternate setting 1
+    alt_setting_gated: bool,
+    /// Current alternate setting of the interface
+    alt_setting: u8,
+    /// Bytes the host allows to be sent, if credit-based flow control is
+    /// enabled
+    credit: Option<u32>,
+    /// Signals remote wakeup, called for urgent records while suspended
+    wakeup_hook: Option<fn()>,
+    /// The host has enabled remote wakeup
+    remote_wakeup_enabled: bool,
 }
 
-impl<'a, B: UsbBus, const N: usize> UsbLogChannel<'a, B, N> {
-
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize> UsbLogChannel<'a, B, N, EP_SIZE> {
     /// Create a new USB log channel
+    ///
+    /// If the USB peripheral has no bulk endpoint left, the channel is
+    /// created without endpoint and the log is read by control transfers.
     pub fn new(
         alloc: &'a UsbBusAllocator<B>,
         log_buffer: &'a LogBuffer<N>,
-    ) -> UsbLogChannel<'a, B, N> {
+    ) -> UsbLogChannel<'a, B, N, EP_SIZE> {
+        Self::with_source(alloc, log_buffer)
+    }
+
+    /// Create a new USB log channel having a bulk OUT endpoint
+    ///
+    /// `handler` is called from `poll()` with each packet the host sends to
+    /// the OUT endpoint, e.g. by `usb-logread send`. Packets are at most
+    /// `EP_SIZE` bytes long; the handler has to reassemble longer messages if
+    /// needed.
+    pub fn with_out_endpoint(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+        handler: fn(&[u8]),
+    ) -> UsbLogChannel<'a, B, N, EP_SIZE> {
+        let mut channel = Self::new(alloc, log_buffer);
+        channel.ep_out = Some(alloc.bulk(EP_SIZE as u16));
+        channel.out_handler = handler;
+        channel
+    }
+}
+
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize, S: LogSource>
+    UsbLogChannel<'a, B, N, EP_SIZE, S>
+{
+    /// Valid bulk packet sizes, 512 bytes for high speed only
+    const VALID_EP_SIZE: () = assert!(matches!(EP_SIZE, 8 | 16 | 32 | 64 | 512));
+
+    /// Create a new USB log channel sending the data of `source`
+    ///
+    /// This lets the application feed the channel from its own queues, see
+    /// [`crate::log_source`]. `N` is not used by other sources than a log
+    /// buffer. Data a source cannot lend is read a packet at a time and kept
+    /// by the channel until the endpoint has accepted it.
+    pub fn with_source(
+        alloc: &'a UsbBusAllocator<B>,
+        source: S,
+    ) -> UsbLogChannel<'a, B, N, EP_SIZE, S> {
+        let () = Self::VALID_EP_SIZE;
         let iface = alloc.interface();
         let iface_string = alloc.string();
-        let ep_in = alloc.bulk(EP_SIZE as u16);
-        let packet_buffer = [0; EP_SIZE];
-        let packet_buffer_len = 1;
+        let ep_in = alloc.alloc(None, EndpointType::Bulk, EP_SIZE as u16, 0).ok();
         UsbLogChannel {
             iface,
             iface_string,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            iface_class: (0xff, 0, 0),
+            ms_os_vendor_code: None,
+            webusb: None,
+            iface_strings: &[],
             ep_in,
-            log_buffer,
-            packet_buffer,
-            packet_buffer_len,
+            ep_out: None,
+            out_handler: |_| (),
+            source,
+            packet: [0; EP_SIZE],
+            packet_len: 0,
+            fill_timeout: 0,
+            fill_polls: 0,
+            bus: BusState::new(),
+            flush: false,
+            zlp_pending: false,
+            sof_clock: None,
+            alt_setting_gated: false,
+            alt_setting: 0,
+            credit: None,
+            wakeup_hook: None,
+            remote_wakeup_enabled: false,
         }
     }
 
+    /// Access the log source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Set the number of polls to wait for a full packet
+    ///
+    /// A partial packet is sent once it has been pending for `polls` calls of
+    /// `poll()`. The default of 0 sends data immediately, which minimizes the
+    /// log latency. Larger values coalesce log data into fewer packets, which
+    /// uses the USB more efficiently.
+    pub fn set_fill_timeout(&mut self, polls: u16) {
+        self.fill_timeout = polls;
+    }
+
+    /// Set the interface name, see [`control::interface_string`]
+    pub fn set_interface_name(&mut self, name: &'a str) {
+        self.iface_name = name;
+    }
+
+    /// Set the class, subclass and protocol codes of the interface
+    ///
+    /// The default is the vendor specific class `0xff` with subclass and
+    /// protocol 0. The subclass and protocol can be used e.g. to encode a
+    /// version of the application protocol. Changing the class may make
+    /// operating systems bind a class driver to the interface, which prevents
+    /// the host tool from claiming it.
+    pub fn set_interface_class(&mut self, class: u8, subclass: u8, protocol: u8) {
+        self.iface_class = (class, subclass, protocol);
+    }
+
+    /// Provide Microsoft OS 2.0 descriptors binding WinUSB to the interface, see
+    /// [`control::write_capabilities`]
+    pub fn enable_ms_os_descriptors(&mut self, vendor_code: u8) {
+        self.ms_os_vendor_code = Some(vendor_code);
+    }
+
+    /// Announce WebUSB support with an optional landing page, see
+    /// [`control::write_capabilities`]
+    pub fn enable_webusb(&mut self, vendor_code: u8, landing_page: Option<&'a str>) {
+        self.webusb = Some((vendor_code, landing_page));
+    }
+
+    /// Select how log records are handled while the USB is suspended
+    ///
+    /// The default is [`SuspendPolicy::Buffer`].
+    pub fn set_suspend_policy(&mut self, policy: SuspendPolicy) {
+        self.bus.set_suspend_policy(policy, &mut self.source);
+    }
+
+    /// Tell the channel whether the USB is suspended
+    ///
+    /// The USB device does not notify its classes of a suspension, so the
+    /// application has to pass on the device state after polling:
+    ///
+    /// ```ignore
+    /// usb_dev.poll(&mut [&mut log_channel]);
+    /// log_channel.set_suspended(usb_dev.state() == UsbDeviceState::Suspend);
+    /// ```
+    ///
+    /// No packets are sent while the USB is suspended; the log records are
+    /// handled according to the suspend policy. A bus reset ends the
+    /// suspension as well.
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.bus.set_suspended(suspended, &mut self.source);
+    }
+
+    /// Select how log records are handled while the USB device is not
+    /// configured, i.e. before enumeration and after a bus reset
+    ///
+    /// The default is [`UnconfiguredPolicy::Buffer`].
+    pub fn set_unconfigured_policy(&mut self, policy: UnconfiguredPolicy) {
+        self.bus.set_unconfigured_policy(policy, &mut self.source);
+    }
+
+    /// Move the endpoints to alternate setting 1 of the interface
+    ///
+    /// The default alternate setting 0 then has no endpoint and log data is
+    /// only sent after the host has selected alternate setting 1 by a
+    /// SET_INTERFACE request, which `usb-logread` does. Until then, the log is
+    /// kept in the buffer and can still be read by control transfers.
+    #[cfg(feature = "usb-device-03")]
+    pub fn enable_alt_setting_gating(&mut self) {
+        self.alt_setting_gated = true;
+    }
+
+    /// Send only as many bytes as the host has granted
+    ///
+    /// The host grants credit by a vendor request, which `usb-logread
+    /// --credit` does as it reads the log. Without a reader, the data stays
+    /// in the log buffer instead of being sent into the void. The credit is
+    /// revoked by a bus reset or when the host changes the configuration or
+    /// the alternate setting. Capturing is lossless if the log buffer blocks
+    /// writers when full, see [`LogBuffer::set_blocking`].
+    pub fn enable_credit_flow_control(&mut self) {
+        self.credit = Some(0);
+    }
+
+    /// Wake up the host when an urgent record is logged while suspended
+    ///
+    /// `hook` signals remote wakeup to the host, which is specific to the USB
+    /// peripheral and therefore done by the HAL. It is called from `tasks()`
+    /// if the USB is suspended, the host has enabled remote wakeup and a
+    /// record of the urgent level set by [`LogBuffer::set_urgent_level`] has
+    /// been written since, so that critical messages are not stuck in the
+    /// buffer until the host resumes the bus by itself. The device must
+    /// announce remote wakeup support:
+    ///
+    /// ```ignore
+    /// log_buffer.set_urgent_level(LevelFilter::Error);
+    /// log_channel.enable_remote_wakeup(|| usb_bus_remote_wakeup());
+    /// let usb_dev = UsbDeviceBuilder::new(&usb_bus, vid_pid)
+    ///     .supports_remote_wakeup(true)
+    ///     .build();
+    /// ```
+    pub fn enable_remote_wakeup(&mut self, hook: fn()) {
+        self.wakeup_hook = Some(hook);
+    }
+
+    /// Read a clock counting USB frames when polled
+    ///
+    /// This keeps track of the wrap-arounds of the frame number if the clock
+    /// timestamps the records of the log buffer, see [`SofClock`].
+    pub fn set_sof_clock(&mut self, clock: &'a SofClock) {
+        self.sof_clock = Some(clock);
+    }
+
+    /// Set localized interface names, see [`control::interface_string`]
+    pub fn set_interface_strings(&mut self, strings: &'a [(LangID, &'a str)]) {
+        self.iface_strings = strings;
+    }
+
+    /// Send the buffered data without waiting for the fill timeout
+    ///
+    /// The current partial packet is sent right away and the data buffered
+    /// later on is sent as if the fill timeout were 0 until the buffer has
+    /// been drained. If the data ends on a packet boundary, a zero-length
+    /// packet follows so that the host completes its transfer even if its
+    /// transfer buffer is larger than the data.
+    pub fn flush(&mut self) {
+        self.flush = true;
+        self.transmit();
+    }
+
     /// Periodic tasks.
     ///
-    /// his needs to be called periodically to process the log messages.
+    /// This needs to be called periodically to process the log messages,
+    /// or from the USB interrupt handler if the interrupt is pended by the
+    /// data hook of the log buffer, see [`LogBuffer::set_data_hook`].
     pub fn tasks(&mut self) {
+        self.wake_up_host();
         self.poll();
     }
-
 }
 
-impl<B: UsbBus, const N: usize> UsbClass<B> for UsbLogChannel<'_, B, N> {
+impl<B: UsbBus, const N: usize, const EP_SIZE: usize, S: LogSource> UsbClass<B>
+    for UsbLogChannel<'_, B, N, EP_SIZE, S>
+{
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-        writer.interface_alt(self.iface, 0, 0xff, 0, 0, Some(self.iface_string))?;
-        writer.endpoint(&self.ep_in)
+        let (class, subclass, protocol) = self.iface_class;
+        writer.interface_alt(self.iface, 0, class, subclass, protocol, Some(self.iface_string))?;
+        if self.alt_setting_gated {
+            // streaming alternate setting
+            writer.interface_alt(self.iface, 1, class, subclass, protocol, Some(self.iface_string))?;
+        }
+        if let Some(ep_in) = &self.ep_in {
+            writer.endpoint(ep_in)?;
+        }
+        if let Some(ep_out) = &self.ep_out {
+            writer.endpoint(ep_out)?;
+        }
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        let (name, strings) = (self.iface_name, self.iface_strings);
+        control::interface_string(index, self.iface_string, lang_id, name, strings)
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        control::write_capabilities(writer, self.ms_os_vendor_code, self.webusb)
     }
 
-    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
-        if index == self.iface_string {
-            Some(INTERFACE_NAME)
-        } else {
-            None
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let (ms_os_vendor_code, webusb) = (self.ms_os_vendor_code, self.webusb);
+        let Some(xfer) = control::platform_request(xfer, self.iface, ms_os_vendor_code, webusb)
+        else {
+            return;
+        };
+        let request = xfer.request();
+        if !control::is_vendor_request(request, self.iface) {
+            return;
         }
+        let (request, value) = (request.request, request.value);
+        control::respond(xfer, |buf| self.source.control_in(request, value, buf));
     }
 
-    fn poll(&mut self) {
-        if self.packet_buffer_len == 0 {
-            while let Some(byte) = self.log_buffer.read() {
-                self.packet_buffer[self.packet_buffer_len] = byte;
-                self.packet_buffer_len += 1;
-                if self.packet_buffer_len >= EP_SIZE - 1 {
-                    break;
+    fn reset(&mut self) {
+        // the packet read from the source is lost with the bus reset
+        self.packet_len = 0;
+        self.remote_wakeup_enabled = false;
+        self.alt_setting = 0;
+        self.revoke_credit();
+        self.bus.reset(&mut self.source);
+    }
+
+    /// Answer vendor requests and notice the host clearing a halt of the IN
+    /// endpoint, changing the configuration or switching remote wakeup
+    ///
+    /// The standard requests themselves are handled by the USB device.
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if control::is_vendor_request(request, self.iface) {
+            if request.request == control::GRANT_CREDIT_REQUEST {
+                if let Some(credit) = &mut self.credit {
+                    *credit = credit.saturating_add(request.value.into());
+                    xfer.accept().ok();
                 }
+                return;
             }
+            let accepted = self.source.control_out(request.request, request.value);
+            control::acknowledge(xfer, accepted);
+            return;
+        }
+        if request.request_type != RequestType::Standard {
+            return;
         }
-        if self.packet_buffer_len > 0
-            && self
-                .ep_in
-                .write(&self.packet_buffer[..self.packet_buffer_len])
-                .is_ok()
+        if request.recipient == Recipient::Device
+            && request.value == Request::FEATURE_DEVICE_REMOTE_WAKEUP
         {
-            self.packet_buffer_len = 0;
+            match request.request {
+                Request::SET_FEATURE => self.remote_wakeup_enabled = true,
+                Request::CLEAR_FEATURE => self.remote_wakeup_enabled = false,
+                _ => (),
+            }
+        }
+        let ep_in = self.ep_in.as_ref().map(|ep| ep.address());
+        if self.bus.standard_request(request, ep_in, &mut self.source) {
+            self.alt_setting = 0;
+            self.revoke_credit();
+        }
+    }
+
+    #[cfg(feature = "usb-device-03")]
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        (interface == self.iface && self.alt_setting_gated).then_some(self.alt_setting)
+    }
+
+    #[cfg(feature = "usb-device-03")]
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        if interface != self.iface {
+            return false;
         }
+        self.bus.request_resync();
+        self.revoke_credit();
+        if !self.alt_setting_gated {
+            // only the default alternate setting exists, which the USB device
+            // accepts by itself
+            return false;
+        }
+        if alternative > 1 {
+            return false;
+        }
+        self.alt_setting = alternative;
+        // a reader selects the streaming alternate setting
+        self.source.set_reader_attached(alternative == 1);
+        true
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        let Some(ep_out) = &self.ep_out else {
+            return;
+        };
+        if self.alt_setting_gated && self.alt_setting == 0 {
+            return;
+        }
+        if addr != ep_out.address() {
+            return;
+        }
+        let mut packet = [0; EP_SIZE];
+        if let Ok(len) = ep_out.read(&mut packet) {
+            (self.out_handler)(&packet[..len]);
+        }
+    }
+
+    /// Send the next packet as soon as the previous one has been sent, so
+    /// that the endpoint is not idle until the next poll
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if self.ep_in.as_ref().is_some_and(|ep| ep.address() == addr) {
+            self.transmit();
+        }
+    }
+
+    /// Send the buffered data in packets of up to `EP_SIZE` bytes
+    ///
+    /// A transfer ending with a full packet is terminated by a zero-length
+    /// packet.
+    fn poll(&mut self) {
+        if self.bus.resync(&mut self.source) {
+            // a packet may have been lost, the source continues at a record
+            // boundary
+            self.fill_polls = 0;
+            self.zlp_pending = false;
+            self.packet_len = 0;
+        }
+        self.bus.diagnostics.check_overflow(&mut self.source);
+        if let Some(clock) = self.sof_clock {
+            clock.update();
+        }
+        self.transmit();
+        // a partial packet is left over
+        let pending = self.packet_len > 0 || !self.source.is_empty();
+        if pending && self.fill_polls < self.fill_timeout {
+            self.fill_polls += 1;
+        }
+    }
+}
+
+impl<B: UsbBus, const N: usize, const EP_SIZE: usize, S: LogSource>
+    UsbLogChannel<'_, B, N, EP_SIZE, S>
+{
+    /// Signal remote wakeup if an urgent record has been written while
+    /// suspended
+    ///
+    /// Urgent records written while the bus is active are sent as usual.
+    fn wake_up_host(&mut self) {
+        let Some(hook) = self.wakeup_hook else {
+            return;
+        };
+        if self.source.take_urgent() && self.bus.is_suspended() && self.remote_wakeup_enabled {
+            hook();
+        }
+    }
+
+    /// Write packets to the IN endpoint while they are ready
+    ///
+    /// Peripherals with double-buffered endpoints accept the next packet
+    /// while the previous one is still waiting for the host.
+    fn transmit(&mut self) {
+        while self.write_packet() {}
+    }
+
+    /// Write the next packet to the IN endpoint if it is ready
+    ///
+    /// Returns true if a packet has been written.
+    fn write_packet(&mut self) -> bool {
+        let Some(ep_in) = &self.ep_in else {
+            return false;
+        };
+        if self.bus.is_suspended() || (self.alt_setting_gated && self.alt_setting == 0) {
+            return false;
+        }
+        // full packets are sent right away, as are the bytes followed by more
+        // data, e.g. those up to the end of a ring buffer, and the bytes up to
+        // the granted credit
+        let credit = self.credit.map_or(usize::MAX, |credit| credit as usize);
+        let wait = !self.flush && self.fill_polls < self.fill_timeout;
+        let send = |data: &[u8], more: bool| {
+            let len = data.len().min(EP_SIZE);
+            let ready = len == EP_SIZE || more || data.len() > len || credit <= len;
+            if credit == 0 || (!ready && wait) {
+                // counted by poll()
+                return 0;
+            }
+            ep_in.write(&data[..len.min(credit)]).unwrap_or(0)
+        };
+        let mut written = 0;
+        // transmit straight out of the source if it lends its data, which
+        // then stays in the source until the endpoint has accepted it
+        let lent = self.source.lend(&mut |data, more| {
+            written = send(data, more);
+            written
+        });
+        let available = match lent {
+            Some(available) => available,
+            None => {
+                // fill up the packet kept for the endpoint
+                let packet = &mut self.packet[self.packet_len..];
+                self.packet_len += self.source.read_chunk(packet);
+                if self.packet_len > 0 {
+                    let more = !self.source.is_empty();
+                    written = send(&self.packet[..self.packet_len], more);
+                    self.packet.copy_within(written..self.packet_len, 0);
+                    self.packet_len -= written;
+                }
+                self.packet_len > 0 || written > 0
+            }
+        };
+        if !available {
+            // a transfer ending with a full packet is terminated by a
+            // zero-length packet
+            self.flush = false;
+            if self.zlp_pending && ep_in.write(&[]).is_ok() {
+                self.zlp_pending = false;
+            }
+            return false;
+        }
+        if written == 0 {
+            return false;
+        }
+        self.fill_polls = 0;
+        self.zlp_pending = written == EP_SIZE;
+        if let Some(credit) = &mut self.credit {
+            *credit -= written as u32;
+        }
+        true
+    }
+
+    /// Forget the credit granted by the host, as its reader may be gone
+    fn revoke_credit(&mut self) {
+        if let Some(credit) = &mut self.credit {
+            *credit = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "null-logger"))]
+    use crate::mock_bus::{control_transfer, setup};
+    use crate::mock_bus::MockBus;
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    extern crate std;
+    use std::collections::VecDeque;
+    #[cfg(not(feature = "null-logger"))]
+    use std::vec::Vec;
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn full_packets() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        let poll = |channel: &mut UsbLogChannel<_, 256>| {
+            for _ in 0..4 {
+                UsbClass::poll(channel);
+            }
+        };
+
+        write!(log_buffer.writer(), "{:100}", "").unwrap();
+        poll(&mut channel);
+        let packets = usb_dev.bus().take_packets(ep);
+        let lens: Vec<usize> = packets.iter().map(Vec::len).collect();
+        assert_eq!(lens, [64, 36]);
+
+        // a transfer ending on a packet boundary is terminated by a ZLP
+        write!(log_buffer.writer(), "{:128}", "").unwrap();
+        poll(&mut channel);
+        let packets = usb_dev.bus().take_packets(ep);
+        let lens: Vec<usize> = packets.iter().map(Vec::len).collect();
+        assert_eq!(lens, [64, 64, 0]);
+
+        // a partial packet waits for the fill timeout unless flushed
+        channel.set_fill_timeout(10);
+        write!(log_buffer.writer(), "abc").unwrap();
+        poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+        channel.flush();
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"abc"]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn multiple_channels() {
+        let log_buffer = LogBuffer::<256>::new();
+        let trace_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let mut trace: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &trace_buffer);
+        trace.set_interface_name("kiffielog-trace");
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        let trace_ep = trace.ep_in.as_ref().unwrap().address();
+
+        let get_config = setup(0x80, 6, 0x0200, 0, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut channel, &mut trace], get_config);
+        let desc = desc.unwrap();
+        assert_eq!(&desc[9..18], [9, 4, 0, 0, 1, 0xff, 0, 0, 4]);
+        assert_eq!(&desc[25..34], [9, 4, 1, 0, 1, 0xff, 0, 0, 5]);
+        let get_string = setup(0x80, 6, 0x0305, 0x0409, 255);
+        let name = control_transfer(&mut usb_dev, &mut [&mut channel, &mut trace], get_string);
+        let name: Vec<u8> = name.unwrap()[2..].iter().step_by(2).copied().collect();
+        assert_eq!(name, b"kiffielog-trace");
+
+        writeln!(log_buffer.writer(), "log").unwrap();
+        let mut grant = trace_buffer.grant(2).unwrap();
+        grant.copy_from_slice(b"\x01\x02");
+        grant.commit(2);
+        UsbClass::poll(&mut channel);
+        UsbClass::poll(&mut trace);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"log\n"]);
+        assert_eq!(usb_dev.bus().take_packets(trace_ep), [b"\x01\x02"]);
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn control_requests() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let iface = u8::from(channel.iface) as u16;
+        let mut transfer = |channel: &mut UsbLogChannel<_, 256>, setup| {
+            control_transfer(&mut usb_dev, &mut [channel], setup)
+        };
+
+        // configuration, interface and endpoint descriptor
+        let desc = transfer(&mut channel, setup(0x80, 6, 0x0200, 0, 255)).unwrap();
+        assert_eq!(desc.len(), 9 + 9 + 7);
+        assert_eq!(&desc[9..18], [9, 4, iface as u8, 0, 1, 0xff, 0, 0, 4]);
+        assert_eq!(&desc[18..], [7, 5, 0x81, 0x02, 64, 0, 0]);
+
+        writeln!(log_buffer.writer(), "abc").unwrap();
+        let data = transfer(&mut channel, setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 64));
+        assert_eq!(data.unwrap(), b"abc\n");
+        let stats = transfer(&mut channel, setup(0xc1, control::GET_STATS_REQUEST, 0, iface, 12));
+        assert_eq!(stats.unwrap(), [0, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0]);
+        let get_version = setup(0xc1, control::GET_VERSION_REQUEST, 0, iface, 2);
+        let version = transfer(&mut channel, get_version);
+        assert_eq!(version.unwrap(), control::PROTOCOL_VERSION.to_le_bytes());
+
+        // the time echo needs a clock
+        static CLOCK: fn() -> u64 = || 0x0102_0304;
+        let time_echo = setup(0xc1, control::TIME_ECHO_REQUEST, 0xabcd, iface, 10);
+        assert!(transfer(&mut channel, time_echo).is_none());
+        log_buffer.set_clock(Some(&CLOCK));
+        let response = transfer(&mut channel, time_echo).unwrap();
+        assert_eq!(response, [0xcd, 0xab, 4, 3, 2, 1, 0, 0, 0, 0]);
+
+        writeln!(log_buffer.writer(), "abc").unwrap();
+        let res = transfer(&mut channel, setup(0x41, control::CLEAR_REQUEST, 0, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        assert!(log_buffer.is_empty());
+        let res = transfer(&mut channel, setup(0x41, control::SET_READER_REQUEST, 1, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        assert!(log_buffer.is_reader_attached());
+
+        // invalid level, unknown request
+        let res = transfer(&mut channel, setup(0x41, control::SET_LEVEL_REQUEST, 6, iface, 0));
+        assert!(res.is_none());
+        assert!(transfer(&mut channel, setup(0xc1, 0x7f, 0, iface, 64)).is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn next_packet_on_completion() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::with_in_capacity(1));
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+
+        write!(log_buffer.writer(), "{:100}", "").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep).len(), 1);
+        // the host has read the packet, the next one is sent without polling
+        channel.endpoint_in_complete(ep);
+        let packets = usb_dev.bus().take_packets(ep);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 36);
+    }
+
+    #[test]
+    #[cfg(feature = "null-logger")]
+    fn null_logger() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+
+        // the records are discarded, so nothing is sent
+        writeln!(log_buffer.writer(), "abc").unwrap();
+        UsbClass::poll(&mut channel);
+        channel.flush();
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+    }
+
+    /// Source without a ring buffer, which the channel reads packet by packet
+    struct Queue(VecDeque<u8>);
+
+    impl LogSource for Queue {
+        fn read(&mut self) -> Option<u8> {
+            self.0.pop_front()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    #[test]
+    fn packets_kept_until_sent() {
+        let alloc = UsbBusAllocator::new(MockBus::with_in_capacity(1));
+        let queue = Queue(b"0123456789".iter().copied().collect());
+        let mut channel: UsbLogChannel<_, 0, 8, Queue> = UsbLogChannel::with_source(&alloc, queue);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+
+        UsbClass::poll(&mut channel);
+        // the endpoint is busy, the second packet waits in the channel
+        UsbClass::poll(&mut channel);
+        assert!(channel.source().is_empty());
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"01234567"]);
+        channel.endpoint_in_complete(ep);
+
+        // a bus reset discards the waiting packet
+        channel.source().0.extend(b"abc");
+        UsbClass::poll(&mut channel);
+        UsbClass::<MockBus>::reset(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"89"]);
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn suspended() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        channel.set_suspend_policy(SuspendPolicy::Drop);
+
+        writeln!(log_buffer.writer(), "a").unwrap();
+        channel.set_suspended(true);
+        writeln!(log_buffer.writer(), "b").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+
+        channel.set_suspended(false);
+        writeln!(log_buffer.writer(), "c").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(
+            usb_dev.bus().take_packets(ep),
+            [b"a\n[usb-log] suspended\n[DROPPED] 1 records\n[usb-log] resumed\nc\n"]
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn overflow_reported_once() {
+        let log_buffer = LogBuffer::<64>::new();
+        let alloc = UsbBusAllocator::new(MockBus::with_in_capacity(0));
+        let mut channel: UsbLogChannel<_, 64> = UsbLogChannel::new(&alloc, &log_buffer);
+        let _usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+        // no report while the overflow goes on
+        for _ in 0..3 {
+            writeln!(log_buffer.writer(), "{:80}", "x").unwrap();
+            let total_bytes = log_buffer.stats().total_bytes;
+            UsbClass::poll(&mut channel);
+            assert_eq!(log_buffer.stats().total_bytes, total_bytes);
+        }
+        let lost = log_buffer.stats().dropped_bytes;
+        assert!(lost > 0);
+
+        // a single report once it has ended
+        let total_bytes = log_buffer.stats().total_bytes;
+        UsbClass::poll(&mut channel);
+        let total_bytes_reported = log_buffer.stats().total_bytes;
+        assert!(total_bytes_reported > total_bytes);
+        UsbClass::poll(&mut channel);
+        assert_eq!(log_buffer.stats().total_bytes, total_bytes_reported);
+        let report = std::format!("[usb-log] buffer overflowed, {lost} bytes lost\n");
+        let data: Vec<u8> = core::iter::from_fn(|| log_buffer.read()).collect();
+        assert!(data.ends_with(report.as_bytes()));
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn remote_wakeup() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use log::{Level, LevelFilter, Log, Record};
+
+        static WAKEUPS: AtomicUsize = AtomicUsize::new(0);
+
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        log_buffer.set_urgent_level(LevelFilter::Error);
+        channel.enable_remote_wakeup(|| {
+            WAKEUPS.fetch_add(1, Ordering::Relaxed);
+        });
+        let log = |level| {
+            log_buffer.log(&Record::builder().level(level).args(format_args!("x")).build())
+        };
+
+        // remote wakeup has not been enabled by the host
+        channel.set_suspended(true);
+        log(Level::Error);
+        channel.tasks();
+        assert_eq!(WAKEUPS.load(Ordering::Relaxed), 0);
+
+        let set_feature = setup(0x00, Request::SET_FEATURE, 1, 0, 0);
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], set_feature).is_some());
+        channel.set_suspended(true);
+        log(Level::Warn);
+        channel.tasks();
+        assert_eq!(WAKEUPS.load(Ordering::Relaxed), 0);
+        log(Level::Error);
+        channel.tasks();
+        channel.tasks();
+        assert_eq!(WAKEUPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn unconfigured() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        let set_config = setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0);
+
+        channel.set_unconfigured_policy(UnconfiguredPolicy::Drop);
+        writeln!(log_buffer.writer(), "a").unwrap();
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], set_config).is_some());
+        writeln!(log_buffer.writer(), "b").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"[DROPPED] 1 records\nb\n"]);
+
+        UsbClass::reset(&mut channel);
+        channel.set_unconfigured_policy(UnconfiguredPolicy::ClearOnConfigure);
+        writeln!(log_buffer.writer(), "c").unwrap();
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], set_config).is_some());
+        writeln!(log_buffer.writer(), "d").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"d\n"]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "usb-device-03", not(feature = "null-logger")))]
+    fn alt_setting_gating() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        let iface = channel.iface;
+        channel.enable_alt_setting_gating();
+
+        writeln!(log_buffer.writer(), "a").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+        assert_eq!(channel.get_alt_setting(iface), Some(0));
+        assert!(!channel.set_alt_setting(iface, 2));
+
+        assert!(channel.set_alt_setting(iface, 1));
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"a\n"]);
+
+        // a bus reset selects the default alternate setting
+        UsbClass::reset(&mut channel);
+        assert_eq!(channel.get_alt_setting(iface), Some(0));
+        writeln!(log_buffer.writer(), "b").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+    }
+
+    #[test]
+    #[cfg(not(feature = "null-logger"))]
+    fn credit_flow_control() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannel<_, 256> = UsbLogChannel::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.as_ref().unwrap().address();
+        channel.enable_credit_flow_control();
+
+        writeln!(log_buffer.writer(), "abcdef").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+
+        channel.credit = Some(3);
+        UsbClass::poll(&mut channel);
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"abc"]);
+
+        // the credit is revoked by a bus reset
+        UsbClass::reset(&mut channel);
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+        assert_eq!(channel.credit, Some(0));
     }
 }
@@ -0,0 +1,232 @@
+//! USB Log channel based on CDC-ACM
+//!
+//! This log channel provides a virtual serial port, so that the log can be
+//! read with any serial terminal without `usb-logread`. It consists of a
+//! communication and a data interface grouped by an interface association,
+//! so the device must announce the "use interface association descriptors"
+//! device class:
+//!
+//! ```ignore
+//! let log_channel = UsbLogChannelCdc::new(&usb_bus, log_buffer);
+//! let usb_dev = UsbDeviceBuilder::new(&usb_bus, vid_pid)
+//!     .composite_with_iads()
+//!     .build();
+//! ```
+//!
+//! The log is sent only while a terminal has the port open, i.e. while the
+//! host asserts DTR. Data sent by the terminal is discarded. The text format
+//! of the log buffer should be used, as terminals cannot decode binary
+//! frames. The channel can be used alongside one of the other variants
+//! reading another log buffer.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// Size of the bulk packets
+const EP_SIZE: usize = 64;
+
+const CLASS_CDC: u8 = 0x02;
+const CLASS_CDC_DATA: u8 = 0x0a;
+const SUBCLASS_ACM: u8 = 0x02;
+
+/// Descriptor type of the functional descriptors
+const CS_INTERFACE: u8 = 0x24;
+
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// 115200 baud, 1 stop bit, no parity, 8 data bits
+const DEFAULT_LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+/// Log channel providing a virtual serial port
+pub struct UsbLogChannelCdc<'a, B: UsbBus, const N: usize> {
+    comm_iface: InterfaceNumber,
+    data_iface: InterfaceNumber,
+    ep_notify: EndpointIn<'a, B>,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    log_buffer: &'a LogBuffer<N>,
+    /// Line coding set by the host, which has no effect
+    line_coding: [u8; 7],
+    /// A terminal has the port open
+    dtr: bool,
+    /// Data in transit may have been lost, continue at a record boundary
+    resync: bool,
+    /// The last packet was a full one, so the host waits for more data
+    zlp_pending: bool,
+}
+
+impl<'a, B: UsbBus, const N: usize> UsbLogChannelCdc<'a, B, N> {
+    /// Create a new USB log channel
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+    ) -> UsbLogChannelCdc<'a, B, N> {
+        UsbLogChannelCdc {
+            comm_iface: alloc.interface(),
+            data_iface: alloc.interface(),
+            ep_notify: alloc.interrupt(8, 255),
+            ep_in: alloc.bulk(EP_SIZE as u16),
+            ep_out: alloc.bulk(EP_SIZE as u16),
+            log_buffer,
+            line_coding: DEFAULT_LINE_CODING,
+            dtr: false,
+            resync: false,
+            zlp_pending: false,
+        }
+    }
+
+    /// Returns true if a terminal has the port open
+    pub fn is_open(&self) -> bool {
+        self.dtr
+    }
+
+    /// Returns true if `request` is a class request to the communication
+    /// interface
+    fn is_class_request(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.comm_iface) as u16
+    }
+}
+
+impl<B: UsbBus, const N: usize> UsbClass<B> for UsbLogChannelCdc<'_, B, N> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        usb_device_compat::iad(writer, self.comm_iface, 2, CLASS_CDC, SUBCLASS_ACM, 0)?;
+        writer.interface(self.comm_iface, CLASS_CDC, SUBCLASS_ACM, 0)?;
+        // header, call management, abstract control management and union
+        // functional descriptors
+        writer.write(CS_INTERFACE, &[0x00, 0x10, 0x01])?;
+        writer.write(CS_INTERFACE, &[0x01, 0x00, self.data_iface.into()])?;
+        writer.write(CS_INTERFACE, &[0x02, 0x02])?;
+        writer.write(CS_INTERFACE, &[0x06, self.comm_iface.into(), self.data_iface.into()])?;
+        writer.endpoint(&self.ep_notify)?;
+        writer.interface(self.data_iface, CLASS_CDC_DATA, 0, 0)?;
+        writer.endpoint(&self.ep_out)?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if self.is_class_request(request) && request.request == GET_LINE_CODING {
+            let len = self.line_coding.len().min(request.length as usize);
+            xfer.accept_with(&self.line_coding[..len]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = *xfer.request();
+        if self.is_class_request(&request) {
+            match request.request {
+                SET_LINE_CODING if xfer.data().len() >= self.line_coding.len() => {
+                    self.line_coding.copy_from_slice(&xfer.data()[..7]);
+                    xfer.accept().ok();
+                }
+                SET_CONTROL_LINE_STATE => {
+                    let dtr = request.value & 1 != 0;
+                    if dtr && !self.dtr {
+                        // start the terminal session at a record boundary
+                        self.resync = true;
+                    }
+                    self.dtr = dtr;
+                    // the terminal is the reader
+                    self.log_buffer.set_reader_attached(dtr);
+                    xfer.accept().ok();
+                }
+                _ => (),
+            }
+            return;
+        }
+        let configured = request.request_type == RequestType::Standard
+            && request.recipient == Recipient::Device
+            && request.request == Request::SET_CONFIGURATION;
+        if configured {
+            self.dtr = false;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.dtr = false;
+        self.zlp_pending = false;
+        self.log_buffer.set_reader_attached(false);
+    }
+
+    /// Discard the data sent by the terminal
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr == self.ep_out.address() {
+            self.ep_out.read(&mut [0; EP_SIZE]).ok();
+        }
+    }
+
+    fn poll(&mut self) {
+        if self.resync {
+            self.resync = false;
+            self.zlp_pending = false;
+            self.log_buffer.resync();
+        }
+        if !self.dtr {
+            return;
+        }
+        let Some(grant) = self.log_buffer.read_grant() else {
+            // a transfer ending with a full packet is terminated by a
+            // zero-length packet
+            if self.zlp_pending && self.ep_in.write(&[]).is_ok() {
+                self.zlp_pending = false;
+            }
+            return;
+        };
+        let len = grant.len().min(EP_SIZE);
+        if let Ok(written) = self.ep_in.write(&grant[..len]) {
+            grant.release(written);
+            self.zlp_pending = written == EP_SIZE;
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    #[test]
+    fn sent_while_open() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelCdc<_, 256> = UsbLogChannelCdc::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+            .composite_with_iads()
+            .build();
+        let iface = u8::from(channel.comm_iface) as u16;
+        let ep = channel.ep_in.address();
+        let mut transfer = |channel: &mut UsbLogChannelCdc<_, 256>, setup| {
+            control_transfer(&mut usb_dev, &mut [channel], setup)
+        };
+
+        let coding = transfer(&mut channel, setup(0xa1, GET_LINE_CODING, 0, iface, 7));
+        assert_eq!(coding.unwrap(), DEFAULT_LINE_CODING);
+
+        writeln!(log_buffer.writer(), "a").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(!channel.is_open());
+
+        // the terminal opens the port
+        let res = transfer(&mut channel, setup(0x21, SET_CONTROL_LINE_STATE, 3, iface, 0));
+        assert_eq!(res.unwrap(), []);
+        writeln!(log_buffer.writer(), "b").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(channel.is_open());
+        // the USB device has polled the channel after the request already
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"a\n", b"b\n"]);
+    }
+}
@@ -0,0 +1,247 @@
+//! USB Log channel based on HID input reports
+//!
+//! This log channel provides a HID interface with a vendor-defined report
+//! descriptor and one interrupt IN endpoint. Operating systems bind their HID
+//! driver to the interface, so no driver has to be installed on the host,
+//! which matters for locked-down machines. The interface is labelled like the
+//! other variants so that the host tool finds it by its name.
+//!
+//! HID reports have a fixed length of 64 bytes. The first byte of a report
+//! gives the number of log bytes that follow.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::bus_state::{BusState, SuspendPolicy, UnconfiguredPolicy};
+use crate::control;
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat::LangID;
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// Length of a report
+const REPORT_LEN: usize = 64;
+
+/// Interface class code of HID
+const CLASS_HID: u8 = 0x03;
+
+/// Descriptor types of the HID and the report descriptor
+const DESCRIPTOR_HID: u8 = 0x21;
+const DESCRIPTOR_REPORT: u8 = 0x22;
+
+/// HID class request accepted to keep hosts happy
+const HID_SET_IDLE: u8 = 0x0a;
+
+/// Vendor-defined usage page 0xff00 with one input report of 64 bytes
+const REPORT_DESCRIPTOR: [u8; 21] = [
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, // Usage (0x01)
+    0xa1, 0x01, // Collection (Application)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, REPORT_LEN as u8, //   Report Count (64)
+    0x09, 0x01, //   Usage (0x01)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xc0, // End Collection
+];
+
+/// Log channel sending the log as HID input reports
+///
+/// ```ignore
+/// // poll every 4 ms
+/// let log_channel = UsbLogChannelHid::new(&usb_bus, log_buffer, 4);
+/// ```
+pub struct UsbLogChannelHid<'a, B: UsbBus, const N: usize> {
+    iface: InterfaceNumber,
+    iface_string: StringIndex,
+    ep_in: EndpointIn<'a, B>,
+    iface_name: &'a str,
+    iface_strings: &'a [(LangID, &'a str)],
+    log_buffer: &'a LogBuffer<N>,
+    /// Suspension, configuration and resynchronization
+    bus: BusState,
+}
+
+impl<'a, B: UsbBus, const N: usize> UsbLogChannelHid<'a, B, N> {
+    /// Create a new USB log channel
+    ///
+    /// `interval` is the polling interval of the endpoint in frames (1 ms) at
+    /// full speed or as an exponent of 2 microframes (125 µs) at high speed.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+        interval: u8,
+    ) -> UsbLogChannelHid<'a, B, N> {
+        let iface = alloc.interface();
+        let iface_string = alloc.string();
+        let ep_in = alloc.interrupt(REPORT_LEN as u16, interval);
+        UsbLogChannelHid {
+            iface,
+            iface_string,
+            ep_in,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            iface_strings: &[],
+            log_buffer,
+            bus: BusState::new(),
+        }
+    }
+
+    /// Set the interface name, see [`control::interface_string`]
+    pub fn set_interface_name(&mut self, name: &'a str) {
+        self.iface_name = name;
+    }
+
+    /// Set localized interface names, see [`control::interface_string`]
+    pub fn set_interface_strings(&mut self, strings: &'a [(LangID, &'a str)]) {
+        self.iface_strings = strings;
+    }
+
+    /// Select how log records are handled while the USB is suspended
+    ///
+    /// The default is [`SuspendPolicy::Buffer`].
+    pub fn set_suspend_policy(&mut self, policy: SuspendPolicy) {
+        self.bus.set_suspend_policy(policy, &mut self.log_buffer);
+    }
+
+    /// Tell the channel whether the USB is suspended
+    ///
+    /// See [`crate::usb_log_channel_bulk::UsbLogChannel::set_suspended`].
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.bus.set_suspended(suspended, &mut self.log_buffer);
+    }
+
+    /// Select how log records are handled while the USB device is not
+    /// configured
+    ///
+    /// The default is [`UnconfiguredPolicy::Buffer`].
+    pub fn set_unconfigured_policy(&mut self, policy: UnconfiguredPolicy) {
+        self.bus.set_unconfigured_policy(policy, &mut self.log_buffer);
+    }
+
+    /// Returns true if `request` reads the report descriptor
+    fn is_report_descriptor_request(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Standard
+            && request.recipient == Recipient::Interface
+            && request.request == Request::GET_DESCRIPTOR
+            && request.value == u16::from(DESCRIPTOR_REPORT) << 8
+            && request.index == u8::from(self.iface) as u16
+    }
+}
+
+impl<B: UsbBus, const N: usize> UsbClass<B> for UsbLogChannelHid<'_, B, N> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.iface, 0, CLASS_HID, 0, 0, Some(self.iface_string))?;
+        let len = (REPORT_DESCRIPTOR.len() as u16).to_le_bytes();
+        // HID 1.11, no country code, one report descriptor
+        writer.write(
+            DESCRIPTOR_HID,
+            &[0x11, 0x01, 0, 1, DESCRIPTOR_REPORT, len[0], len[1]],
+        )?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        let (name, strings) = (self.iface_name, self.iface_strings);
+        control::interface_string(index, self.iface_string, lang_id, name, strings)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if self.is_report_descriptor_request(request) {
+            let len = REPORT_DESCRIPTOR.len().min(request.length as usize);
+            xfer.accept_with_static(&REPORT_DESCRIPTOR[..len]).ok();
+            return;
+        }
+        if !control::is_vendor_request(request, self.iface) {
+            return;
+        }
+        // the log is only sent over the IN endpoint
+        if control::is_log_read(request.request) {
+            return;
+        }
+        let (code, value) = (request.request, request.value);
+        control::respond(xfer, |buf| control::response(self.log_buffer, code, value, buf));
+    }
+
+    fn reset(&mut self) {
+        self.bus.reset(&mut self.log_buffer);
+    }
+
+    /// Answer vendor requests and SET_IDLE and notice the host clearing a
+    /// halt of the IN endpoint or changing the configuration
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if control::is_vendor_request(request, self.iface) {
+            let accepted = control::perform(self.log_buffer, request.request, request.value);
+            control::acknowledge(xfer, accepted);
+            return;
+        }
+        if request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.request == HID_SET_IDLE
+            && request.index == u8::from(self.iface) as u16
+        {
+            // reports are sent only when there is data anyway
+            xfer.accept().ok();
+            return;
+        }
+        let ep_in = Some(self.ep_in.address());
+        self.bus.standard_request(request, ep_in, &mut self.log_buffer);
+    }
+
+    fn poll(&mut self) {
+        // a report may have been lost, continue at a record boundary
+        self.bus.resync(&mut self.log_buffer);
+        self.bus.diagnostics.check_overflow(&mut self.log_buffer);
+        if self.bus.is_suspended() {
+            return;
+        }
+        let Some(grant) = self.log_buffer.read_grant() else {
+            return;
+        };
+        let len = grant.len().min(REPORT_LEN - 1);
+        let mut report = [0; REPORT_LEN];
+        report[0] = len as u8;
+        report[1..=len].copy_from_slice(&grant[..len]);
+        if self.ep_in.write(&report).is_ok() {
+            grant.release(len);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    #[test]
+    fn reports() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelHid<_, 256> = UsbLogChannelHid::new(&alloc, &log_buffer, 4);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let iface = u8::from(channel.iface) as u16;
+        let ep = channel.ep_in.address();
+
+        let get_config = setup(0x80, 6, 0x0200, 0, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut channel], get_config).unwrap();
+        assert_eq!(&desc[9..18], [9, 4, iface as u8, 0, 1, CLASS_HID, 0, 0, 4]);
+        assert_eq!(&desc[18..25], [9, DESCRIPTOR_HID, 0x11, 0x01, 0, 1, DESCRIPTOR_REPORT]);
+        let get_report_desc = setup(0x81, 6, 0x2200, iface, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut channel], get_report_desc);
+        assert_eq!(desc.unwrap(), REPORT_DESCRIPTOR);
+
+        writeln!(log_buffer.writer(), "abc").unwrap();
+        UsbClass::poll(&mut channel);
+        let reports = usb_dev.bus().take_packets(ep);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].len(), REPORT_LEN);
+        assert_eq!(&reports[0][..5], b"\x04abc\n");
+    }
+}
@@ -0,0 +1,370 @@
+//! USB Log channel based on an interrupt endpoint
+//!
+//! This log channel provides an USB interface having one interrupt IN
+//! endpoint. It is meant for devices that have no bulk endpoint to spare. The
+//! interface is labelled like the other variants so that the host tool finds
+//! it by its name.
+//!
+//! The host polls the endpoint once per interval, which limits the throughput
+//! to one packet per interval.
+//!
+//! Like the bulk channel, the interrupt channel can send the data of another
+//! [`LogSource`] than a log buffer, see [`UsbLogChannelInterrupt::with_source`].
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::bus_state::{BusState, SuspendPolicy, UnconfiguredPolicy};
+use crate::control;
+use crate::log_buffer::LogBuffer;
+use crate::log_source::LogSource;
+use crate::usb_device_compat::LangID;
+use usb_device::{class_prelude::*, Result};
+
+/// Log channel with an interrupt IN endpoint of `EP_SIZE` bytes
+///
+/// ```ignore
+/// // poll every 4 ms
+/// let log_channel = UsbLogChannelInterrupt::new(&usb_bus, log_buffer, 4);
+/// ```
+///
+/// The data is read from a log buffer of `N` bytes unless another source `S`
+/// is given.
+pub struct UsbLogChannelInterrupt<
+    'a,
+    B: UsbBus,
+    const N: usize,
+    const EP_SIZE: usize = 64,
+    S: LogSource = &'a LogBuffer<N>,
+> {
+    iface: InterfaceNumber,
+    iface_string: StringIndex,
+    ep_in: EndpointIn<'a, B>,
+    iface_name: &'a str,
+    /// Interface class, subclass and protocol codes
+    iface_class: (u8, u8, u8),
+    /// Vendor code of the Microsoft OS 2.0 descriptor request, if enabled
+    ms_os_vendor_code: Option<u8>,
+    /// Vendor code of WebUSB requests and landing page, if enabled
+    webusb: Option<(u8, Option<&'a str>)>,
+    iface_strings: &'a [(LangID, &'a str)],
+    source: S,
+    /// Packet read from a source that cannot lend its data but not yet
+    /// accepted by the endpoint
+    packet: [u8; EP_SIZE],
+    packet_len: usize,
+    /// Suspension, configuration and resynchronization
+    bus: BusState,
+}
+
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize>
+    UsbLogChannelInterrupt<'a, B, N, EP_SIZE>
+{
+    /// Create a new USB log channel
+    ///
+    /// `interval` is the polling interval of the endpoint in frames (1 ms) at
+    /// full speed or as an exponent of 2 microframes (125 µs) at high speed.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+        interval: u8,
+    ) -> UsbLogChannelInterrupt<'a, B, N, EP_SIZE> {
+        Self::with_source(alloc, log_buffer, interval)
+    }
+}
+
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize, S: LogSource>
+    UsbLogChannelInterrupt<'a, B, N, EP_SIZE, S>
+{
+    /// Interrupt packets are at most 64 bytes long at full speed
+    const VALID_EP_SIZE: () = assert!(EP_SIZE >= 2 && EP_SIZE <= 64);
+
+    /// Create a new USB log channel sending the data of `source`
+    ///
+    /// See [`crate::usb_log_channel_bulk::UsbLogChannel::with_source`].
+    pub fn with_source(
+        alloc: &'a UsbBusAllocator<B>,
+        source: S,
+        interval: u8,
+    ) -> UsbLogChannelInterrupt<'a, B, N, EP_SIZE, S> {
+        let () = Self::VALID_EP_SIZE;
+        let iface = alloc.interface();
+        let iface_string = alloc.string();
+        let ep_in = alloc.interrupt(EP_SIZE as u16, interval);
+        UsbLogChannelInterrupt {
+            iface,
+            iface_string,
+            ep_in,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            iface_class: (0xff, 0, 0),
+            ms_os_vendor_code: None,
+            webusb: None,
+            iface_strings: &[],
+            source,
+            packet: [0; EP_SIZE],
+            packet_len: 0,
+            bus: BusState::new(),
+        }
+    }
+
+    /// Access the log source
+    pub fn source(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Set the interface name, see [`control::interface_string`]
+    pub fn set_interface_name(&mut self, name: &'a str) {
+        self.iface_name = name;
+    }
+
+    /// Set the class, subclass and protocol codes of the interface, see
+    /// [`crate::usb_log_channel_bulk::UsbLogChannel::set_interface_class`]
+    pub fn set_interface_class(&mut self, class: u8, subclass: u8, protocol: u8) {
+        self.iface_class = (class, subclass, protocol);
+    }
+
+    /// Provide Microsoft OS 2.0 descriptors binding WinUSB to the interface, see
+    /// [`control::write_capabilities`]
+    pub fn enable_ms_os_descriptors(&mut self, vendor_code: u8) {
+        self.ms_os_vendor_code = Some(vendor_code);
+    }
+
+    /// Announce WebUSB support with an optional landing page, see
+    /// [`control::write_capabilities`]
+    pub fn enable_webusb(&mut self, vendor_code: u8, landing_page: Option<&'a str>) {
+        self.webusb = Some((vendor_code, landing_page));
+    }
+
+    /// Select how log records are handled while the USB is suspended
+    ///
+    /// The default is [`SuspendPolicy::Buffer`].
+    pub fn set_suspend_policy(&mut self, policy: SuspendPolicy) {
+        self.bus.set_suspend_policy(policy, &mut self.source);
+    }
+
+    /// Tell the channel whether the USB is suspended
+    ///
+    /// See [`crate::usb_log_channel_bulk::UsbLogChannel::set_suspended`].
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.bus.set_suspended(suspended, &mut self.source);
+    }
+
+    /// Select how log records are handled while the USB device is not
+    /// configured
+    ///
+    /// The default is [`UnconfiguredPolicy::Buffer`].
+    pub fn set_unconfigured_policy(&mut self, policy: UnconfiguredPolicy) {
+        self.bus.set_unconfigured_policy(policy, &mut self.source);
+    }
+
+    /// Set localized interface names, see [`control::interface_string`]
+    pub fn set_interface_strings(&mut self, strings: &'a [(LangID, &'a str)]) {
+        self.iface_strings = strings;
+    }
+}
+
+impl<B: UsbBus, const N: usize, const EP_SIZE: usize, S: LogSource> UsbClass<B>
+    for UsbLogChannelInterrupt<'_, B, N, EP_SIZE, S>
+{
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        let (class, subclass, protocol) = self.iface_class;
+        writer.interface_alt(self.iface, 0, class, subclass, protocol, Some(self.iface_string))?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        let (name, strings) = (self.iface_name, self.iface_strings);
+        control::interface_string(index, self.iface_string, lang_id, name, strings)
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        control::write_capabilities(writer, self.ms_os_vendor_code, self.webusb)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let (ms_os_vendor_code, webusb) = (self.ms_os_vendor_code, self.webusb);
+        let Some(xfer) = control::platform_request(xfer, self.iface, ms_os_vendor_code, webusb)
+        else {
+            return;
+        };
+        let request = xfer.request();
+        if !control::is_vendor_request(request, self.iface) {
+            return;
+        }
+        // the log is only sent over the IN endpoint
+        if control::is_log_read(request.request) {
+            return;
+        }
+        let (code, value) = (request.request, request.value);
+        control::respond(xfer, |buf| self.source.control_in(code, value, buf));
+    }
+
+    fn reset(&mut self) {
+        // the packet read from the source is lost with the bus reset
+        self.packet_len = 0;
+        self.bus.reset(&mut self.source);
+    }
+
+    /// Answer vendor requests and notice the host clearing a halt of the IN
+    /// endpoint or changing the configuration
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if control::is_vendor_request(request, self.iface) {
+            let accepted = self.source.control_out(request.request, request.value);
+            control::acknowledge(xfer, accepted);
+            return;
+        }
+        let ep_in = Some(self.ep_in.address());
+        self.bus.standard_request(request, ep_in, &mut self.source);
+    }
+
+    fn poll(&mut self) {
+        if self.bus.resync(&mut self.source) {
+            // a packet may have been lost, continue at a record boundary
+            self.packet_len = 0;
+        }
+        self.bus.diagnostics.check_overflow(&mut self.source);
+        if self.bus.is_suspended() {
+            return;
+        }
+        // the host reads one packet per transfer, so that full packets need
+        // no terminating short packet
+        let ep_in = &self.ep_in;
+        let lent = self.source.lend(&mut |data, _| {
+            let len = data.len().min(EP_SIZE);
+            ep_in.write(&data[..len]).unwrap_or(0)
+        });
+        if lent.is_none() {
+            // fill up the packet kept for the endpoint
+            let packet = &mut self.packet[self.packet_len..];
+            self.packet_len += self.source.read_chunk(packet);
+            if self.packet_len > 0 {
+                let written = ep_in.write(&self.packet[..self.packet_len]).unwrap_or(0);
+                self.packet.copy_within(written..self.packet_len, 0);
+                self.packet_len -= written;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::control::Request;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    extern crate std;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn full_packets() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::with_in_capacity(1));
+        let mut channel: UsbLogChannelInterrupt<_, 256> =
+            UsbLogChannelInterrupt::new(&alloc, &log_buffer, 4);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.address();
+
+        let get_config = setup(0x80, 6, 0x0200, 0, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut channel], get_config).unwrap();
+        assert_eq!(&desc[18..], [7, 5, 0x81, 0x03, 64, 0, 4]);
+
+        write!(log_buffer.writer(), "{:100}", "").unwrap();
+        UsbClass::poll(&mut channel);
+        // the host has not yet read the packet, the data stays buffered
+        UsbClass::poll(&mut channel);
+        assert_eq!(log_buffer.len(), 36);
+        let packets = usb_dev.bus().take_packets(ep);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].len(), 64);
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep)[0].len(), 36);
+    }
+
+    /// Source without a ring buffer, which the channel reads packet by packet
+    struct Queue(VecDeque<u8>);
+
+    impl LogSource for Queue {
+        fn read(&mut self) -> Option<u8> {
+            self.0.pop_front()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    #[test]
+    fn packets_kept_until_sent() {
+        let alloc = UsbBusAllocator::new(MockBus::with_in_capacity(1));
+        let queue = Queue(b"0123456789".iter().copied().collect());
+        let mut channel: UsbLogChannelInterrupt<_, 0, 8, Queue> =
+            UsbLogChannelInterrupt::with_source(&alloc, queue, 4);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.address();
+
+        UsbClass::poll(&mut channel);
+        // the endpoint is busy, the second packet waits in the channel
+        UsbClass::poll(&mut channel);
+        assert!(channel.source().is_empty());
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"01234567"]);
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"89"]);
+    }
+
+    #[test]
+    fn policies() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelInterrupt<_, 256> =
+            UsbLogChannelInterrupt::new(&alloc, &log_buffer, 4);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.address();
+        let set_config = setup(0x00, Request::SET_CONFIGURATION, 1, 0, 0);
+
+        channel.set_unconfigured_policy(UnconfiguredPolicy::Drop);
+        writeln!(log_buffer.writer(), "a").unwrap();
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], set_config).is_some());
+        writeln!(log_buffer.writer(), "b").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [b"[DROPPED] 1 records\nb\n"]);
+
+        channel.set_suspend_policy(SuspendPolicy::Drop);
+        channel.set_suspended(true);
+        writeln!(log_buffer.writer(), "c").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+        channel.set_suspended(false);
+        writeln!(log_buffer.writer(), "d").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(
+            usb_dev.bus().take_packets(ep),
+            [b"[usb-log] suspended\n[DROPPED] 1 records\n[usb-log] resumed\nd\n"]
+        );
+    }
+
+    #[test]
+    fn vendor_requests() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelInterrupt<_, 256> =
+            UsbLogChannelInterrupt::new(&alloc, &log_buffer, 4);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let iface = u8::from(channel.iface) as u16;
+
+        // keep the data in the buffer
+        channel.set_suspended(true);
+        writeln!(log_buffer.writer(), "a").unwrap();
+        let version = setup(0xc1, control::GET_VERSION_REQUEST, 0, iface, 2);
+        let data = control_transfer(&mut usb_dev, &mut [&mut channel], version).unwrap();
+        assert_eq!(data, control::PROTOCOL_VERSION.to_le_bytes());
+        let read = setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 64);
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], read).is_none());
+        assert!(!log_buffer.is_empty());
+        let clear = setup(0x41, control::CLEAR_REQUEST, 0, iface, 0);
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], clear).is_some());
+        assert!(log_buffer.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! USB Log channel based on an isochronous endpoint
+//!
+//! This log channel provides an USB interface having one isochronous IN
+//! endpoint, which gets a guaranteed share of the bus bandwidth at the cost of
+//! packets being lost without retransmission. It is meant for very high log
+//! rates where losing some data is preferred over stalling.
+//!
+//! Each packet starts with a sequence number, which is incremented with each
+//! packet sent, followed by up to `EP_SIZE - 1` log bytes. The host accounts
+//! for lost packets by gaps in the sequence numbers. As required by the USB
+//! specification, the endpoint is only present in alternate setting 1 of the
+//! interface, which the host selects to start streaming.
+//!
+//! The log can also be read by control transfers like from the control
+//! transfer variant, e.g. by readers without isochronous transfer support.
+//! Control reads are rejected while the stream is running, as the host could
+//! not tell which data the packets have taken.
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::bus_state::{BusState, SuspendPolicy, UnconfiguredPolicy};
+use crate::control;
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat::LangID;
+use usb_device::{
+    class_prelude::*,
+    endpoint::{IsochronousSynchronizationType, IsochronousUsageType},
+    Result,
+};
+
+/// Log channel with an isochronous IN endpoint of `EP_SIZE` bytes
+///
+/// ```ignore
+/// let log_channel: UsbLogChannelIso<_, 8192, 256> = UsbLogChannelIso::new(&usb_bus, log_buffer);
+/// ```
+pub struct UsbLogChannelIso<'a, B: UsbBus, const N: usize, const EP_SIZE: usize = 64> {
+    iface: InterfaceNumber,
+    iface_string: StringIndex,
+    ep_in: EndpointIn<'a, B>,
+    iface_name: &'a str,
+    log_buffer: &'a LogBuffer<N>,
+    /// The host has selected the streaming alternate setting
+    streaming: bool,
+    /// Sequence number of the next packet
+    sequence: u8,
+    /// Suspension, configuration and resynchronization
+    bus: BusState,
+}
+
+impl<'a, B: UsbBus, const N: usize, const EP_SIZE: usize> UsbLogChannelIso<'a, B, N, EP_SIZE> {
+    /// Isochronous packets are at most 1023 bytes long at full speed
+    const VALID_EP_SIZE: () = assert!(EP_SIZE >= 2 && EP_SIZE <= 1023);
+
+    /// Create a new USB log channel sending one packet per frame
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+    ) -> UsbLogChannelIso<'a, B, N, EP_SIZE> {
+        let () = Self::VALID_EP_SIZE;
+        let iface = alloc.interface();
+        let iface_string = alloc.string();
+        let ep_in = alloc.isochronous(
+            IsochronousSynchronizationType::Asynchronous,
+            IsochronousUsageType::Data,
+            EP_SIZE as u16,
+            1,
+        );
+        UsbLogChannelIso {
+            iface,
+            iface_string,
+            ep_in,
+            iface_name: control::DEFAULT_INTERFACE_NAME,
+            log_buffer,
+            streaming: false,
+            sequence: 0,
+            bus: BusState::new(),
+        }
+    }
+
+    /// Set the interface name, see [`control::interface_string`]
+    pub fn set_interface_name(&mut self, name: &'a str) {
+        self.iface_name = name;
+    }
+
+    /// Select how log records are handled while the USB is suspended
+    ///
+    /// The default is [`SuspendPolicy::Buffer`].
+    pub fn set_suspend_policy(&mut self, policy: SuspendPolicy) {
+        self.bus.set_suspend_policy(policy, &mut self.log_buffer);
+    }
+
+    /// Tell the channel whether the USB is suspended
+    ///
+    /// See [`crate::usb_log_channel_bulk::UsbLogChannel::set_suspended`].
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.bus.set_suspended(suspended, &mut self.log_buffer);
+    }
+
+    /// Select how log records are handled while the USB device is not
+    /// configured
+    ///
+    /// The default is [`UnconfiguredPolicy::Buffer`].
+    pub fn set_unconfigured_policy(&mut self, policy: UnconfiguredPolicy) {
+        self.bus.set_unconfigured_policy(policy, &mut self.log_buffer);
+    }
+}
+
+impl<B: UsbBus, const N: usize, const EP_SIZE: usize> UsbClass<B>
+    for UsbLogChannelIso<'_, B, N, EP_SIZE>
+{
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(self.iface, 0, 0xff, 0, 0, Some(self.iface_string))?;
+        writer.interface_alt(self.iface, 1, 0xff, 0, 0, Some(self.iface_string))?;
+        writer.endpoint(&self.ep_in)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        control::interface_string(index, self.iface_string, lang_id, self.iface_name, &[])
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let request = xfer.request();
+        if !control::is_vendor_request(request, self.iface) {
+            return;
+        }
+        if self.streaming && control::is_log_read(request.request) {
+            return;
+        }
+        let (code, value) = (request.request, request.value);
+        control::respond(xfer, |buf| control::response(self.log_buffer, code, value, buf));
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if control::is_vendor_request(request, self.iface) {
+            let accepted = control::perform(self.log_buffer, request.request, request.value);
+            control::acknowledge(xfer, accepted);
+            return;
+        }
+        let ep_in = Some(self.ep_in.address());
+        if self.bus.standard_request(request, ep_in, &mut self.log_buffer) {
+            self.streaming = false;
+        }
+    }
+
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        (interface == self.iface).then_some(self.streaming.into())
+    }
+
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        if interface != self.iface || alternative > 1 {
+            return false;
+        }
+        if alternative == 1 && !self.streaming {
+            // start the stream at a record boundary
+            self.bus.request_resync();
+        }
+        self.streaming = alternative == 1;
+        self.log_buffer.set_reader_attached(self.streaming);
+        true
+    }
+
+    fn reset(&mut self) {
+        self.streaming = false;
+        self.bus.reset(&mut self.log_buffer);
+    }
+
+    /// Queue the next packet
+    ///
+    /// The peripheral sends it in the next frame; packets the host misses are
+    /// lost.
+    fn poll(&mut self) {
+        self.bus.diagnostics.check_overflow(&mut self.log_buffer);
+        if !self.streaming || self.bus.is_suspended() {
+            return;
+        }
+        self.bus.resync(&mut self.log_buffer);
+        let Some(grant) = self.log_buffer.read_grant() else {
+            return;
+        };
+        let len = grant.len().min(EP_SIZE - 1);
+        let mut packet = [0; EP_SIZE];
+        packet[0] = self.sequence;
+        packet[1..=len].copy_from_slice(&grant[..len]);
+        if self.ep_in.write(&packet[..=len]).is_ok() {
+            grant.release(len);
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    #[test]
+    fn sequence_numbers() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelIso<_, 256, 8> = UsbLogChannelIso::new(&alloc, &log_buffer);
+        let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_in.address();
+        let iface = channel.iface;
+
+        writeln!(log_buffer.writer(), "0123456789").unwrap();
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+
+        assert!(channel.set_alt_setting(iface, 1));
+        for _ in 0..3 {
+            UsbClass::poll(&mut channel);
+        }
+        assert_eq!(
+            usb_dev.bus().take_packets(ep),
+            [&b"\x000123456"[..], b"\x01789\n"]
+        );
+    }
+
+    #[test]
+    fn no_control_reads_while_streaming() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelIso<_, 256> = UsbLogChannelIso::new(&alloc, &log_buffer);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let iface = u8::from(channel.iface) as u16;
+        let read = setup(0xc1, control::LOG_READ_REQUEST, 0, iface, 64);
+
+        assert!(channel.set_alt_setting(channel.iface, 1));
+        assert!(control_transfer(&mut usb_dev, &mut [&mut channel], read).is_none());
+        assert!(channel.set_alt_setting(channel.iface, 0));
+        writeln!(log_buffer.writer(), "a").unwrap();
+        let data = control_transfer(&mut usb_dev, &mut [&mut channel], read);
+        assert_eq!(data.unwrap(), b"a\n");
+    }
+}
@@ -0,0 +1,145 @@
+//! USB Log channel based on control transfers with a notification endpoint
+//!
+//! Like [`crate::usb_log_channel::UsbLogChannel`], this log channel is read
+//! by control transfers. Its interface additionally has an interrupt IN
+//! endpoint with a maximum packet size of one byte, on which a one-byte
+//! notification is sent when data arrives in the log buffer after the host
+//! has emptied it. The host waits for the notification instead of polling
+//! the log buffer blindly. Devices that have no endpoint to spare use the
+//! plain channel, which the host keeps polling.
+//!
+//! The endpoint is told apart from the one of
+//! [`crate::usb_log_channel_interrupt`], which carries log data, by its
+//! packet size. The notification is sent when the USB device is polled, so
+//! new data should trigger a poll, e.g. by [`LogBuffer::set_data_hook`].
+//!
+//! ```ignore
+//! // the host polls the notification endpoint every 10 ms
+//! let mut log_channel = UsbLogChannelNotify::new(&usb_bus, log_buffer, 10);
+//! log_channel.channel().set_interface_name("kiffielog-notify");
+//! ```
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use crate::log_buffer::LogBuffer;
+use crate::usb_device_compat::LangID;
+use crate::usb_log_channel::UsbLogChannel;
+use usb_device::{class_prelude::*, Result};
+
+/// Packet sent on the notification endpoint
+pub const DATA_AVAILABLE: u8 = 1;
+
+/// Log channel based on control transfers with an interrupt IN endpoint
+/// notifying the host of new data
+pub struct UsbLogChannelNotify<'a, B: UsbBus, const N: usize> {
+    channel: UsbLogChannel<'a, N>,
+    ep_notify: EndpointIn<'a, B>,
+    log_buffer: &'a LogBuffer<N>,
+    /// The log buffer has been empty since the last notification
+    armed: bool,
+}
+
+impl<'a, B: UsbBus, const N: usize> UsbLogChannelNotify<'a, B, N> {
+    /// Create a new USB log channel
+    ///
+    /// `interval` is the polling interval of the notification endpoint in
+    /// frames (1 ms) at full speed or as an exponent of 2 microframes (125
+    /// µs) at high speed.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        log_buffer: &'a LogBuffer<N>,
+        interval: u8,
+    ) -> UsbLogChannelNotify<'a, B, N> {
+        UsbLogChannelNotify {
+            channel: UsbLogChannel::new(alloc, log_buffer),
+            ep_notify: alloc.interrupt(1, interval),
+            log_buffer,
+            armed: true,
+        }
+    }
+
+    /// Access the log channel, e.g. to set the interface name
+    pub fn channel(&mut self) -> &mut UsbLogChannel<'a, N> {
+        &mut self.channel
+    }
+}
+
+impl<B: UsbBus, const N: usize> UsbClass<B> for UsbLogChannelNotify<'_, B, N> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        UsbClass::<B>::get_configuration_descriptors(&self.channel, writer)?;
+        writer.endpoint(&self.ep_notify)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        UsbClass::<B>::get_string(&self.channel, index, lang_id)
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> Result<()> {
+        UsbClass::<B>::get_bos_descriptors(&self.channel, writer)
+    }
+
+    fn reset(&mut self) {
+        UsbClass::<B>::reset(&mut self.channel);
+        self.armed = true;
+    }
+
+    /// A read emptying the log buffer arms the notification, even if the
+    /// next data arrives before the device is polled again
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.channel.control_in(xfer);
+        if self.log_buffer.is_empty() {
+            self.armed = true;
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.channel.control_out(xfer);
+    }
+
+    fn poll(&mut self) {
+        if self.log_buffer.is_empty() {
+            self.armed = true;
+        } else if self.armed && self.ep_notify.write(&[DATA_AVAILABLE]).is_ok() {
+            self.armed = false;
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "null-logger")))]
+mod tests {
+    use super::*;
+    use crate::control;
+    use crate::mock_bus::{control_transfer, setup, MockBus};
+    use core::fmt::Write;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+
+    #[test]
+    fn notify_once_per_burst() {
+        let log_buffer = LogBuffer::<256>::new();
+        let alloc = UsbBusAllocator::new(MockBus::new());
+        let mut channel: UsbLogChannelNotify<_, 256> =
+            UsbLogChannelNotify::new(&alloc, &log_buffer, 10);
+        let mut usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+        let ep = channel.ep_notify.address();
+
+        let get_config = setup(0x80, 6, 0x0200, 0, 255);
+        let desc = control_transfer(&mut usb_dev, &mut [&mut channel], get_config).unwrap();
+        assert_eq!(&desc[9..], [9, 4, 0, 0, 1, 0xff, 0, 0, 4, 7, 5, 0x81, 0x03, 1, 0, 10]);
+
+        UsbClass::poll(&mut channel);
+        assert!(usb_dev.bus().take_packets(ep).is_empty());
+        write!(log_buffer.writer(), "abc").unwrap();
+        UsbClass::poll(&mut channel);
+        write!(log_buffer.writer(), "def").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [[DATA_AVAILABLE]]);
+
+        // data arriving after the host emptied the log buffer is notified
+        let read = setup(0xc1, control::LOG_READ_REQUEST, 0, 0, 64);
+        assert_eq!(control_transfer(&mut usb_dev, &mut [&mut channel], read).unwrap(), b"abcdef");
+        write!(log_buffer.writer(), "ghi").unwrap();
+        UsbClass::poll(&mut channel);
+        assert_eq!(usb_dev.bus().take_packets(ep), [[DATA_AVAILABLE]]);
+    }
+}
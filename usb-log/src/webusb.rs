@@ -0,0 +1,119 @@
+//! WebUSB descriptors
+//!
+//! The WebUSB platform capability in the BOS descriptor tells browsers that
+//! the device can be used by web pages, e.g. a browser based log viewer, and
+//! optionally gives the URL of a landing page that the browser suggests when
+//! the device is plugged in. The browser reads the URL by a vendor request
+//! with the vendor code given in the capability.
+//!
+//! Allowed origins are no longer part of the WebUSB specification, so they
+//! are not supported. On Windows, the interface must be bound to WinUSB, see
+//! [`crate::ms_os`].
+//!
+// Copyright (C) 2025 Stephan <kiffie@mailbox.org>
+// SPDX-License-Identifier: GPL-2.0-or-later
+
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// wIndex of the request reading a URL descriptor
+const WEBUSB_GET_URL: u16 = 2;
+
+/// Descriptor type of a URL descriptor
+const WEBUSB_URL: u8 = 3;
+
+/// Device capability type of a platform capability
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// Platform capability UUID {3408B638-09A9-47A0-8BFD-A0768815B665}
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+/// Index of the landing page URL
+const LANDING_PAGE_INDEX: u8 = 1;
+
+/// URL schemes that are encoded by a code in the URL descriptor
+const SCHEMES: [(&str, u8); 2] = [("http://", 0), ("https://", 1)];
+
+/// Code of a URL descriptor holding the complete URL
+const SCHEME_NONE: u8 = 255;
+
+/// Write the platform capability announcing WebUSB support
+pub(crate) fn write_capability(
+    writer: &mut BosWriter,
+    vendor_code: u8,
+    landing_page: Option<&str>,
+) -> Result<()> {
+    let mut data = [0; 21];
+    data[1..17].copy_from_slice(&WEBUSB_UUID);
+    data[17..19].copy_from_slice(&0x0100u16.to_le_bytes());
+    data[19] = vendor_code;
+    data[20] = if landing_page.is_some() { LANDING_PAGE_INDEX } else { 0 };
+    writer.capability(CAPABILITY_PLATFORM, &data)
+}
+
+/// Returns true if `request` reads a URL descriptor
+pub(crate) fn is_url_request(request: &Request, vendor_code: u8) -> bool {
+    request.request_type == RequestType::Vendor
+        && request.recipient == Recipient::Device
+        && request.request == vendor_code
+        && request.index == WEBUSB_GET_URL
+}
+
+/// Answer a request for a URL descriptor
+///
+/// Requests for unknown URLs are rejected.
+pub(crate) fn url<B: UsbBus>(xfer: ControlIn<B>, landing_page: Option<&str>) {
+    let request = *xfer.request();
+    let url = landing_page.filter(|_| request.value == LANDING_PAGE_INDEX.into());
+    let Some(url) = url else {
+        xfer.reject().ok();
+        return;
+    };
+    xfer.accept(|buf| {
+        let len = write_url(buf, url)?;
+        Ok(len.min(request.length as usize))
+    })
+    .ok();
+}
+
+/// Write a URL descriptor
+fn write_url(buf: &mut [u8], url: &str) -> Result<usize> {
+    let (scheme, rest) = SCHEMES
+        .iter()
+        .find_map(|(prefix, code)| Some((*code, url.strip_prefix(prefix)?)))
+        .unwrap_or((SCHEME_NONE, url));
+    let len = 3 + rest.len();
+    if len > buf.len() || len > 255 {
+        return Err(UsbError::BufferOverflow);
+    }
+    buf[0] = len as u8;
+    buf[1] = WEBUSB_URL;
+    buf[2] = scheme;
+    buf[3..len].copy_from_slice(rest.as_bytes());
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_descriptor() {
+        let mut buf = [0; 64];
+        let len = write_url(&mut buf, "https://example.com/log").unwrap();
+        assert_eq!(&buf[..len], b"\x12\x03\x01example.com/log");
+        let len = write_url(&mut buf, "file:///log.html").unwrap();
+        assert_eq!(&buf[..3], [19, WEBUSB_URL, SCHEME_NONE]);
+        assert_eq!(&buf[3..len], b"file:///log.html");
+        assert!(matches!(write_url(&mut buf[..8], "http://x.org"), Ok(8)));
+        assert!(matches!(
+            write_url(&mut buf[..7], "http://x.org"),
+            Err(UsbError::BufferOverflow)
+        ));
+    }
+}
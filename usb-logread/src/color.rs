@@ -0,0 +1,118 @@
+//! Colors of the log lines by log level
+//!
+//! The level of a line is taken from its tag, see [`crate::level`]. Errors
+//! and panics are shown in red, warnings in yellow, debug messages in cyan
+//! and trace messages dimmed. Other lines are left as they are.
+//!
+//! When several devices are read at once, the prefix naming the device is
+//! colored as well, each device in a color of its own.
+//!
+//! With `--color auto`, the default, lines are colored if stdout is a
+//! terminal and the `NO_COLOR` environment variable is not set.
+//!
+
+use crate::level::Level;
+use clap::ValueEnum;
+use std::io::{self, IsTerminal, Write};
+
+/// When to color the output
+#[derive(Clone, Copy, ValueEnum)]
+pub enum When {
+    /// If stdout is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl When {
+    /// Returns true if lines written to stdout are to be colored
+    pub fn enabled(self) -> bool {
+        match self {
+            When::Auto => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            When::Always => true,
+            When::Never => false,
+        }
+    }
+}
+
+/// ANSI escape sequence starting the color of `level`
+fn color(level: Level) -> Option<&'static str> {
+    match level {
+        Level::Panic => Some("\x1b[1;31m"),
+        Level::Error => Some("\x1b[31m"),
+        Level::Warn => Some("\x1b[33m"),
+        Level::Info => None,
+        Level::Debug => Some("\x1b[36m"),
+        Level::Trace => Some("\x1b[2m"),
+    }
+}
+
+/// Colors of the device prefixes, which differ from those of the levels
+const DEVICE_COLORS: [&str; 6] = [
+    "\x1b[32m", "\x1b[34m", "\x1b[35m", "\x1b[92m", "\x1b[94m", "\x1b[95m",
+];
+
+/// Color `text` in the color of the device number `index`
+///
+/// Colors repeat after a few devices.
+pub fn device(text: &str, index: usize) -> String {
+    let color = DEVICE_COLORS[index % DEVICE_COLORS.len()];
+    format!("{color}{text}\x1b[0m")
+}
+
+/// Writer coloring each line by its level
+pub struct Colorizer<W: Write> {
+    out: W,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+}
+
+impl<W: Write> Colorizer<W> {
+    pub fn new(out: W) -> Self {
+        Colorizer {
+            out,
+            line: Vec::new(),
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let text = String::from_utf8_lossy(&self.line);
+        match Level::of_line(&text).and_then(color) {
+            Some(color) => {
+                let text = text.strip_suffix('\n').unwrap_or(&text);
+                writeln!(self.out, "{color}{text}\x1b[0m")
+            }
+            None => self.out.write_all(&self.line),
+        }
+    }
+}
+
+impl<W: Write> Write for Colorizer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_by_level() {
+        let mut out = Vec::new();
+        let mut colorizer = Colorizer::new(&mut out);
+        colorizer.write_all(b"[W] a\nb\n").unwrap();
+        assert_eq!(out, b"\x1b[33m[W] a\x1b[0m\nb\n");
+    }
+}
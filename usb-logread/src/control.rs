@@ -0,0 +1,273 @@
+//! Vendor control requests changing the state of the device
+//!
+
+use crate::DeviceInfo;
+use clap::ValueEnum;
+use rusb::{Context, DeviceHandle, Direction};
+use std::time::Duration;
+
+const SET_ENABLED_REQUEST: u8 = 2;
+const GET_STATS_REQUEST: u8 = 3;
+const SET_LEVEL_REQUEST: u8 = 4;
+const CLEAR_REQUEST: u8 = 5;
+const GRANT_CREDIT_REQUEST: u8 = 6;
+const GET_VERSION_REQUEST: u8 = 7;
+const SET_READER_REQUEST: u8 = 9;
+const GET_LATENCY_REQUEST: u8 = 10;
+const GET_AVAILABLE_REQUEST: u8 = 11;
+const GET_INFO_REQUEST: u8 = 12;
+const LOG_READ_NEXT_REQUEST: u8 = 13;
+
+/// Newest protocol version understood by this reader
+pub const PROTOCOL_VERSION: u16 = 8;
+
+/// First protocol version with continued log reads, see [`read_next`]
+pub const READ_NEXT_VERSION: u16 = 8;
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Maximum log level of the device
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Statistics of the log buffer of the device
+pub struct Stats {
+    /// Bytes of log data lost because the buffer was full
+    pub dropped_bytes: u32,
+    /// Highest fill level of the buffer in bytes, None for devices before
+    /// protocol version 7
+    pub high_water_mark: Option<u32>,
+    /// Bytes of log data written, wrapping around at 2^32
+    pub total_bytes: Option<u32>,
+}
+
+/// Time log records spend in the log buffer of the device
+pub struct Latency {
+    /// Number of records measured
+    pub samples: u32,
+    pub min_us: u32,
+    pub avg_us: u32,
+    pub max_us: u32,
+}
+
+/// Information about the device
+pub struct Info {
+    pub protocol_version: u16,
+    /// Capacity of the log buffer in bytes
+    pub capacity: u32,
+    /// Firmware version set by the application, empty if unknown
+    pub firmware_version: String,
+}
+
+/// Send a vendor request without data to the log interface
+fn write_request(device_info: &DeviceInfo, request: u8, value: u16) -> Result<(), rusb::Error> {
+    let handle = device_info.device().open()?;
+    let iface = device_info.iface_id;
+    handle.claim_interface(iface)?;
+    let request_type = rusb::request_type(
+        Direction::Out,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    handle.write_control(request_type, request, value, iface as u16, &[], TIMEOUT)?;
+    Ok(())
+}
+
+/// Pause or resume logging on the device
+///
+/// The log buffer of the device keeps its contents while logging is paused.
+pub fn set_enabled(device_info: &DeviceInfo, enabled: bool) -> Result<(), rusb::Error> {
+    write_request(device_info, SET_ENABLED_REQUEST, enabled.into())
+}
+
+/// Set the maximum log level of the device
+pub fn set_level(device_info: &DeviceInfo, level: Level) -> Result<(), rusb::Error> {
+    write_request(device_info, SET_LEVEL_REQUEST, level as u16)
+}
+
+/// Discard the log data buffered on the device
+pub fn clear(device_info: &DeviceInfo) -> Result<(), rusb::Error> {
+    write_request(device_info, CLEAR_REQUEST, 0)
+}
+
+/// Read the response of a vendor request to the log interface
+///
+/// Fails if the response is shorter than `min_len`. Returns the length of the
+/// response.
+fn read_request(
+    device_info: &DeviceInfo,
+    request: u8,
+    buf: &mut [u8],
+    min_len: usize,
+) -> Result<usize, rusb::Error> {
+    let handle = device_info.device().open()?;
+    let iface = device_info.iface_id;
+    handle.claim_interface(iface)?;
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let len = handle.read_control(request_type, request, 0, iface as u16, buf, TIMEOUT)?;
+    if len < min_len {
+        return Err(rusb::Error::Other);
+    }
+    Ok(len)
+}
+
+/// Read the statistics of the log buffer of the device
+pub fn get_stats(device_info: &DeviceInfo) -> Result<Stats, rusb::Error> {
+    let mut buf = [0; 12];
+    let len = read_request(device_info, GET_STATS_REQUEST, &mut buf, 4)?;
+    // older devices send the dropped bytes only
+    let field = |i: usize| {
+        (4 * i + 4 <= len).then(|| u32::from_le_bytes(buf[4 * i..4 * i + 4].try_into().unwrap()))
+    };
+    Ok(Stats {
+        dropped_bytes: field(0).unwrap(),
+        high_water_mark: field(1),
+        total_bytes: field(2),
+    })
+}
+
+/// Read the latency statistics of the log buffer of the device
+///
+/// Devices before protocol version 4 reject the request.
+pub fn get_latency(device_info: &DeviceInfo) -> Result<Latency, rusb::Error> {
+    let mut buf = [0; 16];
+    read_request(device_info, GET_LATENCY_REQUEST, &mut buf, 16)?;
+    let field = |i: usize| u32::from_le_bytes(buf[4 * i..4 * i + 4].try_into().unwrap());
+    Ok(Latency {
+        samples: field(0),
+        min_us: field(1),
+        avg_us: field(2),
+        max_us: field(3),
+    })
+}
+
+/// Read information about the device
+///
+/// Devices before protocol version 6 reject the request.
+pub fn get_info(device_info: &DeviceInfo) -> Result<Info, rusb::Error> {
+    let handle = device_info.device().open()?;
+    let iface = device_info.iface_id;
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let mut buf = [0; 64];
+    let req = GET_INFO_REQUEST;
+    let len = handle.read_control(request_type, req, 0, iface as u16, &mut buf, TIMEOUT)?;
+    if len < 6 {
+        return Err(rusb::Error::Other);
+    }
+    Ok(Info {
+        protocol_version: u16::from_le_bytes([buf[0], buf[1]]),
+        capacity: u32::from_le_bytes(buf[2..6].try_into().unwrap()),
+        firmware_version: String::from_utf8_lossy(&buf[6..len]).into_owned(),
+    })
+}
+
+/// Allow the device to send `bytes` more bytes on the bulk IN endpoint
+///
+/// The interface must have been claimed on `handle`.
+pub fn grant_credit(handle: &DeviceHandle<Context>, iface: u8, bytes: u16) -> Result<(), rusb::Error> {
+    let request_type = rusb::request_type(
+        Direction::Out,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    handle.write_control(request_type, GRANT_CREDIT_REQUEST, bytes, iface as u16, &[], TIMEOUT)?;
+    Ok(())
+}
+
+/// Tell the device that this reader has attached or is about to detach
+///
+/// The interface must have been claimed on `handle`. Devices that predate the
+/// request reject it, which is ignored.
+pub fn set_reader(handle: &DeviceHandle<Context>, iface: u8, attached: bool) {
+    let request_type = rusb::request_type(
+        Direction::Out,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let value = attached.into();
+    handle.write_control(request_type, SET_READER_REQUEST, value, iface as u16, &[], TIMEOUT).ok();
+}
+
+/// Read the number of bytes waiting in the log buffer of the device
+///
+/// The interface must have been claimed on `handle`. Returns None if the
+/// device does not answer the request, e.g. because it predates it.
+pub fn get_available(handle: &DeviceHandle<Context>, iface: u8) -> Option<u32> {
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let mut buf = [0; 4];
+    let req = GET_AVAILABLE_REQUEST;
+    match handle.read_control(request_type, req, 0, iface as u16, &mut buf, TIMEOUT) {
+        Ok(4) => Some(u32::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+/// Read log data together with the number of bytes left on the device
+///
+/// The interface must have been claimed on `handle`. Returns the number of
+/// bytes left in the log buffer of the device and the log data read into
+/// `buf`. The device must speak at least [`READ_NEXT_VERSION`].
+pub fn read_next<'a>(
+    handle: &DeviceHandle<Context>,
+    iface: u8,
+    buf: &'a mut [u8],
+) -> Result<(u32, &'a [u8]), rusb::Error> {
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let req = LOG_READ_NEXT_REQUEST;
+    let len = handle.read_control(request_type, req, 0, iface as u16, buf, TIMEOUT)?;
+    if len < 4 {
+        return Err(rusb::Error::Other);
+    }
+    let remaining = u32::from_le_bytes(buf[..4].try_into().unwrap());
+    Ok((remaining, &buf[4..len]))
+}
+
+/// Read the protocol version of the device
+///
+/// Devices that predate the version request reject it and speak version 0.
+pub fn get_version(device_info: &DeviceInfo) -> Result<u16, rusb::Error> {
+    let handle = device_info.device().open()?;
+    let iface = device_info.iface_id;
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let mut buf = [0; 2];
+    let res = handle.read_control(
+        request_type,
+        GET_VERSION_REQUEST,
+        0,
+        iface as u16,
+        &mut buf,
+        TIMEOUT,
+    );
+    match res {
+        Ok(2) => Ok(u16::from_le_bytes(buf)),
+        Ok(_) => Err(rusb::Error::Other),
+        Err(rusb::Error::Pipe) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
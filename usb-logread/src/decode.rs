@@ -0,0 +1,697 @@
+//! Decoding of the log stream
+//!
+//! The device sends either plain text or COBS framed binary records. Binary
+//! records are converted into the same text format the device uses in text
+//! mode. Frames with a CRC that does not match are discarded. Records merged
+//! from other log buffers of the device are prefixed with their source tag.
+//! File paths the device sends only once are looked up by their id.
+//!
+//! With `--elf`, the data is decoded as defmt messages instead, see
+//! [`crate::defmt`].
+//!
+//! Raw binary payloads are shown as a line of hex digits, which is the format
+//! the device uses in text mode, unless they are written to files.
+//! Backtraces of panics are shown as a line of return addresses.
+//! Diagnostics the USB classes of the device report under the `usb-log`
+//! target are shown as `[usb-log] message` lines and can be hidden.
+//! Records and lines below a minimum level can be hidden as well. The level
+//! of binary records can be shown as a tag like `[W]` in front of the
+//! location, which text lines of the device do not have.
+//!
+//! The decoder counts the records the device reports as lost as well as the
+//! frames that had to be discarded so that a summary can be printed at the end
+//! of a session.
+//!
+
+use crate::decrypt::Decryptor;
+use crate::defmt::DefmtDecoder;
+use crate::level::Level;
+use crate::raw::RawFiles;
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const FRAME_RECORD: u8 = 0x01;
+const FRAME_TIMESTAMP: u8 = 0x02;
+const FRAME_SOURCE: u8 = 0x03;
+const FRAME_DROPPED: u8 = 0x04;
+const FRAME_FILE: u8 = 0x05;
+const FRAME_RAW: u8 = 0x06;
+const FRAME_BACKTRACE: u8 = 0x07;
+const FRAME_FLAG_CRC16: u8 = 0x40;
+const FRAME_FLAG_CRC32: u8 = 0x80;
+const FRAME_TYPE_MASK: u8 = 0x3f;
+const RECORD_FLAG_TIMESTAMP: u8 = 0x80;
+const RECORD_FLAG_FILE_ID: u8 = 0x40;
+const RECORD_LEVEL_MASK: u8 = 0x07;
+const LEVEL_PANIC: u8 = 0;
+const MAX_FILE_LEN: usize = 32;
+const DROP_MARKER: &str = "[DROPPED] ";
+const RAW_MARKER: &str = "[RAW ";
+const BACKTRACE_MARKER: &str = "[BACKTRACE]";
+const DIAGNOSTICS_MARKER: &str = "[usb-log] ";
+/// File name of diagnostic records, which have line number 0
+const DIAGNOSTICS_FILE: &[u8] = b"usb-log";
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Decoder for the byte stream received from the device
+pub enum Decoder {
+    /// Bytes are passed through unchanged
+    Text(TextDecoder),
+    /// Bytes are decoded as binary frames
+    Binary(FrameDecoder),
+    /// Bytes are decoded as defmt messages
+    Defmt(DefmtDecoder),
+    /// Bytes are decrypted before being decoded
+    Encrypted(Decryptor, Box<Decoder>),
+}
+
+impl Decoder {
+    pub fn new(binary: bool) -> Self {
+        if binary {
+            Decoder::Binary(FrameDecoder::default())
+        } else {
+            Decoder::Text(TextDecoder::default())
+        }
+    }
+
+    /// Decrypt the stream with `decryptor` before decoding it
+    pub fn encrypted(self, decryptor: Decryptor) -> Self {
+        Decoder::Encrypted(decryptor, Box::new(self))
+    }
+
+    /// Write raw payloads to `files` instead of the decoder output
+    pub fn set_raw_files(&mut self, files: RawFiles) {
+        match self {
+            Decoder::Text(dec) => dec.raw = Some(files),
+            Decoder::Binary(dec) => dec.raw = Some(files),
+            Decoder::Defmt(_) => (),
+            Decoder::Encrypted(_, dec) => dec.set_raw_files(files),
+        }
+    }
+
+    /// Discard the diagnostics of the device
+    pub fn set_hide_diagnostics(&mut self, hide: bool) {
+        match self {
+            Decoder::Text(dec) => dec.hide_diagnostics = hide,
+            Decoder::Binary(dec) => dec.hide_diagnostics = hide,
+            Decoder::Defmt(_) => (),
+            Decoder::Encrypted(_, dec) => dec.set_hide_diagnostics(hide),
+        }
+    }
+
+    /// Discard the records less severe than `level`
+    pub fn set_min_level(&mut self, level: Option<Level>) {
+        match self {
+            Decoder::Text(dec) => dec.min_level = level,
+            Decoder::Binary(dec) => dec.min_level = level,
+            Decoder::Defmt(dec) => dec.min_level = level,
+            Decoder::Encrypted(_, dec) => dec.set_min_level(level),
+        }
+    }
+
+    /// Tag binary records with their level
+    pub fn set_level_tags(&mut self, tags: bool) {
+        match self {
+            Decoder::Text(_) | Decoder::Defmt(_) => (),
+            Decoder::Binary(dec) => dec.level_tags = tags,
+            Decoder::Encrypted(_, dec) => dec.set_level_tags(tags),
+        }
+    }
+
+    /// Decode a chunk of received bytes and write the result to `out`
+    pub fn decode(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Decoder::Text(dec) => dec.decode(data, out),
+            Decoder::Binary(dec) => dec.decode(data, out),
+            Decoder::Defmt(dec) => dec.decode(data, out),
+            Decoder::Encrypted(decryptor, dec) => dec.decode(&decryptor.decrypt(data), out),
+        }
+    }
+
+    /// Discard a partially received record after data has been lost
+    ///
+    /// A partial text line already passed on is terminated so that the next
+    /// line starts on its own.
+    pub fn resync(&mut self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Decoder::Text(dec) => {
+                if dec.line.is_empty() {
+                    return Ok(());
+                }
+                dec.line.clear();
+                if dec.filtering() {
+                    return Ok(());
+                }
+                writeln!(out)
+            }
+            Decoder::Binary(dec) => {
+                dec.pending.clear();
+                dec.timestamp = None;
+                Ok(())
+            }
+            Decoder::Defmt(dec) => {
+                dec.resync();
+                Ok(())
+            }
+            Decoder::Encrypted(decryptor, dec) => {
+                decryptor.resync();
+                dec.resync(out)
+            }
+        }
+    }
+
+    /// Discard the records cut by a gap in the middle of the stream
+    ///
+    /// Other than with [`Decoder::resync`], the data received next continues
+    /// a record whose start has been lost, so it is discarded as well up to
+    /// the end of that record.
+    pub fn skip_gap(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.resync(out)?;
+        match self {
+            Decoder::Text(dec) => dec.skipping = true,
+            Decoder::Binary(dec) => dec.skipping = true,
+            Decoder::Defmt(dec) => dec.skip(),
+            // the decrypted chunks consist of complete records
+            Decoder::Encrypted(decryptor, _) => decryptor.skipping = true,
+        }
+        Ok(())
+    }
+
+    /// Describe the data lost so far, None if nothing was lost
+    pub fn loss_summary(&self) -> Option<String> {
+        let (dropped, corrupted) = match self {
+            Decoder::Text(dec) => (dec.dropped, 0),
+            Decoder::Binary(dec) => (dec.dropped, dec.corrupted),
+            Decoder::Defmt(dec) => (0, dec.corrupted),
+            Decoder::Encrypted(decryptor, dec) => {
+                let summary = dec.loss_summary();
+                if decryptor.failed == 0 {
+                    return summary;
+                }
+                let failed = format!("{} frames could not be decrypted", decryptor.failed);
+                return Some(summary.map_or(failed.clone(), |s| format!("{s}\n{failed}")));
+            }
+        };
+        let mut summary = Vec::new();
+        if dropped > 0 {
+            summary.push(format!(
+                "Device dropped {dropped} records during capture; \
+                 consider a larger buffer or filters"
+            ));
+        }
+        if corrupted > 0 {
+            summary.push(format!("{corrupted} corrupted frames were discarded"));
+        }
+        (!summary.is_empty()).then(|| summary.join("\n"))
+    }
+}
+
+/// Decoder passing text through while looking for drop markers
+#[derive(Default)]
+pub struct TextDecoder {
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+    /// Number of records reported as lost
+    dropped: u64,
+    /// Destination of raw payloads, None to pass them through
+    raw: Option<RawFiles>,
+    /// Diagnostic lines are discarded
+    hide_diagnostics: bool,
+    /// Lines less severe than this level are discarded
+    min_level: Option<Level>,
+    /// Bytes are discarded up to the end of the current line
+    skipping: bool,
+}
+
+impl TextDecoder {
+    fn decode(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let data = skip_tail(&mut self.skipping, data, b'\n');
+        for &byte in data {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line);
+            self.dropped += parse_drop_marker(&line).unwrap_or(0);
+            if self.filtering() {
+                // complete lines are passed on unless they hold a payload or
+                // are hidden
+                let payload = self.raw.as_mut().zip(parse_raw_line(&line));
+                if let Some((raw, (tag, payload))) = payload {
+                    raw.write(tag, &payload)?;
+                } else if !(self.hide_diagnostics && is_diagnostic(&line)
+                    || is_below(Level::of_line(&line), self.min_level))
+                {
+                    out.write_all(&self.line)?;
+                    out.write_all(b"\n")?;
+                }
+            }
+            self.line.clear();
+        }
+        if !self.filtering() {
+            out.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if lines are passed on only once they are complete
+    fn filtering(&self) -> bool {
+        self.raw.is_some() || self.hide_diagnostics || self.min_level.is_some()
+    }
+}
+
+/// Returns true if `level` is less severe than `min_level`
+///
+/// Lines without a level are never below the minimum.
+fn is_below(level: Option<Level>, min_level: Option<Level>) -> bool {
+    matches!((level, min_level), (Some(level), Some(min)) if level > min)
+}
+
+/// Strip the source tag prefix of a merged line
+fn strip_source(line: &str) -> Option<&str> {
+    match line.strip_prefix('#') {
+        Some(rest) => Some(rest.split_once(' ')?.1),
+        None => Some(line),
+    }
+}
+
+/// Get the number of lost records from a drop marker line
+fn parse_drop_marker(line: &str) -> Option<u64> {
+    // merged records are prefixed with their source tag
+    strip_source(line)?
+        .strip_prefix(DROP_MARKER)?
+        .split_once(' ')?
+        .0
+        .parse()
+        .ok()
+}
+
+/// Get the tag and payload of a raw payload line
+fn parse_raw_line(line: &str) -> Option<(u8, Vec<u8>)> {
+    let (tag, hex) = strip_source(line)?.strip_prefix(RAW_MARKER)?.split_once("] ")?;
+    Some((tag.parse().ok()?, parse_hex(hex)?))
+}
+
+/// Returns true if `line` holds a diagnostic of the device
+fn is_diagnostic(line: &str) -> bool {
+    let Some(line) = strip_source(line) else {
+        return false;
+    };
+    // skip the timestamp
+    let line = match line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+        Some((ts, rest)) if ts == "?" || ts.contains('.') => rest,
+        _ => line,
+    };
+    line.starts_with(DIAGNOSTICS_MARKER)
+}
+
+/// Parse a string of hex digits
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decoder for COBS framed binary records
+#[derive(Default)]
+pub struct FrameDecoder {
+    /// Bytes of the current, not yet terminated frame
+    pending: Vec<u8>,
+    /// Timestamp of the previous record, None until an absolute timestamp has
+    /// been received
+    timestamp: Option<u64>,
+    /// Source tag of the current frames, None for the device's own records
+    source: Option<u8>,
+    /// Timestamps of the other sources
+    timestamps: HashMap<Option<u8>, Option<u64>>,
+    /// File paths interned by the device, by source and id
+    files: HashMap<(Option<u8>, u64), Vec<u8>>,
+    /// Number of records reported as lost
+    dropped: u64,
+    /// Number of frames discarded due to decoding errors
+    corrupted: u64,
+    /// Destination of raw payloads, None to print them
+    raw: Option<RawFiles>,
+    /// Diagnostic records are discarded
+    hide_diagnostics: bool,
+    /// Records less severe than this level are discarded
+    min_level: Option<Level>,
+    /// Records are tagged with their level
+    level_tags: bool,
+    /// Bytes are discarded up to the end of the current frame
+    skipping: bool,
+}
+
+impl FrameDecoder {
+    fn decode(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let data = skip_tail(&mut self.skipping, data, 0);
+        for &byte in data {
+            if byte != 0 {
+                self.pending.push(byte);
+                continue;
+            }
+            let encoded = std::mem::take(&mut self.pending);
+            match cobs_decode(&encoded) {
+                Some(frame) => self.frame(&frame, out)?,
+                // lost data, wait for the next absolute timestamp
+                None => {
+                    self.timestamp = None;
+                    self.corrupted += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, frame: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let Some(frame) = check_crc(frame) else {
+            // corrupted frame, wait for the next absolute timestamp
+            self.timestamp = None;
+            self.corrupted += 1;
+            return Ok(());
+        };
+        let mut rd = Reader(frame);
+        // level of a record, which is decoded even if it is discarded to
+        // keep track of the timestamp
+        let level = frame
+            .get(1)
+            .and_then(|header| Level::from_record(header & RECORD_LEVEL_MASK));
+        match rd.byte().map(|t| t & FRAME_TYPE_MASK) {
+            Some(FRAME_RECORD) => match self.record(&mut rd) {
+                Some(line) if self.hide_diagnostics && is_diagnostic(&line) => Ok(()),
+                Some(_) if is_below(level, self.min_level) => Ok(()),
+                Some(line) => writeln!(out, "{line}"),
+                None => {
+                    self.timestamp = None;
+                    self.corrupted += 1;
+                    Ok(())
+                }
+            },
+            Some(FRAME_TIMESTAMP) => {
+                self.timestamp = rd.varint();
+                Ok(())
+            }
+            Some(FRAME_DROPPED) => {
+                let Some(dropped) = rd.varint() else {
+                    self.corrupted += 1;
+                    return Ok(());
+                };
+                self.dropped += dropped;
+                writeln!(out, "{}{DROP_MARKER}{dropped} records", self.source_prefix())
+            }
+            Some(FRAME_RAW) => {
+                let Some(tag) = rd.byte() else {
+                    self.corrupted += 1;
+                    return Ok(());
+                };
+                match &mut self.raw {
+                    Some(raw) => raw.write(tag, rd.0),
+                    None => {
+                        let hex: String = rd.0.iter().map(|b| format!("{b:02x}")).collect();
+                        writeln!(out, "{}{RAW_MARKER}{tag}] {hex}", self.source_prefix())
+                    }
+                }
+            }
+            Some(FRAME_BACKTRACE) => {
+                let mut line = format!("{}{BACKTRACE_MARKER}", self.source_prefix());
+                while !rd.0.is_empty() {
+                    let Some(address) = rd.varint() else {
+                        self.corrupted += 1;
+                        return Ok(());
+                    };
+                    line.push_str(&format!(" {address:#010x}"));
+                }
+                writeln!(out, "{line}")
+            }
+            Some(FRAME_FILE) => {
+                let Some(id) = rd.varint() else {
+                    self.corrupted += 1;
+                    return Ok(());
+                };
+                self.files.insert((self.source, id), rd.0.to_vec());
+                Ok(())
+            }
+            Some(FRAME_SOURCE) => {
+                let source = rd.byte();
+                if source != self.source {
+                    self.timestamps.insert(self.source, self.timestamp);
+                    self.timestamp = self.timestamps.remove(&source).flatten();
+                    self.source = source;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Prefix of lines of the current source
+    fn source_prefix(&self) -> String {
+        match self.source {
+            Some(tag) => format!("#{tag} "),
+            None => String::new(),
+        }
+    }
+
+    fn record(&mut self, rd: &mut Reader) -> Option<String> {
+        let header = rd.byte()?;
+        let mut line = self.source_prefix();
+        if header & RECORD_FLAG_TIMESTAMP != 0 {
+            let delta = rd.varint()?;
+            self.timestamp = self.timestamp.map(|ts| ts.wrapping_add(delta));
+            match self.timestamp {
+                Some(ts) => line += &format!("[{}.{:06}]", ts / 1_000_000, ts % 1_000_000),
+                None => line += "[?]",
+            }
+        }
+        let lineno = rd.varint()?;
+        let file = if header & RECORD_FLAG_FILE_ID != 0 {
+            let id = rd.varint()?;
+            // unknown if the definition has been lost
+            self.files.get(&(self.source, id)).map_or(&[][..], Vec::as_slice)
+        } else {
+            let file_len = rd.varint()? as usize;
+            rd.bytes(file_len)?
+        };
+        let msg = String::from_utf8_lossy(rd.0);
+        if header & RECORD_LEVEL_MASK == LEVEL_PANIC {
+            line += &format!("[PANIC] {msg}");
+        } else if lineno == 0 && file == DIAGNOSTICS_FILE {
+            line += &format!("{DIAGNOSTICS_MARKER}{msg}");
+        } else {
+            let level = Level::from_record(header & RECORD_LEVEL_MASK);
+            if let Some(level) = level.filter(|_| self.level_tags) {
+                line += &format!("[{}]", level.tag());
+            }
+            let (prefix, file) = if file.is_empty() {
+                ("???", file)
+            } else if file.len() <= MAX_FILE_LEN {
+                ("", file)
+            } else {
+                ("...", &file[file.len() - MAX_FILE_LEN..])
+            };
+            let file = String::from_utf8_lossy(file);
+            line += &format!("[{prefix}{file}:{lineno}] {msg}");
+        }
+        Some(line)
+    }
+}
+
+/// Verify and strip the CRC of a frame if it has one
+///
+/// Returns None if the CRC does not match.
+fn check_crc(frame: &[u8]) -> Option<&[u8]> {
+    let flags = frame.first()?;
+    if flags & FRAME_FLAG_CRC16 != 0 {
+        let (data, crc) = frame.split_at(frame.len().checked_sub(2)?);
+        (CRC16.checksum(data).to_le_bytes() == crc).then_some(data)
+    } else if flags & FRAME_FLAG_CRC32 != 0 {
+        let (data, crc) = frame.split_at(frame.len().checked_sub(4)?);
+        (CRC32.checksum(data).to_le_bytes() == crc).then_some(data)
+    } else {
+        Some(frame)
+    }
+}
+
+/// Cursor over the contents of a frame
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&first, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(first)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            val |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Some(val);
+            }
+        }
+        None
+    }
+}
+
+/// Strip the tail of a record from `data` while `skipping`
+///
+/// The bytes up to and including the first `delimiter` are removed, which
+/// ends skipping.
+pub fn skip_tail<'a>(skipping: &mut bool, data: &'a [u8], delimiter: u8) -> &'a [u8] {
+    if !*skipping {
+        return data;
+    }
+    match data.iter().position(|&byte| byte == delimiter) {
+        Some(end) => {
+            *skipping = false;
+            &data[end + 1..]
+        }
+        None => &[],
+    }
+}
+
+/// Decode a COBS encoded frame without the terminating zero byte
+pub fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some((&code, tail)) = rest.split_first() {
+        if code == 0 || code as usize - 1 > tail.len() {
+            return None;
+        }
+        let len = code as usize - 1;
+        out.extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+        if code < 0xff && !rest.is_empty() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_markers_are_counted() {
+        let mut decoder = Decoder::new(false);
+        let mut out = Vec::new();
+        let text = b"[DROPPED] 3 records\n[main.rs:1] a\n#2 [DROPPED] 4 records\n";
+        for chunk in text.chunks(5) {
+            decoder.decode(chunk, &mut out).unwrap();
+        }
+        assert_eq!(out, text);
+        assert_eq!(
+            decoder.loss_summary().as_deref(),
+            Some("Device dropped 7 records during capture; consider a larger buffer or filters")
+        );
+        assert_eq!(Decoder::new(true).loss_summary(), None);
+    }
+
+    #[test]
+    fn backtrace_frames_are_decoded() {
+        let mut decoder = Decoder::new(true);
+        let mut out = Vec::new();
+        decoder.decode(&[4, FRAME_BACKTRACE, 0x81, 0x01, 0], &mut out).unwrap();
+        assert_eq!(out, b"[BACKTRACE] 0x00000081\n");
+    }
+
+    #[test]
+    fn diagnostics_are_hidden() {
+        let mut decoder = Decoder::new(false);
+        decoder.set_hide_diagnostics(true);
+        let mut out = Vec::new();
+        let text = b"[main.rs:1] a\n[usb-log] suspended\n[1.000000][usb-log] resumed\n";
+        decoder.decode(text, &mut out).unwrap();
+        let text = b"#1 [usb-log] x\n#1 [main.rs:2] b\n";
+        decoder.decode(text, &mut out).unwrap();
+        assert_eq!(out, b"[main.rs:1] a\n#1 [main.rs:2] b\n");
+
+        // binary record with file "usb-log" and line 0
+        let mut decoder = Decoder::new(true);
+        let mut out = Vec::new();
+        let frame = b"\x03\x01\x03\x0a\x07usb-logx\x00";
+        decoder.decode(frame, &mut out).unwrap();
+        assert_eq!(out, b"[usb-log] x\n");
+        decoder.set_hide_diagnostics(true);
+        decoder.decode(frame, &mut out).unwrap();
+        assert_eq!(out, b"[usb-log] x\n");
+    }
+
+    #[test]
+    fn records_below_min_level_are_hidden() {
+        let mut decoder = Decoder::new(false);
+        decoder.set_min_level(Some(Level::Warn));
+        let mut out = Vec::new();
+        let text = b"[I] [main.rs:1] a\n[W] [main.rs:2] b\n[main.rs:3] c\n";
+        decoder.decode(text, &mut out).unwrap();
+        assert_eq!(out, b"[W] [main.rs:2] b\n[main.rs:3] c\n");
+
+        // debug and warning records of file "a", line 1
+        let mut decoder = Decoder::new(true);
+        decoder.set_min_level(Some(Level::Warn));
+        let mut out = Vec::new();
+        decoder.decode(b"\x07\x01\x04\x01\x01ax\x00", &mut out).unwrap();
+        decoder.decode(b"\x07\x01\x02\x01\x01ay\x00", &mut out).unwrap();
+        assert_eq!(out, b"[a:1] y\n");
+        decoder.set_level_tags(true);
+        decoder.decode(b"\x07\x01\x02\x01\x01ay\x00", &mut out).unwrap();
+        assert_eq!(out, b"[a:1] y\n[W][a:1] y\n");
+    }
+
+    #[test]
+    fn record_tails_are_skipped_after_gap() {
+        // the partial line already passed on is terminated
+        let mut decoder = Decoder::new(false);
+        let mut out = Vec::new();
+        decoder.decode(b"[main.rs:1] a\n[ma", &mut out).unwrap();
+        decoder.skip_gap(&mut out).unwrap();
+        decoder.decode(b"s:3] c\n[main.rs:4] d\n", &mut out).unwrap();
+        assert_eq!(out, b"[main.rs:1] a\n[ma\n[main.rs:4] d\n");
+
+        // the tail of a frame is not counted as corrupted
+        let mut decoder = Decoder::new(true);
+        let mut out = Vec::new();
+        decoder.decode(b"\x07\x01\x02", &mut out).unwrap();
+        decoder.skip_gap(&mut out).unwrap();
+        decoder.decode(b"\x01ax\x00\x07\x01\x02\x01\x01ay\x00", &mut out).unwrap();
+        assert_eq!(out, b"[a:1] y\n");
+        assert_eq!(decoder.loss_summary(), None);
+    }
+
+    #[test]
+    fn raw_payloads_are_written_to_files() {
+        let dir = std::env::temp_dir().join(format!("usb-logread-raw-{}", std::process::id()));
+        let mut decoder = Decoder::new(false);
+        decoder.set_raw_files(RawFiles::new(dir.clone()).unwrap());
+        let mut out = Vec::new();
+        let text = b"[main.rs:1] a\n[RAW 3] 00ff\n#1 [RAW 3] 10\n[RAW 4] 1\n[main.rs:2] b";
+        for chunk in text.chunks(3) {
+            decoder.decode(chunk, &mut out).unwrap();
+        }
+        // the last line is incomplete
+        assert_eq!(out, b"[main.rs:1] a\n[RAW 4] 1\n");
+        drop(decoder);
+        assert_eq!(std::fs::read(dir.join("raw-3.bin")).unwrap(), [0x00, 0xff, 0x10]);
+        assert!(!dir.join("raw-4.bin").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! Decryption of an encrypted log stream
+//!
+//! Devices using the `encryption` feature of `usb-log` send their log as COBS
+//! encoded frames, each consisting of a 12-byte nonce, the ChaCha20-Poly1305
+//! ciphertext of a chunk of the log and the 16-byte tag. The decrypted chunks
+//! form the log stream, which is decoded as usual.
+//!
+
+use crate::decode::{cobs_decode, skip_tail};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Length of the key in bytes
+pub const KEY_LEN: usize = 32;
+
+/// Decryptor for the frames received from the device
+pub struct Decryptor {
+    cipher: ChaCha20Poly1305,
+    /// Bytes of the current, not yet terminated frame
+    pending: Vec<u8>,
+    /// Number of frames that could not be decrypted
+    pub failed: u64,
+    /// Bytes are discarded up to the end of the current frame
+    pub skipping: bool,
+}
+
+impl Decryptor {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Decryptor {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            pending: Vec::new(),
+            failed: 0,
+            skipping: false,
+        }
+    }
+
+    /// Decrypt the frames completed by `data`, returning the log bytes
+    ///
+    /// Frames that are corrupted or were encrypted with another key are
+    /// discarded.
+    pub fn decrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut plain = Vec::new();
+        for &byte in skip_tail(&mut self.skipping, data, 0) {
+            if byte != 0 {
+                self.pending.push(byte);
+                continue;
+            }
+            let encoded = std::mem::take(&mut self.pending);
+            match cobs_decode(&encoded).and_then(|frame| self.open(frame)) {
+                Some(chunk) => plain.extend_from_slice(&chunk),
+                None => self.failed += 1,
+            }
+        }
+        plain
+    }
+
+    /// Discard a partially received frame after data has been lost
+    pub fn resync(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Authenticate and decrypt a frame
+    fn open(&self, mut frame: Vec<u8>) -> Option<Vec<u8>> {
+        if frame.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let tag = *Tag::from_slice(&frame[frame.len() - TAG_LEN..]);
+        frame.truncate(frame.len() - TAG_LEN);
+        let nonce = *Nonce::from_slice(&frame[..NONCE_LEN]);
+        let mut data = frame.split_off(NONCE_LEN);
+        self.cipher.decrypt_in_place_detached(&nonce, &[], &mut data, &tag).ok()?;
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// COBS encode a frame shorter than 254 bytes
+    fn cobs_encode(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for block in data.split(|&b| b == 0) {
+            out.push(block.len() as u8 + 1);
+            out.extend_from_slice(block);
+        }
+        out.push(0);
+        out
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let key = [7; KEY_LEN];
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let nonce = [1; NONCE_LEN];
+        let mut data = b"secret\n".to_vec();
+        let tag = cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut data)
+            .unwrap();
+        let mut frame = nonce.to_vec();
+        frame.extend_from_slice(&data);
+        frame.extend_from_slice(&tag);
+        let stream = cobs_encode(&frame);
+
+        let mut decryptor = Decryptor::new(&key);
+        assert_eq!(decryptor.decrypt(&stream), b"secret\n");
+        let mut decryptor = Decryptor::new(&[8; KEY_LEN]);
+        assert!(decryptor.decrypt(&stream).is_empty());
+        assert_eq!(decryptor.failed, 1);
+    }
+}
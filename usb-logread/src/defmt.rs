@@ -0,0 +1,573 @@
+//! Decoding of defmt log data
+//!
+//! With `--elf`, the log stream is decoded as defmt data, using the format
+//! strings the firmware ELF file holds in the symbol names of its `.defmt`
+//! section. Each message is encoded as the index of its format string followed
+//! by the timestamp, if the firmware defines one, and by its arguments. The
+//! messages are separated by rzCOBS framing, or follow each other without
+//! framing if the firmware uses the raw encoding, which cannot recover from
+//! lost data.
+//!
+//! The lines have the same form as those of the other decoders, e.g.
+//! `[1.000000][I] message`, so that they can be colored, filtered and
+//! converted to JSON. The location of the log statements is not shown since
+//! it is only available from the debug information.
+//!
+
+use crate::decode::skip_tail;
+use crate::level::Level;
+use chrono::DateTime;
+use defmt_parser::{DisplayHint, Fragment, Parameter, ParserMode, TimePrecision, Type};
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Kind of a format string
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// Log message with its level, None for `println!`
+    Message(Option<Level>),
+    Timestamp,
+    /// Format of a type, which is an enum if it contains `|`
+    Derived,
+    /// Interned string or other format
+    Other,
+}
+
+/// Format string of the firmware
+struct Entry {
+    kind: Kind,
+    format: String,
+}
+
+/// Format strings of the firmware by their index
+#[derive(Default)]
+pub struct Table {
+    entries: HashMap<u16, Entry>,
+    /// Index of the timestamp format, if the firmware defines one
+    timestamp: Option<u16>,
+    /// Messages are not framed
+    raw: bool,
+}
+
+impl Table {
+    /// Load the format strings from an ELF file, None if the firmware does
+    /// not use defmt
+    pub fn load(path: &Path) -> Result<Option<Table>, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let file = object::File::parse(&*data).map_err(|e| format!("{}: {e}", path.display()))?;
+        let Some(section) = file.section_by_name(".defmt") else {
+            return Ok(None);
+        };
+        let mut table = Table::default();
+        for symbol in file.symbols() {
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+            if let Some(encoding) = name.strip_prefix("_defmt_encoding_ = ") {
+                table.raw = encoding == "raw";
+            } else if symbol.section_index() == Some(section.index()) {
+                let Ok(json) = serde_json::from_str::<serde_json::Value>(name) else {
+                    continue;
+                };
+                if let (Some(tag), Some(format)) = (json["tag"].as_str(), json["data"].as_str()) {
+                    table.insert(symbol.address() as u16, tag, format);
+                }
+            }
+        }
+        Ok(Some(table))
+    }
+
+    /// Add the format string with index `index` and the symbol tag `tag`
+    fn insert(&mut self, index: u16, tag: &str, format: &str) {
+        let kind = match tag {
+            "defmt_println" => Kind::Message(None),
+            "defmt_error" => Kind::Message(Some(Level::Error)),
+            "defmt_warn" => Kind::Message(Some(Level::Warn)),
+            "defmt_info" => Kind::Message(Some(Level::Info)),
+            "defmt_debug" => Kind::Message(Some(Level::Debug)),
+            "defmt_trace" => Kind::Message(Some(Level::Trace)),
+            "defmt_timestamp" => Kind::Timestamp,
+            "defmt_derived" => Kind::Derived,
+            _ => Kind::Other,
+        };
+        if kind == Kind::Timestamp {
+            self.timestamp = Some(index);
+        }
+        let format = format.to_string();
+        self.entries.insert(index, Entry { kind, format });
+    }
+}
+
+/// Reason a message could not be decoded
+#[derive(Debug, PartialEq)]
+enum Error {
+    /// The data ends within the message
+    Incomplete,
+    /// The data does not match the format strings
+    Invalid,
+}
+
+/// Decoder for defmt messages
+pub struct DefmtDecoder {
+    table: Table,
+    /// Bytes of the current, not yet terminated frame or, with the raw
+    /// encoding, of the messages not yet decoded
+    pending: Vec<u8>,
+    /// Number of frames or, with the raw encoding, of chunks of data
+    /// discarded due to decoding errors
+    pub(crate) corrupted: u64,
+    /// Messages less severe than this level are discarded
+    pub(crate) min_level: Option<Level>,
+    /// Bytes are discarded up to the end of the current frame
+    skipping: bool,
+}
+
+impl DefmtDecoder {
+    pub fn new(table: Table) -> Self {
+        DefmtDecoder {
+            table,
+            pending: Vec::new(),
+            corrupted: 0,
+            min_level: None,
+            skipping: false,
+        }
+    }
+
+    pub(crate) fn decode(&mut self, data: &[u8], out: &mut impl Write) -> io::Result<()> {
+        if self.table.raw {
+            self.pending.extend_from_slice(data);
+            let mut start = 0;
+            while start < self.pending.len() {
+                let mut rd = Reader(&self.pending[start..]);
+                match self.message(&mut rd) {
+                    Ok(line) => {
+                        start = self.pending.len() - rd.0.len();
+                        if let Some(line) = line {
+                            writeln!(out, "{line}")?;
+                        }
+                    }
+                    Err(Error::Incomplete) => break,
+                    // there is no way to find the start of the next message
+                    Err(Error::Invalid) => {
+                        self.corrupted += 1;
+                        start = self.pending.len();
+                    }
+                }
+            }
+            self.pending.drain(..start);
+            return Ok(());
+        }
+        for &byte in skip_tail(&mut self.skipping, data, 0) {
+            if byte != 0 {
+                self.pending.push(byte);
+                continue;
+            }
+            let encoded = std::mem::take(&mut self.pending);
+            if encoded.is_empty() {
+                continue;
+            }
+            let frame = rzcobs_decode(&encoded);
+            match frame.ok_or(Error::Invalid).and_then(|f| self.message(&mut Reader(&f))) {
+                Ok(Some(line)) => writeln!(out, "{line}")?,
+                Ok(None) => (),
+                Err(_) => self.corrupted += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the data of a partially received message
+    pub(crate) fn resync(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Discard the data up to the end of the current frame
+    ///
+    /// The raw encoding has no frames to find the start of the next message.
+    pub(crate) fn skip(&mut self) {
+        self.skipping = !self.table.raw;
+    }
+
+    /// Decode a message, None if it is below the minimum level
+    fn message(&self, rd: &mut Reader) -> Result<Option<String>, Error> {
+        let index = rd.u16()?;
+        let entry = self.table.entries.get(&index).ok_or(Error::Invalid)?;
+        let Kind::Message(level) = entry.kind else {
+            return Err(Error::Invalid);
+        };
+        let mut line = String::new();
+        if let Some(timestamp) = self.table.timestamp {
+            let format = &self.table.entries[&timestamp].format;
+            line = format!("[{}]", self.format(format, rd)?);
+        }
+        if let Some(level) = level {
+            if self.min_level.is_some_and(|min| level > min) {
+                // decoded nevertheless to find the end of the message
+                self.format(&entry.format, rd)?;
+                return Ok(None);
+            }
+            line += &format!("[{}] ", level.tag());
+        } else if !line.is_empty() {
+            line.push(' ');
+        }
+        line += &self.format(&entry.format, rd)?;
+        Ok(Some(line))
+    }
+
+    /// Decode the arguments of a format string and format them
+    fn format(&self, format: &str, rd: &mut Reader) -> Result<String, Error> {
+        let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+            .map_err(|_| Error::Invalid)?;
+        let mut params: Vec<&Parameter> = fragments
+            .iter()
+            .filter_map(|fragment| match fragment {
+                Fragment::Parameter(param) => Some(param),
+                Fragment::Literal(_) => None,
+            })
+            .collect();
+        // the arguments are sent in the order of their index, each one once
+        params.sort_by_key(|param| param.index);
+        let mut args = HashMap::new();
+        for param in &params {
+            if args.contains_key(&param.index) {
+                continue;
+            }
+            let arg = match &param.ty {
+                Type::BitField(_) => {
+                    let fields = params.iter().copied().filter(|p| p.index == param.index);
+                    let (start, end) =
+                        defmt_parser::get_max_bitfield_range(fields).ok_or(Error::Invalid)?;
+                    // only the bytes containing the fields are sent
+                    let lowest = usize::from(start / 8);
+                    let len = match usize::from((end - 1) / 8) - lowest + 1 {
+                        1 => 1,
+                        2 => 2,
+                        3..=4 => 4,
+                        5..=8 => 8,
+                        _ => 16,
+                    };
+                    Arg::Uint(rd.uint(len)? << (lowest * 8))
+                }
+                ty => self.arg(ty, rd)?,
+            };
+            args.insert(param.index, arg);
+        }
+        let mut text = String::new();
+        for fragment in &fragments {
+            match fragment {
+                Fragment::Literal(literal) => text += literal,
+                Fragment::Parameter(param) => text += &render(&args[&param.index], param),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Decode an argument of type `ty`
+    fn arg(&self, ty: &Type, rd: &mut Reader) -> Result<Arg, Error> {
+        Ok(match ty {
+            Type::U8 => Arg::Uint(rd.uint(1)?),
+            Type::U16 => Arg::Uint(rd.uint(2)?),
+            Type::U32 | Type::Usize => Arg::Uint(rd.uint(4)?),
+            Type::U64 => Arg::Uint(rd.uint(8)?),
+            Type::U128 => Arg::Uint(rd.uint(16)?),
+            Type::I8 => Arg::Int(rd.uint(1)? as i8 as i128),
+            Type::I16 => Arg::Int(rd.uint(2)? as i16 as i128),
+            Type::I32 | Type::Isize => Arg::Int(rd.uint(4)? as i32 as i128),
+            Type::I64 => Arg::Int(rd.uint(8)? as i64 as i128),
+            Type::I128 => Arg::Int(rd.uint(16)? as i128),
+            Type::F32 => Arg::Float(f32::from_bits(rd.uint(4)? as u32).into()),
+            Type::F64 => Arg::Float(f64::from_bits(rd.uint(8)? as u64)),
+            Type::Bool => Arg::Bool(rd.uint(1)? != 0),
+            Type::Char => Arg::Char(char::from_u32(rd.uint(4)? as u32).ok_or(Error::Invalid)?),
+            Type::Str => {
+                let len = rd.uint(4)? as usize;
+                Arg::Str(String::from_utf8_lossy(rd.bytes(len)?).into_owned())
+            }
+            Type::IStr => {
+                let entry = self.table.entries.get(&rd.u16()?).ok_or(Error::Invalid)?;
+                Arg::Str(entry.format.clone())
+            }
+            Type::U8Slice => {
+                let len = rd.uint(4)? as usize;
+                Arg::Bytes(rd.bytes(len)?.to_vec())
+            }
+            Type::U8Array(len) => Arg::Bytes(rd.bytes(*len)?.to_vec()),
+            Type::Format => {
+                let tag = rd.u16()?;
+                Arg::Formatted(self.tagged(tag, rd)?)
+            }
+            Type::FormatSlice => {
+                let len = rd.uint(4)? as usize;
+                Arg::Formatted(self.elements(len, rd)?)
+            }
+            Type::FormatArray(len) => Arg::Formatted(self.elements(*len, rd)?),
+            Type::FormatSequence => {
+                let mut text = String::new();
+                loop {
+                    match rd.u16()? {
+                        0 => break,
+                        tag => text += &self.tagged(tag, rd)?,
+                    }
+                }
+                Arg::Formatted(text)
+            }
+            Type::Debug | Type::Display => {
+                // terminated by 0xff, which does not occur in UTF-8
+                let len = rd.0.iter().position(|&b| b == 0xff).ok_or(Error::Incomplete)?;
+                let text = String::from_utf8_lossy(rd.bytes(len)?).into_owned();
+                rd.bytes(1)?;
+                Arg::Formatted(text)
+            }
+            Type::BitField(_) => return Err(Error::Invalid),
+        })
+    }
+
+    /// Decode the data of a value formatted by the format string `tag`
+    fn tagged(&self, tag: u16, rd: &mut Reader) -> Result<String, Error> {
+        let entry = self.table.entries.get(&tag).ok_or(Error::Invalid)?;
+        if entry.kind != Kind::Derived || !entry.format.contains('|') {
+            return self.format(&entry.format, rd);
+        }
+        // enum, whose variant is selected by a discriminant
+        let variants: Vec<&str> = entry.format.split('|').collect();
+        let discriminant = match variants.len() {
+            0..=0xff => rd.uint(1)?,
+            0x100..=0xffff => rd.uint(2)?,
+            _ => rd.uint(4)?,
+        };
+        let variant = variants.get(discriminant as usize).ok_or(Error::Invalid)?;
+        self.format(variant, rd)
+    }
+
+    /// Decode `len` elements sharing one format string
+    fn elements(&self, len: usize, rd: &mut Reader) -> Result<String, Error> {
+        let tag = if len > 0 { rd.u16()? } else { 0 };
+        let elements = (0..len)
+            .map(|_| self.tagged(tag, rd))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("[{}]", elements.join(", ")))
+    }
+}
+
+/// Decoded argument
+enum Arg {
+    Uint(u128),
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// Value already formatted by its own format string
+    Formatted(String),
+}
+
+/// Format an argument as given by a parameter
+fn render(arg: &Arg, param: &Parameter) -> String {
+    let hint = param.hint.as_ref();
+    match arg {
+        Arg::Uint(val) => match &param.ty {
+            Type::BitField(range) => {
+                let bits = u32::from(range.end - range.start);
+                let mask = u128::MAX.checked_shr(128 - bits).unwrap_or(0);
+                uint(val >> range.start & mask, hint)
+            }
+            _ => uint(*val, hint),
+        },
+        Arg::Int(val) => match hint {
+            // shown as the two's complement, as core::fmt does
+            Some(DisplayHint::Hexadecimal { .. } | DisplayHint::Binary { .. }) => {
+                let bits = match &param.ty {
+                    Type::I8 => 8,
+                    Type::I16 => 16,
+                    Type::I32 | Type::Isize => 32,
+                    Type::I64 => 64,
+                    _ => 128,
+                };
+                uint(*val as u128 & u128::MAX >> (128 - bits), hint)
+            }
+            Some(DisplayHint::NoHint { zero_pad }) => format!("{val:0zero_pad$}"),
+            _ => val.to_string(),
+        },
+        Arg::Float(val) => val.to_string(),
+        Arg::Bool(val) => val.to_string(),
+        Arg::Char(c) if hint == Some(&DisplayHint::Debug) => format!("{c:?}"),
+        Arg::Char(c) => c.to_string(),
+        Arg::Str(s) if hint == Some(&DisplayHint::Debug) => format!("{s:?}"),
+        Arg::Str(s) => s.clone(),
+        Arg::Bytes(bytes) if hint == Some(&DisplayHint::Ascii) => {
+            let mut text = String::from("b\"");
+            for &byte in bytes {
+                text.extend(std::ascii::escape_default(byte).map(char::from));
+            }
+            text + "\""
+        }
+        Arg::Bytes(bytes) => {
+            let bytes: Vec<String> = bytes.iter().map(|&b| uint(b.into(), hint)).collect();
+            format!("[{}]", bytes.join(", "))
+        }
+        Arg::Formatted(text) => text.clone(),
+    }
+}
+
+/// Format an unsigned integer as given by a display hint
+fn uint(val: u128, hint: Option<&DisplayHint>) -> String {
+    let mut text = String::new();
+    let _ = match hint {
+        Some(&DisplayHint::NoHint { zero_pad }) => write!(text, "{val:0zero_pad$}"),
+        Some(&DisplayHint::Hexadecimal {
+            alternate,
+            uppercase,
+            zero_pad,
+        }) => match (alternate, uppercase) {
+            (false, false) => write!(text, "{val:0zero_pad$x}"),
+            (false, true) => write!(text, "{val:0zero_pad$X}"),
+            (true, false) => write!(text, "{val:#0zero_pad$x}"),
+            (true, true) => write!(text, "{val:#0zero_pad$X}"),
+        },
+        Some(&DisplayHint::Octal {
+            alternate,
+            zero_pad,
+        }) if alternate => write!(text, "{val:#0zero_pad$o}"),
+        Some(&DisplayHint::Octal { zero_pad, .. }) => write!(text, "{val:0zero_pad$o}"),
+        Some(&DisplayHint::Binary {
+            alternate,
+            zero_pad,
+        }) if alternate => write!(text, "{val:#0zero_pad$b}"),
+        Some(&DisplayHint::Binary { zero_pad, .. }) => write!(text, "{val:0zero_pad$b}"),
+        Some(DisplayHint::Seconds(precision)) => match precision {
+            TimePrecision::Micros => write!(text, "{}.{:06}", val / 1_000_000, val % 1_000_000),
+            TimePrecision::Millis => write!(text, "{}.{:03}", val / 1_000, val % 1_000),
+            TimePrecision::Seconds => write!(text, "{val}"),
+        },
+        Some(DisplayHint::Time(precision)) => {
+            let (secs, frac) = match precision {
+                TimePrecision::Micros => (val / 1_000_000, format!(".{:06}", val % 1_000_000)),
+                TimePrecision::Millis => (val / 1_000, format!(".{:03}", val % 1_000)),
+                TimePrecision::Seconds => (val, String::new()),
+            };
+            let (hours, mins) = (secs / 3600, secs / 60 % 60);
+            write!(text, "{hours:02}:{mins:02}:{:02}{frac}", secs % 60)
+        }
+        Some(DisplayHint::ISO8601(precision)) => {
+            let millis = match precision {
+                TimePrecision::Seconds => val.saturating_mul(1_000),
+                _ => val,
+            };
+            match DateTime::from_timestamp_millis(millis.try_into().unwrap_or(i64::MAX)) {
+                Some(time) => write!(text, "{}", time.format("%Y-%m-%dT%H:%M:%S%.3fZ")),
+                None => write!(text, "{val}"),
+            }
+        }
+        _ => write!(text, "{val}"),
+    };
+    text
+}
+
+/// Cursor over the data of a message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.0.len() {
+            return Err(Error::Incomplete);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    /// Little endian unsigned integer of `len` bytes
+    fn uint(&mut self, len: usize) -> Result<u128, Error> {
+        let bytes = self.bytes(len)?;
+        Ok(bytes.iter().rev().fold(0, |val, &b| val << 8 | u128::from(b)))
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(self.uint(2)? as u16)
+    }
+}
+
+/// Decode an rzCOBS encoded frame without the terminating zero byte
+///
+/// rzCOBS is decoded from the end of the frame. Codes below 0x80 tell by
+/// their 7 bits which of the next 7 bytes are zeros, which are not sent.
+/// Other codes stand for up to 134 bytes without a zero, which are followed
+/// by a zero unless the code is 0xff.
+pub fn rzcobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 8 / 7);
+    let mut rest = data.iter().rev().copied();
+    while let Some(code) = rest.next() {
+        match code {
+            0 => return None,
+            0x01..=0x7f => {
+                for bit in (0..7).rev() {
+                    if code & 1 << bit != 0 {
+                        out.push(0);
+                    } else {
+                        out.push(rest.next()?);
+                    }
+                }
+            }
+            _ => {
+                if code != 0xff {
+                    out.push(0);
+                }
+                for _ in 0..(code & 0x7f) + 7 {
+                    out.push(rest.next()?);
+                }
+            }
+        }
+    }
+    out.reverse();
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_decoded() {
+        assert_eq!(rzcobs_decode(&[0x01, 0x7e]).unwrap(), [0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(rzcobs_decode(&[0x01, 0x7d]).unwrap(), [0, 0x01, 0, 0, 0, 0, 0]);
+
+        let mut table = Table::default();
+        table.insert(1, "defmt_timestamp", "{=u32:us}");
+        table.insert(2, "defmt_warn", "temperature {=i16} at {=[u8]:#x}");
+        table.insert(3, "defmt_debug", "state {}");
+        table.insert(4, "defmt_derived", "Idle|Busy({=u8})");
+        table.insert(5, "defmt_println", "flags {0=0..4:b} {0=4..8}");
+        let mut decoder = DefmtDecoder::new(table);
+        decoder.min_level = Some(Level::Info);
+        let mut out = Vec::new();
+        let warn = [2, 0, 0x40, 0x42, 0x0f, 0, 0xfe, 0xff, 2, 0, 0, 0, 0x0a, 0x1b];
+        let debug = [3, 0, 1, 0, 0, 0, 4, 0, 1, 7];
+        let println = [5, 0, 2, 0, 0, 0, 0x3a];
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&warn);
+        raw.extend_from_slice(&debug);
+        raw.extend_from_slice(&println);
+        decoder.table.raw = true;
+        for chunk in raw.chunks(3) {
+            decoder.decode(chunk, &mut out).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[1.000000][W] temperature -2 at [0xa, 0x1b]\n[0.000002] flags 1010 3\n"
+        );
+
+        decoder.table.raw = false;
+        decoder.min_level = None;
+        let mut out = Vec::new();
+        // [3, 0, 1, 0, 0, 0, 4, 0, 1, 7] in rzCOBS
+        let frame = [0x03, 0x01, 0x04, 0x3a, 0x01, 0x07, 0x79, 0x00];
+        decoder.decode(&[0x00, 0x55, 0x00], &mut out).unwrap();
+        decoder.decode(&frame, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "[0.000001][D] state Busy(7)\n");
+        assert_eq!(decoder.corrupted, 1);
+    }
+}
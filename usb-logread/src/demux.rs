@@ -0,0 +1,96 @@
+//! Separation of the logical channels of the log stream
+//!
+//! Lines of the decoded log that are tagged with a channel, i.e. prefixed with
+//! `#<channel> `, are written to a file `channel-<channel>.log` without the
+//! prefix. Records merged from other log buffers of the device carry the same
+//! prefix and are separated as well. Untagged lines are passed on.
+//!
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub struct Demux<W: Write> {
+    out: W,
+    dir: PathBuf,
+    files: HashMap<u8, File>,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+}
+
+impl<W: Write> Demux<W> {
+    /// Write the channels to files in `dir`, which is created if needed
+    pub fn new(out: W, dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Demux {
+            out,
+            dir,
+            files: HashMap::new(),
+            line: Vec::new(),
+        })
+    }
+
+    /// Write a complete line to its destination
+    ///
+    /// The file of a channel is truncated when its first line arrives.
+    fn write_line(&mut self) -> io::Result<()> {
+        let Some((channel, text)) = split_channel(&self.line) else {
+            return self.out.write_all(&self.line);
+        };
+        let file = match self.files.entry(channel) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(File::create(self.dir.join(format!("channel-{channel}.log")))?)
+            }
+        };
+        file.write_all(text)
+    }
+}
+
+/// Split a line into its channel and the text following the prefix
+pub fn split_channel(line: &[u8]) -> Option<(u8, &[u8])> {
+    let rest = line.strip_prefix(b"#")?;
+    let end = rest.iter().position(|&b| b == b' ')?;
+    let channel = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+    Some((channel, &rest[end + 1..]))
+}
+
+impl<W: Write> Write for Demux<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_written_to_files() {
+        let dir = std::env::temp_dir().join(format!("usb-logread-demux-{}", std::process::id()));
+        let mut out = Vec::new();
+        let mut demux = Demux::new(&mut out, dir.clone()).unwrap();
+        let text = b"[main.rs:1] a\n#2 x=1\n#2 y=2\n#x b\n#7 [main.rs:3] c";
+        for chunk in text.chunks(3) {
+            demux.write_all(chunk).unwrap();
+        }
+        drop(demux);
+        // the last line is incomplete
+        assert_eq!(out, b"[main.rs:1] a\n#x b\n");
+        assert_eq!(std::fs::read(dir.join("channel-2.log")).unwrap(), b"x=1\ny=2\n");
+        assert!(!dir.join("channel-7.log").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
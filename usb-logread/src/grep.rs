@@ -0,0 +1,82 @@
+//! Filtering of the log lines by regular expressions
+//!
+//! With `--grep`, only lines matching one of the given patterns are shown,
+//! and with `--grep-v`, lines matching one of the given patterns are hidden.
+//! Both are applied to complete lines as decoded, i.e. including the channel
+//! tag, but before the timestamps are added. Exclusions take precedence.
+//!
+
+use regex::Regex;
+use std::io::{self, Write};
+
+/// Writer passing on only the lines selected by the patterns
+pub struct Grep<W: Write> {
+    out: W,
+    /// A line must match one of these unless there are none
+    include: Vec<Regex>,
+    /// A line must not match any of these
+    exclude: Vec<Regex>,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+}
+
+impl<W: Write> Grep<W> {
+    pub fn new(out: W, include: Vec<Regex>, exclude: Vec<Regex>) -> Self {
+        Grep {
+            out,
+            include,
+            exclude,
+            line: Vec::new(),
+        }
+    }
+
+    fn selected(&self) -> bool {
+        let text = String::from_utf8_lossy(&self.line);
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(text)))
+            && !self.exclude.iter().any(|re| re.is_match(text))
+    }
+}
+
+impl<W: Write> Write for Grep<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                if self.selected() {
+                    self.out.write_all(&self.line)?;
+                }
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Parse a regular expression given on the command line
+pub fn parse_pattern(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_and_exclude() {
+        let include = vec![parse_pattern(r"\[spi\.rs:\d+\]").unwrap()];
+        let exclude = vec![parse_pattern("idle$").unwrap()];
+        let mut out = Vec::new();
+        let mut grep = Grep::new(&mut out, include, exclude);
+        for chunk in b"[main.rs:1] a\n[spi.rs:2] b\n[spi.rs:3] idle\n#1 [spi.rs:4] c".chunks(5) {
+            grep.write_all(chunk).unwrap();
+        }
+        grep.write_all(b"\n").unwrap();
+        assert_eq!(out, b"[spi.rs:2] b\n#1 [spi.rs:4] c\n");
+        assert!(parse_pattern("(").is_err());
+    }
+}
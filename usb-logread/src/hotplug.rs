@@ -0,0 +1,199 @@
+//! Tracking of the devices having a log interface
+//!
+//! Where libusb supports hotplug events, the arrival and removal of devices
+//! is reported by callbacks, so that a device is noticed right away without
+//! enumerating the whole bus again and again. Otherwise, the bus is
+//! enumerated periodically. A device arriving without a log interface, e.g.
+//! since it has not been configured yet, is checked again for a few seconds.
+//!
+
+use crate::{find_log_interface, DeviceInfo};
+use rusb::{Context, Device, Hotplug, HotplugBuilder, Registration, UsbContext};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// Time a device arriving without a log interface is checked again
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+/// Interval of checking arrived devices again and of enumerating the bus if
+/// hotplug events are not supported
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Arrival or removal of a device with a log interface
+pub enum Event {
+    Arrived(DeviceInfo),
+    Left(DeviceInfo),
+}
+
+/// Arrival or removal of any device
+enum RawEvent {
+    Arrived(Device<Context>),
+    Left(Device<Context>),
+}
+
+/// Hotplug callback passing the events on to the monitor
+struct Callback(Sender<RawEvent>);
+
+impl Hotplug<Context> for Callback {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        self.0.send(RawEvent::Arrived(device)).ok();
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        self.0.send(RawEvent::Left(device)).ok();
+    }
+}
+
+/// Bus number and address identifying a device while it is connected
+fn key(device: &Device<Context>) -> (u8, u8) {
+    (device.bus_number(), device.address())
+}
+
+/// Monitor of the devices having a log interface
+pub struct Monitor {
+    context: Context,
+    sender: Sender<RawEvent>,
+    receiver: Receiver<RawEvent>,
+    /// Events received but not yet processed
+    queue: VecDeque<RawEvent>,
+    /// Keeps the callback registered, None if hotplug is not supported
+    registration: Option<Registration<Context>>,
+    /// Devices found by the last enumeration if hotplug is not supported
+    enumerated: Vec<Device<Context>>,
+    /// Devices with a log interface by bus number and address
+    present: BTreeMap<(u8, u8), DeviceInfo>,
+    /// Devices that arrived without a log interface and their arrival time
+    pending: Vec<(Device<Context>, Instant)>,
+    last_retry: Instant,
+}
+
+impl Monitor {
+    /// Start monitoring the devices
+    ///
+    /// The devices that are already connected are reported as arriving.
+    pub fn new(context: &Context) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let registration = if rusb::has_hotplug() {
+            HotplugBuilder::new()
+                .enumerate(true)
+                .register(context, Box::new(Callback(sender.clone())))
+                .ok()
+        } else {
+            None
+        };
+        Monitor {
+            context: context.clone(),
+            sender,
+            receiver,
+            queue: VecDeque::new(),
+            registration,
+            enumerated: Vec::new(),
+            present: BTreeMap::new(),
+            pending: Vec::new(),
+            last_retry: Instant::now(),
+        }
+    }
+
+    /// Devices with a log interface that are currently connected
+    pub fn devices(&mut self) -> Vec<DeviceInfo> {
+        while self.next_event(Duration::ZERO).is_some() {}
+        self.present.values().cloned().collect()
+    }
+
+    /// Wait up to `timeout` for a device with a log interface to arrive or
+    /// leave
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event> {
+        let deadline = Instant::now() + timeout;
+        let mut collected = false;
+        loop {
+            while let Some(raw) = self.queue.pop_front() {
+                if let Some(event) = self.process(raw) {
+                    return Some(event);
+                }
+            }
+            if let Some(event) = self.retry_pending() {
+                return Some(event);
+            }
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if collected && wait.is_zero() {
+                return None;
+            }
+            self.collect(wait.min(RETRY_INTERVAL));
+            collected = true;
+        }
+    }
+
+    /// Wait up to `timeout` for events of any device
+    fn collect(&mut self, timeout: Duration) {
+        if self.registration.is_some() {
+            // hotplug callbacks are called while handling events
+            self.context.handle_events(Some(timeout)).ok();
+        } else {
+            self.enumerate();
+        }
+        self.queue.extend(self.receiver.try_iter());
+        if self.registration.is_none() && self.queue.is_empty() {
+            std::thread::sleep(timeout);
+        }
+    }
+
+    /// Enumerate the bus, reporting the changes since the last enumeration
+    fn enumerate(&mut self) {
+        let Ok(devices) = self.context.devices() else {
+            return;
+        };
+        let devices: Vec<_> = devices.iter().collect();
+        for device in &self.enumerated {
+            if !devices.iter().any(|d| key(d) == key(device)) {
+                self.sender.send(RawEvent::Left(device.clone())).ok();
+            }
+        }
+        for device in &devices {
+            if !self.enumerated.iter().any(|d| key(d) == key(device)) {
+                self.sender.send(RawEvent::Arrived(device.clone())).ok();
+            }
+        }
+        self.enumerated = devices;
+    }
+
+    fn process(&mut self, raw: RawEvent) -> Option<Event> {
+        match raw {
+            RawEvent::Arrived(device) => match log_interface(&device) {
+                Some(info) => {
+                    self.present.insert(key(&device), info.clone());
+                    Some(Event::Arrived(info))
+                }
+                None => {
+                    self.pending.push((device, Instant::now()));
+                    None
+                }
+            },
+            RawEvent::Left(device) => {
+                self.pending.retain(|(d, _)| key(d) != key(&device));
+                self.present.remove(&key(&device)).map(Event::Left)
+            }
+        }
+    }
+
+    /// Check the devices that arrived without a log interface again
+    fn retry_pending(&mut self) -> Option<Event> {
+        if self.pending.is_empty() || self.last_retry.elapsed() < RETRY_INTERVAL {
+            return None;
+        }
+        self.last_retry = Instant::now();
+        self.pending.retain(|(_, arrival)| arrival.elapsed() < SETTLE_TIME);
+        let (pos, info) = self
+            .pending
+            .iter()
+            .enumerate()
+            .find_map(|(pos, (device, _))| log_interface(device).map(|info| (pos, info)))?;
+        let (device, _) = self.pending.remove(pos);
+        self.present.insert(key(&device), info.clone());
+        Some(Event::Arrived(info))
+    }
+}
+
+/// Find the log interface of a device
+fn log_interface(device: &Device<Context>) -> Option<DeviceInfo> {
+    device.open().ok().and_then(|handle| find_log_interface(&handle))
+}
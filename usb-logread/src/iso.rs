@@ -0,0 +1,190 @@
+//! Reading of isochronous log channels
+//!
+//! Isochronous packets are not retransmitted. Each packet of the device
+//! therefore starts with a sequence number, which is incremented with each
+//! packet sent, and gaps in the sequence numbers tell how many packets have
+//! been lost. After a gap, the partial record received before it and the
+//! tail of the record cut by it are discarded.
+//!
+//! As rusb has no API for isochronous transfers, they are submitted to
+//! libusb directly.
+//!
+
+use crate::decode::Decoder;
+use libusb1_sys::constants::*;
+use libusb1_sys::*;
+use rusb::{Context, DeviceHandle, UsbContext};
+use std::ffi::{c_int, c_uint, c_void};
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Number of packets read by one transfer
+const PACKETS_PER_TRANSFER: usize = 32;
+
+/// Checker of the sequence numbers of the packets
+#[derive(Default)]
+pub struct Sequence {
+    /// Sequence number of the next packet, None until a packet has arrived
+    next: Option<u8>,
+}
+
+impl Sequence {
+    /// Split a packet into the number of packets lost before it and its log
+    /// data
+    ///
+    /// The number of lost packets is only known modulo 256. Empty packets
+    /// carry neither a sequence number nor data.
+    pub fn check<'a>(&mut self, packet: &'a [u8]) -> (u8, &'a [u8]) {
+        let Some((&sequence, data)) = packet.split_first() else {
+            return (0, packet);
+        };
+        let lost = self.next.map_or(0, |next| sequence.wrapping_sub(next));
+        self.next = Some(sequence.wrapping_add(1));
+        (lost, data)
+    }
+
+    /// Check a packet and decode its log data
+    ///
+    /// Lost packets are reported on stderr.
+    pub fn decode(
+        &mut self,
+        packet: &[u8],
+        decoder: &mut Decoder,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let (lost, data) = self.check(packet);
+        if lost > 0 {
+            eprintln!("{lost} isochronous packets lost, resynchronizing");
+            decoder.skip_gap(out)?;
+        }
+        decoder.decode(data, out)
+    }
+
+    /// Accept any sequence number for the next packet, e.g. after the
+    /// interface has been claimed again
+    pub fn restart(&mut self) {
+        self.next = None;
+    }
+}
+
+/// Read the packets of one transfer from the isochronous IN endpoint `ep`
+///
+/// `packet` is called with each packet received in order. Packets with
+/// errors are skipped, which shows as a gap in the sequence numbers.
+pub fn read_packets(
+    handle: &DeviceHandle<Context>,
+    ep: u8,
+    timeout: Duration,
+    mut packet: impl FnMut(&[u8]),
+) -> Result<(), rusb::Error> {
+    // SAFETY: the device outlives the call
+    let size = unsafe { libusb_get_max_iso_packet_size(handle.device().as_raw(), ep) };
+    if size <= 0 {
+        return Err(error(size));
+    }
+    let size = size as usize;
+    let mut buf = vec![0u8; size * PACKETS_PER_TRANSFER];
+    let mut completed: c_int = 0;
+    // SAFETY: the transfer is handled until it has completed, so that the
+    // buffer and the completion flag outlive it, and freed afterwards
+    unsafe {
+        let transfer = libusb_alloc_transfer(PACKETS_PER_TRANSFER as c_int);
+        if transfer.is_null() {
+            return Err(rusb::Error::NoMem);
+        }
+        libusb_fill_iso_transfer(
+            transfer,
+            handle.as_raw(),
+            ep,
+            buf.as_mut_ptr(),
+            buf.len() as c_int,
+            PACKETS_PER_TRANSFER as c_int,
+            transfer_completed,
+            &mut completed as *mut c_int as *mut c_void,
+            timeout.as_millis() as c_uint,
+        );
+        libusb_set_iso_packet_lengths(transfer, size as c_uint);
+        let res = libusb_submit_transfer(transfer);
+        if res < 0 {
+            libusb_free_transfer(transfer);
+            return Err(error(res));
+        }
+        let context = handle.context().as_raw();
+        let mut cancelled = false;
+        while completed == 0 {
+            // the transfer cannot be freed before it has completed, so after
+            // an error, it is cancelled and waited for
+            if libusb_handle_events_completed(context, &mut completed) < 0 && !cancelled {
+                libusb_cancel_transfer(transfer);
+                cancelled = true;
+            }
+        }
+        let res = match (*transfer).status {
+            LIBUSB_TRANSFER_COMPLETED => {
+                let descs = (*transfer).iso_packet_desc.as_ptr();
+                for i in 0..PACKETS_PER_TRANSFER {
+                    let desc = &*descs.add(i);
+                    if desc.status == LIBUSB_TRANSFER_COMPLETED {
+                        packet(&buf[i * size..][..desc.actual_length as usize]);
+                    }
+                }
+                Ok(())
+            }
+            LIBUSB_TRANSFER_TIMED_OUT => Err(rusb::Error::Timeout),
+            LIBUSB_TRANSFER_STALL => Err(rusb::Error::Pipe),
+            LIBUSB_TRANSFER_NO_DEVICE => Err(rusb::Error::NoDevice),
+            LIBUSB_TRANSFER_OVERFLOW => Err(rusb::Error::Overflow),
+            _ => Err(rusb::Error::Io),
+        };
+        libusb_free_transfer(transfer);
+        res
+    }
+}
+
+extern "system" fn transfer_completed(transfer: *mut libusb_transfer) {
+    // SAFETY: the user data is the completion flag of `read_packets()`
+    unsafe { *((*transfer).user_data as *mut c_int) = 1 };
+}
+
+/// Convert a libusb error code
+fn error(code: c_int) -> rusb::Error {
+    match code {
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_are_counted() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.check(b"\x07a"), (0, &b"a"[..]));
+        assert_eq!(sequence.check(b"\x08b"), (0, &b"b"[..]));
+        assert_eq!(sequence.check(b""), (0, &b""[..]));
+        assert_eq!(sequence.check(b"\x0bc"), (2, &b"c"[..]));
+        // the sequence numbers wrap around
+        sequence.check(b"\xfe");
+        assert_eq!(sequence.check(b"\x01d"), (2, &b"d"[..]));
+        sequence.restart();
+        assert_eq!(sequence.check(b"\x40e"), (0, &b"e"[..]));
+    }
+
+    #[test]
+    fn partial_line_is_discarded_after_gap() {
+        let mut sequence = Sequence::default();
+        let mut decoder = Decoder::new(false);
+        decoder.set_hide_diagnostics(true);
+        let mut out = Vec::new();
+        for packet in [&b"\x00[main.rs:1] a\n[ma"[..], b"\x02s:3] c\n", b"\x03[main.rs:4] d\n"] {
+            sequence.decode(packet, &mut decoder, &mut out).unwrap();
+        }
+        assert_eq!(out, b"[main.rs:1] a\n[main.rs:4] d\n");
+    }
+}
@@ -0,0 +1,179 @@
+//! Output of the log as JSON lines
+//!
+//! With `--format json`, each line of the log is written as a JSON object on
+//! a line of its own, for ingestion into log pipelines. The prefixes of the
+//! line are parsed into the fields
+//!
+//! - `timestamp`: time the host received the line, in ISO 8601 format
+//! - `device`: bus and port path of the device, e.g. `1-4.2`
+//! - `channel`: channel or source tag, null for untagged lines
+//! - `device_time`: timestamp of the device in seconds, if it sent one
+//! - `level`: log level, if it is known, see [`crate::level`]
+//! - `file` and `line`: location of the log statement, if it is known
+//! - `message`: the rest of the line
+//!
+//! Lines that are not records, e.g. drop markers, have only a message. With
+//! `--channel-dir`, the lines of the channels are still written to their
+//! files as text.
+//!
+
+use crate::demux::split_channel;
+use crate::level::Level;
+use chrono::{DateTime, Local, SecondsFormat};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+/// Format of the output
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Lines of text as sent by the device
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writer converting each line into a JSON object
+pub struct Json<W: Write> {
+    out: W,
+    device: String,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+    /// Time the first byte of the current line was received
+    received: Option<DateTime<Local>>,
+}
+
+impl<W: Write> Json<W> {
+    pub fn new(out: W, device: String) -> Self {
+        Json {
+            out,
+            device,
+            line: Vec::new(),
+            received: None,
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let received = self.received.take().unwrap_or_else(Local::now);
+        let line = String::from_utf8_lossy(&self.line);
+        let mut record = parse(line.trim_end_matches(['\r', '\n']));
+        record["timestamp"] = json!(received.to_rfc3339_opts(SecondsFormat::Micros, false));
+        record["device"] = json!(self.device);
+        writeln!(self.out, "{record}")
+    }
+}
+
+/// Parse the fields of a line of the log
+fn parse(line: &str) -> Value {
+    let (channel, mut rest) = match split_channel(line.as_bytes()) {
+        Some((channel, text)) => (Some(channel), &line[line.len() - text.len()..]),
+        None => (None, line),
+    };
+    let mut device_time = None;
+    if let Some((tag, after)) = bracket(rest) {
+        // `?` if the device has lost track of the time
+        if tag == "?" || tag.contains('.') && tag.parse::<f64>().is_ok() {
+            device_time = tag.parse::<f64>().ok();
+            rest = after;
+        }
+    }
+    let mut level = None;
+    if let Some((tag, after)) = bracket(rest) {
+        if let Some(tag_level) = Level::from_name(tag) {
+            level = Some(tag_level);
+            rest = after.strip_prefix(' ').unwrap_or(after);
+        }
+    }
+    let (mut file, mut lineno) = (None, None);
+    let location = bracket(rest).and_then(|(tag, after)| Some((tag, after.strip_prefix(' ')?)));
+    if let Some((tag, message)) = location {
+        match tag.rsplit_once(':').map(|(path, n)| (path, n.parse::<u32>())) {
+            Some((path, Ok(n))) => {
+                // `???` if the device has lost the path
+                file = Some(path).filter(|&path| path != "???");
+                lineno = Some(n);
+                rest = message;
+            }
+            // diagnostics of the USB classes of the device
+            None if tag == "usb-log" => {
+                file = Some(tag);
+                rest = message;
+            }
+            _ => (),
+        }
+    }
+    json!({
+        "channel": channel,
+        "device_time": device_time,
+        "level": level.or_else(|| Level::of_line(rest)).map(level_name),
+        "file": file,
+        "line": lineno,
+        "message": rest,
+    })
+}
+
+/// Split off the contents of a tag in brackets at the start of `s`
+fn bracket(s: &str) -> Option<(&str, &str)> {
+    s.strip_prefix('[')?.split_once(']')
+}
+
+/// Name of a level as given on the command line
+fn level_name(level: Level) -> String {
+    level.to_possible_value().unwrap().get_name().to_string()
+}
+
+impl<W: Write> Write for Json<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if self.received.is_none() {
+                self.received = Some(Local::now());
+            }
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_prefixes() {
+        let record = parse("#2 [12.000345][W][src/main.rs:10] low battery");
+        assert_eq!(
+            record,
+            json!({
+                "channel": 2,
+                "device_time": 12.000345,
+                "level": "warn",
+                "file": "src/main.rs",
+                "line": 10,
+                "message": "low battery",
+            })
+        );
+        let record = parse("[?][PANIC] at main.rs:3");
+        assert_eq!(record["level"], "panic");
+        assert_eq!(record["message"], "at main.rs:3");
+        assert_eq!(parse("[usb-log] suspended")["file"], "usb-log");
+        let record = parse("[DROPPED] 3 records");
+        assert_eq!(record["file"], Value::Null);
+        assert_eq!(record["message"], "[DROPPED] 3 records");
+
+        let mut out = Vec::new();
+        let mut writer = Json::new(&mut out, "1-4".to_string());
+        writer.write_all(b"[main.rs:1] a\n").unwrap();
+        let record: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(record["device"], "1-4");
+        assert_eq!(record["message"], "a");
+        assert!(record["timestamp"].is_string());
+    }
+}
@@ -0,0 +1,96 @@
+//! Log levels of the lines
+//!
+//! Text lines carry their level, if at all, as a tag like `[E]`, `[WARN]` or
+//! `[PANIC]` or as a word like `ERROR` or `WARNING`. Binary records carry it
+//! in their header, which the decoder can turn into a tag. The level is used
+//! to color lines and, with `--min-level`, to hide the less severe ones.
+//! Lines without a level, e.g. those of firmware not tagging its messages,
+//! are never hidden.
+//!
+
+use clap::ValueEnum;
+
+/// Severity of a line, the most severe first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    Panic,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Level of a tag name like `E` or `WARN`
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "PANIC" => Some(Level::Panic),
+            "E" | "ERROR" => Some(Level::Error),
+            "W" | "WARN" | "WARNING" => Some(Level::Warn),
+            "I" | "INFO" => Some(Level::Info),
+            "D" | "DEBUG" => Some(Level::Debug),
+            "T" | "TRACE" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// Short tag name of the level
+    pub fn tag(self) -> &'static str {
+        match self {
+            Level::Panic => "PANIC",
+            Level::Error => "E",
+            Level::Warn => "W",
+            Level::Info => "I",
+            Level::Debug => "D",
+            Level::Trace => "T",
+        }
+    }
+
+    /// Level of a binary record, whose header holds the panic level 0 or the
+    /// `log::Level` of the record
+    pub fn from_record(level: u8) -> Option<Level> {
+        match level {
+            0 => Some(Level::Panic),
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// Find the level of a text line
+    ///
+    /// Tags in brackets take precedence over words, since a message may
+    /// mention e.g. an error without being one. Single letters are only
+    /// recognized as tags.
+    pub fn of_line(line: &str) -> Option<Level> {
+        let tag = line.split('[').skip(1).find_map(|s| {
+            let (name, _) = s.split_once(']')?;
+            Level::from_name(name)
+        });
+        tag.or_else(|| {
+            line.split(|c: char| !c.is_ascii_alphabetic())
+                .filter(|word| word.len() > 1)
+                .find_map(Level::from_name)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_of_line() {
+        assert_eq!(Level::of_line("[E] [main.rs:1] failed"), Some(Level::Error));
+        assert_eq!(Level::of_line("[main.rs:1] [WARN] low battery"), Some(Level::Warn));
+        assert_eq!(Level::of_line("[PANIC] at main.rs:3"), Some(Level::Panic));
+        assert_eq!(Level::of_line("[I] no ERROR here"), Some(Level::Info));
+        assert_eq!(Level::of_line("12:00 ERROR: timeout"), Some(Level::Error));
+        assert_eq!(Level::of_line("[main.rs:1] E = 3"), None);
+        assert!(Level::Warn < Level::Info);
+    }
+}
@@ -1,32 +1,208 @@
 //! USB Log Reader
 //!
-//! Looks for device having a logging interface named 'kiffielog'. Then copies
-//! all bytes from the endpoint to stdout.
+//! Looks for device having a logging interface named 'kiffielog', or the name
+//! given by `--interface-name`. Then copies all bytes from the endpoint to
+//! stdout.
 //!
-//! The logging interface can have a bulk endpoint or control transfer can be
-//! used to retrieve the log data.
+//! If several devices have a log interface, one is selected by `--bus` and
+//! `--address`, by its serial number with `--serial`, which identifies it
+//! even after it has been plugged in again, by its vendor and product ID
+//! with `--device` or by a part of its product name with `--product`. With
+//! `--port-path`, the device plugged into a given port is selected, which
+//! suits fixed test setups. `--list` shows the port paths and serial numbers.
 //!
+//! A device can have several log channels, whose interfaces are named after
+//! the log interface with a suffix, e.g. 'kiffielog-trace'. `--channel trace`
+//! reads that channel instead of the main one, and `--list` shows the
+//! channels of each device.
+//!
+//! The logging interface can have a bulk or an interrupt endpoint or control
+//! transfer can be used to retrieve the log data. HID log interfaces send the
+//! log in input reports, which are read from the interrupt endpoint; on Linux,
+//! the kernel HID driver is detached from the interface while reading. The
+//! packets of an isochronous endpoint are checked for gaps in their sequence
+//! numbers, see [`iso`]. With `--control`, the log
+//! is read by control transfers from interfaces having a bulk or an
+//! isochronous endpoint, which answer them as well.
+//!
+//! If the endpoints are only present in an alternate setting of the log
+//! interface, that alternate setting is selected when reading from them.
+//!
+//! While reading, the reader announces itself to the device by a control
+//! request, so that the firmware can adapt its buffering policy.
+//!
+//! With `--credit`, the device sends bulk data only as far as the reader has
+//! granted it, which requires credit-based flow control on the device.
+//!
+//! When reading by control transfers, devices that report the amount of data
+//! they have buffered are polled less often while they are idle. Devices
+//! that report it with each read have bursts read back to back. Devices with
+//! a notification endpoint are waited for until they announce new data.
+//!
+//! If the device changes its configuration while reading, the log interface is
+//! claimed again once it reappears.
+//!
+//! If no device is present, the reader waits for one to appear, and if the
+//! device disconnects, e.g. when it is reset or unplugged, the reader waits
+//! for it to come back and continues reading. With `--no-wait`, the reader
+//! exits in both cases, as it always does for subcommands.
+//!
+//! With `--key`, the log stream is decrypted with the given ChaCha20-Poly1305
+//! key before decoding, for devices encrypting their log.
+//!
+//! With `--binary`, the data is decoded as framed binary records. When reading
+//! stops, a summary of the records lost during the session is printed.
+//! With `--elf`, the data is decoded as defmt messages using the format
+//! strings of the given firmware, see [`defmt`], if the firmware uses defmt.
+//! The code addresses in the log are followed by the functions they belong
+//! to, see [`symbols`].
+//!
+//! With `--raw-dir`, raw binary payloads sent along with the log are written
+//! to one file per tag. With `--channel-dir`, the lines of each logical
+//! channel are written to a file of their own.
+//!
+//! With `--output`, the log is written to a file, which is rotated by size
+//! with `--rotate-size` and by time with `--rotate-interval`.
+//!
+//! With `--timestamp`, each line is prefixed with the time the host received
+//! it, or with the time since the start or since the previous line.
+//!
+//! With `--format json`, each line is written as a JSON object with the
+//! prefixes of the line parsed into fields.
+//!
+//! Lines are colored by their log level on a terminal, see `--color`. With
+//! `--min-level`, lines less severe than the given level are hidden. With
+//! `--grep` and `--grep-v`, lines are shown or hidden by regular expressions.
+//!
+//! With `--no-diagnostics`, the diagnostics the USB classes of the device
+//! report under the `usb-log` target, e.g. about a buffer overflow, are not
+//! shown.
+//!
+//! With `--all`, all selected devices are read at once, see [`multi`].
+//!
+//! With `--watch`, the arrival and removal of devices having a log interface
+//! is reported instead of reading the log.
+//!
+//! Before talking to a device, its protocol version is read by a control
+//! request, and a warning is printed if the device is newer than the reader.
+//! Before reading the log, the firmware version and the log buffer size are
+//! shown if the device reports them.
+//!
+//! The `ping` subcommand measures the control transfer round-trip time and,
+//! with `--clock`, the offset between the device and the host clock. The
+//! `selftest` subcommand checks the protocol features supported by a device.
+//! The `pause` and `resume` subcommands switch logging on the device. The
+//! `level`, `clear` and `stats` subcommands set the log level, discard the
+//! buffered log and show the buffer statistics of the device, respectively.
+//! The statistics include the time records spend in the buffer if the device
+//! measures it.
+//! The `send` subcommand sends data to the OUT endpoint of the log interface
+//! or, if there is none, as vendor control requests.
+//! The `test-vectors` subcommand checks the decoder against golden outputs.
+//!
+
+mod color;
+mod control;
+mod decode;
+mod defmt;
+mod decrypt;
+mod demux;
+mod grep;
+mod hotplug;
+mod iso;
+mod json;
+mod level;
+mod multi;
+mod output;
+mod ping;
+mod raw;
+mod select;
+mod selftest;
+mod send;
+mod symbols;
+mod test_vectors;
+mod timestamp;
+mod watch;
 
-use clap::Parser;
-use rusb::{Context, Device, DeviceList, Direction, TransferType, UsbContext};
+use clap::{Parser, Subcommand};
+use decode::Decoder;
+use rusb::{Context, Device, DeviceHandle, Direction, TransferType};
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+use std::time::{Duration, Instant};
 
-const INTERFACE_NAME: &str = "kiffielog";
+const DEFAULT_INTERFACE_NAME: &str = "kiffielog";
 const TIMEOUT: Duration = Duration::from_millis(100);
+const LANG_ID_EN_US: u16 = 0x0409;
+const RECLAIM_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval of looking for a device while waiting for it
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+/// Polling interval of control transfer reads while the device has data
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Longest polling interval while the device is idle
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const CLASS_HID: u8 = 0x03;
+
+/// Set when reading is to be stopped, e.g. by Ctrl-C
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Name identifying the log interface
+static INTERFACE_NAME: OnceLock<String> = OnceLock::new();
+
+fn interface_name() -> &'static str {
+    INTERFACE_NAME.get().map_or(DEFAULT_INTERFACE_NAME, String::as_str)
+}
 
 #[derive(Clone, Copy, Debug)]
 enum IfaceType {
     Control,
     Bulk(u8),
+    Interrupt(u8),
+    /// Interrupt endpoint sending HID reports
+    Hid(u8),
+    /// Isochronous endpoint sending packets with sequence numbers
+    Isochronous(u8),
+}
+
+impl IfaceType {
+    /// Log data contained in a packet read from the interface
+    ///
+    /// HID reports start with the number of log bytes that follow.
+    fn payload(self, packet: &[u8]) -> &[u8] {
+        match self {
+            IfaceType::Hid(_) => match packet.split_first() {
+                Some((&len, data)) => &data[..data.len().min(len as usize)],
+                None => packet,
+            },
+            _ => packet,
+        }
+    }
+}
+
+impl std::fmt::Display for IfaceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IfaceType::Control => write!(f, "control transfers"),
+            IfaceType::Bulk(ep) => write!(f, "bulk EP 0x{ep:02x}"),
+            IfaceType::Interrupt(ep) => write!(f, "interrupt EP 0x{ep:02x}"),
+            IfaceType::Hid(ep) => write!(f, "HID reports on EP 0x{ep:02x}"),
+            IfaceType::Isochronous(ep) => write!(f, "isochronous EP 0x{ep:02x}"),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct DeviceInfo {
     device: Device<Context>,
     iface_id: u8,
+    /// Alternate setting of the log interface to be selected
+    alt_setting: u8,
     iface_type: IfaceType,
+    /// Interrupt endpoint notifying of new data of a control channel
+    notify_ep: Option<u8>,
 }
 
 impl DeviceInfo {
@@ -34,16 +210,33 @@ impl DeviceInfo {
         Self {
             device,
             iface_id,
+            alt_setting: 0,
             iface_type: IfaceType::Control,
+            notify_ep: None,
         }
     }
 
-    fn bulk(device: Device<Context>, iface_id: u8, ep: u8) -> Self {
+    fn with_type(device: Device<Context>, iface_id: u8, iface_type: IfaceType) -> Self {
         Self {
             device,
             iface_id,
-            iface_type: IfaceType::Bulk(ep),
+            alt_setting: 0,
+            iface_type,
+            notify_ep: None,
+        }
+    }
+
+    /// Claim the log interface and select its alternate setting
+    fn claim(&self, handle: &DeviceHandle<Context>) -> Result<(), rusb::Error> {
+        if let IfaceType::Hid(_) = self.iface_type {
+            // not supported on all platforms
+            handle.set_auto_detach_kernel_driver(true).ok();
         }
+        handle.claim_interface(self.iface_id)?;
+        if self.alt_setting != 0 {
+            handle.set_alternate_setting(self.iface_id, self.alt_setting)?;
+        }
+        Ok(())
     }
 
     fn device(&self) -> &Device<Context> {
@@ -70,117 +263,784 @@ struct Args {
     #[clap(short = 'b', long = "bus")]
     bus: Option<u8>,
 
+    /// Select device based on its serial number, which unlike the address
+    /// stays the same when it is plugged in again
+    #[clap(long = "serial", value_name = "SN")]
+    serial: Option<String>,
+
+    /// Select device based on its vendor and product ID, given in hex
+    #[clap(long = "device", value_name = "VID:PID", value_parser = select::parse_vid_pid)]
+    device: Option<(u16, u16)>,
+
+    /// Select device whose product name contains NAME, ignoring case
+    #[clap(long = "product", value_name = "NAME")]
+    product: Option<String>,
+
+    /// Select the device plugged into the port given by the bus number and
+    /// the port numbers along the hubs, e.g. 1-3.4
+    #[clap(long = "port-path", value_name = "PATH", value_parser = select::parse_port_path)]
+    port_path: Option<String>,
+
+    /// Report arrival and removal of devices instead of reading the log
+    #[clap(short = 'w', long = "watch")]
+    watch: bool,
+
+    /// Name of the log interface if the device does not use the default
+    #[clap(short = 'i', long = "interface-name", default_value = DEFAULT_INTERFACE_NAME)]
+    interface_name: String,
+
+    /// Read the log channel NAME, i.e. the interface named
+    /// <interface-name>-NAME, instead of the main log interface
+    #[clap(long = "channel", value_name = "NAME")]
+    channel: Option<String>,
+
+    /// Decode framed binary log records
+    #[clap(short = 'B', long = "binary")]
+    binary: bool,
+
+    /// Resolve code addresses using the firmware ELF file and decode the log as
+    /// defmt data if the firmware uses defmt
+    #[clap(long = "elf", value_name = "PATH", conflicts_with_all = ["binary", "raw_dir"])]
+    elf: Option<PathBuf>,
+
+    /// Decrypt the log stream with KEY, given as 64 hex digits
+    #[clap(long = "key", value_name = "KEY")]
+    key: Option<String>,
+
+    /// Write raw payloads to files raw-<tag>.bin in DIR instead of printing them
+    #[clap(long = "raw-dir", value_name = "DIR")]
+    raw_dir: Option<PathBuf>,
+
+    /// Write each logical channel to a file channel-<channel>.log in DIR
+    #[clap(long = "channel-dir", value_name = "DIR")]
+    channel_dir: Option<PathBuf>,
+
+    /// Write the log to FILE instead of stdout
+    #[clap(short = 'o', long = "output", value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Rotate the output file once it has reached SIZE bytes, which may be
+    /// followed by K, M or G
+    #[clap(
+        long = "rotate-size",
+        value_name = "SIZE",
+        requires = "output",
+        value_parser = output::parse_size
+    )]
+    rotate_size: Option<u64>,
+
+    /// Rotate the output file at each multiple of INTERVAL in local time,
+    /// given as a number followed by s, m, h or d, e.g. 1h or 24h
+    #[clap(
+        long = "rotate-interval",
+        value_name = "INTERVAL",
+        requires = "output",
+        value_parser = output::parse_interval
+    )]
+    rotate_interval: Option<Duration>,
+
+    /// Prefix each line with the time it was received, as ISO 8601 date and
+    /// time (default), seconds since the Unix epoch, time of day, seconds
+    /// since the start or seconds since the previous line
+    #[clap(
+        long = "timestamp",
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "iso"
+    )]
+    timestamp: Option<timestamp::Format>,
+
+    /// Write the log as lines of text or as one JSON object per line
+    #[clap(long = "format", value_name = "FORMAT", default_value = "text")]
+    format: json::Format,
+
+    /// Color the lines by log level: auto (if stdout is a terminal), always
+    /// or never
+    #[clap(long = "color", value_name = "WHEN", default_value = "auto")]
+    color: color::When,
+
+    /// Hide the lines less severe than LEVEL, as far as their level is known
+    #[clap(long = "min-level", value_name = "LEVEL")]
+    min_level: Option<level::Level>,
+
+    /// Show only lines matching the regular expression PATTERN; may be given
+    /// several times to show lines matching any of them
+    #[clap(long = "grep", value_name = "PATTERN", value_parser = grep::parse_pattern)]
+    grep: Vec<regex::Regex>,
+
+    /// Hide lines matching the regular expression PATTERN; may be given
+    /// several times
+    #[clap(long = "grep-v", value_name = "PATTERN", value_parser = grep::parse_pattern)]
+    grep_v: Vec<regex::Regex>,
+
+    /// Hide the diagnostics of the device's USB classes
+    #[clap(long = "no-diagnostics")]
+    no_diagnostics: bool,
+
+    /// Read all selected devices at once, prefixing the lines with the serial
+    /// number or the bus number and address of the device
+    #[clap(long = "all", conflicts_with_all = ["channel_dir", "raw_dir"])]
+    all: bool,
+
+    /// Exit if no device is present or the device disconnects instead of
+    /// waiting for it
+    #[clap(long = "no-wait")]
+    no_wait: bool,
+
+    /// Read the log by control transfers even if the interface has a bulk
+    /// endpoint
+    #[clap(long = "control")]
+    control: bool,
+
+    /// Grant the device credit for BYTES of bulk data not yet read, for
+    /// devices using credit-based flow control
+    #[clap(long = "credit", value_name = "BYTES")]
+    credit: Option<u16>,
+
     /// Show version information
     #[clap(long = "version")]
     version_info: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Find devices with log interface
-fn find_devices(devices: &'_ DeviceList<Context>) -> impl Iterator<Item = DeviceInfo> + '_ {
-    devices
+#[derive(Subcommand)]
+enum Command {
+    /// Measure the control transfer round-trip time using the echo request
+    Ping {
+        /// Number of echo requests
+        #[clap(short = 'c', long = "count", default_value_t = 4)]
+        count: u32,
+
+        /// Number of data bytes per request
+        #[clap(short = 's', long = "size", default_value_t = 64)]
+        size: u16,
+
+        /// Use the time echo request and estimate the offset between the
+        /// device clock and the host clock
+        #[clap(long = "clock")]
+        clock: bool,
+    },
+
+    /// Check the protocol features supported by the device
+    Selftest,
+
+    /// Pause logging on the device, keeping the buffered log
+    Pause,
+
+    /// Resume logging on the device
+    Resume,
+
+    /// Set the maximum log level of the device
+    Level {
+        #[clap(value_enum)]
+        level: control::Level,
+    },
+
+    /// Discard the log data buffered on the device
+    Clear,
+
+    /// Show the statistics of the log buffer of the device
+    Stats,
+
+    /// Send data to the OUT endpoint of the log interface
+    Send {
+        /// Data to be sent, as text or, with --hex, as hex digits
+        data: String,
+
+        /// Interpret the data as hex digits
+        #[clap(long = "hex")]
+        hex: bool,
+    },
+
+    /// Decode binary test vectors and compare with golden text outputs
+    TestVectors {
+        /// Directory containing the test vectors
+        #[clap(short = 'd', long = "dir", default_value = test_vectors::DEFAULT_DIR)]
+        dir: PathBuf,
+
+        /// Rewrite the golden outputs instead of comparing
+        #[clap(long = "update")]
+        update: bool,
+    },
+}
+
+/// Exit after a control request, reporting errors
+///
+/// A stalled request means that the device does not support `feature`.
+fn exit_with(res: Result<(), rusb::Error>, feature: &str) -> ! {
+    match res {
+        Ok(()) => exit(0),
+        Err(rusb::Error::Pipe) => {
+            eprintln!("Error: device does not support {feature}");
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            exit(1);
+        }
+    }
+}
+
+/// Warn if the device speaks a newer protocol than this reader
+///
+/// The version cannot be read while another driver has claimed the log
+/// interface, which is not an error.
+fn check_protocol_version(device_info: &DeviceInfo) {
+    if let Ok(version) = control::get_version(device_info) {
+        if version > control::PROTOCOL_VERSION {
+            eprintln!(
+                "Warning: device speaks protocol version {version}, this reader supports up to \
+                 version {}; consider updating usb-logread",
+                control::PROTOCOL_VERSION
+            );
+        }
+    }
+}
+
+/// Read a string descriptor
+///
+/// The string is read in the language `lang_id` if the device supports it and
+/// in the first supported language otherwise. Falls back to an ASCII read if
+/// the device does not report its languages.
+fn read_string(handle: &DeviceHandle<Context>, index: u8, lang_id: Option<u16>) -> Option<String> {
+    let languages = handle.read_languages(TIMEOUT).unwrap_or_default();
+    let language = languages
+        .iter()
+        .find(|lang| Some(lang.lang_id()) == lang_id)
+        .or(languages.first());
+    language
+        .and_then(|lang| handle.read_string_descriptor(*lang, index, TIMEOUT).ok())
+        .or_else(|| handle.read_string_descriptor_ascii(index).ok())
+}
+
+/// Describe a device by its location, IDs and names
+fn describe(device_info: &DeviceInfo) -> Result<String, rusb::Error> {
+    let dev = device_info.device();
+    let bus = dev.bus_number();
+    let addr = dev.address();
+    let desc = dev.device_descriptor()?;
+    let vid = desc.vendor_id();
+    let pid = desc.product_id();
+    let handle = dev.open()?;
+    let mut names = vec![];
+    let indices = [desc.manufacturer_string_index(), desc.product_string_index()];
+    for index in indices.into_iter().flatten() {
+        if let Some(name) = read_string(&handle, index, None) {
+            names.push(name);
+        }
+    }
+    let names_str = names
         .iter()
-        .filter_map(|dev| dev.open().ok())
-        .filter_map(|handle| {
-            let dev = handle.device();
-            dev.active_config_descriptor().ok().and_then(|conf_desc| {
-                conf_desc.interfaces().find_map(|iface| {
-                    iface.descriptors().find_map(|if_desc| {
-                        if_desc
-                            .description_string_index()
-                            .and_then(|string_index| {
-                                handle.read_string_descriptor_ascii(string_index).ok()
-                            })
-                            .and_then(|if_name| {
-                                (if_name == INTERFACE_NAME).then(|| {
-                                    let ep = if_desc.endpoint_descriptors().find(|ep_desc| {
-                                        ep_desc.direction() == Direction::In
-                                            && ep_desc.transfer_type() == TransferType::Bulk
-                                    });
-                                    match ep {
-                                        Some(ep_desc) => DeviceInfo::bulk(
-                                            dev.clone(),
-                                            iface.number(),
-                                            ep_desc.address(),
-                                        ),
-                                        None => DeviceInfo::control(dev.clone(), iface.number()),
-                                    }
-                                })
-                            })
-                    })
+        .map(String::from)
+        .reduce(|a, b| format!("{a} - {b}"))
+        .map(|s| format!(": {s}"))
+        .unwrap_or_default();
+    let serial_str = desc
+        .serial_number_string_index()
+        .and_then(|index| read_string(&handle, index, None))
+        .map(|sn| format!(", serial {sn}"))
+        .unwrap_or_default();
+    let port = select::port_path(dev);
+    Ok(format!(
+        "Bus {bus:03} Device {addr:03} (port {port}): {vid:04x}:{pid:04x}{names_str}{serial_str}"
+    ))
+}
+
+/// Names of the log channel interfaces of a device
+///
+/// These are the interfaces named `base` or `base` followed by `-` and the
+/// channel name.
+fn log_channels(handle: &DeviceHandle<Context>, base: &str) -> Vec<String> {
+    let Ok(conf_desc) = handle.device().active_config_descriptor() else {
+        return vec![];
+    };
+    let mut channels = vec![];
+    for if_desc in conf_desc.interfaces().flat_map(|iface| iface.descriptors()) {
+        let Some(string_index) = if_desc.description_string_index() else {
+            continue;
+        };
+        let Some(if_name) = read_string(handle, string_index, Some(LANG_ID_EN_US)) else {
+            continue;
+        };
+        let is_channel = if_name == base
+            || if_name.strip_prefix(base).is_some_and(|suffix| suffix.starts_with('-'));
+        // alternate settings of an interface share its name
+        if is_channel && !channels.contains(&if_name) {
+            channels.push(if_name);
+        }
+    }
+    channels
+}
+
+/// Find the devices whose log interface is selected
+///
+/// With `control`, the log is to be read from bulk and isochronous
+/// interfaces by control transfers.
+fn select_devices(
+    monitor: &mut hotplug::Monitor,
+    selected: &impl Fn(&DeviceInfo) -> bool,
+    control: bool,
+) -> Vec<DeviceInfo> {
+    let mut devices: Vec<DeviceInfo> = monitor.devices().into_iter().filter(selected).collect();
+    if control {
+        devices.iter_mut().for_each(read_by_control);
+    }
+    devices
+}
+
+/// Read the log of a bulk or an isochronous interface by control transfers
+fn read_by_control(device_info: &mut DeviceInfo) {
+    if let IfaceType::Bulk(_) | IfaceType::Isochronous(_) = device_info.iface_type {
+        // the streaming alternate setting is not needed
+        device_info.iface_type = IfaceType::Control;
+        device_info.alt_setting = 0;
+    }
+}
+
+/// Wait until a selected device is present
+///
+/// Returns None if stopped by Ctrl-C.
+fn wait_for_device(
+    monitor: &mut hotplug::Monitor,
+    selected: &impl Fn(&DeviceInfo) -> bool,
+    control: bool,
+) -> Option<DeviceInfo> {
+    while !STOP.load(Ordering::Relaxed) {
+        if let Some(device_info) = select_devices(monitor, selected, control).into_iter().next() {
+            return Some(device_info);
+        }
+        monitor.next_event(WAIT_INTERVAL);
+    }
+    None
+}
+
+/// Stop on Ctrl-C, so that the reader can detach from the device
+fn stop_on_ctrlc() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| ctrlc::set_handler(|| STOP.store(true, Ordering::Relaxed)).unwrap());
+}
+
+/// Find the log interface in the active configuration of a device
+///
+/// Of the alternate settings of the log interface, the first one having the
+/// preferred kind of IN endpoint is chosen.
+fn find_log_interface(handle: &DeviceHandle<Context>) -> Option<DeviceInfo> {
+    let dev = handle.device();
+    dev.active_config_descriptor().ok().and_then(|conf_desc| {
+        conf_desc.interfaces().find_map(|iface| {
+            iface
+                .descriptors()
+                .filter_map(|if_desc| {
+                    let string_index = if_desc.description_string_index()?;
+                    let if_name = read_string(handle, string_index, Some(LANG_ID_EN_US))?;
+                    if if_name != interface_name() {
+                        return None;
+                    }
+                    // a bulk endpoint is preferred over an interrupt or an
+                    // isochronous endpoint, control transfers are the fallback
+                    let ep_in = |transfer_type| {
+                        if_desc.endpoint_descriptors().find(|ep_desc| {
+                            ep_desc.direction() == Direction::In
+                                && ep_desc.transfer_type() == transfer_type
+                        })
+                    };
+                    // an interrupt endpoint of one byte only notifies of new
+                    // data of a control channel
+                    let notify_ep = ep_in(TransferType::Interrupt)
+                        .filter(|ep_desc| ep_desc.max_packet_size() == 1)
+                        .map(|ep_desc| ep_desc.address());
+                    let ep_data = ep_in(TransferType::Interrupt)
+                        .filter(|ep_desc| ep_desc.max_packet_size() > 1);
+                    let (rank, iface_type) = if let Some(ep_desc) = ep_in(TransferType::Bulk) {
+                        (2, IfaceType::Bulk(ep_desc.address()))
+                    } else if let Some(ep_desc) = ep_data {
+                        if if_desc.class_code() == CLASS_HID {
+                            (1, IfaceType::Hid(ep_desc.address()))
+                        } else {
+                            (1, IfaceType::Interrupt(ep_desc.address()))
+                        }
+                    } else if let Some(ep_desc) = ep_in(TransferType::Isochronous) {
+                        (1, IfaceType::Isochronous(ep_desc.address()))
+                    } else {
+                        (0, IfaceType::Control)
+                    };
+                    let mut info = DeviceInfo::with_type(dev.clone(), iface.number(), iface_type);
+                    info.alt_setting = if_desc.setting_number();
+                    info.notify_ep = notify_ep;
+                    Some((rank, info))
                 })
-            })
+                .fold(None, |best: Option<(u8, DeviceInfo)>, (rank, info)| match best {
+                    Some(best) if best.0 >= rank => Some(best),
+                    _ => Some((rank, info)),
+                })
+                .map(|(_, info)| info)
         })
+    })
+}
+
+/// Claim the log interface again after the device changed its configuration
+///
+/// Waits for the log interface to reappear in the active configuration, e.g.
+/// after the device returned from a DFU alternate setting. The interface
+/// number and endpoint may differ from the previous ones.
+fn reclaim(handle: &DeviceHandle<Context>, device_info: &DeviceInfo) -> Result<DeviceInfo, rusb::Error> {
+    handle.release_interface(device_info.iface_id).ok();
+    let start = Instant::now();
+    loop {
+        if let Some(info) = find_log_interface(handle) {
+            if info.claim(handle).is_ok() {
+                return Ok(info);
+            }
+        }
+        if start.elapsed() > RECLAIM_TIMEOUT || STOP.load(Ordering::Relaxed) {
+            return Err(rusb::Error::NotFound);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
 }
 
-fn read_control_log_loop(device_info: &DeviceInfo) -> Result<(), rusb::Error> {
+fn read_control_log_loop(
+    device_info: &DeviceInfo,
+    decoder: &mut Decoder,
+    out: &mut impl Write,
+) -> Result<(), rusb::Error> {
     assert!(matches!(device_info.iface_type(), IfaceType::Control));
 
     let mut buf = [0; 1024];
     let dev = device_info.device();
     let handle = dev.open()?;
-    let iface = device_info.iface_id;
+    let mut iface = device_info.iface_id;
+    let mut notify_ep = device_info.notify_ep;
     handle.claim_interface(iface)?;
-    let mut stdout = std::io::stdout();
+    control::set_reader(&handle, iface, true);
     let bus = dev.bus_number();
     let addr = dev.address();
     let dev_desc = dev.device_descriptor()?;
     let vid = dev_desc.vendor_id();
     let pid = dev_desc.product_id();
-    println!(
+    eprintln!(
         "Reading USB log channel from device {vid:04x}:{pid:04x} on bus {bus} at address {addr}"
     );
-    loop {
-        let request_type = rusb::request_type(
-            Direction::In,
-            rusb::RequestType::Vendor,
-            rusb::Recipient::Interface,
-        );
-        let res = handle.read_control(request_type, 0, 0, iface as u16, &mut buf, TIMEOUT);
-        match res {
-            Ok(len) => {
-                stdout.write_all(&buf[..len]).unwrap();
+    let read_next = control::get_version(device_info)
+        .is_ok_and(|version| version >= control::READ_NEXT_VERSION);
+    let mut interval = POLL_INTERVAL;
+    while !STOP.load(Ordering::Relaxed) {
+        let res = if read_next {
+            // each read tells how much data is left, so that a burst is read
+            // back to back and an idle device is polled slowly
+            control::read_next(&handle, iface, &mut buf).map(|(remaining, data)| {
+                interval = if remaining > 0 {
+                    Duration::ZERO
+                } else if data.is_empty() {
+                    (interval * 2).clamp(POLL_INTERVAL, MAX_POLL_INTERVAL)
+                } else {
+                    POLL_INTERVAL
+                };
+                decoder.decode(data, out).unwrap();
+            })
+        } else {
+            // devices telling how much data they have are polled fast while
+            // busy and slowly while idle
+            let available = control::get_available(&handle, iface);
+            if available == Some(0) {
+                wait_for_data(&handle, notify_ep, interval);
+                interval = (interval * 2).clamp(POLL_INTERVAL, MAX_POLL_INTERVAL);
+                continue;
             }
+            // the rest is read right away if it does not fit into one read
+            interval = match available {
+                Some(n) if n as usize > buf.len() => Duration::ZERO,
+                _ => POLL_INTERVAL,
+            };
+            let request_type = rusb::request_type(
+                Direction::In,
+                rusb::RequestType::Vendor,
+                rusb::Recipient::Interface,
+            );
+            handle
+                .read_control(request_type, 0, 0, iface as u16, &mut buf, TIMEOUT)
+                .map(|len| decoder.decode(&buf[..len], out).unwrap())
+        };
+        match res {
+            Ok(()) => (),
             Err(rusb::Error::Timeout) => (),
+            Err(rusb::Error::NoDevice) => return Err(rusb::Error::NoDevice),
             Err(e) => {
-                eprintln!("Error in Reading from USB: {e}");
-                exit(1);
+                // the device may have changed its configuration
+                eprintln!("Error in Reading from USB: {e}, claiming interface again");
+                let info = reclaim(&handle, &DeviceInfo::control(dev.clone(), iface))?;
+                // bulk and isochronous interfaces answer control reads as
+                // well
+                if matches!(info.iface_type(), IfaceType::Interrupt(_) | IfaceType::Hid(_)) {
+                    return Err(rusb::Error::NotSupported);
+                }
+                iface = info.iface_id;
+                notify_ep = info.notify_ep;
+                control::set_reader(&handle, iface, true);
+                decoder.resync(out).unwrap();
             }
         }
-        std::thread::sleep(Duration::from_millis(10));
+        wait_for_data(&handle, notify_ep, interval);
+    }
+    control::set_reader(&handle, iface, false);
+    Ok(())
+}
+
+/// Wait for `timeout` before polling the device for data again
+///
+/// Devices with a notification endpoint end the wait early when data
+/// arrives.
+fn wait_for_data(handle: &DeviceHandle<Context>, notify_ep: Option<u8>, timeout: Duration) {
+    match notify_ep {
+        // a timeout of zero would wait forever
+        Some(_) if timeout.is_zero() => (),
+        Some(ep) => {
+            handle.read_interrupt(ep, &mut [0], timeout).ok();
+        }
+        None => std::thread::sleep(timeout),
     }
 }
 
-fn read_bulk_log_loop(device_info: &DeviceInfo) -> Result<(), rusb::Error> {
-    assert!(matches!(device_info.iface_type, IfaceType::Bulk(_)));
+/// Maximum packet size of the endpoint `ep` in the active configuration
+fn max_packet_size(dev: &Device<Context>, ep: u8) -> Option<usize> {
+    let conf_desc = dev.active_config_descriptor().ok()?;
+    conf_desc.interfaces().flat_map(|iface| iface.descriptors()).find_map(|if_desc| {
+        if_desc
+            .endpoint_descriptors()
+            .find(|ep_desc| ep_desc.address() == ep)
+            .map(|ep_desc| ep_desc.max_packet_size() as usize)
+    })
+}
+
+/// Read the log from a bulk, interrupt or isochronous IN endpoint
+///
+/// With `credit`, the device is granted credit for that many bytes at start
+/// and, as the data is read, for each byte received from the bulk endpoint.
+fn read_endpoint_log_loop(
+    device_info: &DeviceInfo,
+    decoder: &mut Decoder,
+    out: &mut impl Write,
+    credit: Option<u16>,
+) -> Result<(), rusb::Error> {
+    let mut iface_type = device_info.iface_type();
+    let mut ep = match iface_type {
+        IfaceType::Bulk(ep)
+        | IfaceType::Interrupt(ep)
+        | IfaceType::Hid(ep)
+        | IfaceType::Isochronous(ep) => ep,
+        IfaceType::Control => panic!("log interface has no IN endpoint"),
+    };
+    let mut sequence = iso::Sequence::default();
 
     let dev = device_info.device();
+    // interrupt transfers end early only with a short packet, so that the
+    // packets are read one at a time
+    let mut packet_size = max_packet_size(dev, ep);
     let handle = dev.open()?;
-    let ep = match device_info.iface_type() {
-        IfaceType::Bulk(ep) => ep,
-        _ => 0,
+    let mut iface = device_info.iface_id;
+    device_info.claim(&handle)?;
+    control::set_reader(&handle, iface, true);
+    let grant = |iface, iface_type, bytes| match (credit, iface_type) {
+        (Some(_), IfaceType::Bulk(_)) => control::grant_credit(&handle, iface, bytes),
+        _ => Ok(()),
     };
-    handle.claim_interface(device_info.iface_id).unwrap();
+    grant(iface, iface_type, credit.unwrap_or(0))?;
 
-    let mut stdout = std::io::stdout();
     let bus = dev.bus_number();
     let addr = dev.address();
     let dev_desc = dev.device_descriptor()?;
     let vid = dev_desc.vendor_id();
     let pid = dev_desc.product_id();
-    println!("Reading USB log channel from device {vid:04x}:{pid:04x} on bus {bus} at address {addr}, EP 0x{ep:02x}");
-    loop {
+    eprintln!("Reading USB log channel from device {vid:04x}:{pid:04x} on bus {bus} at address {addr}, {iface_type}");
+    while !STOP.load(Ordering::Relaxed) {
         let mut buf = [0; 1024];
-        match handle.read_bulk(ep, &mut buf, TIMEOUT) {
+        let res = match iface_type {
+            IfaceType::Interrupt(_) | IfaceType::Hid(_) => {
+                let len = packet_size.unwrap_or(buf.len()).min(buf.len());
+                handle.read_interrupt(ep, &mut buf[..len], TIMEOUT)
+            }
+            // the packets are decoded as they arrive, nothing is left in buf
+            IfaceType::Isochronous(_) => iso::read_packets(&handle, ep, TIMEOUT, |packet| {
+                sequence.decode(packet, decoder, out).unwrap();
+            })
+            .map(|()| 0),
+            _ => handle.read_bulk(ep, &mut buf, TIMEOUT),
+        };
+        match res {
             Ok(len) => {
-                stdout.write_all(&buf[..len]).unwrap();
+                decoder.decode(iface_type.payload(&buf[..len]), out).unwrap();
+                grant(iface, iface_type, len as u16)?;
             }
             Err(rusb::Error::Timeout) => (),
+            Err(rusb::Error::Pipe) => {
+                // the endpoint has been halted, e.g. across a configuration
+                // change; the device skips to the next record after the halt
+                // is cleared
+                eprintln!("Endpoint 0x{ep:02x} halted, resynchronizing");
+                handle.clear_halt(ep)?;
+                decoder.resync(out).unwrap();
+            }
+            Err(rusb::Error::NoDevice) => return Err(rusb::Error::NoDevice),
+            Err(e) => {
+                // the device may have changed its configuration
+                eprintln!("Error in Reading from USB: {e}, claiming interface again");
+                let info = reclaim(&handle, &DeviceInfo::with_type(dev.clone(), iface, iface_type))?;
+                let (IfaceType::Bulk(new_ep)
+                | IfaceType::Interrupt(new_ep)
+                | IfaceType::Hid(new_ep)
+                | IfaceType::Isochronous(new_ep)) = info.iface_type()
+                else {
+                    return Err(rusb::Error::NotSupported);
+                };
+                iface = info.iface_id;
+                iface_type = info.iface_type();
+                ep = new_ep;
+                packet_size = max_packet_size(dev, ep);
+                sequence.restart();
+                control::set_reader(&handle, iface, true);
+                // the device revokes the credit on a configuration change
+                grant(iface, iface_type, credit.unwrap_or(0))?;
+                decoder.resync(out).unwrap();
+            }
+        }
+    }
+    control::set_reader(&handle, iface, false);
+    Ok(())
+}
+
+/// Print the firmware version and log buffer size of a device, which is
+/// called `name`
+fn print_device_info(device_info: &DeviceInfo, name: &str) {
+    if let Ok(info) = control::get_info(device_info) {
+        let firmware = match info.firmware_version.as_str() {
+            "" => String::new(),
+            version => format!("firmware {version}, "),
+        };
+        eprintln!(
+            "{name} {firmware}protocol version {}, log buffer of {} bytes",
+            info.protocol_version, info.capacity
+        );
+    }
+}
+
+/// Read the log of a device until stopped or an error occurs
+fn read_log(
+    device_info: &DeviceInfo,
+    decoder: &mut Decoder,
+    out: &mut impl Write,
+    credit: Option<u16>,
+) -> Result<(), rusb::Error> {
+    match device_info.iface_type() {
+        IfaceType::Control => read_control_log_loop(device_info, decoder, out),
+        IfaceType::Bulk(_)
+        | IfaceType::Interrupt(_)
+        | IfaceType::Hid(_)
+        | IfaceType::Isochronous(_) => read_endpoint_log_loop(device_info, decoder, out, credit),
+    }
+}
+
+/// Create the decoder as given by the arguments
+fn new_decoder(args: &Args) -> Decoder {
+    let mut decoder = match &args.elf {
+        Some(path) => match defmt::Table::load(path) {
+            Ok(Some(table)) => Decoder::Defmt(defmt::DefmtDecoder::new(table)),
+            Ok(None) => Decoder::new(false),
+            Err(e) => {
+                eprintln!("Error: cannot load the defmt format strings: {e}");
+                exit(1);
+            }
+        },
+        None => Decoder::new(args.binary),
+    };
+    if let Some(key) = &args.key {
+        let key = decode::parse_hex(key).and_then(|key| key.try_into().ok());
+        let Some(key) = key else {
+            eprintln!("Error: the key must consist of {} hex digits", 2 * decrypt::KEY_LEN);
+            exit(1);
+        };
+        decoder = decoder.encrypted(decrypt::Decryptor::new(&key));
+    }
+    decoder.set_hide_diagnostics(args.no_diagnostics);
+    decoder.set_min_level(args.min_level);
+    decoder.set_level_tags(args.format == json::Format::Json);
+    if let Some(dir) = &args.raw_dir {
+        match raw::RawFiles::new(dir.clone()) {
+            Ok(files) => decoder.set_raw_files(files),
+            Err(e) => {
+                eprintln!("Error: cannot create directory for raw payloads: {e}");
+                exit(1);
+            }
+        }
+    }
+    decoder
+}
+
+/// Create the output file or stdout as given by the arguments
+fn new_output(args: &Args) -> Box<dyn Write + Send> {
+    match &args.output {
+        Some(path) => {
+            match output::Output::new(path.clone(), args.rotate_size, args.rotate_interval) {
+                Ok(output) => Box::new(output),
+                Err(e) => {
+                    eprintln!("Error: cannot create output file: {e}");
+                    exit(1);
+                }
+            }
+        }
+        None => Box::new(std::io::stdout()),
+    }
+}
+
+/// Returns true if the output is to be colored
+fn colored(args: &Args) -> bool {
+    // files are only colored on request
+    args.format == json::Format::Text
+        && args.color.enabled()
+        && (args.output.is_none() || matches!(args.color, color::When::Always))
+}
+
+/// Wrap `out` in the writers processing the lines of `device_info` as given
+/// by the arguments
+fn wrap_output(
+    args: &Args,
+    mut out: Box<dyn Write + Send>,
+    device_info: &DeviceInfo,
+) -> Box<dyn Write + Send> {
+    let json = args.format == json::Format::Json;
+    if colored(args) {
+        out = Box::new(color::Colorizer::new(out));
+    }
+    if json {
+        out = Box::new(json::Json::new(out, select::port_path(device_info.device())));
+    }
+    out = match &args.channel_dir {
+        Some(dir) => match demux::Demux::new(out, dir.clone()) {
+            Ok(demux) => Box::new(demux),
+            Err(e) => {
+                eprintln!("Error: cannot create directory for channels: {e}");
+                exit(1);
+            }
+        },
+        None => out,
+    };
+    if let Some(format) = args.timestamp {
+        out = Box::new(timestamp::Timestamps::new(out, format));
+    }
+    if !args.grep.is_empty() || !args.grep_v.is_empty() {
+        out = Box::new(grep::Grep::new(out, args.grep.clone(), args.grep_v.clone()));
+    }
+    if let Some(path) = &args.elf {
+        match symbols::Symbols::load(path) {
+            Ok(symbols) => out = Box::new(symbols::Symbolizer::new(out, symbols)),
             Err(e) => {
-                eprintln!("Error in Reading from USB: {e}");
+                eprintln!("Error: cannot load the symbols: {e}");
                 exit(1);
             }
         }
     }
+    out
 }
 
 fn main() {
@@ -196,55 +1056,192 @@ fn main() {
         exit(0);
     }
 
+    if args.format == json::Format::Json && args.timestamp.is_some() {
+        eprintln!("Error: --timestamp cannot be used with --format json, which has timestamps");
+        exit(1);
+    }
+
+    if let Some(Command::TestVectors { dir, update }) = &args.command {
+        match test_vectors::run(dir, *update) {
+            Ok(passed) => exit(if passed { 0 } else { 1 }),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                exit(1);
+            }
+        }
+    }
+
+    let interface_name = match &args.channel {
+        Some(channel) => format!("{}-{channel}", args.interface_name),
+        None => args.interface_name.clone(),
+    };
+    INTERFACE_NAME.set(interface_name).unwrap();
     let context = Context::new().unwrap();
-    let device_list = context.devices().unwrap();
-    let mut devices: Vec<DeviceInfo> = find_devices(&device_list).collect();
+    let mut monitor = hotplug::Monitor::new(&context);
 
     if args.list {
-        for dev_info in devices {
-            let dev = dev_info.device();
-            let bus = dev.bus_number();
-            let addr = dev.address();
-            let desc = dev.device_descriptor().unwrap();
-            let vid = desc.vendor_id();
-            let pid = desc.product_id();
-            let handle = dev.open().unwrap();
-            let mut names = vec![];
-            if let Ok(name) = handle.read_manufacturer_string_ascii(&desc) {
-                names.push(name);
-            }
-            if let Ok(name) = handle.read_product_string_ascii(&desc) {
-                names.push(name);
-            }
-            let names_str = names
-                .iter()
-                .map(String::from)
-                .reduce(|a, b| format!("{a} - {b}"))
-                .map(|s| format!(": {s}"))
+        for dev_info in monitor.devices() {
+            let channels = dev_info
+                .device()
+                .open()
+                .map(|handle| log_channels(&handle, &args.interface_name))
                 .unwrap_or_default();
-            println!("Bus {bus:03} Device {addr:03}: {vid:04x}:{pid:04x}{names_str}");
+            println!("{} [{}]", describe(&dev_info).unwrap(), channels.join(", "));
         }
         exit(0);
     }
 
-    if let Some(bus) = args.bus {
-        devices.retain(|d| d.device().bus_number() == bus);
-    }
-    if let Some(addr) = args.address {
-        devices.retain(|d| d.device().address() == addr);
+    let selection = select::Selection {
+        bus: args.bus,
+        address: args.address,
+        serial: args.serial.clone(),
+        vid_pid: args.device,
+        product: args.product.clone(),
+        port_path: args.port_path.clone(),
+    };
+    let selected = |d: &DeviceInfo| selection.matches(d);
+    if args.watch {
+        stop_on_ctrlc();
+        watch::watch(&mut monitor, selected);
+        exit(0);
     }
-
-    if devices.is_empty() {
-        println!("Error: no device found");
-        exit(1);
+    let devices = select_devices(&mut monitor, &selected, args.control);
+    if args.all {
+        if args.command.is_some() {
+            eprintln!("Error: --all only applies to reading the log");
+            exit(1);
+        }
+        stop_on_ctrlc();
+        let out = new_output(&args);
+        multi::read_all(&args, &mut monitor, &selected, devices, out);
+        exit(0);
     }
     if devices.len() > 1 {
-        println!("Warning: there are multiple log channel interfaces.");
+        eprintln!(
+            "Warning: there are multiple log channel interfaces, reading the first one; \
+             --all reads all of them"
+        );
+    }
+    let mut selected_device = match devices.into_iter().next() {
+        Some(device_info) => device_info,
+        None if args.no_wait || args.command.is_some() => {
+            eprintln!("Error: no device found");
+            exit(1);
+        }
+        None => {
+            eprintln!("Waiting for a device with a log interface, press Ctrl-C to stop");
+            stop_on_ctrlc();
+            match wait_for_device(&mut monitor, &selected, args.control) {
+                Some(device_info) => device_info,
+                None => exit(0),
+            }
+        }
+    };
+    check_protocol_version(&selected_device);
+
+    match args.command {
+        Some(Command::Ping { count, size, clock }) => {
+            match ping::ping(&selected_device, count, size, clock) {
+                Ok(()) => exit(0),
+                Err(rusb::Error::Pipe) => exit(1),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    exit(1);
+                }
+            }
+        }
+        Some(Command::Selftest) => match selftest::selftest(&selected_device) {
+            Ok(passed) => exit(if passed { 0 } else { 1 }),
+            Err(e) => {
+                eprintln!("Error: {e}");
+                exit(1);
+            }
+        },
+        Some(command @ (Command::Pause | Command::Resume)) => {
+            let enabled = matches!(command, Command::Resume);
+            let res = control::set_enabled(&selected_device, enabled);
+            exit_with(res, "pausing the log");
+        }
+        Some(Command::Level { level }) => {
+            let res = control::set_level(&selected_device, level);
+            exit_with(res, "setting the log level");
+        }
+        Some(Command::Clear) => {
+            exit_with(control::clear(&selected_device), "clearing the log");
+        }
+        Some(Command::Stats) => {
+            let res = control::get_stats(&selected_device).map(|stats| {
+                println!("dropped bytes: {}", stats.dropped_bytes);
+                if let Some(high_water_mark) = stats.high_water_mark {
+                    println!("high-water mark: {high_water_mark} bytes");
+                }
+                if let Some(total_bytes) = stats.total_bytes {
+                    println!("total bytes: {total_bytes}");
+                }
+                // only known by devices measuring it
+                let latency = control::get_latency(&selected_device);
+                if let Some(latency) = latency.ok().filter(|latency| latency.samples > 0) {
+                    println!(
+                        "latency: min {} us, avg {} us, max {} us ({} records)",
+                        latency.min_us, latency.avg_us, latency.max_us, latency.samples
+                    );
+                }
+            });
+            exit_with(res, "statistics");
+        }
+        Some(Command::Send { data, hex }) => {
+            let data = if hex {
+                let Some(data) = send::parse_hex(&data) else {
+                    eprintln!("Error: invalid hex data");
+                    exit(1);
+                };
+                data
+            } else {
+                data.into_bytes()
+            };
+            match send::send(&selected_device, &data) {
+                Ok(()) => exit(0),
+                Err(rusb::Error::NotSupported) => {
+                    eprintln!("Error: log interface does not accept commands");
+                    exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    exit(1);
+                }
+            }
+        }
+        Some(Command::TestVectors { .. }) | None => (),
     }
-    let selected_device = &devices[0];
 
-    match selected_device.iface_type() {
-        IfaceType::Control => read_control_log_loop(selected_device).unwrap(),
-        IfaceType::Bulk(_) => read_bulk_log_loop(selected_device).unwrap(),
+    stop_on_ctrlc();
+    let mut decoder = new_decoder(&args);
+    let mut out = wrap_output(&args, new_output(&args), &selected_device);
+    let res = loop {
+        print_device_info(&selected_device, "Device");
+        let res = read_log(&selected_device, &mut decoder, &mut out, args.credit);
+        let Err(e) = res else {
+            break res;
+        };
+        if args.no_wait || STOP.load(Ordering::Relaxed) {
+            break Err(e);
+        }
+        eprintln!("Device lost ({e}), waiting for it to reappear");
+        // the records in transit were cut off
+        decoder.resync(&mut out).unwrap();
+        // the removal of the device may not have been noticed yet
+        std::thread::sleep(WAIT_INTERVAL);
+        match wait_for_device(&mut monitor, &selected, args.control) {
+            Some(device_info) => selected_device = device_info,
+            None => break Ok(()),
+        }
+        check_protocol_version(&selected_device);
+    };
+    if let Some(summary) = decoder.loss_summary() {
+        eprintln!("{summary}");
+    }
+    if let Err(e) = res {
+        eprintln!("Error in Reading from USB: {e}");
+        exit(1);
     }
 }
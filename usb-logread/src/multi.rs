@@ -0,0 +1,192 @@
+//! Reading from several devices at once
+//!
+//! With `--all`, the log of each selected device is read by a thread of its
+//! own, which has a device handle and a decoder of its own. Devices arriving
+//! later are read as well. Lines are prefixed with the serial number of the
+//! device or, if it has none, with its bus number and address, e.g. `[1:4] `,
+//! and each line is written to the common output as a whole, so that the
+//! lines of different devices do not mix. If the output is colored, each
+//! device keeps a prefix color of its own, also when it is plugged in again.
+//! With `--format json`, lines have no prefix since the records name the
+//! device.
+//!
+//! A device that is lost is read again when it reappears. With `--no-wait`,
+//! only the devices present at the start are read, and reading ends when all
+//! of them are gone.
+//!
+
+use crate::hotplug::{Event, Monitor};
+use crate::{
+    check_protocol_version, color, colored, json, new_decoder, print_device_info, read_by_control,
+    read_log, select, wrap_output, Args, DeviceInfo, STOP, WAIT_INTERVAL,
+};
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Common output of several threads
+type SharedOutput = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// Writer passing complete, prefixed lines to a shared output
+pub struct Shared<W: Write> {
+    out: Arc<Mutex<W>>,
+    prefix: String,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+}
+
+impl<W: Write> Shared<W> {
+    pub fn new(out: Arc<Mutex<W>>, prefix: String) -> Self {
+        Shared {
+            out,
+            prefix,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Shared<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                let mut out = self.out.lock().unwrap();
+                out.write_all(self.prefix.as_bytes())?;
+                out.write_all(&self.line)?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+/// Bus number and address identifying a device while it is connected
+fn key(device_info: &DeviceInfo) -> (u8, u8) {
+    let dev = device_info.device();
+    (dev.bus_number(), dev.address())
+}
+
+/// Name of a device in the prefix of its lines
+fn identifier(device_info: &DeviceInfo) -> String {
+    let dev = device_info.device();
+    select::serial_number(dev)
+        .unwrap_or_else(|| format!("{}:{}", dev.bus_number(), dev.address()))
+}
+
+/// Read the log of `devices` and of the selected devices arriving later
+/// until stopped
+pub fn read_all(
+    args: &Args,
+    monitor: &mut Monitor,
+    selected: &impl Fn(&DeviceInfo) -> bool,
+    devices: Vec<DeviceInfo>,
+    out: Box<dyn Write + Send>,
+) {
+    let out = Arc::new(Mutex::new(out));
+    if devices.is_empty() {
+        if args.no_wait {
+            eprintln!("Error: no device found");
+            return;
+        }
+        eprintln!("Waiting for devices with a log interface, press Ctrl-C to stop");
+    }
+    // identifiers of the devices read so far, whose positions select their
+    // colors
+    let mut ids = Vec::new();
+    let mut readers: Vec<((u8, u8), JoinHandle<()>)> = devices
+        .into_iter()
+        .map(|device_info| (key(&device_info), spawn(args, device_info, &out, &mut ids)))
+        .collect();
+    // devices that arrived while the reader of their predecessor at the same
+    // address was still running
+    let mut arrived: Vec<DeviceInfo> = Vec::new();
+    while !STOP.load(Ordering::Relaxed) {
+        readers.retain(|(_, reader)| !reader.is_finished());
+        if args.no_wait && readers.is_empty() {
+            break;
+        }
+        let (busy, ready): (Vec<_>, Vec<_>) = arrived
+            .drain(..)
+            .partition(|device_info| readers.iter().any(|(k, _)| *k == key(device_info)));
+        arrived = busy;
+        for device_info in ready {
+            readers.push((key(&device_info), spawn(args, device_info, &out, &mut ids)));
+        }
+        if let Some(Event::Arrived(mut device_info)) = monitor.next_event(WAIT_INTERVAL) {
+            if !args.no_wait && selected(&device_info) {
+                if args.control {
+                    read_by_control(&mut device_info);
+                }
+                arrived.push(device_info);
+            }
+        }
+    }
+    for (_, reader) in readers {
+        reader.join().ok();
+    }
+}
+
+/// Start reading the log of a device
+///
+/// `ids` are the identifiers of the devices read so far, to which the one of
+/// this device is added.
+fn spawn(
+    args: &Args,
+    device_info: DeviceInfo,
+    out: &SharedOutput,
+    ids: &mut Vec<String>,
+) -> JoinHandle<()> {
+    let id = identifier(&device_info);
+    let index = ids.iter().position(|known| *known == id).unwrap_or_else(|| {
+        ids.push(id.clone());
+        ids.len() - 1
+    });
+    let prefix = match args.format {
+        json::Format::Json => String::new(),
+        json::Format::Text => {
+            let tag = format!("[{id}]");
+            let tag = if colored(args) { color::device(&tag, index) } else { tag };
+            format!("{tag} ")
+        }
+    };
+    let mut out = wrap_output(args, Box::new(Shared::new(out.clone(), prefix)), &device_info);
+    let mut decoder = new_decoder(args);
+    let credit = args.credit;
+    thread::spawn(move || {
+        check_protocol_version(&device_info);
+        print_device_info(&device_info, &format!("Device {id}:"));
+        let res = read_log(&device_info, &mut decoder, &mut out, credit);
+        // the records in transit were cut off
+        decoder.resync(&mut out).ok();
+        out.flush().ok();
+        if let Some(summary) = decoder.loss_summary() {
+            summary.lines().for_each(|line| eprintln!("{id}: {line}"));
+        }
+        match res {
+            Err(e) if !STOP.load(Ordering::Relaxed) => eprintln!("Device {id} lost ({e})"),
+            _ => (),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_not_mixed() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut a = Shared::new(out.clone(), "[A] ".to_string());
+        let mut b = Shared::new(out.clone(), "[B] ".to_string());
+        a.write_all(b"one ").unwrap();
+        b.write_all(b"two\nthr").unwrap();
+        a.write_all(b"line\n").unwrap();
+        b.write_all(b"ee\n").unwrap();
+        assert_eq!(*out.lock().unwrap(), b"[B] two\n[A] one line\n[B] three\n");
+    }
+}
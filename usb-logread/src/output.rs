@@ -0,0 +1,197 @@
+//! Output of the log to a file
+//!
+//! With `--output`, the log is written to a file instead of stdout. With
+//! `--rotate-size`, the file is rotated once it has reached the given size:
+//! it is renamed by appending the next free number, e.g. `capture.log.1`,
+//! `capture.log.2`, and a new file is started. With `--rotate-interval`, the
+//! file is also rotated when the first line after a multiple of the interval
+//! in local time arrives, e.g. each full hour, giving one file per interval
+//! unless it also reaches the size limit. Files are rotated at line
+//! boundaries only, so no line is split between two files.
+//!
+
+use chrono::Local;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Log file rotated by size and time
+pub struct Output {
+    path: PathBuf,
+    file: File,
+    /// Number of bytes in the current file
+    size: u64,
+    /// Size from which on the file is rotated
+    rotate_size: Option<u64>,
+    /// Interval of rotation in seconds
+    rotate_interval: Option<i64>,
+    /// Local time in seconds since the epoch at which the file is rotated
+    next_rotation: i64,
+    /// The next byte starts a new line
+    line_start: bool,
+}
+
+impl Output {
+    /// Create the file at `path`, truncating an existing one
+    pub fn new(
+        path: PathBuf,
+        rotate_size: Option<u64>,
+        rotate_interval: Option<Duration>,
+    ) -> io::Result<Self> {
+        let file = File::create(&path)?;
+        let rotate_interval = rotate_interval.map(|interval| interval.as_secs() as i64);
+        Ok(Output {
+            path,
+            file,
+            size: 0,
+            rotate_size,
+            rotate_interval,
+            next_rotation: rotate_interval.map_or(0, |interval| next_boundary(now(), interval)),
+            line_start: true,
+        })
+    }
+
+    /// Write `buf`, which was received at the local time `now` in seconds
+    fn write_at(&mut self, buf: &[u8], now: i64) -> io::Result<()> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if let Some(interval) = self.rotate_interval {
+                if self.line_start && now >= self.next_rotation {
+                    self.next_rotation = next_boundary(now, interval);
+                    // no empty files are left behind by quiet intervals
+                    if self.size > 0 {
+                        self.rotate()?;
+                    }
+                }
+            }
+            let line_end = rest.iter().position(|&b| b == b'\n');
+            let len = line_end.map_or(rest.len(), |pos| pos + 1);
+            self.file.write_all(&rest[..len])?;
+            self.size += len as u64;
+            self.line_start = line_end.is_some();
+            rest = &rest[len..];
+            if self.line_start && self.rotate_size.is_some_and(|max| self.size >= max) {
+                self.rotate()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename the current file to the next free number and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = (1..)
+            .map(|n| numbered(&self.path, n))
+            .find(|path| !path.exists())
+            .unwrap();
+        fs::rename(&self.path, rotated)?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Path of the rotated file number `n`
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Local time in seconds since the epoch
+fn now() -> i64 {
+    let now = Local::now();
+    now.timestamp() + i64::from(now.offset().local_minus_utc())
+}
+
+/// First multiple of `interval` after `now`
+fn next_boundary(now: i64, interval: i64) -> i64 {
+    (now.div_euclid(interval) + 1) * interval
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(buf, now())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Parse a size in bytes with an optional suffix `K`, `M` or `G`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, factor) = match s.char_indices().last() {
+        Some((pos, 'K' | 'k')) => (&s[..pos], 1 << 10),
+        Some((pos, 'M')) => (&s[..pos], 1 << 20),
+        Some((pos, 'G')) => (&s[..pos], 1 << 30),
+        _ => (s, 1),
+    };
+    let size: u64 = digits.parse().map_err(|_| format!("invalid size: {s}"))?;
+    match size.checked_mul(factor) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(format!("invalid size: {s}")),
+    }
+}
+
+/// Parse an interval given as a number followed by `s`, `m`, `h` or `d`
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (digits, factor) = match s.char_indices().last() {
+        Some((pos, 's')) => (&s[..pos], 1),
+        Some((pos, 'm')) => (&s[..pos], 60),
+        Some((pos, 'h')) => (&s[..pos], 3600),
+        Some((pos, 'd')) => (&s[..pos], 86400),
+        _ => return Err(format!("invalid interval, e.g. 1h or 24h expected: {s}")),
+    };
+    let n: u64 = digits.parse().map_err(|_| format!("invalid interval: {s}"))?;
+    match n.checked_mul(factor) {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("invalid interval: {s}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_at_line_boundary() {
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert!(parse_size("0").is_err());
+
+        let dir = std::env::temp_dir().join(format!("usb-logread-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.log");
+        let mut output = Output::new(path.clone(), Some(10), None).unwrap();
+        for chunk in b"0123456789abc\nde\nfghijklm\nn".chunks(4) {
+            output.write_all(chunk).unwrap();
+        }
+        assert_eq!(fs::read(numbered(&path, 1)).unwrap(), b"0123456789abc\n");
+        assert_eq!(fs::read(numbered(&path, 2)).unwrap(), b"de\nfghijklm\n");
+        assert_eq!(fs::read(&path).unwrap(), b"n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_on_interval_boundary() {
+        assert_eq!(parse_interval("24h"), Ok(Duration::from_secs(86400)));
+        assert!(parse_interval("1").is_err());
+
+        let dir = std::env::temp_dir().join(format!("usb-logread-interval-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("soak.log");
+        let mut output = Output::new(path.clone(), None, Some(Duration::from_secs(3600))).unwrap();
+        output.next_rotation = 7200;
+        output.write_at(b"a\nb", 7199).unwrap();
+        // the line started before the boundary is completed first
+        output.write_at(b"c\nd\n", 7200).unwrap();
+        // quiet intervals leave no empty files
+        output.write_at(b"e\n", 18000).unwrap();
+        assert_eq!(output.next_rotation, 21600);
+        assert_eq!(fs::read(numbered(&path, 1)).unwrap(), b"a\nbc\n");
+        assert_eq!(fs::read(numbered(&path, 2)).unwrap(), b"d\n");
+        assert_eq!(fs::read(&path).unwrap(), b"e\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! Round-trip measurement using the echo request
+//!
+//! The device answers the echo request with wValue repeated up to the
+//! requested length. This requires the `echo` feature of the device library.
+//!
+//! The time echo request returns wValue followed by the current time of the
+//! device clock, which is used to estimate the offset between the device and
+//! the host clock. The sample with the shortest round trip gives the most
+//! accurate estimate, assuming that the device has read its clock halfway.
+//!
+
+use crate::DeviceInfo;
+use rusb::{Context, DeviceHandle, Direction};
+use std::time::{Duration, Instant, SystemTime};
+
+const ECHO_REQUEST: u8 = 1;
+const TIME_ECHO_REQUEST: u8 = 8;
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Send `count` echo requests of `size` bytes and print the round-trip times
+///
+/// With `clock`, time echo requests are sent instead, and the offset of the
+/// device clock is printed as well.
+pub fn ping(device_info: &DeviceInfo, count: u32, size: u16, clock: bool) -> Result<(), rusb::Error> {
+    let dev = device_info.device();
+    let handle = dev.open()?;
+    let iface = device_info.iface_id;
+    handle.claim_interface(iface)?;
+    let dev_desc = dev.device_descriptor()?;
+    let vid = dev_desc.vendor_id();
+    let pid = dev_desc.product_id();
+    if clock {
+        return ping_clock(&handle, iface, count);
+    }
+    println!("PING device {vid:04x}:{pid:04x} with {size} bytes of data");
+
+    let mut times = Vec::new();
+    for seq in 0..count {
+        let token = (std::process::id() as u16).wrapping_add(seq as u16);
+        let start = Instant::now();
+        let res = echo(&handle, iface, token, size);
+        let elapsed = start.elapsed();
+        match res {
+            Ok(valid) => {
+                let ms = elapsed.as_secs_f64() * 1e3;
+                if valid {
+                    println!("{size} bytes: seq={seq} time={ms:.3} ms");
+                    times.push(ms);
+                } else {
+                    println!("{size} bytes: seq={seq} time={ms:.3} ms (CORRUPTED)");
+                }
+            }
+            Err(rusb::Error::Pipe) => {
+                eprintln!("Error: device does not support the echo request");
+                return Err(rusb::Error::Pipe);
+            }
+            Err(e) => println!("seq={seq}: {e}"),
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    let received = times.len();
+    let loss = if count > 0 {
+        100.0 * (count as usize - received) as f64 / count as f64
+    } else {
+        0.0
+    };
+    println!("{count} requests, {received} valid responses, {loss:.0}% loss");
+    print_rtt(&times);
+    Ok(())
+}
+
+/// Print the minimum, average and maximum of round-trip times in ms
+fn print_rtt(times: &[f64]) {
+    if times.is_empty() {
+        return;
+    }
+    let min = times.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = times.iter().copied().fold(0.0, f64::max);
+    let avg = times.iter().sum::<f64>() / times.len() as f64;
+    println!("rtt min/avg/max = {min:.3}/{avg:.3}/{max:.3} ms");
+}
+
+/// Send `count` time echo requests and print the round-trip times and the
+/// clock offsets
+fn ping_clock(handle: &DeviceHandle<Context>, iface: u8, count: u32) -> Result<(), rusb::Error> {
+    println!("PING device clock");
+    let mut times = Vec::new();
+    // round-trip time and offset of the best sample
+    let mut best: Option<(f64, i64)> = None;
+    for seq in 0..count {
+        let token = (std::process::id() as u16).wrapping_add(seq as u16);
+        let start = SystemTime::now();
+        let res = time_echo(handle, iface, token);
+        let end = SystemTime::now();
+        match res {
+            Ok(Some(device_us)) => {
+                let start_us = unix_us(start);
+                let mid_us = start_us + (unix_us(end) - start_us) / 2;
+                let offset = device_us as i64 - mid_us;
+                let ms = end.duration_since(start).unwrap_or_default().as_secs_f64() * 1e3;
+                println!("seq={seq} time={ms:.3} ms offset={offset} us");
+                times.push(ms);
+                if best.is_none_or(|(best_ms, _)| ms < best_ms) {
+                    best = Some((ms, offset));
+                }
+            }
+            Ok(None) => println!("seq={seq}: corrupted response"),
+            Err(rusb::Error::Pipe) => {
+                eprintln!("Error: device does not support the time echo request or has no clock");
+                return Err(rusb::Error::Pipe);
+            }
+            Err(e) => println!("seq={seq}: {e}"),
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    print_rtt(&times);
+    if let Some((ms, offset)) = best {
+        let error_us = ms * 1e3 / 2.0;
+        println!("device clock = host clock {offset:+} us (+/- {error_us:.0} us)");
+    }
+    Ok(())
+}
+
+/// Microseconds since the Unix epoch
+fn unix_us(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_micros() as i64
+}
+
+/// Send one time echo request
+///
+/// Returns the device time in microseconds or None if the response is
+/// corrupted.
+pub fn time_echo(
+    handle: &DeviceHandle<Context>,
+    iface: u8,
+    token: u16,
+) -> Result<Option<u64>, rusb::Error> {
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let mut buf = [0; 10];
+    let len = handle.read_control(request_type, TIME_ECHO_REQUEST, token, iface as u16, &mut buf, TIMEOUT)?;
+    if len != buf.len() || buf[..2] != token.to_le_bytes() {
+        return Ok(None);
+    }
+    Ok(Some(u64::from_le_bytes(buf[2..].try_into().unwrap())))
+}
+
+/// Send one echo request
+///
+/// Returns true if the response has the expected length and contents.
+pub fn echo(
+    handle: &DeviceHandle<Context>,
+    iface: u8,
+    token: u16,
+    size: u16,
+) -> Result<bool, rusb::Error> {
+    let request_type = rusb::request_type(
+        Direction::In,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    let mut buf = vec![0; size as usize];
+    let len = handle.read_control(request_type, ECHO_REQUEST, token, iface as u16, &mut buf, TIMEOUT)?;
+    let pattern = token.to_le_bytes();
+    Ok(len == buf.len() && buf.iter().enumerate().all(|(i, &b)| b == pattern[i % 2]))
+}
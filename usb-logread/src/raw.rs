@@ -0,0 +1,40 @@
+//! Files receiving the raw binary payloads of the log stream
+//!
+//! The device can send opaque payloads, e.g. sensor dumps, tagged with a
+//! number along with the log. The payloads of each tag are appended to a file
+//! `raw-<tag>.bin` so that they can be processed by other tools.
+//!
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub struct RawFiles {
+    dir: PathBuf,
+    files: HashMap<u8, File>,
+}
+
+impl RawFiles {
+    /// Write the payloads to files in `dir`, which is created if needed
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(RawFiles {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Append a payload to the file of its tag
+    ///
+    /// The file is truncated when the first payload of a tag arrives.
+    pub fn write(&mut self, tag: u8, data: &[u8]) -> io::Result<()> {
+        let file = match self.files.entry(tag) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(File::create(self.dir.join(format!("raw-{tag}.bin")))?)
+            }
+        };
+        file.write_all(data)
+    }
+}
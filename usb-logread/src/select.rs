@@ -0,0 +1,123 @@
+//! Selection of the device to read from
+//!
+//! If several devices have a log interface, the one to read from is selected
+//! by its location on the bus, its serial number, its vendor and product ID,
+//! a part of its product name or the port it is plugged into. All given
+//! criteria must match. Bus number and address change when a device is
+//! plugged in again, while the other criteria identify the device itself or
+//! its port.
+//!
+//! The port path consists of the bus number and the port numbers along the
+//! hubs, e.g. `1-3.4` for port 4 of a hub plugged into port 3 of the root hub
+//! of bus 1, as Linux names the devices in sysfs.
+//!
+
+use crate::{read_string, DeviceInfo};
+use rusb::{Context, Device, DeviceDescriptor};
+
+/// Criteria a device must meet to be selected
+#[derive(Default)]
+pub struct Selection {
+    pub bus: Option<u8>,
+    pub address: Option<u8>,
+    pub serial: Option<String>,
+    /// Vendor ID and product ID
+    pub vid_pid: Option<(u16, u16)>,
+    /// Part of the product name, ignoring case
+    pub product: Option<String>,
+    /// Bus and port numbers, e.g. `1-3.4`
+    pub port_path: Option<String>,
+}
+
+impl Selection {
+    /// Returns true if the device meets all criteria
+    pub fn matches(&self, device_info: &DeviceInfo) -> bool {
+        let dev = device_info.device();
+        self.bus.is_none_or(|bus| dev.bus_number() == bus)
+            && self.address.is_none_or(|addr| dev.address() == addr)
+            && self.vid_pid.is_none_or(|(vid, pid)| {
+                dev.device_descriptor()
+                    .is_ok_and(|desc| desc.vendor_id() == vid && desc.product_id() == pid)
+            })
+            && self.serial.as_ref().is_none_or(|sn| serial_number(dev).as_ref() == Some(sn))
+            && self.product.as_ref().is_none_or(|part| {
+                device_string(dev, DeviceDescriptor::product_string_index)
+                    .is_some_and(|name| contains_ignoring_case(&name, part))
+            })
+            && self.port_path.as_ref().is_none_or(|path| port_path(dev) == *path)
+    }
+}
+
+/// String of a device, e.g. its serial number, whose index is returned by
+/// `index`, None if it has none or cannot be opened
+fn device_string(
+    device: &Device<Context>,
+    index: fn(&DeviceDescriptor) -> Option<u8>,
+) -> Option<String> {
+    let index = index(&device.device_descriptor().ok()?)?;
+    let handle = device.open().ok()?;
+    read_string(&handle, index, None)
+}
+
+/// Serial number of a device, None if it has none or cannot be opened
+pub fn serial_number(device: &Device<Context>) -> Option<String> {
+    device_string(device, DeviceDescriptor::serial_number_string_index)
+}
+
+fn contains_ignoring_case(s: &str, part: &str) -> bool {
+    s.to_lowercase().contains(&part.to_lowercase())
+}
+
+/// Port path of a device, e.g. `1-3.4`
+pub fn port_path(device: &Device<Context>) -> String {
+    let ports = device.port_numbers().unwrap_or_default();
+    let ports: Vec<String> = ports.iter().map(u8::to_string).collect();
+    format!("{}-{}", device.bus_number(), ports.join("."))
+}
+
+/// Parse a port path, normalizing the numbers, e.g. `001-03.4` to `1-3.4`
+pub fn parse_port_path(s: &str) -> Result<String, String> {
+    let invalid = || format!("port path expected, e.g. 1-3.4: {s}");
+    let (bus, ports) = s.split_once('-').ok_or_else(invalid)?;
+    let bus: u8 = bus.parse().map_err(|_| invalid())?;
+    let ports = ports
+        .split('.')
+        .map(|port| port.parse::<u8>().map(|port| port.to_string()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    Ok(format!("{bus}-{}", ports.join(".")))
+}
+
+/// Parse a vendor and product ID given as hex numbers separated by a colon
+pub fn parse_vid_pid(s: &str) -> Result<(u16, u16), String> {
+    let (vid, pid) = s.split_once(':').ok_or(format!("VID:PID expected, e.g. 1209:0001: {s}"))?;
+    let parse = |id: &str| u16::from_str_radix(id, 16).map_err(|_| format!("invalid ID: {id}"));
+    Ok((parse(vid)?, parse(pid)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vid_pid() {
+        assert_eq!(parse_vid_pid("1209:0001"), Ok((0x1209, 0x0001)));
+        assert_eq!(parse_vid_pid("CafE:b"), Ok((0xcafe, 0x000b)));
+        assert!(parse_vid_pid("1209").is_err());
+        assert!(parse_vid_pid("1209:10000").is_err());
+    }
+
+    #[test]
+    fn port_paths() {
+        assert_eq!(parse_port_path("1-3.4"), Ok("1-3.4".to_string()));
+        assert_eq!(parse_port_path("001-03.4"), Ok("1-3.4".to_string()));
+        assert!(parse_port_path("1-3.").is_err());
+        assert!(parse_port_path("1").is_err());
+    }
+
+    #[test]
+    fn product_part() {
+        assert!(contains_ignoring_case("Sensor Board rev B", "sensor board"));
+        assert!(!contains_ignoring_case("Sensor Board", "motor"));
+    }
+}
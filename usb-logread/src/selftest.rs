@@ -0,0 +1,170 @@
+//! Self-test of the protocol features of a device
+//!
+//! Each check exercises one capability of the log channel protocol against the
+//! connected device. The results are printed as a matrix so that regressions
+//! between firmware and host tool versions are easy to spot.
+//!
+
+use crate::decode::Decoder;
+use crate::{control, iso, ping, DeviceInfo, IfaceType};
+use rusb::{Context, DeviceHandle, Direction};
+use std::time::{Duration, Instant};
+
+const READ_PERIOD: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Result of a single check
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    /// The device does not implement the capability
+    Unsupported,
+    /// The check could not be carried out
+    Skipped(String),
+}
+
+struct Session<'a> {
+    device_info: &'a DeviceInfo,
+    handle: DeviceHandle<Context>,
+}
+
+type Check = fn(&Session) -> Outcome;
+
+/// Capabilities in the order they are checked
+const CHECKS: &[(&str, Check)] = &[
+    ("version", check_version),
+    ("read", check_read),
+    ("framing", check_framing),
+    ("echo", check_echo),
+    ("clock", check_clock),
+];
+
+/// Run all checks and print the results
+///
+/// Returns true if no check failed.
+pub fn selftest(device_info: &DeviceInfo) -> Result<bool, rusb::Error> {
+    let dev = device_info.device();
+    let handle = dev.open()?;
+    device_info.claim(&handle)?;
+    let dev_desc = dev.device_descriptor()?;
+    let vid = dev_desc.vendor_id();
+    let pid = dev_desc.product_id();
+    let transport = device_info.iface_type();
+    println!("Self-test of device {vid:04x}:{pid:04x} using {transport}");
+    println!();
+    println!("{:<12} {:<12} DETAILS", "CAPABILITY", "RESULT");
+
+    let session = Session {
+        device_info,
+        handle,
+    };
+    let mut passed = true;
+    for (name, check) in CHECKS {
+        let (result, details) = match check(&session) {
+            Outcome::Pass(details) => ("PASS", details),
+            Outcome::Fail(details) => {
+                passed = false;
+                ("FAIL", details)
+            }
+            Outcome::Unsupported => ("UNSUPPORTED", String::new()),
+            Outcome::Skipped(details) => ("SKIPPED", details),
+        };
+        println!("{name:<12} {result:<12} {details}");
+    }
+    Ok(passed)
+}
+
+/// Read log data for a short period
+fn read_some(session: &Session) -> Result<Vec<u8>, rusb::Error> {
+    let mut data = Vec::new();
+    let mut buf = [0; 1024];
+    let start = Instant::now();
+    let iface_type = session.device_info.iface_type();
+    let mut sequence = iso::Sequence::default();
+    while start.elapsed() < READ_PERIOD {
+        let res = match iface_type {
+            IfaceType::Control => {
+                let request_type = rusb::request_type(
+                    Direction::In,
+                    rusb::RequestType::Vendor,
+                    rusb::Recipient::Interface,
+                );
+                let iface = session.device_info.iface_id as u16;
+                session.handle.read_control(request_type, 0, 0, iface, &mut buf, TIMEOUT)
+            }
+            IfaceType::Bulk(ep) => session.handle.read_bulk(ep, &mut buf, TIMEOUT),
+            IfaceType::Interrupt(ep) | IfaceType::Hid(ep) => {
+                session.handle.read_interrupt(ep, &mut buf, TIMEOUT)
+            }
+            // lost packets show as framing errors
+            IfaceType::Isochronous(ep) => iso::read_packets(&session.handle, ep, TIMEOUT, |packet| {
+                data.extend_from_slice(sequence.check(packet).1);
+            })
+            .map(|()| 0),
+        };
+        match res {
+            Ok(len) => data.extend_from_slice(iface_type.payload(&buf[..len])),
+            Err(rusb::Error::Timeout) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(data)
+}
+
+fn check_version(session: &Session) -> Outcome {
+    match control::get_version(session.device_info) {
+        Ok(0) => Outcome::Unsupported,
+        Ok(version) if version <= control::PROTOCOL_VERSION => Outcome::Pass(format!("{version}")),
+        Ok(version) => Outcome::Fail(format!("{version} is newer than this reader")),
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+fn check_read(session: &Session) -> Outcome {
+    match read_some(session) {
+        Ok(data) => Outcome::Pass(format!("{} bytes received", data.len())),
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+fn check_framing(session: &Session) -> Outcome {
+    let data = match read_some(session) {
+        Ok(data) => data,
+        Err(e) => return Outcome::Fail(e.to_string()),
+    };
+    if data.is_empty() {
+        return Outcome::Skipped("no log data".to_string());
+    }
+    if !data.contains(&0) {
+        return Outcome::Pass("text".to_string());
+    }
+    let mut decoder = Decoder::new(true);
+    let mut text = Vec::new();
+    match decoder.decode(&data, &mut text) {
+        Ok(()) if !text.is_empty() => Outcome::Pass("binary".to_string()),
+        _ => Outcome::Fail("no valid frame".to_string()),
+    }
+}
+
+fn check_echo(session: &Session) -> Outcome {
+    let iface = session.device_info.iface_id;
+    for (token, size) in [(0x1234, 0), (0xa55a, 8), (0x0ff0, 64)] {
+        match ping::echo(&session.handle, iface, token, size) {
+            Ok(true) => (),
+            Ok(false) => return Outcome::Fail(format!("corrupted response to {size} bytes")),
+            Err(rusb::Error::Pipe) => return Outcome::Unsupported,
+            Err(e) => return Outcome::Fail(e.to_string()),
+        }
+    }
+    Outcome::Pass(String::new())
+}
+
+fn check_clock(session: &Session) -> Outcome {
+    let iface = session.device_info.iface_id;
+    match ping::time_echo(&session.handle, iface, 0x5aa5) {
+        Ok(Some(us)) => Outcome::Pass(format!("device time {us} us")),
+        Ok(None) => Outcome::Fail("corrupted response".to_string()),
+        Err(rusb::Error::Pipe) => Outcome::Unsupported,
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
@@ -0,0 +1,71 @@
+//! Sending data to the bulk OUT endpoint of the log interface
+//!
+//! The device passes the data to the application, which can interpret it as
+//! commands. Log interfaces without an OUT endpoint, such as the log channel
+//! based on control transfers, receive the data as vendor control requests
+//! instead, split into chunks that fit into the control buffer of the device.
+//!
+
+use crate::decode;
+use crate::DeviceInfo;
+use rusb::{Context, DeviceHandle, Direction, TransferType};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// First vendor request code passed on to the application by the device
+const FIRST_USER_REQUEST: u8 = 0x80;
+
+/// Maximum data length of a vendor control request
+const MAX_CONTROL_LEN: usize = 64;
+
+/// Send `data` to the OUT endpoint of the log interface
+///
+/// Without an OUT endpoint, the data is sent by vendor control requests.
+pub fn send(device_info: &DeviceInfo, data: &[u8]) -> Result<(), rusb::Error> {
+    let dev = device_info.device();
+    let iface = device_info.iface_id;
+    let conf_desc = dev.active_config_descriptor()?;
+    let ep = conf_desc
+        .interfaces()
+        .filter(|i| i.number() == iface)
+        .flat_map(|i| i.descriptors())
+        .flat_map(|d| d.endpoint_descriptors().collect::<Vec<_>>())
+        .find(|ep| ep.direction() == Direction::Out && ep.transfer_type() == TransferType::Bulk)
+        .map(|ep| ep.address());
+    let handle = dev.open()?;
+    handle.claim_interface(iface)?;
+    let Some(ep) = ep else {
+        return send_control(&handle, iface, data);
+    };
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = handle.write_bulk(ep, rest, TIMEOUT)?;
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+/// Send `data` by vendor control requests reserved for the application
+fn send_control(handle: &DeviceHandle<Context>, iface: u8, data: &[u8]) -> Result<(), rusb::Error> {
+    let request_type = rusb::request_type(
+        Direction::Out,
+        rusb::RequestType::Vendor,
+        rusb::Recipient::Interface,
+    );
+    for chunk in data.chunks(MAX_CONTROL_LEN) {
+        handle
+            .write_control(request_type, FIRST_USER_REQUEST, 0, iface as u16, chunk, TIMEOUT)
+            .map_err(|e| match e {
+                // the device has no command handler
+                rusb::Error::Pipe => rusb::Error::NotSupported,
+                e => e,
+            })?;
+    }
+    Ok(())
+}
+
+/// Parse a string of hex digits, optionally separated by whitespace
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    decode::parse_hex(&s.split_whitespace().collect::<String>())
+}
@@ -0,0 +1,140 @@
+//! Symbolication of code addresses
+//!
+//! With `--elf`, hex numbers in the log that point into the code of the
+//! firmware, e.g. the return addresses of `[BACKTRACE]` lines or addresses
+//! logged by a panic handler, are followed by the function and, if the ELF
+//! file has debug information, the source location they belong to, e.g.
+//! `0x08000a3f <app::run at src/main.rs:42>`. Numbers that are not code
+//! addresses are left as they are.
+//!
+//! Return addresses point behind the call instruction and have the lowest bit
+//! set on Thumb targets, so the addresses of backtraces are looked up one byte
+//! before the instruction, which belongs to the call.
+//!
+
+use regex::Regex;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::LazyLock;
+
+const BACKTRACE_MARKER: &str = "[BACKTRACE]";
+
+static ADDRESS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b0x[0-9a-fA-F]{1,16}\b").unwrap());
+
+/// Symbols and debug information of the firmware
+pub struct Symbols {
+    loader: addr2line::Loader,
+}
+
+impl Symbols {
+    /// Load the symbols of an ELF file
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let loader =
+            addr2line::Loader::new(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(Symbols { loader })
+    }
+
+    /// Function and source location of a code address, None if the address
+    /// is not within a function
+    fn resolve(&self, address: u64) -> Option<String> {
+        let frame = self
+            .loader
+            .find_frames(address)
+            .ok()
+            .and_then(|mut frames| frames.next().ok().flatten());
+        let function = frame
+            .as_ref()
+            .and_then(|frame| frame.function.as_ref()?.demangle().ok())
+            .or_else(|| {
+                let name = self.loader.find_symbol(address)?;
+                Some(addr2line::demangle_auto(Cow::from(name), None))
+            })?;
+        let location = frame.as_ref().and_then(|frame| frame.location.as_ref());
+        Some(match location.and_then(|l| Some((l.file?, l.line?))) {
+            Some((file, line)) => format!("{function} at {file}:{line}"),
+            None => function.into_owned(),
+        })
+    }
+}
+
+/// Writer adding the functions and locations to the code addresses of each
+/// line
+pub struct Symbolizer<W: Write> {
+    out: W,
+    symbols: Symbols,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+}
+
+impl<W: Write> Symbolizer<W> {
+    pub fn new(out: W, symbols: Symbols) -> Self {
+        Symbolizer {
+            out,
+            symbols,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Symbolizer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line);
+                let line = annotate(&line, |address| self.symbols.resolve(address));
+                self.out.write_all(line.as_bytes())?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Add the description `resolve` returns for each address in a line
+fn annotate(line: &str, resolve: impl Fn(u64) -> Option<String>) -> String {
+    let backtrace = line.contains(BACKTRACE_MARKER);
+    ADDRESS
+        .replace_all(line, |caps: &regex::Captures| {
+            let text = &caps[0];
+            let Ok(address) = u64::from_str_radix(&text[2..], 16) else {
+                return text.to_string();
+            };
+            let address = match backtrace {
+                true => (address & !1).saturating_sub(1),
+                false => address & !1,
+            };
+            match resolve(address) {
+                Some(symbol) => format!("{text} <{symbol}>"),
+                None => text.to_string(),
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_annotated() {
+        let resolve = |address| match address {
+            0x0800_0100..0x0800_0200 => Some(format!("app::run@{address:x}")),
+            _ => None,
+        };
+        assert_eq!(
+            annotate("[BACKTRACE] 0x08000105 0x08000201 0x20001000\n", resolve),
+            "[BACKTRACE] 0x08000105 <app::run@8000103> 0x08000201 <app::run@80001ff> \
+             0x20001000\n"
+        );
+        assert_eq!(
+            annotate("[PANIC] fault at 0x08000101, sp 0x2000", resolve),
+            "[PANIC] fault at 0x08000101 <app::run@8000100>, sp 0x2000"
+        );
+    }
+}
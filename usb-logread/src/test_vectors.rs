@@ -0,0 +1,108 @@
+//! Golden transcript tests of the stream decoder
+//!
+//! Test vectors are stored in one directory per protocol version. Each vector
+//! is a pair of files: `<name>.bin` holds the raw byte stream as received from
+//! a device and `<name>.txt` the expected decoder output. The vectors are
+//! checked by `cargo test`, so that protocol changes altering the decoding are
+//! caught without hardware.
+//!
+
+use crate::decode::Decoder;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directory of the test vectors checked into the repository
+pub const DEFAULT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-vectors");
+
+/// Check all test vectors below `dir` and print the results
+///
+/// With `update`, the golden outputs are rewritten instead of compared.
+/// Returns true if all vectors passed.
+pub fn run(dir: &Path, update: bool) -> io::Result<bool> {
+    let mut passed = true;
+    for bin in find_vectors(dir)? {
+        let golden = bin.with_extension("txt");
+        let name = bin.strip_prefix(dir).unwrap_or(&bin).display();
+        if update {
+            fs::write(&golden, decode(&fs::read(&bin)?, usize::MAX)?)?;
+            println!("{name}: updated");
+            continue;
+        }
+        match check(&bin, &golden)? {
+            Ok(()) => println!("{name}: PASS"),
+            Err(msg) => {
+                println!("{name}: FAIL ({msg})");
+                passed = false;
+            }
+        }
+    }
+    Ok(passed)
+}
+
+/// Find all `.bin` files in the version directories below `dir`
+fn find_vectors(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut vectors = Vec::new();
+    for version in fs::read_dir(dir)? {
+        let version = version?.path();
+        if !version.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(&version)? {
+            let file = file?.path();
+            if file.extension().is_some_and(|ext| ext == "bin") {
+                vectors.push(file);
+            }
+        }
+    }
+    vectors.sort();
+    Ok(vectors)
+}
+
+/// Decode a byte stream passing it to the decoder in chunks of `chunk_len`
+fn decode(data: &[u8], chunk_len: usize) -> io::Result<Vec<u8>> {
+    let mut decoder = Decoder::new(true);
+    let mut out = Vec::new();
+    for chunk in data.chunks(chunk_len.max(1)) {
+        decoder.decode(chunk, &mut out)?;
+    }
+    Ok(out)
+}
+
+/// Compare the decoder output for `bin` with `golden`
+///
+/// The stream is decoded at once and byte by byte to verify that the result
+/// does not depend on how the data is split into USB transfers.
+fn check(bin: &Path, golden: &Path) -> io::Result<Result<(), String>> {
+    let data = fs::read(bin)?;
+    let expected = fs::read(golden)?;
+    for chunk_len in [usize::MAX, 1] {
+        let actual = decode(&data, chunk_len)?;
+        if actual != expected {
+            let line = actual
+                .split(|&b| b == b'\n')
+                .zip(expected.split(|&b| b == b'\n'))
+                .take_while(|(a, e)| a == e)
+                .count()
+                + 1;
+            return Ok(Err(format!("output differs at line {line}, chunk length {chunk_len}")));
+        }
+    }
+    Ok(Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_transcripts() {
+        let dir = Path::new(DEFAULT_DIR);
+        let vectors = find_vectors(dir).unwrap();
+        assert!(!vectors.is_empty());
+        for bin in vectors {
+            let result = check(&bin, &bin.with_extension("txt")).unwrap();
+            assert_eq!(result, Ok(()), "{}", bin.display());
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Host timestamps of the log lines
+//!
+//! With `--timestamp`, each line of the log is prefixed with the time the
+//! host received its first byte, so that the device log can be correlated
+//! with other logs of the host. Lines tagged with a channel keep the tag in
+//! front, so that `--channel-dir` still separates them.
+//!
+//! Besides absolute times, the timestamps can give the seconds since the
+//! start of the capture or since the previous line, which makes timing
+//! regressions stand out.
+//!
+
+use crate::demux::split_channel;
+use chrono::{DateTime, Local, SecondsFormat};
+use clap::ValueEnum;
+use std::io::{self, Write};
+
+/// Format of the timestamps
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// ISO 8601 date and time with the offset from UTC
+    Iso,
+    /// Seconds since the Unix epoch
+    Unix,
+    /// Local time of day
+    Time,
+    /// Seconds since the start of the capture
+    Relative,
+    /// Seconds since the previous line
+    Delta,
+}
+
+impl Format {
+    /// Format `time`, relative to `since` for the relative formats
+    fn format(self, time: &DateTime<Local>, since: &DateTime<Local>) -> String {
+        let seconds = || (*time - *since).num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6;
+        match self {
+            Format::Iso => time.to_rfc3339_opts(SecondsFormat::Micros, false),
+            Format::Unix => {
+                format!("{}.{:06}", time.timestamp(), time.timestamp_subsec_micros())
+            }
+            Format::Time => time.format("%H:%M:%S%.3f").to_string(),
+            Format::Relative => format!("{:.6}", seconds()),
+            Format::Delta => format!("+{:.6}", seconds()),
+        }
+    }
+}
+
+/// Writer prefixing each line with the time it was received
+pub struct Timestamps<W: Write> {
+    out: W,
+    format: Format,
+    /// Bytes of the current, not yet terminated line
+    line: Vec<u8>,
+    /// Time the first byte of the current line was received
+    received: Option<DateTime<Local>>,
+    /// Start of the capture
+    start: DateTime<Local>,
+    /// Time the previous line was received
+    previous: DateTime<Local>,
+}
+
+impl<W: Write> Timestamps<W> {
+    pub fn new(out: W, format: Format) -> Self {
+        Timestamps {
+            out,
+            format,
+            line: Vec::new(),
+            received: None,
+            start: Local::now(),
+            previous: Local::now(),
+        }
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let received = self.received.take().unwrap_or_else(Local::now);
+        let since = match self.format {
+            Format::Delta => self.previous,
+            _ => self.start,
+        };
+        let stamp = self.format.format(&received, &since);
+        self.previous = received;
+        self.out.write_all(&stamp_line(&self.line, &stamp))
+    }
+}
+
+/// Insert `stamp` into `line` after its channel tag, if any
+fn stamp_line(line: &[u8], stamp: &str) -> Vec<u8> {
+    let prefix_len = split_channel(line).map_or(0, |(_, text)| line.len() - text.len());
+    let mut stamped = Vec::with_capacity(line.len() + stamp.len() + 1);
+    stamped.extend_from_slice(&line[..prefix_len]);
+    stamped.extend_from_slice(stamp.as_bytes());
+    stamped.push(b' ');
+    stamped.extend_from_slice(&line[prefix_len..]);
+    stamped
+}
+
+impl<W: Write> Write for Timestamps<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if self.received.is_none() {
+                self.received = Some(Local::now());
+            }
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+                self.line.clear();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn stamps_after_channel_tag() {
+        let time = Local.timestamp_opt(1_700_000_000, 123_456_000).unwrap();
+        let stamp = Format::Unix.format(&time, &time);
+        assert_eq!(stamp, "1700000000.123456");
+        assert_eq!(stamp_line(b"[main.rs:1] a\n", &stamp), b"1700000000.123456 [main.rs:1] a\n");
+        assert_eq!(stamp_line(b"#2 x=1\n", &stamp), b"#2 1700000000.123456 x=1\n");
+        assert_eq!(Format::Time.format(&time, &time).len(), "00:00:00.000".len());
+        let later = time + chrono::Duration::microseconds(1_500_250);
+        assert_eq!(Format::Relative.format(&later, &time), "1.500250");
+        assert_eq!(Format::Delta.format(&later, &time), "+1.500250");
+    }
+}
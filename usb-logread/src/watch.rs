@@ -0,0 +1,60 @@
+//! Reporting of device arrival and removal
+//!
+//! The arrival and removal of devices having a log interface are printed
+//! with a timestamp, which helps to debug enumeration problems and flaky
+//! connections without reading the log.
+//!
+
+use crate::hotplug::{Event, Monitor};
+use crate::{describe, DeviceInfo, STOP};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Print device arrival and removal events until stopped
+///
+/// Only devices for which `selected` returns true are reported.
+pub fn watch(monitor: &mut Monitor, selected: impl Fn(&DeviceInfo) -> bool) {
+    let start = Instant::now();
+    // descriptions of the present devices by bus number and address, as a
+    // removed device cannot be asked for its strings
+    let mut present: BTreeMap<(u8, u8), String> = BTreeMap::new();
+    println!("Watching for devices with a log interface, press Ctrl-C to stop");
+    while !STOP.load(Ordering::Relaxed) {
+        match monitor.next_event(POLL_INTERVAL) {
+            Some(Event::Arrived(dev_info)) if selected(&dev_info) => {
+                let description = describe_iface(&dev_info);
+                print_event(start, '+', &description);
+                present.insert(key(&dev_info), description);
+            }
+            Some(Event::Left(dev_info)) => {
+                if let Some(description) = present.remove(&key(&dev_info)) {
+                    print_event(start, '-', &description);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn key(device_info: &DeviceInfo) -> (u8, u8) {
+    let dev = device_info.device();
+    (dev.bus_number(), dev.address())
+}
+
+/// Describe a device including its log interface
+fn describe_iface(device_info: &DeviceInfo) -> String {
+    let device = describe(device_info).unwrap_or_else(|e| {
+        let dev = device_info.device();
+        format!("Bus {:03} Device {:03}: {e}", dev.bus_number(), dev.address())
+    });
+    let transport = device_info.iface_type();
+    format!("{device} (interface {}, {transport})", device_info.iface_id)
+}
+
+fn print_event(start: Instant, event: char, description: &str) {
+    let t = start.elapsed().as_secs_f64();
+    println!("[{t:10.3}] {event} {description}");
+}